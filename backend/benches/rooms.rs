@@ -0,0 +1,247 @@
+//! Measures how much one flooded room slows down every other room.
+//!
+//! ```sh
+//! cargo bench --bench rooms
+//! BENCH_THREADS=1,2,8 BENCH_SECONDS=5 BENCH_FLOOD_RATE=5000 cargo bench --bench rooms
+//! ```
+//!
+//! Each run drives the real `ChatServer` and `Room` actors in-process. Anonymous members
+//! keep the database out of the picture. One room has `BENCH_BUSY_MEMBERS` members and
+//! receives `BENCH_FLOOD_RATE` messages a second; `BENCH_QUIET_ROOMS` rooms of three
+//! members each get a probe every couple of milliseconds. The report shows probe latency
+//! in the quiet rooms and deliveries per second in the busy one. `BENCH_THREADS=1` puts
+//! every room on a single thread, which is how the old single `ChatServer` actor behaved.
+//! Members that cannot keep up are cut off by their outbox, which the report also counts.
+
+use actix::prelude::*;
+use backend::commands::CommandRegistry;
+use backend::db::DbPool;
+use backend::filters::FilterChain;
+use backend::outbox::{self, Flush, Outbox, OutboxLimits};
+use backend::pubsub::LocalOnly;
+use backend::settings::Settings;
+use backend::ws::{ChatServer, ClientMessage, CloseSession, Connect, Joined};
+use diesel::pg::PgConnection;
+use diesel::r2d2::{self, ConnectionManager};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::Span;
+
+const QUIET_MEMBERS: usize = 3;
+const PROBE_INTERVAL: Duration = Duration::from_millis(2);
+
+fn env_or<T: std::str::FromStr>(key: &str, default: T) -> T {
+    std::env::var(key).ok().and_then(|v| v.parse().ok()).unwrap_or(default)
+}
+
+struct Stats {
+    epoch: Instant,
+    delivered: AtomicU64,
+    latencies: Mutex<Vec<Duration>>,
+}
+
+struct Member {
+    stats: Arc<Stats>,
+    outbox: Arc<OnceLock<Arc<Outbox>>>,
+}
+
+impl Actor for Member {
+    type Context = Context<Self>;
+}
+
+impl Handler<Flush> for Member {
+    type Result = ();
+    fn handle(&mut self, _: Flush, _: &mut Context<Self>) {
+        let Some(outbox) = self.outbox.get() else { return };
+        for frame in outbox.drain().frames {
+            self.stats.delivered.fetch_add(1, Ordering::Relaxed);
+            if let Some(sent) = frame.text.strip_prefix("probe ").and_then(|n| n.parse::<u64>().ok()) {
+                let latency = self.stats.epoch.elapsed().saturating_sub(Duration::from_nanos(sent));
+                self.stats.latencies.lock().unwrap().push(latency);
+            }
+        }
+    }
+}
+
+impl Handler<CloseSession> for Member {
+    type Result = ();
+    fn handle(&mut self, _: CloseSession, _: &mut Context<Self>) {}
+}
+
+// Never connected to: anonymous members do not touch the database
+fn unused_pool() -> DbPool {
+    r2d2::Pool::builder()
+        .max_size(1)
+        .min_idle(Some(0))
+        .build_unchecked(ConnectionManager::<PgConnection>::new("postgres://unused"))
+}
+
+async fn join(server: &Addr<ChatServer>, room: &str, arbiter: &ArbiterHandle, stats: &Arc<Stats>) -> Joined {
+    let (stats, slot) = (stats.clone(), Arc::new(OnceLock::new()));
+    let outbox = slot.clone();
+    let member = Member::start_in_arbiter(arbiter, move |_| Member { stats, outbox });
+    let limits = OutboxLimits::from_settings(&Settings::default().websocket);
+    let outbox = slot.get_or_init(|| Arc::new(Outbox::new(member.clone().recipient(), limits)));
+    server
+        .send(Connect {
+            outbox: outbox.clone(),
+            close: member.recipient(),
+            user_id: None,
+            room: room.to_owned(),
+            span: Span::none(),
+        })
+        .await
+        .expect("chat server is running")
+}
+
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    sorted[((sorted.len() - 1) as f64 * p) as usize]
+}
+
+struct Run {
+    room_threads: usize,
+    seconds: u64,
+    quiet_rooms: usize,
+    busy_members: usize,
+    flood_rate: usize,
+}
+
+async fn run(config: Run) {
+    let Run {
+        room_threads,
+        seconds,
+        quiet_rooms,
+        busy_members,
+        flood_rate,
+    } = config;
+    let mut settings = Settings::default();
+    settings.websocket.room_threads = room_threads;
+    let server = ChatServer::new(
+        unused_pool(),
+        CommandRegistry::with_builtins(),
+        // Filters query the database, which the benchmark does without
+        FilterChain::default(),
+        settings.limits.max_message_ttl_secs,
+        Box::new(LocalOnly),
+        &settings,
+    )
+    .start();
+    // Members stand in for sessions, which live on the HTTP workers
+    let member_arbiters: Vec<Arbiter> = (0..2).map(|_| Arbiter::new()).collect();
+    let quiet = Arc::new(Stats {
+        epoch: Instant::now(),
+        delivered: AtomicU64::new(0),
+        latencies: Mutex::new(Vec::new()),
+    });
+    let busy = Arc::new(Stats {
+        epoch: quiet.epoch,
+        delivered: AtomicU64::new(0),
+        latencies: Mutex::new(Vec::new()),
+    });
+
+    let mut busy_room: Option<Joined> = None;
+    for i in 0..busy_members {
+        let joined = join(&server, "busy", &member_arbiters[i % 2].handle(), &busy).await;
+        busy_room.get_or_insert(joined);
+    }
+    let busy_room = busy_room.expect("BENCH_BUSY_MEMBERS must be at least 1");
+    let mut probes: Vec<Joined> = Vec::new();
+    for room in 0..quiet_rooms {
+        for i in 0..QUIET_MEMBERS {
+            let joined = join(&server, &format!("quiet-{}", room), &member_arbiters[i % 2].handle(), &quiet).await;
+            if i == 0 {
+                probes.push(joined);
+            }
+        }
+    }
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let flood = {
+        let (stop, room, id) = (stop.clone(), busy_room.room.clone(), busy_room.id);
+        thread::spawn(move || {
+            // flood_rate messages a second, sent in one batch per millisecond
+            while !stop.load(Ordering::Relaxed) {
+                for _ in 0..flood_rate.div_ceil(1000) {
+                    room.do_send(ClientMessage {
+                        id,
+                        user_id: None,
+                        message: "flood".to_owned(),
+                        span: Span::none(),
+                    });
+                }
+                thread::sleep(Duration::from_millis(1));
+            }
+        })
+    };
+
+    let before = outbox::stats();
+    let started = Instant::now();
+    let mut sent = 0;
+    while started.elapsed() < Duration::from_secs(seconds) {
+        let probe: &Joined = &probes[sent % probes.len()];
+        sent += 1;
+        probe.room.do_send(ClientMessage {
+            id: probe.id,
+            user_id: None,
+            message: format!("probe {}", quiet.epoch.elapsed().as_nanos()),
+            span: Span::none(),
+        });
+        actix::clock::sleep(PROBE_INTERVAL).await;
+    }
+    stop.store(true, Ordering::Relaxed);
+    flood.join().unwrap();
+    let elapsed = started.elapsed();
+
+    let after = outbox::stats();
+    let mut latencies = std::mem::take(&mut *quiet.latencies.lock().unwrap());
+    latencies.sort();
+    println!(
+        "room_threads={:<3} quiet p50={:>9.2?} p99={:>9.2?} max={:>9.2?} ({}/{} probes arrived)  busy {:>10.0} deliveries/s",
+        room_threads,
+        percentile(&latencies, 0.50),
+        percentile(&latencies, 0.99),
+        latencies.last().copied().unwrap_or_default(),
+        latencies.len() / QUIET_MEMBERS,
+        sent,
+        busy.delivered.load(Ordering::Relaxed) as f64 / elapsed.as_secs_f64(),
+    );
+    println!(
+        "                 outbox peak depth {}, {} ephemeral frames dropped, {} slow members cut off",
+        after.peak_depth,
+        after.dropped_ephemeral - before.dropped_ephemeral,
+        after.slow_consumer_disconnects - before.slow_consumer_disconnects,
+    );
+}
+
+fn main() {
+    let seconds = env_or("BENCH_SECONDS", 3);
+    let quiet_rooms = env_or("BENCH_QUIET_ROOMS", 50);
+    let busy_members = env_or("BENCH_BUSY_MEMBERS", 500);
+    let flood_rate = env_or("BENCH_FLOOD_RATE", 2000);
+    let threads: Vec<usize> = std::env::var("BENCH_THREADS")
+        .map(|list| list.split(',').filter_map(|n| n.trim().parse().ok()).collect())
+        .unwrap_or_else(|_| vec![1, 4]);
+
+    println!(
+        "{} quiet rooms x {} members, 1 busy room x {} members at {} messages/s, {}s per run",
+        quiet_rooms, QUIET_MEMBERS, busy_members, flood_rate, seconds
+    );
+    for room_threads in threads {
+        // A fresh system per run, so the previous run's backlog cannot leak into this one
+        let system = System::new();
+        system.block_on(run(Run {
+            room_threads,
+            seconds,
+            quiet_rooms,
+            busy_members,
+            flood_rate,
+        }));
+        System::current().stop();
+        system.run().expect("system shuts down cleanly");
+    }
+}
@@ -0,0 +1,74 @@
+//! Local stand-in for a webhook receiver, for trying outgoing webhooks end to end.
+//!
+//! ```sh
+//! WEBHOOK_SECRET=<secret from POST /groups/{id}/webhooks> cargo run --example webhook_receiver
+//! ```
+//!
+//! Set `webhooks.allow_private_destinations = true` on the backend, then register
+//! `http://127.0.0.1:9000/hook` as the webhook URL. Set `FAIL_FIRST=3` to answer
+//! the first three deliveries with a 500 and watch the retries in the delivery log.
+
+use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+struct Receiver {
+    secret: String,
+    fail_first: usize,
+    received: AtomicUsize,
+}
+
+fn header<'a>(req: &'a HttpRequest, name: &str) -> &'a str {
+    req.headers().get(name).and_then(|v| v.to_str().ok()).unwrap_or("")
+}
+
+fn signature_matches(secret: &str, timestamp: &str, body: &[u8], signature: &str) -> bool {
+    let Some(hex_sig) = signature.strip_prefix("sha256=") else { return false };
+    let Ok(expected) = hex::decode(hex_sig) else { return false };
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(timestamp.as_bytes());
+    mac.update(b".");
+    mac.update(body);
+    mac.verify_slice(&expected).is_ok()
+}
+
+async fn hook(req: HttpRequest, body: web::Bytes, state: web::Data<Receiver>) -> HttpResponse {
+    let n = state.received.fetch_add(1, Ordering::SeqCst) + 1;
+    let valid = signature_matches(
+        &state.secret,
+        header(&req, "X-NeuroChat-Timestamp"),
+        &body,
+        header(&req, "X-NeuroChat-Signature"),
+    );
+    println!(
+        "#{} {} delivery={} signature={} body={}",
+        n,
+        header(&req, "X-NeuroChat-Event"),
+        header(&req, "X-NeuroChat-Delivery"),
+        if valid { "ok" } else { "INVALID" },
+        String::from_utf8_lossy(&body)
+    );
+    if !valid {
+        return HttpResponse::Unauthorized().finish();
+    }
+    if n <= state.fail_first {
+        return HttpResponse::InternalServerError().finish();
+    }
+    HttpResponse::NoContent().finish()
+}
+
+#[actix_web::main]
+async fn main() -> std::io::Result<()> {
+    let state = web::Data::new(Receiver {
+        secret: std::env::var("WEBHOOK_SECRET").expect("WEBHOOK_SECRET must be set"),
+        fail_first: std::env::var("FAIL_FIRST").ok().and_then(|v| v.parse().ok()).unwrap_or(0),
+        received: AtomicUsize::new(0),
+    });
+    let bind = std::env::var("RECEIVER_ADDR").unwrap_or_else(|_| "127.0.0.1:9000".to_owned());
+    println!("Listening for webhooks on http://{}/hook", bind);
+    HttpServer::new(move || App::new().app_data(state.clone()).route("/hook", web::post().to(hook)))
+        .bind(bind)?
+        .run()
+        .await
+}
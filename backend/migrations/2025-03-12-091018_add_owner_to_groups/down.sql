@@ -1,2 +1,2 @@
 -- This file should undo anything in `up.sql`
-ALTER TABLE groups DROP COLUMN owner;
+ALTER TABLE groups DROP COLUMN IF EXISTS members;
@@ -1,3 +1,3 @@
 -- This file should undo anything in `up.sql`
 ALTER TABLE groups 
-DROP COLUMN members;
+DROP COLUMN IF EXISTS members;
@@ -1,3 +1,5 @@
 -- Your SQL goes here
+-- 2025-03-12-091018_add_owner_to_groups already adds this column under the wrong
+-- name; IF NOT EXISTS keeps fresh databases from failing here
 ALTER TABLE groups 
-ADD COLUMN members JSONB NOT NULL DEFAULT '[]';
+ADD COLUMN IF NOT EXISTS members JSONB NOT NULL DEFAULT '[]';
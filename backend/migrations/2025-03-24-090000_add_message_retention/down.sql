@@ -0,0 +1,5 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX messages_group_id_timestamp_idx;
+DROP INDEX messages_expires_at_idx;
+ALTER TABLE messages DROP COLUMN expires_at;
+ALTER TABLE groups DROP COLUMN retention_seconds;
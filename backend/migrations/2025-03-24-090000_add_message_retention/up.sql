@@ -0,0 +1,8 @@
+-- NULL keeps messages forever
+ALTER TABLE groups ADD COLUMN retention_seconds BIGINT CHECK (retention_seconds > 0);
+
+-- Per-message time-to-live, resolved to an absolute time when the message is stored
+ALTER TABLE messages ADD COLUMN expires_at TIMESTAMP;
+
+CREATE INDEX messages_expires_at_idx ON messages (expires_at) WHERE expires_at IS NOT NULL;
+CREATE INDEX messages_group_id_timestamp_idx ON messages (group_id, timestamp);
@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE bot_api_keys;
+ALTER TABLE users DROP COLUMN owner_id;
+ALTER TABLE users DROP COLUMN is_bot;
@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE messages DROP COLUMN sender_name;
+ALTER TABLE messages DROP COLUMN integration_id;
+DROP TABLE incoming_webhooks;
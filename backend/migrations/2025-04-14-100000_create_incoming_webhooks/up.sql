@@ -0,0 +1,17 @@
+-- Per-group URLs that let scripts post into a room with a secret token
+CREATE TABLE incoming_webhooks (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    group_id UUID NOT NULL REFERENCES groups(id) ON DELETE CASCADE,
+    name TEXT NOT NULL,
+    token_hash TEXT NOT NULL,
+    created_by UUID REFERENCES users(id) ON DELETE SET NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT NOW(),
+    rotated_at TIMESTAMP,
+    last_used_at TIMESTAMP,
+    revoked_at TIMESTAMP
+);
+
+-- Messages posted by an integration have no sender_id; sender_name keeps the
+-- integration's name as it was when the message was posted
+ALTER TABLE messages ADD COLUMN integration_id UUID REFERENCES incoming_webhooks(id) ON DELETE SET NULL;
+ALTER TABLE messages ADD COLUMN sender_name TEXT;
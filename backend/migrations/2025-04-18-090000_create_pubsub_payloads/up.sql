@@ -0,0 +1,9 @@
+-- NOTIFY payloads are capped at 8000 bytes; larger cross-instance events are
+-- parked here and only their id is sent over the channel
+CREATE TABLE pubsub_payloads (
+    id BIGSERIAL PRIMARY KEY,
+    payload TEXT NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX pubsub_payloads_created_at_idx ON pubsub_payloads (created_at);
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+-- Nothing to undo: the columns it reconciles are owned by earlier migrations
+SELECT 1;
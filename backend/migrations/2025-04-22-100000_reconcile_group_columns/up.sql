@@ -0,0 +1,25 @@
+-- Databases set up from the migrations alone never got groups.owner, and the
+-- two members migrations may have left it with another default. Bring every
+-- database to the shape in schema.rs.
+ALTER TABLE groups ADD COLUMN IF NOT EXISTS members JSONB NOT NULL DEFAULT '[]';
+ALTER TABLE groups ALTER COLUMN members SET DEFAULT '[]';
+UPDATE groups SET members = '[]' WHERE members IS NULL;
+ALTER TABLE groups ALTER COLUMN members SET NOT NULL;
+
+DO $$
+BEGIN
+    IF NOT EXISTS (
+        SELECT 1 FROM information_schema.columns
+        WHERE table_schema = current_schema() AND table_name = 'groups' AND column_name = 'owner'
+    ) THEN
+        ALTER TABLE groups ADD COLUMN owner UUID;
+        -- Hand ownerless groups to one of their members
+        UPDATE groups SET owner = (
+            SELECT user_id FROM user_groups WHERE user_groups.group_id = groups.id ORDER BY user_id LIMIT 1
+        );
+        -- Nobody can reach a group with no members
+        DELETE FROM groups WHERE owner IS NULL;
+        ALTER TABLE groups ALTER COLUMN owner SET NOT NULL;
+    END IF;
+END
+$$;
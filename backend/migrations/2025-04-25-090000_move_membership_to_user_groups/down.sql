@@ -0,0 +1,10 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE groups ADD COLUMN members JSONB NOT NULL DEFAULT '[]';
+UPDATE groups SET members = COALESCE(
+    (SELECT jsonb_agg(user_id::text ORDER BY joined_at) FROM user_groups WHERE user_groups.group_id = groups.id),
+    '[]'
+);
+
+DROP INDEX user_groups_group_id_joined_at_idx;
+ALTER TABLE user_groups DROP COLUMN role;
+ALTER TABLE user_groups DROP COLUMN joined_at;
@@ -0,0 +1,15 @@
+-- user_groups is the only record of membership; groups.members was a copy of it
+ALTER TABLE user_groups ADD COLUMN joined_at TIMESTAMP NOT NULL DEFAULT NOW();
+ALTER TABLE user_groups ADD COLUMN role TEXT NOT NULL DEFAULT 'member' CHECK (role IN ('owner', 'member'));
+
+-- Owners are always members
+INSERT INTO user_groups (user_id, group_id)
+SELECT owner, id FROM groups
+ON CONFLICT DO NOTHING;
+UPDATE user_groups SET role = 'owner'
+FROM groups
+WHERE groups.id = user_groups.group_id AND groups.owner = user_groups.user_id;
+
+CREATE INDEX user_groups_group_id_joined_at_idx ON user_groups (group_id, joined_at);
+
+ALTER TABLE groups DROP COLUMN members;
@@ -0,0 +1,2 @@
+-- Disabled accounts keep their history but can no longer sign in, connect or post
+ALTER TABLE users ADD COLUMN disabled_at TIMESTAMP;
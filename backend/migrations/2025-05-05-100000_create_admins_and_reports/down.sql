@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE reports;
+ALTER TABLE users DROP COLUMN is_admin;
@@ -0,0 +1,19 @@
+-- Site-wide administrators may use the /admin API
+ALTER TABLE users ADD COLUMN is_admin BOOLEAN NOT NULL DEFAULT FALSE;
+
+-- Reported messages and users. The content is copied at report time so it
+-- can still be reviewed after the message is deleted.
+-- status is 'open' or 'resolved'
+CREATE TABLE reports (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    reporter_id UUID REFERENCES users(id) ON DELETE SET NULL,
+    group_id UUID REFERENCES groups(id) ON DELETE CASCADE,
+    message_id UUID REFERENCES messages(id) ON DELETE SET NULL,
+    reported_user_id UUID REFERENCES users(id) ON DELETE CASCADE,
+    reason TEXT NOT NULL,
+    content_snapshot TEXT,
+    status TEXT NOT NULL DEFAULT 'open',
+    created_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX reports_status_created_at_idx ON reports (status, created_at);
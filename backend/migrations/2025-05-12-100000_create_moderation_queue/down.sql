@@ -0,0 +1,13 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE audit_log;
+DROP TABLE group_bans;
+DROP INDEX reports_group_id_status_idx;
+ALTER TABLE reports DROP COLUMN resolution_note;
+ALTER TABLE reports DROP COLUMN outcome;
+ALTER TABLE reports DROP COLUMN resolved_at;
+ALTER TABLE reports DROP COLUMN resolved_by;
+ALTER TABLE reports DROP COLUMN claimed_at;
+ALTER TABLE reports DROP COLUMN claimed_by;
+UPDATE user_groups SET role = 'member' WHERE role = 'moderator';
+ALTER TABLE user_groups DROP CONSTRAINT user_groups_role_check;
+ALTER TABLE user_groups ADD CONSTRAINT user_groups_role_check CHECK (role IN ('owner', 'member'));
@@ -0,0 +1,41 @@
+-- Moderators work a group's report queue alongside its owner
+ALTER TABLE user_groups DROP CONSTRAINT user_groups_role_check;
+ALTER TABLE user_groups ADD CONSTRAINT user_groups_role_check CHECK (role IN ('owner', 'moderator', 'member'));
+
+-- status is now 'open', 'claimed' or 'resolved'; outcome is 'dismiss',
+-- 'delete_message', 'mute' or 'ban' once resolved
+ALTER TABLE reports ADD COLUMN claimed_by UUID REFERENCES users(id) ON DELETE SET NULL;
+ALTER TABLE reports ADD COLUMN claimed_at TIMESTAMP;
+ALTER TABLE reports ADD COLUMN resolved_by UUID REFERENCES users(id) ON DELETE SET NULL;
+ALTER TABLE reports ADD COLUMN resolved_at TIMESTAMP;
+ALTER TABLE reports ADD COLUMN outcome TEXT;
+ALTER TABLE reports ADD COLUMN resolution_note TEXT;
+
+CREATE INDEX reports_group_id_status_idx ON reports (group_id, status);
+
+-- Banned users are removed from the group and cannot join it again
+CREATE TABLE group_bans (
+    group_id UUID NOT NULL REFERENCES groups(id) ON DELETE CASCADE,
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    banned_by UUID REFERENCES users(id) ON DELETE SET NULL,
+    reason TEXT,
+    created_at TIMESTAMP NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (group_id, user_id)
+);
+
+-- Who did what to whom. Group and target ids are not foreign keys so entries
+-- outlive what they describe.
+CREATE TABLE audit_log (
+    id BIGSERIAL PRIMARY KEY,
+    actor_id UUID REFERENCES users(id) ON DELETE SET NULL,
+    action TEXT NOT NULL,
+    group_id UUID,
+    target_type TEXT NOT NULL,
+    target_id UUID,
+    ip TEXT,
+    before JSONB,
+    after JSONB,
+    created_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX audit_log_group_id_created_at_idx ON audit_log (group_id, created_at);
@@ -0,0 +1,2 @@
+ALTER TABLE reports DROP COLUMN kind;
+DROP TABLE group_banned_words;
@@ -0,0 +1,10 @@
+-- Words a group does not want posted, stored lowercase and matched as whole words
+CREATE TABLE group_banned_words (
+    group_id UUID NOT NULL REFERENCES groups(id) ON DELETE CASCADE,
+    word TEXT NOT NULL,
+    PRIMARY KEY (group_id, word)
+);
+
+-- Messages a content filter holds back wait in the moderation queue as 'held'
+-- entries until a moderator approves or dismisses them
+ALTER TABLE reports ADD COLUMN kind TEXT NOT NULL DEFAULT 'report' CHECK (kind IN ('report', 'held'));
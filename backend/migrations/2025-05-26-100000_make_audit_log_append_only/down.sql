@@ -0,0 +1,9 @@
+DROP INDEX audit_log_target_id_idx;
+DROP INDEX audit_log_actor_id_idx;
+DROP INDEX audit_log_created_at_idx;
+DROP TRIGGER audit_log_no_truncate ON audit_log;
+DROP TRIGGER audit_log_no_update_or_delete ON audit_log;
+DROP FUNCTION audit_log_append_only();
+-- Entries may name users that are gone by now
+ALTER TABLE audit_log ADD CONSTRAINT audit_log_actor_id_fkey
+    FOREIGN KEY (actor_id) REFERENCES users(id) ON DELETE SET NULL NOT VALID;
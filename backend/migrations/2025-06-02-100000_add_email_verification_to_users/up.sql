@@ -0,0 +1,5 @@
+-- New accounts start unverified and confirm their address through a mailed link
+ALTER TABLE users ADD COLUMN email_verified_at TIMESTAMP;
+
+-- Accounts made before verification existed, and bots, which never receive mail, count as verified
+UPDATE users SET email_verified_at = NOW();
@@ -0,0 +1,3 @@
+-- Session tokens carry the version they were issued under; bumping it signs
+-- the account out everywhere, e.g. after a password change
+ALTER TABLE users ADD COLUMN session_version INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,570 @@
+use crate::audit;
+use crate::bots;
+use crate::db::{self, DbPool};
+use crate::errors::{ApiError, ApiResult};
+use crate::groups;
+use crate::models::{Group, Message, NewAuditEntry, NewMessage, Report, User};
+use crate::polls;
+use crate::ratelimit::RateLimits;
+use crate::sessions;
+use crate::settings::Settings;
+use crate::ws::{ChatServer, DisconnectUser, ListRooms, MessagesDeleted};
+use actix::Addr;
+use actix_web::{web, HttpRequest, HttpResponse};
+use chrono::NaiveDateTime;
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::fmt;
+use uuid::Uuid;
+
+// Demo accounts created by `neurochat-admin seed`, all with this password
+pub const SEED_PASSWORD: &str = "password";
+const SEED_USERS: [&str; 3] = ["alice", "bob", "carol"];
+const SEED_GROUPS: [(&str, &str, &str); 2] = [
+    ("general", "Say hello", "alice"),
+    ("random", "Anything goes", "bob"),
+];
+const SEED_MESSAGES: [(&str, &str); 3] = [
+    ("alice", "Welcome to NeuroChat!"),
+    ("bob", "Hi everyone"),
+    ("carol", "Try /help for the slash commands"),
+];
+
+// Largest page the list endpoints return
+const PAGE_MAX: i64 = 200;
+
+/// Who is using the admin API.
+#[derive(Debug, Clone, Copy)]
+pub enum AdminActor {
+    // Holder of admin.api_token, e.g. neurochat-admin
+    Operator,
+    // A user with users.is_admin
+    User(Uuid),
+}
+
+impl AdminActor {
+    pub fn user_id(self) -> Option<Uuid> {
+        match self {
+            AdminActor::Operator => None,
+            AdminActor::User(user_id) => Some(user_id),
+        }
+    }
+
+    /// An audit entry for something this admin did to `target_id`.
+    pub fn audit_entry(self, action: &str, target_type: &str, target_id: Uuid, ip: Option<String>) -> NewAuditEntry {
+        NewAuditEntry {
+            actor_id: self.user_id(),
+            action: action.to_owned(),
+            target_type: target_type.to_owned(),
+            target_id: Some(target_id),
+            ip,
+            ..Default::default()
+        }
+    }
+}
+
+impl fmt::Display for AdminActor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AdminActor::Operator => f.write_str("operator"),
+            AdminActor::User(user_id) => write!(f, "{}", user_id),
+        }
+    }
+}
+
+/// Lets the request through for `Authorization: Bearer <admin.api_token>` or
+/// for the session token of an enabled site admin.
+pub async fn require_admin(req: &HttpRequest, settings: &Settings, pool: &DbPool) -> ApiResult<AdminActor> {
+    let token = bots::bearer_token(req).ok_or(ApiError::Unauthorized("Admin credentials required"))?;
+    // Compare digests so the time taken says nothing about how much of the token matched
+    if let Some(expected) = &settings.admin.api_token {
+        if Sha256::digest(token.as_bytes()) == Sha256::digest(expected.as_bytes()) {
+            return Ok(AdminActor::Operator);
+        }
+    }
+    let user = sessions::current_user(req, pool).await?;
+    if !user.is_admin {
+        return Err(ApiError::Forbidden("Only site admins can do that"));
+    }
+    Ok(AdminActor::User(user.id))
+}
+
+/// True for an enabled account with the site admin role.
+pub fn is_site_admin(user_id: Uuid, conn: &mut PgConnection) -> QueryResult<bool> {
+    use crate::schema::users;
+    diesel::select(diesel::dsl::exists(
+        users::table
+            .find(user_id)
+            .filter(users::is_admin.eq(true))
+            .filter(users::disabled_at.is_null()),
+    ))
+    .get_result(conn)
+}
+
+/// Rooms running on this instance with their session counts.
+pub async fn list_rooms(
+    req: HttpRequest,
+    settings: web::Data<Settings>,
+    pool: web::Data<DbPool>,
+    srv: web::Data<Addr<ChatServer>>,
+) -> ApiResult {
+    require_admin(&req, &settings, &pool).await?;
+    let rooms = srv
+        .send(ListRooms)
+        .await
+        .map_err(|e| ApiError::Internal(format!("chat server: {}", e)))?;
+    Ok(HttpResponse::Ok().json(rooms))
+}
+
+#[derive(Deserialize)]
+pub struct UserSearchQuery {
+    // Matched against usernames and emails, case-insensitively
+    pub q: Option<String>,
+    pub limit: Option<i64>,
+    pub offset: Option<i64>,
+}
+
+#[derive(Queryable, Serialize)]
+pub struct AdminUserView {
+    pub id: Uuid,
+    pub username: String,
+    pub email: String,
+    pub is_bot: bool,
+    pub is_admin: bool,
+    pub disabled_at: Option<NaiveDateTime>,
+    pub email_verified_at: Option<NaiveDateTime>,
+}
+
+// Escapes LIKE wildcards so a search for "a_b" matches only that text
+fn like_pattern(text: &str) -> String {
+    let escaped = text.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_");
+    format!("%{}%", escaped)
+}
+
+pub async fn list_users(
+    req: HttpRequest,
+    query: web::Query<UserSearchQuery>,
+    settings: web::Data<Settings>,
+    pool: web::Data<DbPool>,
+) -> ApiResult {
+    use crate::schema::users;
+
+    require_admin(&req, &settings, &pool).await?;
+    let response = db::run(&pool, move |conn| {
+        let mut found = users::table
+            .order(users::username)
+            .limit(query.limit.unwrap_or(50).clamp(1, PAGE_MAX))
+            .offset(query.offset.unwrap_or(0).max(0))
+            .select((
+                users::id,
+                users::username,
+                users::email,
+                users::is_bot,
+                users::is_admin,
+                users::disabled_at,
+                users::email_verified_at,
+            ))
+            .into_boxed();
+        if let Some(q) = query.q.as_deref().filter(|q| !q.is_empty()) {
+            let pattern = like_pattern(q);
+            found = found.filter(users::username.ilike(pattern.clone()).or(users::email.ilike(pattern)));
+        }
+        Ok(found.load::<AdminUserView>(conn)?)
+    })
+    .await?;
+    Ok(HttpResponse::Ok().json(response))
+}
+
+#[derive(Deserialize)]
+pub struct SuspendRequest {
+    // Shown to the user when their sessions are closed
+    pub reason: Option<String>,
+}
+
+/// Disables the account and closes its sessions on every instance.
+pub async fn suspend_user(
+    req: HttpRequest,
+    path: web::Path<Uuid>,
+    form: web::Json<SuspendRequest>,
+    settings: web::Data<Settings>,
+    pool: web::Data<DbPool>,
+    rate_limits: web::Data<RateLimits>,
+    srv: web::Data<Addr<ChatServer>>,
+) -> ApiResult {
+    let actor = require_admin(&req, &settings, &pool).await?;
+    let user_id = path.into_inner();
+    if let AdminActor::User(admin_id) = actor {
+        if admin_id == user_id {
+            return Err(ApiError::BadRequest("You cannot suspend yourself"));
+        }
+    }
+    let reason = form.into_inner().reason.unwrap_or_else(|| "Your account has been suspended".to_owned());
+    let entry = NewAuditEntry {
+        after: Some(json!({"reason": reason})),
+        ..actor.audit_entry("user.suspend", "user", user_id, Some(rate_limits.client_addr(&req)))
+    };
+    db::run(&pool, move |conn| {
+        conn.transaction(|conn| {
+            crate::schema::users::table
+                .find(user_id)
+                .select(crate::schema::users::id)
+                .first::<Uuid>(conn)
+                .map_err(ApiError::db("User not found"))?;
+            set_disabled(user_id, true, conn)?;
+            Ok(audit::record(conn, entry)?)
+        })
+    })
+    .await?;
+    srv.do_send(DisconnectUser {
+        user_id,
+        room: None,
+        reason,
+    });
+    tracing::info!(%actor, %user_id, "user suspended");
+    Ok(HttpResponse::Ok().json(json!({"message": "User suspended"})))
+}
+
+pub async fn unsuspend_user(
+    req: HttpRequest,
+    path: web::Path<Uuid>,
+    settings: web::Data<Settings>,
+    pool: web::Data<DbPool>,
+    rate_limits: web::Data<RateLimits>,
+) -> ApiResult {
+    let actor = require_admin(&req, &settings, &pool).await?;
+    let user_id = path.into_inner();
+    let entry = actor.audit_entry("user.unsuspend", "user", user_id, Some(rate_limits.client_addr(&req)));
+    db::run(&pool, move |conn| {
+        conn.transaction(|conn| {
+            set_disabled(user_id, false, conn)?;
+            Ok(audit::record(conn, entry)?)
+        })
+    })
+    .await?;
+    tracing::info!(%actor, %user_id, "user unsuspended");
+    Ok(HttpResponse::Ok().json(json!({"message": "User unsuspended"})))
+}
+
+/// Deletes any group, whoever owns it.
+pub async fn delete_group(
+    req: HttpRequest,
+    path: web::Path<Uuid>,
+    settings: web::Data<Settings>,
+    pool: web::Data<DbPool>,
+    rate_limits: web::Data<RateLimits>,
+) -> ApiResult {
+    let actor = require_admin(&req, &settings, &pool).await?;
+    let group_id = path.into_inner();
+    let ip = rate_limits.client_addr(&req);
+    db::run(&pool, move |conn| {
+        conn.transaction(|conn| {
+            let group = find_group(&group_id.to_string(), conn)?;
+            groups::delete_group_rows(group_id, conn)?;
+            Ok(audit::record(conn, groups::deletion_entry(&group, actor.user_id(), Some(ip)))?)
+        })
+    })
+    .await?;
+    tracing::info!(%actor, %group_id, "group force-deleted");
+    Ok(HttpResponse::Ok().json(json!({"message": "Group deleted successfully"})))
+}
+
+/// Deletes any message and removes it from its room's open sessions.
+pub async fn delete_message(
+    req: HttpRequest,
+    path: web::Path<Uuid>,
+    settings: web::Data<Settings>,
+    pool: web::Data<DbPool>,
+    rate_limits: web::Data<RateLimits>,
+    srv: web::Data<Addr<ChatServer>>,
+) -> ApiResult {
+    use crate::schema::messages;
+
+    let actor = require_admin(&req, &settings, &pool).await?;
+    let message_id = path.into_inner();
+    let entry = actor.audit_entry("message.delete", "message", message_id, Some(rate_limits.client_addr(&req)));
+    let room = db::run(&pool, move |conn| {
+        conn.transaction(|conn| {
+            let message = diesel::delete(messages::table.find(message_id))
+                .get_result::<Message>(conn)
+                .map_err(ApiError::db("Message not found"))?;
+            audit::record(
+                conn,
+                NewAuditEntry {
+                    group_id: message.group_id,
+                    before: Some(json!(message)),
+                    ..entry
+                },
+            )?;
+            match message.group_id {
+                Some(group_id) => Ok(polls::room_for_group(group_id, conn).optional()?),
+                None => Ok(None),
+            }
+        })
+    })
+    .await?;
+    if let Some(room) = room {
+        srv.do_send(MessagesDeleted {
+            room,
+            message_ids: vec![message_id],
+        });
+    }
+    tracing::info!(%actor, %message_id, "message force-deleted");
+    Ok(HttpResponse::Ok().json(json!({"message": "Message deleted successfully"})))
+}
+
+#[derive(Deserialize)]
+pub struct ReportQuery {
+    // "open" (the default), "claimed" or "resolved"
+    pub status: Option<String>,
+    pub limit: Option<i64>,
+    pub offset: Option<i64>,
+}
+
+// Oldest first, so the queue is worked in order
+pub async fn list_reports(
+    req: HttpRequest,
+    query: web::Query<ReportQuery>,
+    settings: web::Data<Settings>,
+    pool: web::Data<DbPool>,
+) -> ApiResult {
+    use crate::schema::reports;
+
+    require_admin(&req, &settings, &pool).await?;
+    let response = db::run(&pool, move |conn| {
+        let status = query.status.clone().unwrap_or_else(|| "open".to_owned());
+        Ok(reports::table
+            .filter(reports::status.eq(status))
+            .order(reports::created_at)
+            .limit(query.limit.unwrap_or(50).clamp(1, PAGE_MAX))
+            .offset(query.offset.unwrap_or(0).max(0))
+            .load::<Report>(conn)?)
+    })
+    .await?;
+    Ok(HttpResponse::Ok().json(response))
+}
+
+/// Looks a user up by username or email.
+pub fn find_user(who: &str, conn: &mut PgConnection) -> ApiResult<User> {
+    use crate::schema::users;
+    users::table
+        .filter(users::username.eq(who).or(users::email.eq(who)))
+        .first::<User>(conn)
+        .map_err(ApiError::db("User not found"))
+}
+
+/// Looks a group up by name or id.
+pub fn find_group(name_or_id: &str, conn: &mut PgConnection) -> ApiResult<Group> {
+    use crate::schema::groups;
+    let mut query = groups::table.select(Group::as_select()).into_boxed();
+    query = match Uuid::parse_str(name_or_id) {
+        Ok(id) => query.filter(groups::id.eq(id)),
+        Err(_) => query.filter(groups::name.eq(name_or_id)),
+    };
+    query.first(conn).map_err(ApiError::db("Group not found"))
+}
+
+/// Disables or re-enables an account. Disabled users cannot log in, connect
+/// to rooms or use their bots' API keys.
+pub fn set_disabled(user_id: Uuid, disabled: bool, conn: &mut PgConnection) -> ApiResult<()> {
+    use crate::schema::users;
+    let disabled_at = disabled.then(|| chrono::Utc::now().naive_utc());
+    diesel::update(users::table.find(user_id))
+        .set(users::disabled_at.eq(disabled_at))
+        .execute(conn)?;
+    Ok(())
+}
+
+/// Marks the account's email address as confirmed, as following the mailed link would.
+pub fn set_verified(user_id: Uuid, conn: &mut PgConnection) -> ApiResult<()> {
+    use crate::schema::users;
+    diesel::update(users::table.find(user_id).filter(users::email_verified_at.is_null()))
+        .set(users::email_verified_at.eq(chrono::Utc::now().naive_utc()))
+        .execute(conn)?;
+    Ok(())
+}
+
+/// Replaces the password and signs the account out of every session.
+pub fn set_password(user_id: Uuid, password_hash: &str, conn: &mut PgConnection) -> ApiResult<()> {
+    use crate::schema::users;
+    diesel::update(users::table.find(user_id))
+        .set((
+            users::password_hash.eq(password_hash),
+            users::session_version.eq(users::session_version + 1),
+        ))
+        .execute(conn)?;
+    Ok(())
+}
+
+/// Grants or revokes the site-wide admin role.
+pub fn set_admin(user_id: Uuid, is_admin: bool, conn: &mut PgConnection) -> ApiResult<()> {
+    use crate::schema::users;
+    diesel::update(users::table.find(user_id))
+        .set(users::is_admin.eq(is_admin))
+        .execute(conn)?;
+    Ok(())
+}
+
+/// Hard-deletes every message of a group and returns how many went.
+/// Polls go with their message through `ON DELETE CASCADE`.
+pub fn purge_messages(group_id: Uuid, conn: &mut PgConnection) -> ApiResult<usize> {
+    use crate::schema::messages;
+    Ok(diesel::delete(messages::table.filter(messages::group_id.eq(group_id))).execute(conn)?)
+}
+
+pub fn transfer_ownership(group_id: Uuid, new_owner: Uuid, conn: &mut PgConnection) -> ApiResult<()> {
+    conn.transaction(|conn| groups::transfer_ownership(group_id, new_owner, conn))
+}
+
+/// Creates a few demo users, groups and messages. Running it again only adds
+/// what is missing.
+pub fn seed(password_hash: &str, conn: &mut PgConnection) -> ApiResult<()> {
+    use crate::schema::{groups, user_groups, users};
+
+    conn.transaction(|conn| {
+        for name in SEED_USERS {
+            diesel::insert_into(users::table)
+                .values((
+                    users::username.eq(name),
+                    users::email.eq(format!("{}@example.com", name)),
+                    users::password_hash.eq(password_hash),
+                    users::email_verified_at.eq(chrono::Utc::now().naive_utc()),
+                ))
+                .on_conflict_do_nothing()
+                .execute(conn)?;
+        }
+        for (name, description, owner) in SEED_GROUPS {
+            let owner = find_user(owner, conn)?;
+            diesel::insert_into(groups::table)
+                .values((
+                    groups::name.eq(name),
+                    groups::description.eq(description),
+                    groups::owner.eq(owner.id),
+                ))
+                .on_conflict_do_nothing()
+                .execute(conn)?;
+            let group = find_group(name, conn)?;
+            for member in SEED_USERS {
+                let user = find_user(member, conn)?;
+                let role = if user.id == group.owner { "owner" } else { "member" };
+                diesel::insert_into(user_groups::table)
+                    .values((
+                        user_groups::user_id.eq(user.id),
+                        user_groups::group_id.eq(group.id),
+                        user_groups::role.eq(role),
+                    ))
+                    .on_conflict_do_nothing()
+                    .execute(conn)?;
+            }
+        }
+
+        let general = find_group(SEED_GROUPS[0].0, conn)?;
+        let has_history = diesel::select(diesel::dsl::exists(
+            crate::schema::messages::table.filter(crate::schema::messages::group_id.eq(general.id)),
+        ))
+        .get_result::<bool>(conn)?;
+        if !has_history {
+            for (sender, content) in SEED_MESSAGES {
+                let sender = find_user(sender, conn)?;
+                Message::create(
+                    NewMessage {
+                        id: Uuid::new_v4(),
+                        group_id: Some(general.id),
+                        sender_id: Some(sender.id),
+                        content: content.to_owned(),
+                        kind: "text".to_owned(),
+                        expires_at: None,
+                        integration_id: None,
+                        sender_name: None,
+                    },
+                    conn,
+                )?;
+            }
+        }
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sessions::SessionSigner;
+    use crate::settings::AuthSettings;
+    use crate::test_support;
+    use actix_web::test::TestRequest;
+
+    const OPERATOR_TOKEN: &str = "operator-token-of-at-least-32-characters";
+
+    fn settings() -> Settings {
+        let mut settings = Settings::default();
+        settings.admin.api_token = Some(OPERATOR_TOKEN.to_owned());
+        settings.auth = AuthSettings {
+            jwt_secret: Some("a-secret-of-at-least-thirty-two-chars".to_owned()),
+            ..Default::default()
+        };
+        settings
+    }
+
+    fn request(settings: &Settings, query: &str, token: Option<&str>) -> HttpRequest {
+        let mut req = TestRequest::get()
+            .uri(&format!("/admin/users{}", query))
+            .app_data(web::Data::new(SessionSigner::new(&settings.auth)));
+        if let Some(token) = token {
+            req = req.insert_header((actix_web::http::header::AUTHORIZATION, format!("Bearer {}", token)));
+        }
+        req.to_http_request()
+    }
+
+    #[actix_web::test]
+    async fn operator_token_is_accepted() {
+        let settings = settings();
+        // The operator token is checked without touching the database
+        let pool = diesel::r2d2::Pool::builder().build_unchecked(diesel::r2d2::ConnectionManager::new("postgres://unused"));
+        let actor = require_admin(&request(&settings, "", Some(OPERATOR_TOKEN)), &settings, &pool).await;
+        assert!(matches!(actor, Ok(AdminActor::Operator)));
+    }
+
+    #[actix_web::test]
+    async fn admin_ids_in_the_query_are_not_credentials() {
+        let Some(pool) = test_support::pool() else { return };
+        let settings = settings();
+        let admin = {
+            let mut conn = pool.get().unwrap();
+            let admin = test_support::user(&mut conn);
+            set_admin(admin.id, true, &mut conn).unwrap();
+            admin
+        };
+        let query = format!("?user_id={}", admin.id);
+        let actor = require_admin(&request(&settings, &query, None), &settings, &pool).await;
+        assert!(matches!(actor, Err(ApiError::Unauthorized(_))));
+        let actor = require_admin(&request(&settings, &query, Some(&admin.id.to_string())), &settings, &pool).await;
+        assert!(matches!(actor, Err(ApiError::Unauthorized(_))));
+    }
+
+    #[actix_web::test]
+    async fn sessions_only_pass_for_enabled_site_admins() {
+        let Some(pool) = test_support::pool() else { return };
+        let settings = settings();
+        let signer = SessionSigner::new(&settings.auth);
+        let (admin, member) = {
+            let mut conn = pool.get().unwrap();
+            let admin = test_support::user(&mut conn);
+            set_admin(admin.id, true, &mut conn).unwrap();
+            (admin, test_support::user(&mut conn))
+        };
+
+        let token = signer.issue(admin.id, 0).unwrap();
+        let actor = require_admin(&request(&settings, "", Some(&token)), &settings, &pool).await;
+        assert!(matches!(actor, Ok(AdminActor::User(id)) if id == admin.id));
+
+        let token = signer.issue(member.id, 0).unwrap();
+        let actor = require_admin(&request(&settings, "", Some(&token)), &settings, &pool).await;
+        assert!(matches!(actor, Err(ApiError::Forbidden(_))));
+
+        set_disabled(admin.id, true, &mut pool.get().unwrap()).unwrap();
+        let token = signer.issue(admin.id, 0).unwrap();
+        let actor = require_admin(&request(&settings, "", Some(&token)), &settings, &pool).await;
+        assert!(matches!(actor, Err(ApiError::Forbidden(_))));
+    }
+}
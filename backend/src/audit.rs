@@ -0,0 +1,218 @@
+use crate::admin;
+use crate::db::{self, DbPool};
+use crate::errors::ApiResult;
+use crate::models::{AuditEntry, NewAuditEntry};
+use crate::moderation;
+use crate::sessions;
+use crate::settings::Settings;
+use actix_web::{web, HttpRequest, HttpResponse};
+use chrono::NaiveDateTime;
+use diesel::prelude::*;
+use serde::Deserialize;
+use uuid::Uuid;
+
+// Largest page either audit endpoint returns
+const PAGE_MAX: i64 = 200;
+
+/// Appends `entry` to the audit log. Call it inside the transaction that makes
+/// the change, so the change and its record stand or fall together.
+///
+/// The table refuses updates and deletes, so entries are never rewritten.
+/// `actor_id` is `None` for the operator (admin token or `neurochat-admin`)
+/// and for anonymous attempts such as a failed login.
+pub fn record(conn: &mut PgConnection, entry: NewAuditEntry) -> QueryResult<()> {
+    diesel::insert_into(crate::schema::audit_log::table)
+        .values(&entry)
+        .execute(conn)?;
+    Ok(())
+}
+
+#[derive(Deserialize)]
+pub struct AuditQuery {
+    // Only entries with this action, e.g. "group.update", or with this prefix when it ends in '.'
+    pub action: Option<String>,
+    pub actor_id: Option<Uuid>,
+    pub target_id: Option<Uuid>,
+    // Site-wide log only; the group log is always filtered by its group
+    pub group_id: Option<Uuid>,
+    pub before: Option<NaiveDateTime>,
+    pub limit: Option<i64>,
+    pub offset: Option<i64>,
+}
+
+// Newest first
+fn search(group_id: Option<Uuid>, query: &AuditQuery, conn: &mut PgConnection) -> QueryResult<Vec<AuditEntry>> {
+    use crate::schema::audit_log;
+
+    let mut found = audit_log::table
+        .order(audit_log::id.desc())
+        .limit(query.limit.unwrap_or(50).clamp(1, PAGE_MAX))
+        .offset(query.offset.unwrap_or(0).max(0))
+        .into_boxed();
+    if let Some(group_id) = group_id.or(query.group_id) {
+        found = found.filter(audit_log::group_id.eq(group_id));
+    }
+    if let Some(action) = &query.action {
+        found = match action.strip_suffix('.') {
+            Some(prefix) => {
+                let escaped = prefix.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_");
+                found.filter(audit_log::action.like(format!("{}.%", escaped)))
+            }
+            None => found.filter(audit_log::action.eq(action.clone())),
+        };
+    }
+    if let Some(actor_id) = query.actor_id {
+        found = found.filter(audit_log::actor_id.eq(actor_id));
+    }
+    if let Some(target_id) = query.target_id {
+        found = found.filter(audit_log::target_id.eq(target_id));
+    }
+    if let Some(before) = query.before {
+        found = found.filter(audit_log::created_at.lt(before));
+    }
+    found.load(conn)
+}
+
+/// A group's own entries, for its signed-in owner, its moderators and site admins.
+pub async fn group_audit_log(
+    req: HttpRequest,
+    path: web::Path<Uuid>,
+    query: web::Query<AuditQuery>,
+    pool: web::Data<DbPool>,
+) -> ApiResult {
+    let group_id = path.into_inner();
+    let caller = sessions::current_user(&req, &pool).await?;
+    let response = db::run(&pool, move |conn| {
+        moderation::require_moderator(caller.id, Some(group_id), conn)?;
+        Ok(search(Some(group_id), &query, conn)?)
+    })
+    .await?;
+    Ok(HttpResponse::Ok().json(response))
+}
+
+/// Every entry, for site admins.
+pub async fn audit_log(
+    req: HttpRequest,
+    query: web::Query<AuditQuery>,
+    settings: web::Data<Settings>,
+    pool: web::Data<DbPool>,
+) -> ApiResult {
+    admin::require_admin(&req, &settings, &pool).await?;
+    let response = db::run(&pool, move |conn| Ok(search(None, &query, conn)?)).await?;
+    Ok(HttpResponse::Ok().json(response))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::User;
+    use crate::test_support;
+    use actix_web::http::StatusCode;
+    use actix_web::test::{call_and_read_body_json, call_service, init_service, TestRequest};
+    use actix_web::App;
+    use serde_json::{json, Value};
+
+    fn query(action: Option<&str>, target_id: Option<Uuid>) -> AuditQuery {
+        AuditQuery {
+            action: action.map(str::to_owned),
+            actor_id: None,
+            target_id,
+            group_id: None,
+            before: None,
+            limit: None,
+            offset: None,
+        }
+    }
+
+    fn entry(action: &str, group_id: Uuid) -> NewAuditEntry {
+        NewAuditEntry {
+            action: action.to_owned(),
+            group_id: Some(group_id),
+            target_type: "group".to_owned(),
+            target_id: Some(group_id),
+            after: Some(json!({"n": 1})),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn entries_cannot_be_changed_or_removed() {
+        use crate::schema::audit_log;
+
+        let Some(mut conn) = test_support::connection() else { return };
+        let conn = &mut conn;
+        let group_id = Uuid::new_v4();
+        record(conn, entry("group.update", group_id)).unwrap();
+
+        // Savepoints, so the refused statements do not end the test's transaction
+        let updated = conn.transaction(|conn| {
+            diesel::update(audit_log::table.filter(audit_log::target_id.eq(group_id)))
+                .set(audit_log::action.eq("group.create"))
+                .execute(conn)
+        });
+        assert!(updated.unwrap_err().to_string().contains("append-only"));
+        let deleted = conn.transaction(|conn| {
+            diesel::delete(audit_log::table.filter(audit_log::target_id.eq(group_id))).execute(conn)
+        });
+        assert!(deleted.unwrap_err().to_string().contains("append-only"));
+
+        let found = search(None, &query(None, Some(group_id)), conn).unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].action, "group.update");
+    }
+
+    #[test]
+    fn actions_match_exactly_or_by_prefix() {
+        let Some(mut conn) = test_support::connection() else { return };
+        let conn = &mut conn;
+        let group_id = Uuid::new_v4();
+        for action in ["member.join", "member.leave", "member_x.join", "group.update"] {
+            record(conn, entry(action, group_id)).unwrap();
+        }
+        let actions = |action: &str, conn: &mut PgConnection| -> Vec<String> {
+            let found = search(Some(group_id), &query(Some(action), None), conn).unwrap();
+            found.into_iter().map(|entry| entry.action).collect()
+        };
+        assert_eq!(actions("member.join", conn), ["member.join"]);
+        // Newest first, and '_' is not a wildcard
+        assert_eq!(actions("member.", conn), ["member.leave", "member.join"]);
+        assert!(actions("member", conn).is_empty());
+    }
+
+    #[actix_web::test]
+    async fn group_logs_are_for_moderators() {
+        let Some(pool) = test_support::pool() else { return };
+        let (group, owner, member) = {
+            let mut conn = pool.get().unwrap();
+            let (owner, member) = (test_support::user(&mut conn), test_support::user(&mut conn));
+            let group = test_support::group(owner.id, &mut conn);
+            test_support::join(member.id, group.id, "member", &mut conn);
+            record(&mut conn, entry("group.update", group.id)).unwrap();
+            record(&mut conn, entry("group.update", Uuid::new_v4())).unwrap();
+            (group, owner, member)
+        };
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(pool.clone()))
+                .app_data(test_support::session_signer())
+                .route("/groups/{id}/audit-log", web::get().to(group_audit_log)),
+        )
+        .await;
+        let log_for = |user: &User| {
+            TestRequest::get()
+                .uri(&format!("/groups/{}/audit-log?user_id={}", group.id, owner.id))
+                .insert_header(test_support::signed_in(user))
+                .to_request()
+        };
+
+        let entries: Vec<Value> = call_and_read_body_json(&app, log_for(&owner)).await;
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0]["group_id"], group.id.to_string());
+        // Naming the owner in the query is not enough
+        assert_eq!(call_service(&app, log_for(&member)).await.status(), StatusCode::FORBIDDEN);
+        let unsigned = TestRequest::get()
+            .uri(&format!("/groups/{}/audit-log?user_id={}", group.id, owner.id))
+            .to_request();
+        assert_eq!(call_service(&app, unsigned).await.status(), StatusCode::UNAUTHORIZED);
+    }
+}
@@ -1,9 +1,18 @@
-use crate::db::DbPool;
-use crate::models::{Group, NewGroup, PublicUser, User};
-use actix_web::{web, HttpResponse, Responder};
+use crate::audit;
+use crate::commands;
+use crate::db::{self, DbPool};
+use crate::errors::{ApiError, ApiResult};
+use crate::mail::Mailer;
+use crate::models::{Group, NewAuditEntry, NewGroup, PublicUser, User};
+use crate::ratelimit::RateLimits;
+use crate::sessions::{self, SessionSigner};
+use crate::settings::Settings;
+use crate::verification::{self, LinkSigner};
+use crate::webhooks::{self, RoomEvent};
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
 use diesel::prelude::*;
-use serde::Deserialize;
-use serde_json::{json, to_value};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
 use uuid::Uuid;
 
 #[derive(Deserialize)]
@@ -19,25 +28,100 @@ pub struct LoginRequest {
     pub password: String,
 }
 
-pub async fn signup(pool: web::Data<DbPool>, form: web::Json<SignupRequest>) -> impl Responder {
-    let mut conn = pool.get().expect("Failed to get DB connection");
-    // Call create with renamed parameters
-    match User::create(&form.username, &form.email, &form.password, &mut conn) {
-        Ok(user) => HttpResponse::Ok().json(user),
-        Err(e) => HttpResponse::BadRequest().json(json!({"error": format!("User creation failed: {:?}", e)})),
-    }
+#[derive(Serialize)]
+pub struct LoginResponse {
+    #[serde(flatten)]
+    pub user: PublicUser,
+    pub token: String,
+}
+
+/// Creates an unverified account and mails it a verification link.
+pub async fn signup(
+    pool: web::Data<DbPool>,
+    settings: web::Data<Settings>,
+    signer: web::Data<LinkSigner>,
+    mailer: web::Data<dyn Mailer>,
+    form: web::Json<SignupRequest>,
+) -> ApiResult {
+    let form = form.into_inner();
+    let (password, cost) = (form.password, settings.auth.bcrypt_cost);
+    let password_hash = web::block(move || User::hash_password(&password, cost)).await??;
+    let user = db::run(&pool, move |conn| User::create(&form.username, &form.email, password_hash, conn))
+        .await
+        .map_err(|e| match e {
+            ApiError::Conflict(_) => ApiError::Conflict("Username or email is already taken"),
+            e => e,
+        })?;
+    verification::send_link(user.id, &user.username, &user.email, &signer, &mailer, &settings)?;
+    Ok(HttpResponse::Ok().json(user))
 }
 
-pub async fn login(pool: web::Data<DbPool>, form: web::Json<LoginRequest>) -> impl Responder {
-    let mut conn = pool.get().expect("Failed to get DB connection");
-    match User::find_by_email(&form.email, &mut conn) {
-        Ok(user) if user.verify_password(&form.password) => HttpResponse::Ok().json(PublicUser {
+// Failed attempts are recorded with why they failed; the client is only told they did
+async fn record_login(
+    pool: &DbPool,
+    user_id: Option<Uuid>,
+    email: &str,
+    ip: &str,
+    failure: Option<&'static str>,
+) -> ApiResult<()> {
+    let entry = NewAuditEntry {
+        // Nobody has proven who they are until the login succeeds
+        actor_id: user_id.filter(|_| failure.is_none()),
+        action: if failure.is_some() { "login.failure" } else { "login.success" }.to_owned(),
+        target_type: "user".to_owned(),
+        target_id: user_id,
+        ip: Some(ip.to_owned()),
+        after: Some(json!({"email": email, "failure": failure})),
+        ..Default::default()
+    };
+    db::run(pool, move |conn| Ok(audit::record(conn, entry)?)).await
+}
+
+/// Checks the password and hands out a session token for `Authorization: Bearer`.
+pub async fn login(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
+    sessions: web::Data<SessionSigner>,
+    rate_limits: web::Data<RateLimits>,
+    form: web::Json<LoginRequest>,
+) -> ApiResult {
+    let form = form.into_inner();
+    let ip = rate_limits.client_addr(&req);
+    let email = form.email.clone();
+    let user = db::run(&pool, move |conn| match User::find_by_email(&email, conn) {
+        Ok(user) => Ok(Some(user)),
+        Err(diesel::result::Error::NotFound) => Ok(None),
+        Err(e) => Err(e.into()),
+    })
+    .await?;
+    let Some(user) = user else {
+        record_login(&pool, None, &form.email, &ip, Some("unknown_email")).await?;
+        return Err(ApiError::Unauthorized("Invalid credentials"));
+    };
+    let password = form.password;
+    let (user, valid) = web::block(move || {
+        let valid = user.verify_password(&password);
+        (user, valid)
+    })
+    .await?;
+    if !valid {
+        record_login(&pool, Some(user.id), &form.email, &ip, Some("wrong_password")).await?;
+        return Err(ApiError::Unauthorized("Invalid credentials"));
+    }
+    if user.is_disabled() {
+        record_login(&pool, Some(user.id), &form.email, &ip, Some("disabled")).await?;
+        return Err(ApiError::Forbidden("This account has been disabled"));
+    }
+    let token = sessions.issue(user.id, user.session_version)?;
+    record_login(&pool, Some(user.id), &form.email, &ip, None).await?;
+    Ok(HttpResponse::Ok().json(LoginResponse {
+        user: PublicUser {
             id: user.id,
             username: user.username,
             email: user.email,
-        }),
-        _ => HttpResponse::Unauthorized().json(json!({"error": "Invalid credentials"})),
-    }
+        },
+        token,
+    }))
 }
 
 pub async fn profile(user: web::ReqData<User>) -> impl Responder {
@@ -48,131 +132,266 @@ pub async fn profile(user: web::ReqData<User>) -> impl Responder {
 pub struct CreateGroupRequest {
     pub name: String,
     pub description: Option<String>,
-    pub owner: Uuid,
 }
 
+// The signed-in user becomes the owner
 pub async fn create_group(
+    req: HttpRequest,
     pool: web::Data<DbPool>,
+    settings: web::Data<Settings>,
+    rate_limits: web::Data<RateLimits>,
     form: web::Json<CreateGroupRequest>,
-) -> impl Responder {
-    let mut conn = pool.get().expect("Failed to get DB connection");
+) -> ApiResult {
+    let ip = rate_limits.client_addr(&req);
+    let owner = sessions::current_user(&req, &pool).await?;
+    let group = db::run(&pool, move |conn| {
+        verification::require_verified(owner.id, &settings.auth, conn)?;
+        create_group_with_owner(&form, owner.id, ip, conn)
+    })
+    .await?;
+    Ok(HttpResponse::Ok().json(group))
+}
 
+fn create_group_with_owner(form: &CreateGroupRequest, owner: Uuid, ip: String, conn: &mut PgConnection) -> ApiResult<Group> {
     let new_group = NewGroup {
         name: form.name.clone(),
         description: form.description.clone(),
-        // Create a JSON array with the owner's UUID (as a string)
-        members: to_value(vec![form.owner.to_string()]).unwrap(),
-        owner: form.owner,
+        owner,
     };
 
-    let group = match diesel::insert_into(crate::schema::groups::table)
-        .values(new_group)
-        .get_result::<Group>(&mut conn)
-    {
-        Ok(g) => g,
-        Err(e) => {
-            return HttpResponse::BadRequest()
-                .json(json!({"error": format!("Error creating group: {:?}", e)}))
-        }
-    };
+    conn.transaction(|conn| {
+        let group = diesel::insert_into(crate::schema::groups::table)
+            .values(new_group)
+            .returning(Group::as_returning())
+            .get_result(conn)
+            .map_err(|e| match ApiError::from(e) {
+                ApiError::Conflict(_) => ApiError::Conflict("A group with that name already exists"),
+                e => e,
+            })?;
 
-    if let Err(e) = diesel::insert_into(crate::schema::user_groups::table)
-        .values((
-            crate::schema::user_groups::user_id.eq(form.owner),
-            crate::schema::user_groups::group_id.eq(group.id),
-        ))
-        .execute(&mut conn)
-    {
-        return HttpResponse::InternalServerError()
-            .json(json!({"error": format!("Failed to add owner to user_groups: {:?}", e)}));
-    }
+        diesel::insert_into(crate::schema::user_groups::table)
+            .values((
+                crate::schema::user_groups::user_id.eq(owner),
+                crate::schema::user_groups::group_id.eq(group.id),
+                crate::schema::user_groups::role.eq("owner"),
+            ))
+            .execute(conn)?;
 
-    HttpResponse::Ok().json(group)
+        audit::record(
+            conn,
+            NewAuditEntry {
+                actor_id: Some(owner),
+                action: "group.create".to_owned(),
+                group_id: Some(group.id),
+                target_type: "group".to_owned(),
+                target_id: Some(group.id),
+                ip: Some(ip),
+                after: Some(json!(group)),
+                ..Default::default()
+            },
+        )?;
+        Ok(group)
+    })
 }
 
 #[derive(Deserialize)]
 pub struct JoinGroupRequest {
-    pub user_id: Uuid,
     pub group_id: Uuid,
 }
 
+// Adds the signed-in user to the group
 pub async fn join_group(
+    req: HttpRequest,
     pool: web::Data<DbPool>,
+    settings: web::Data<Settings>,
+    rate_limits: web::Data<RateLimits>,
     form: web::Json<JoinGroupRequest>,
-) -> impl Responder {
-    let mut conn = pool.get().expect("Failed to get DB connection");
+) -> ApiResult {
+    let ip = rate_limits.client_addr(&req);
+    let user = sessions::current_user(&req, &pool).await?;
+    db::run(&pool, move |conn| {
+        verification::require_verified(user.id, &settings.auth, conn)?;
+        conn.transaction(|conn| add_member(user.id, form.group_id, ip, conn))
+    })
+    .await?;
+    Ok(HttpResponse::Ok().json(json!({"message": "Joined group successfully"})))
+}
 
-    if let Err(e) = diesel::insert_into(crate::schema::user_groups::table)
-        .values((
-            crate::schema::user_groups::user_id.eq(form.user_id),
-            crate::schema::user_groups::group_id.eq(form.group_id),
-        ))
-        .execute(&mut conn)
-    {
-        return HttpResponse::BadRequest()
-            .json(json!({"error": format!("Failed to join group: {:?}", e)}));
+// The member is their own actor, for joining and leaving alike
+fn membership_entry(action: &str, user_id: Uuid, group_id: Uuid, ip: String) -> NewAuditEntry {
+    NewAuditEntry {
+        actor_id: Some(user_id),
+        action: action.to_owned(),
+        group_id: Some(group_id),
+        target_type: "user".to_owned(),
+        target_id: Some(user_id),
+        ip: Some(ip),
+        ..Default::default()
     }
+}
 
-    let members: Vec<String> = match crate::schema::user_groups::table
-        .filter(crate::schema::user_groups::group_id.eq(form.group_id))
-        .select(crate::schema::user_groups::user_id)
-        .load::<Uuid>(&mut conn)
-    {
-        Ok(uuids) => uuids.into_iter().map(|uuid| uuid.to_string()).collect(),
-        Err(e) => {
-            return HttpResponse::InternalServerError()
-                .json(json!({"error": format!("Error loading members: {:?}", e)}))
-        }
-    };
-
-    if let Err(e) = diesel::update(crate::schema::groups::table)
-        .filter(crate::schema::groups::id.eq(form.group_id))
-        .set(crate::schema::groups::members.eq(to_value(&members).unwrap()))
-        .execute(&mut conn)
-    {
-        return HttpResponse::InternalServerError()
-            .json(json!({"error": format!("Error updating group members: {:?}", e)}));
+fn add_member(user_id: Uuid, group_id: Uuid, ip: String, conn: &mut PgConnection) -> ApiResult<()> {
+    if commands::is_banned(user_id, group_id, conn)? {
+        return Err(ApiError::Forbidden("You are banned from this group"));
     }
+    diesel::insert_into(crate::schema::user_groups::table)
+        .values((
+            crate::schema::user_groups::user_id.eq(user_id),
+            crate::schema::user_groups::group_id.eq(group_id),
+        ))
+        .execute(conn)
+        .map_err(|e| match ApiError::from(e) {
+            ApiError::Conflict(_) => ApiError::Conflict("Already a member of this group"),
+            ApiError::BadRequest(_) => ApiError::NotFound("User or group not found"),
+            e => e,
+        })?;
 
-    HttpResponse::Ok().json(json!({"message": "Joined group successfully"}))
+    webhooks::enqueue(conn, group_id, RoomEvent::Join, json!({"user_id": user_id}))?;
+    audit::record(
+        conn,
+        NewAuditEntry {
+            after: Some(json!({"role": "member"})),
+            ..membership_entry("member.join", user_id, group_id, ip)
+        },
+    )?;
+    Ok(())
 }
 
+// Removes the signed-in user from the group
 pub async fn leave_group(
+    req: HttpRequest,
     pool: web::Data<DbPool>,
+    rate_limits: web::Data<RateLimits>,
     form: web::Json<JoinGroupRequest>,
-) -> impl Responder {
-    let mut conn = pool.get().expect("Failed to get DB connection");
-
-    if let Err(e) = diesel::delete(
-        crate::schema::user_groups::table
-            .filter(crate::schema::user_groups::user_id.eq(form.user_id))
-            .filter(crate::schema::user_groups::group_id.eq(form.group_id)),
-    )
-    .execute(&mut conn)
-    {
-        return HttpResponse::BadRequest()
-            .json(json!({"error": format!("Error removing user from group: {:?}", e)}));
+) -> ApiResult {
+    let ip = rate_limits.client_addr(&req);
+    let user = sessions::current_user(&req, &pool).await?;
+    db::run(&pool, move |conn| conn.transaction(|conn| remove_member(user.id, form.group_id, ip, conn))).await?;
+    Ok(HttpResponse::Ok().json(json!({"message": "Left group successfully"})))
+}
+
+fn remove_member(user_id: Uuid, group_id: Uuid, ip: String, conn: &mut PgConnection) -> ApiResult<()> {
+    use crate::schema::user_groups;
+
+    let role = user_groups::table
+        .find((user_id, group_id))
+        .select(user_groups::role)
+        .first::<String>(conn)
+        .map_err(ApiError::db("Not a member of this group"))?;
+    // The group would be left without anyone allowed to manage it
+    if role == "owner" {
+        return Err(ApiError::Conflict("The owner cannot leave the group; transfer ownership first"));
     }
+    diesel::delete(user_groups::table.find((user_id, group_id))).execute(conn)?;
 
-    let members: Vec<String> = match crate::schema::user_groups::table
-        .filter(crate::schema::user_groups::group_id.eq(form.group_id))
-        .select(crate::schema::user_groups::user_id)
-        .load::<Uuid>(&mut conn)
-    {
-        Ok(uuids) => uuids.into_iter().map(|uuid| uuid.to_string()).collect(),
-        Err(e) => {
-            return HttpResponse::InternalServerError()
-                .json(json!({"error": format!("Error loading members: {:?}", e)}))
-        }
-    };
+    webhooks::enqueue(conn, group_id, RoomEvent::Leave, json!({"user_id": user_id}))?;
+    audit::record(
+        conn,
+        NewAuditEntry {
+            before: Some(json!({"role": role})),
+            ..membership_entry("member.leave", user_id, group_id, ip)
+        },
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::groups::member_role;
+    use crate::test_support;
+    use actix_web::http::StatusCode;
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::App;
 
-    if let Err(e) = diesel::update(crate::schema::groups::table.find(form.group_id))
-        .set(crate::schema::groups::members.eq(to_value(&members).unwrap()))
-        .execute(&mut conn)
-    {
-        return HttpResponse::InternalServerError()
-            .json(json!({"error": format!("Error updating group members: {:?}", e)}));
+    const IP: &str = "127.0.0.1";
+
+    #[test]
+    fn memberships_follow_group_creation_joins_and_leaves() {
+        let Some(mut conn) = test_support::connection() else { return };
+        let conn = &mut conn;
+        let (owner, member) = (test_support::user(conn), test_support::user(conn));
+        let form = CreateGroupRequest {
+            name: test_support::unique("group"),
+            description: None,
+        };
+        let group = create_group_with_owner(&form, owner.id, IP.to_owned(), conn).unwrap();
+        assert_eq!(member_role(owner.id, group.id, conn).unwrap().as_deref(), Some("owner"));
+
+        add_member(member.id, group.id, IP.to_owned(), conn).unwrap();
+        assert_eq!(member_role(member.id, group.id, conn).unwrap().as_deref(), Some("member"));
+        let again = conn.transaction(|conn| add_member(member.id, group.id, IP.to_owned(), conn));
+        assert!(matches!(again, Err(ApiError::Conflict(_))));
+
+        remove_member(member.id, group.id, IP.to_owned(), conn).unwrap();
+        assert_eq!(member_role(member.id, group.id, conn).unwrap(), None);
+        assert!(matches!(
+            remove_member(owner.id, group.id, IP.to_owned(), conn),
+            Err(ApiError::Conflict(_))
+        ));
+    }
+
+    #[test]
+    fn banned_users_cannot_join() {
+        let Some(mut conn) = test_support::connection() else { return };
+        let conn = &mut conn;
+        let (owner, banned) = (test_support::user(conn), test_support::user(conn));
+        let group = test_support::group(owner.id, conn);
+        test_support::ban(banned.id, group.id, conn);
+        assert!(matches!(add_member(banned.id, group.id, IP.to_owned(), conn), Err(ApiError::Forbidden(_))));
+        assert_eq!(member_role(banned.id, group.id, conn).unwrap(), None);
     }
 
-    HttpResponse::Ok().json(json!({"message": "Left group successfully"}))
+    #[actix_web::test]
+    async fn the_session_decides_who_joins_and_who_owns() {
+        use crate::schema::audit_log;
+
+        let Some(pool) = test_support::pool() else { return };
+        let (owner, joiner, bystander) = {
+            let mut conn = pool.get().unwrap();
+            (test_support::user(&mut conn), test_support::user(&mut conn), test_support::user(&mut conn))
+        };
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(pool.clone()))
+                .app_data(web::Data::new(Settings::default()))
+                .app_data(web::Data::new(RateLimits::new(&Default::default())))
+                .app_data(test_support::session_signer())
+                .route("/create-group", web::post().to(create_group))
+                .route("/join-group", web::post().to(join_group)),
+        )
+        .await;
+
+        let name = test_support::unique("group");
+        let create = TestRequest::post()
+            .uri("/create-group")
+            .set_json(json!({"name": name, "owner": bystander.id}))
+            .to_request();
+        assert_eq!(call_service(&app, create).await.status(), StatusCode::UNAUTHORIZED);
+        let create = TestRequest::post()
+            .uri("/create-group")
+            .insert_header(test_support::signed_in(&owner))
+            .set_json(json!({"name": name, "owner": bystander.id}))
+            .to_request();
+        let group: Group = actix_web::test::call_and_read_body_json(&app, create).await;
+        assert_eq!(group.owner, owner.id);
+
+        let join = TestRequest::post()
+            .uri("/join-group")
+            .insert_header(test_support::signed_in(&joiner))
+            .set_json(json!({"group_id": group.id, "user_id": bystander.id}))
+            .to_request();
+        assert_eq!(call_service(&app, join).await.status(), StatusCode::OK);
+
+        let mut conn = pool.get().unwrap();
+        assert_eq!(member_role(joiner.id, group.id, &mut conn).unwrap().as_deref(), Some("member"));
+        assert_eq!(member_role(bystander.id, group.id, &mut conn).unwrap(), None);
+        let actors: Vec<Option<Uuid>> = audit_log::table
+            .filter(audit_log::group_id.eq(group.id))
+            .order(audit_log::id)
+            .select(audit_log::actor_id)
+            .load(&mut conn)
+            .unwrap();
+        assert_eq!(actors, [Some(owner.id), Some(joiner.id)]);
+    }
 }
@@ -0,0 +1,349 @@
+use backend::admin::{self, AdminActor};
+use backend::audit;
+use backend::db::{self, establish_connection, DbConnection};
+use backend::errors::{ApiError, ApiResult};
+use backend::migrations;
+use backend::models::{NewAuditEntry, User};
+use backend::room::RoomStats;
+use backend::settings::Settings;
+use clap::{Parser, Subcommand};
+use diesel::pg::PgConnection;
+use diesel::Connection;
+use serde_json::json;
+use std::io::BufRead;
+
+/// Operates a NeuroChat deployment: accounts, groups, rooms and the database.
+///
+/// Reads the same configuration as the server (`NEUROCHAT_CONFIG`,
+/// `neurochat.toml`, `NEUROCHAT_*` and `DATABASE_URL`).
+#[derive(Parser)]
+#[command(name = "neurochat-admin")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Apply pending migrations
+    Migrate,
+    /// Create demo users, groups and messages
+    Seed,
+    /// Create, disable, re-enable, verify or promote accounts and reset passwords
+    #[command(subcommand)]
+    User(UserCommand),
+    /// Change a group's owner or purge its history
+    #[command(subcommand)]
+    Group(GroupCommand),
+    /// List the rooms running on a server through its admin API
+    Rooms {
+        /// Base URL of the server; defaults to the first server.bind address
+        #[arg(long)]
+        server: Option<String>,
+    },
+}
+
+#[derive(Subcommand)]
+enum UserCommand {
+    /// Create a user, prompting for the password; the address counts as verified
+    Create {
+        username: String,
+        email: String,
+        /// Read the password from the first line of stdin instead of prompting
+        #[arg(long)]
+        password_stdin: bool,
+    },
+    /// Stop a user from logging in, connecting or using their bots
+    Disable { user: String },
+    /// Undo `disable`
+    Enable { user: String },
+    /// Mark a user's email address as verified without the mailed link
+    Verify { user: String },
+    /// Make a user a site admin
+    Promote { user: String },
+    /// Take the site admin role away
+    Demote { user: String },
+    /// Set a new password, prompting for it
+    ResetPassword {
+        user: String,
+        #[arg(long)]
+        password_stdin: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum GroupCommand {
+    /// Hand a group to one of its members
+    Transfer { group: String, new_owner: String },
+    /// Delete every message of a group
+    PurgeMessages {
+        group: String,
+        /// Required; purging cannot be undone
+        #[arg(long)]
+        yes: bool,
+    },
+}
+
+fn main() {
+    let cli = Cli::parse();
+    let settings = Settings::load().unwrap_or_else(|e| fail(e));
+    let result = match cli.command {
+        Command::Rooms { server } => list_rooms(&settings, server),
+        command => {
+            let pool = establish_connection(&settings.database);
+            let mut conn = db::connection(&pool).unwrap_or_else(|e| fail(e));
+            run(command, &settings, &mut conn)
+        }
+    };
+    if let Err(e) = result {
+        fail(e);
+    }
+}
+
+fn fail(e: impl std::fmt::Display) -> ! {
+    eprintln!("❌ {}", e);
+    std::process::exit(1);
+}
+
+// Internal errors keep their detail here; there is no client to hide it from
+fn describe(e: ApiError) -> String {
+    match e {
+        ApiError::Internal(detail) => detail,
+        e => e.to_string(),
+    }
+}
+
+// Makes the change and writes its audit entry in one transaction
+fn audited<T>(
+    conn: &mut PgConnection,
+    entry: NewAuditEntry,
+    change: impl FnOnce(&mut PgConnection) -> ApiResult<T>,
+) -> Result<T, String> {
+    conn.transaction(|conn| {
+        let value = change(conn)?;
+        audit::record(conn, entry)?;
+        Ok(value)
+    })
+    .map_err(describe)
+}
+
+fn run(command: Command, settings: &Settings, conn: &mut DbConnection) -> Result<(), String> {
+    let cost = settings.auth.bcrypt_cost;
+    match command {
+        Command::Migrate => {
+            migrations::run_pending(conn).map_err(|e| e.to_string())?;
+            migrations::check_schema(conn).map_err(|e| e.to_string())?;
+            println!("Database is up to date");
+        }
+        Command::Seed => {
+            let hash = User::hash_password(admin::SEED_PASSWORD, cost).map_err(describe)?;
+            admin::seed(&hash, conn).map_err(describe)?;
+            println!("Seeded demo data; every demo user's password is {:?}", admin::SEED_PASSWORD);
+        }
+        Command::User(UserCommand::Create {
+            username,
+            email,
+            password_stdin,
+        }) => {
+            let hash = User::hash_password(&read_password(password_stdin)?, cost).map_err(describe)?;
+            let user = User::create(&username, &email, hash, conn).map_err(describe)?;
+            // The operator vouches for the address
+            admin::set_verified(user.id, conn).map_err(describe)?;
+            let entry = AdminActor::Operator.audit_entry("user.create", "user", user.id, None);
+            audit::record(conn, entry).map_err(|e| e.to_string())?;
+            println!("Created user {} ({})", user.username, user.id);
+        }
+        Command::User(UserCommand::Disable { user }) => {
+            let user = admin::find_user(&user, conn).map_err(describe)?;
+            let entry = AdminActor::Operator.audit_entry("user.suspend", "user", user.id, None);
+            audited(conn, entry, |conn| admin::set_disabled(user.id, true, conn))?;
+            println!("Disabled {}; open sessions last until they reconnect", user.username);
+        }
+        Command::User(UserCommand::Enable { user }) => {
+            let user = admin::find_user(&user, conn).map_err(describe)?;
+            let entry = AdminActor::Operator.audit_entry("user.unsuspend", "user", user.id, None);
+            audited(conn, entry, |conn| admin::set_disabled(user.id, false, conn))?;
+            println!("Enabled {}", user.username);
+        }
+        Command::User(UserCommand::Verify { user }) => {
+            let user = admin::find_user(&user, conn).map_err(describe)?;
+            if user.email_verified_at.is_some() {
+                println!("{} is already verified", user.username);
+                return Ok(());
+            }
+            let entry = AdminActor::Operator.audit_entry("user.verify_email", "user", user.id, None);
+            audited(conn, entry, |conn| admin::set_verified(user.id, conn))?;
+            println!("Marked {} as verified", user.email);
+        }
+        Command::User(UserCommand::Promote { user }) => {
+            let user = admin::find_user(&user, conn).map_err(describe)?;
+            let entry = NewAuditEntry {
+                before: Some(json!({"is_admin": user.is_admin})),
+                after: Some(json!({"is_admin": true})),
+                ..AdminActor::Operator.audit_entry("user.admin", "user", user.id, None)
+            };
+            audited(conn, entry, |conn| admin::set_admin(user.id, true, conn))?;
+            println!("{} is now a site admin", user.username);
+        }
+        Command::User(UserCommand::Demote { user }) => {
+            let user = admin::find_user(&user, conn).map_err(describe)?;
+            let entry = NewAuditEntry {
+                before: Some(json!({"is_admin": user.is_admin})),
+                after: Some(json!({"is_admin": false})),
+                ..AdminActor::Operator.audit_entry("user.admin", "user", user.id, None)
+            };
+            audited(conn, entry, |conn| admin::set_admin(user.id, false, conn))?;
+            println!("{} is no longer a site admin", user.username);
+        }
+        Command::User(UserCommand::ResetPassword { user, password_stdin }) => {
+            let user = admin::find_user(&user, conn).map_err(describe)?;
+            let hash = User::hash_password(&read_password(password_stdin)?, cost).map_err(describe)?;
+            let entry = AdminActor::Operator.audit_entry("user.password", "user", user.id, None);
+            audited(conn, entry, |conn| admin::set_password(user.id, &hash, conn))?;
+            println!("Password changed for {}", user.username);
+        }
+        Command::Group(GroupCommand::Transfer { group, new_owner }) => {
+            let group = admin::find_group(&group, conn).map_err(describe)?;
+            let new_owner = admin::find_user(&new_owner, conn).map_err(describe)?;
+            let entry = NewAuditEntry {
+                group_id: Some(group.id),
+                before: Some(json!({"owner": group.owner})),
+                after: Some(json!({"owner": new_owner.id})),
+                ..AdminActor::Operator.audit_entry("group.update", "group", group.id, None)
+            };
+            audited(conn, entry, |conn| admin::transfer_ownership(group.id, new_owner.id, conn))?;
+            println!("{} now owns {}", new_owner.username, group.name);
+        }
+        Command::Group(GroupCommand::PurgeMessages { group, yes }) => {
+            if !yes {
+                return Err("purging cannot be undone; pass --yes to go ahead".to_owned());
+            }
+            let group = admin::find_group(&group, conn).map_err(describe)?;
+            let entry = NewAuditEntry {
+                group_id: Some(group.id),
+                ..AdminActor::Operator.audit_entry("group.purge_messages", "group", group.id, None)
+            };
+            let purged = audited(conn, entry, |conn| admin::purge_messages(group.id, conn))?;
+            println!("Deleted {} messages from {}", purged, group.name);
+        }
+        Command::Rooms { .. } => unreachable!("handled without a database connection"),
+    }
+    Ok(())
+}
+
+fn read_password(from_stdin: bool) -> Result<String, String> {
+    let password = if from_stdin {
+        let mut line = String::new();
+        std::io::stdin().lock().read_line(&mut line).map_err(|e| e.to_string())?;
+        line.trim_end_matches(['\r', '\n']).to_owned()
+    } else {
+        let password = rpassword::prompt_password("Password: ").map_err(|e| e.to_string())?;
+        if rpassword::prompt_password("Repeat password: ").map_err(|e| e.to_string())? != password {
+            return Err("passwords do not match".to_owned());
+        }
+        password
+    };
+    if password.is_empty() {
+        return Err("the password must not be empty".to_owned());
+    }
+    Ok(password)
+}
+
+fn list_rooms(settings: &Settings, server: Option<String>) -> Result<(), String> {
+    let token = settings
+        .admin
+        .api_token
+        .clone()
+        .ok_or("admin.api_token is not set, so the server has no admin API")?;
+    let base = server.unwrap_or_else(|| format!("http://{}", settings.server.bind[0]));
+    let rooms: Vec<RoomStats> = actix_web::rt::System::new().block_on(async move {
+        let response = reqwest::Client::new()
+            .get(format!("{}/admin/rooms", base.trim_end_matches('/')))
+            .bearer_auth(token)
+            .send()
+            .await
+            .map_err(|e| e.to_string())?;
+        if !response.status().is_success() {
+            return Err(format!("server answered {}", response.status()));
+        }
+        response.json().await.map_err(|e| e.to_string())
+    })?;
+
+    if rooms.is_empty() {
+        println!("No active rooms");
+    }
+    for room in rooms {
+        println!(
+            "{:<32} {:>5} sessions {:>5} users {:>5} remote users",
+            room.name, room.sessions, room.users, room.remote_users
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use backend::schema::{audit_log, users};
+    use clap::CommandFactory;
+    use diesel::prelude::*;
+    use diesel::r2d2::{ConnectionManager, Pool, TestCustomizer};
+
+    // One connection whose changes are rolled back, like the library's test pool
+    fn connection() -> Option<DbConnection> {
+        let url = std::env::var("TEST_DATABASE_URL").or_else(|_| std::env::var("DATABASE_URL")).ok()?;
+        let pool = Pool::builder()
+            .max_size(1)
+            .connection_customizer(Box::new(TestCustomizer))
+            .build(ConnectionManager::<PgConnection>::new(url))
+            .expect("building the test pool");
+        let mut conn = db::connection(&pool).expect("connecting to the test database");
+        migrations::run_pending(&mut conn).expect("migrating the test database");
+        Some(conn)
+    }
+
+    #[test]
+    fn the_command_line_is_well_formed() {
+        Cli::command().debug_assert();
+        let cli = Cli::try_parse_from(["neurochat-admin", "group", "purge-messages", "general", "--yes"]).unwrap();
+        assert!(matches!(cli.command, Command::Group(GroupCommand::PurgeMessages { group, yes: true }) if group == "general"));
+        assert!(Cli::try_parse_from(["neurochat-admin", "user", "create", "alice"]).is_err());
+    }
+
+    #[test]
+    fn internal_errors_keep_their_detail() {
+        assert_eq!(describe(ApiError::Internal("disk full".to_owned())), "disk full");
+        assert_eq!(describe(ApiError::NotFound("User not found")), ApiError::NotFound("User not found").to_string());
+    }
+
+    #[test]
+    fn purging_needs_confirmation() {
+        let Some(mut conn) = connection() else { return };
+        let command = Command::Group(GroupCommand::PurgeMessages {
+            group: "anything".to_owned(),
+            yes: false,
+        });
+        let err = run(command, &Settings::default(), &mut conn).unwrap_err();
+        assert!(err.contains("--yes"));
+    }
+
+    #[test]
+    fn operator_changes_are_audited() {
+        let Some(mut conn) = connection() else { return };
+        let name = format!("cli-{}", uuid::Uuid::new_v4().simple());
+        let user = User::create(&name, &format!("{}@example.com", name), "-".to_owned(), &mut conn).unwrap();
+
+        run(Command::User(UserCommand::Promote { user: name.clone() }), &Settings::default(), &mut conn).unwrap();
+        let is_admin: bool = users::table.find(user.id).select(users::is_admin).first(&mut conn).unwrap();
+        assert!(is_admin);
+        let actions: Vec<String> = audit_log::table
+            .filter(audit_log::target_id.eq(user.id))
+            .select(audit_log::action)
+            .load(&mut conn)
+            .unwrap();
+        assert_eq!(actions, ["user.admin"]);
+
+        let missing = Command::User(UserCommand::Promote { user: "nobody-at-all".to_owned() });
+        assert_eq!(run(missing, &Settings::default(), &mut conn).unwrap_err(), "User not found");
+    }
+}
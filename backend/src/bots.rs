@@ -0,0 +1,385 @@
+use crate::db::{self, DbPool};
+use crate::errors::{ApiError, ApiResult};
+use crate::models::{BotApiKey, Message, NewMessage, NewUser, PublicUser, User};
+use crate::sessions;
+use crate::settings::Settings;
+use crate::verification;
+use crate::webhooks::{self, RoomEvent};
+use crate::ws::{ChatServer, PostMessage};
+use actix::Addr;
+use actix_web::{web, HttpRequest, HttpResponse};
+use diesel::prelude::*;
+use rand::RngCore;
+use serde::Deserialize;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+// Keys look like `nck_<prefix>_<secret>`
+const KEY_SCHEME: &str = "nck";
+
+#[derive(Deserialize)]
+pub struct CreateBotRequest {
+    pub username: String,
+}
+
+#[derive(Deserialize)]
+pub struct CreateKeyRequest {
+    pub name: String,
+}
+
+#[derive(Deserialize)]
+pub struct BotMessageRequest {
+    pub group_id: Uuid,
+    pub content: String,
+}
+
+pub fn random_hex(len: usize) -> String {
+    let mut bytes = vec![0u8; len];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    hex::encode(bytes)
+}
+
+pub fn hash_key(key: &str) -> String {
+    hex::encode(Sha256::digest(key.as_bytes()))
+}
+
+/// Returns the token from an `Authorization: Bearer <token>` header.
+pub fn bearer_token(req: &HttpRequest) -> Option<&str> {
+    req.headers()
+        .get(actix_web::http::header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+        .map(str::trim)
+}
+
+/// True for tokens shaped like an API key rather than a session token.
+pub fn is_api_key(token: &str) -> bool {
+    token.strip_prefix(KEY_SCHEME).is_some_and(|rest| rest.starts_with('_'))
+}
+
+/// Resolves an API key to its bot. Unknown, malformed and revoked keys all give `None`.
+pub fn authenticate_key(key: &str, conn: &mut PgConnection) -> Option<User> {
+    use crate::schema::{bot_api_keys, users};
+
+    let mut parts = key.splitn(3, '_');
+    let (Some(KEY_SCHEME), Some(prefix), Some(_)) = (parts.next(), parts.next(), parts.next()) else {
+        return None;
+    };
+    let api_key = bot_api_keys::table
+        .filter(bot_api_keys::key_prefix.eq(prefix))
+        .filter(bot_api_keys::revoked_at.is_null())
+        .first::<BotApiKey>(conn)
+        .ok()?;
+    if api_key.key_hash != hash_key(key) {
+        return None;
+    }
+    let _ = diesel::update(bot_api_keys::table.find(api_key.id))
+        .set(bot_api_keys::last_used_at.eq(chrono::Utc::now().naive_utc()))
+        .execute(conn);
+    users::table
+        .find(api_key.bot_id)
+        .filter(users::is_bot.eq(true))
+        .filter(users::disabled_at.is_null())
+        .first::<User>(conn)
+        .ok()
+}
+
+// The bot, if `user_id` is the human who owns it
+fn owned_bot(bot_id: Uuid, user_id: Uuid, conn: &mut PgConnection) -> ApiResult<User> {
+    match crate::schema::users::table.find(bot_id).first::<User>(conn).optional()? {
+        Some(bot) if bot.is_bot && bot.owner_id == Some(user_id) => Ok(bot),
+        Some(bot) if bot.is_bot => Err(ApiError::Forbidden("Only the bot's owner can manage its keys")),
+        _ => Err(ApiError::NotFound("Bot not found")),
+    }
+}
+
+/// Adds a bot owned by the signed-in user.
+pub async fn create_bot(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
+    settings: web::Data<Settings>,
+    form: web::Json<CreateBotRequest>,
+) -> ApiResult {
+    use crate::schema::users;
+
+    let owner = sessions::current_user(&req, &pool).await?;
+    if owner.is_bot {
+        return Err(ApiError::Forbidden("Bots must be owned by a human user"));
+    }
+    let response = db::run(&pool, move |conn| {
+        verification::require_verified(owner.id, &settings.auth, conn)?;
+
+        let id = Uuid::new_v4();
+        let bot = NewUser {
+            id,
+            username: form.username.clone(),
+            // Bots never receive mail and never log in with a password
+            email: format!("{}@bots.invalid", id),
+            password_hash: "!".to_owned(),
+        };
+        diesel::insert_into(users::table)
+            .values((
+                &bot,
+                users::is_bot.eq(true),
+                users::owner_id.eq(owner.id),
+                // Nothing to verify, and the owner already has
+                users::email_verified_at.eq(chrono::Utc::now().naive_utc()),
+            ))
+            .execute(conn)
+            .map_err(|e| match ApiError::from(e) {
+                ApiError::Conflict(_) => ApiError::Conflict("Username is already taken"),
+                e => e,
+            })?;
+        Ok(PublicUser {
+            id: bot.id,
+            username: bot.username,
+            email: bot.email,
+        })
+    })
+    .await?;
+    Ok(HttpResponse::Ok().json(response))
+}
+
+pub async fn create_key(
+    req: HttpRequest,
+    path: web::Path<Uuid>,
+    pool: web::Data<DbPool>,
+    form: web::Json<CreateKeyRequest>,
+) -> ApiResult {
+    let owner = sessions::current_user(&req, &pool).await?;
+    let response = db::run(&pool, move |conn| {
+        let bot = owned_bot(path.into_inner(), owner.id, conn)?;
+
+        let prefix = random_hex(6);
+        let key = format!("{}_{}_{}", KEY_SCHEME, prefix, random_hex(32));
+        let api_key = BotApiKey {
+            id: Uuid::new_v4(),
+            bot_id: bot.id,
+            name: form.name.clone(),
+            key_prefix: prefix,
+            key_hash: hash_key(&key),
+            created_at: chrono::Utc::now().naive_utc(),
+            last_used_at: None,
+            revoked_at: None,
+        };
+        diesel::insert_into(crate::schema::bot_api_keys::table)
+            .values(&api_key)
+            .execute(conn)?;
+        // The plaintext key is only ever shown here
+        Ok(json!({"api_key": api_key, "key": key}))
+    })
+    .await?;
+    Ok(HttpResponse::Ok().json(response))
+}
+
+pub async fn list_keys(
+    req: HttpRequest,
+    path: web::Path<Uuid>,
+    pool: web::Data<DbPool>,
+) -> ApiResult {
+    use crate::schema::bot_api_keys;
+
+    let owner = sessions::current_user(&req, &pool).await?;
+    let response = db::run(&pool, move |conn| {
+        let bot = owned_bot(path.into_inner(), owner.id, conn)?;
+        let keys = bot_api_keys::table
+            .filter(bot_api_keys::bot_id.eq(bot.id))
+            .order(bot_api_keys::created_at.asc())
+            .load::<BotApiKey>(conn)?;
+        Ok(keys)
+    })
+    .await?;
+    Ok(HttpResponse::Ok().json(response))
+}
+
+pub async fn revoke_key(
+    req: HttpRequest,
+    path: web::Path<(Uuid, Uuid)>,
+    pool: web::Data<DbPool>,
+) -> ApiResult {
+    use crate::schema::bot_api_keys;
+
+    let (bot_id, key_id) = path.into_inner();
+    let owner = sessions::current_user(&req, &pool).await?;
+    let response = db::run(&pool, move |conn| {
+        owned_bot(bot_id, owner.id, conn)?;
+        let revoked = diesel::update(
+            bot_api_keys::table
+                .filter(bot_api_keys::id.eq(key_id))
+                .filter(bot_api_keys::bot_id.eq(bot_id))
+                .filter(bot_api_keys::revoked_at.is_null()),
+        )
+        .set(bot_api_keys::revoked_at.eq(chrono::Utc::now().naive_utc()))
+        .execute(conn)?;
+        if revoked == 0 {
+            return Err(ApiError::NotFound("API key not found"));
+        }
+        Ok(json!({"message": "API key revoked"}))
+    })
+    .await?;
+    Ok(HttpResponse::Ok().json(response))
+}
+
+/// Posts into a group the calling bot was added to.
+pub async fn post_message(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
+    srv: web::Data<Addr<ChatServer>>,
+    form: web::Json<BotMessageRequest>,
+) -> ApiResult {
+    use crate::schema::{groups, user_groups};
+
+    let key = bearer_token(&req).map(str::to_owned);
+    let response = db::run(&pool, move |conn| {
+        let bot = key
+            .and_then(|key| authenticate_key(&key, conn))
+            .ok_or(ApiError::Unauthorized("Invalid API key"))?;
+        if form.content.trim().is_empty() {
+            return Err(ApiError::BadRequest("Message content must not be empty"));
+        }
+        let room = user_groups::table
+            .inner_join(groups::table)
+            .filter(user_groups::user_id.eq(bot.id))
+            .filter(user_groups::group_id.eq(form.group_id))
+            .select(groups::name)
+            .first::<String>(conn)
+            .optional()?
+            .ok_or(ApiError::Forbidden("Bot is not a member of this group"))?;
+        if crate::commands::is_muted(bot.id, form.group_id, conn)? {
+            return Err(ApiError::Forbidden("Bot is muted in this group"));
+        }
+
+        let message = conn.transaction(|conn| {
+            let message = Message::create(
+                NewMessage {
+                    id: Uuid::new_v4(),
+                    group_id: Some(form.group_id),
+                    sender_id: Some(bot.id),
+                    content: form.content.trim().to_owned(),
+                    kind: "text".to_owned(),
+                    expires_at: None,
+                    integration_id: None,
+                    sender_name: None,
+                },
+                conn,
+            )?;
+            webhooks::enqueue(conn, form.group_id, RoomEvent::Message, json!(message))?;
+            QueryResult::Ok(message)
+        })?;
+        srv.do_send(PostMessage {
+            room,
+            message: message.clone(),
+            span: tracing::Span::current(),
+        });
+        Ok(message)
+    })
+    .await?;
+    Ok(HttpResponse::Ok().json(response))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sessions::SessionSigner;
+    use crate::test_support;
+    use actix_web::http::{header, StatusCode};
+    use actix_web::test::{call_and_read_body_json, call_service, init_service, TestRequest};
+    use actix_web::App;
+
+    #[test]
+    fn keys_are_stored_as_sha256_digests() {
+        let key = format!("{}_{}_{}", KEY_SCHEME, random_hex(6), random_hex(32));
+        assert_eq!(hash_key(&key).len(), 64);
+        assert_ne!(hash_key(&key), key);
+        assert_eq!(hash_key(&key), hash_key(&key));
+        assert_eq!(random_hex(6).len(), 12);
+    }
+
+    #[test]
+    fn bearer_tokens_come_from_the_authorization_header() {
+        let req = TestRequest::default()
+            .insert_header((header::AUTHORIZATION, "Bearer nck_abc_def "))
+            .to_http_request();
+        assert_eq!(bearer_token(&req), Some("nck_abc_def"));
+        let req = TestRequest::default()
+            .insert_header((header::AUTHORIZATION, "Basic abc"))
+            .to_http_request();
+        assert_eq!(bearer_token(&req), None);
+    }
+
+    #[actix_web::test]
+    async fn bots_belong_to_the_signed_in_user_and_keys_can_be_revoked() {
+        let Some(pool) = test_support::pool() else { return };
+        let (owner, other) = {
+            let mut conn = pool.get().unwrap();
+            (test_support::user(&mut conn), test_support::user(&mut conn))
+        };
+        let mut settings = Settings::default();
+        settings.auth.jwt_secret = Some("a-secret-of-at-least-thirty-two-chars".to_owned());
+        let signer = SessionSigner::new(&settings.auth);
+        let session = |user_id| (header::AUTHORIZATION, format!("Bearer {}", signer.issue(user_id, 0).unwrap()));
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(pool.clone()))
+                .app_data(web::Data::new(SessionSigner::new(&settings.auth)))
+                .app_data(web::Data::new(settings))
+                .route("/bots", web::post().to(create_bot))
+                .route("/bots/{id}/keys", web::post().to(create_key))
+                .route("/bots/{id}/keys/{key_id}", web::delete().to(revoke_key)),
+        )
+        .await;
+
+        let req = TestRequest::post()
+            .uri("/bots")
+            .set_json(json!({"username": test_support::unique("bot"), "owner_id": other.id}))
+            .to_request();
+        assert_eq!(call_service(&app, req).await.status(), StatusCode::UNAUTHORIZED);
+
+        // An owner_id in the body is ignored; the session decides
+        let req = TestRequest::post()
+            .uri("/bots")
+            .insert_header(session(owner.id))
+            .set_json(json!({"username": test_support::unique("bot"), "owner_id": other.id}))
+            .to_request();
+        let bot: PublicUser = call_and_read_body_json(&app, req).await;
+        let owner_id = crate::schema::users::table
+            .find(bot.id)
+            .select(crate::schema::users::owner_id)
+            .first::<Option<Uuid>>(&mut pool.get().unwrap())
+            .unwrap();
+        assert_eq!(owner_id, Some(owner.id));
+
+        let req = TestRequest::post()
+            .uri(&format!("/bots/{}/keys", bot.id))
+            .insert_header(session(other.id))
+            .set_json(json!({"name": "ci"}))
+            .to_request();
+        assert_eq!(call_service(&app, req).await.status(), StatusCode::FORBIDDEN);
+
+        let req = TestRequest::post()
+            .uri(&format!("/bots/{}/keys", bot.id))
+            .insert_header(session(owner.id))
+            .set_json(json!({"name": "ci"}))
+            .to_request();
+        let created: serde_json::Value = call_and_read_body_json(&app, req).await;
+        let key = created["key"].as_str().unwrap().to_owned();
+        let key_id = created["api_key"]["id"].as_str().unwrap().to_owned();
+        assert!(key.starts_with("nck_"));
+        {
+            let mut conn = pool.get().unwrap();
+            assert_eq!(authenticate_key(&key, &mut conn).map(|bot| bot.id), Some(bot.id));
+            let tampered = format!("{}0", key);
+            assert!(authenticate_key(&tampered, &mut conn).is_none());
+            assert!(authenticate_key("nck_missing", &mut conn).is_none());
+        }
+
+        let req = TestRequest::delete()
+            .uri(&format!("/bots/{}/keys/{}", bot.id, key_id))
+            .insert_header(session(owner.id))
+            .to_request();
+        assert_eq!(call_service(&app, req).await.status(), StatusCode::OK);
+        assert!(authenticate_key(&key, &mut pool.get().unwrap()).is_none());
+    }
+}
@@ -0,0 +1,538 @@
+use crate::audit;
+use crate::models::{Group, NewAuditEntry};
+use crate::webhooks::{self, RoomEvent};
+use chrono::NaiveDateTime;
+use diesel::prelude::*;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use uuid::Uuid;
+
+/// What a command asks `ChatServer` to do once it has run.
+#[derive(Debug)]
+pub enum CommandEffect {
+    /// Private notice to the session that ran the command
+    Reply(String),
+    /// Notice posted to everyone in the room
+    Post(String),
+    /// Close the user's sessions in this room
+    Disconnect { user_id: Uuid, reason: String },
+}
+
+pub type CommandResult = Result<Vec<CommandEffect>, String>;
+
+/// Everything a command handler gets to work with.
+pub struct CommandContext<'a> {
+    pub sender: Uuid,
+    pub room: &'a str,
+    // None for rooms that are not backed by a group
+    pub group: Option<&'a Group>,
+    pub conn: &'a mut PgConnection,
+    pub registry: &'a CommandRegistry,
+}
+
+impl CommandContext<'_> {
+    pub fn require_group(&self) -> Result<&Group, String> {
+        self.group
+            .ok_or_else(|| format!("{} is not a group room, so this command does not work here", self.room))
+    }
+
+    pub fn require_owner(&self) -> Result<&Group, String> {
+        let group = self.require_group()?;
+        if group.owner != self.sender {
+            return Err("Only the group owner can do that".to_string());
+        }
+        Ok(group)
+    }
+
+    pub fn sender_name(&mut self) -> String {
+        crate::schema::users::table
+            .find(self.sender)
+            .select(crate::schema::users::username)
+            .first::<String>(self.conn)
+            .unwrap_or_else(|_| "someone".to_string())
+    }
+
+    /// An audit entry for something the sender did to `target_id` in this room's
+    /// group. Sessions carry no client address, so there is no IP.
+    pub fn audit_entry(&self, action: &str, target_type: &str, target_id: Uuid, after: Option<Value>) -> NewAuditEntry {
+        NewAuditEntry {
+            actor_id: Some(self.sender),
+            action: action.to_owned(),
+            group_id: self.group.map(|group| group.id),
+            target_type: target_type.to_owned(),
+            target_id: Some(target_id),
+            after,
+            ..Default::default()
+        }
+    }
+
+    pub fn find_user(&mut self, username: &str) -> Result<Uuid, String> {
+        crate::schema::users::table
+            .filter(crate::schema::users::username.eq(username))
+            .select(crate::schema::users::id)
+            .first::<Uuid>(self.conn)
+            .map_err(|_| format!("No user named {}", username))
+    }
+}
+
+/// A `/name args` command. Register custom ones with `CommandRegistry::register`.
+pub trait SlashCommand: Send + Sync {
+    fn name(&self) -> &'static str;
+    /// One-line usage shown by `/help`, e.g. `/kick <username>`
+    fn usage(&self) -> &'static str;
+    fn run(&self, ctx: &mut CommandContext, args: &str) -> CommandResult;
+}
+
+#[derive(Default)]
+pub struct CommandRegistry {
+    commands: BTreeMap<&'static str, Box<dyn SlashCommand>>,
+}
+
+impl CommandRegistry {
+    pub fn with_builtins() -> Self {
+        CommandRegistry::default()
+            .register(Me)
+            .register(Topic)
+            .register(Invite)
+            .register(Kick)
+            .register(Mute)
+            .register(Unmute)
+            .register(Help)
+    }
+
+    // A later registration with the same name replaces the earlier one
+    pub fn register(mut self, command: impl SlashCommand + 'static) -> Self {
+        self.commands.insert(command.name(), Box::new(command));
+        self
+    }
+
+    /// Splits `/name args` and runs the matching command.
+    pub fn dispatch(&self, ctx: &mut CommandContext, line: &str) -> CommandResult {
+        let (name, args) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        match self.commands.get(name) {
+            Some(command) => command.run(ctx, args.trim()),
+            None => Err(format!("Unknown command /{}. Try /help", name)),
+        }
+    }
+}
+
+fn is_member(user_id: Uuid, group_id: Uuid, conn: &mut PgConnection) -> QueryResult<bool> {
+    use crate::schema::user_groups;
+    diesel::select(diesel::dsl::exists(
+        user_groups::table
+            .filter(user_groups::user_id.eq(user_id))
+            .filter(user_groups::group_id.eq(group_id)),
+    ))
+    .get_result(conn)
+}
+
+// The detail goes to the server log; the user only learns that the command failed
+fn db_error(e: diesel::result::Error) -> String {
+    tracing::error!(error = ?e, "command failed");
+    "Command failed".to_string()
+}
+
+/// Returns true when `user_id` has an active mute in the group.
+pub fn is_muted(user_id: Uuid, group_id: Uuid, conn: &mut PgConnection) -> QueryResult<bool> {
+    use crate::schema::group_mutes;
+    let now = chrono::Utc::now().naive_utc();
+    diesel::select(diesel::dsl::exists(
+        group_mutes::table
+            .filter(group_mutes::group_id.eq(group_id))
+            .filter(group_mutes::user_id.eq(user_id))
+            .filter(group_mutes::muted_until.is_null().or(group_mutes::muted_until.gt(now))),
+    ))
+    .get_result(conn)
+}
+
+/// Returns true when `user_id` has been banned from the group.
+pub fn is_banned(user_id: Uuid, group_id: Uuid, conn: &mut PgConnection) -> QueryResult<bool> {
+    use crate::schema::group_bans;
+    diesel::select(diesel::dsl::exists(group_bans::table.find((group_id, user_id)))).get_result(conn)
+}
+
+/// Mutes `user_id` in the group until `muted_until`, or until unmuted when `None`.
+/// Replaces any mute already in place.
+pub fn mute(
+    group_id: Uuid,
+    user_id: Uuid,
+    muted_by: Uuid,
+    muted_until: Option<NaiveDateTime>,
+    conn: &mut PgConnection,
+) -> QueryResult<()> {
+    use crate::schema::group_mutes;
+    diesel::insert_into(group_mutes::table)
+        .values((
+            group_mutes::group_id.eq(group_id),
+            group_mutes::user_id.eq(user_id),
+            group_mutes::muted_by.eq(muted_by),
+            group_mutes::muted_until.eq(muted_until),
+        ))
+        .on_conflict((group_mutes::group_id, group_mutes::user_id))
+        .do_update()
+        .set((group_mutes::muted_by.eq(muted_by), group_mutes::muted_until.eq(muted_until)))
+        .execute(conn)?;
+    Ok(())
+}
+
+struct Me;
+
+impl SlashCommand for Me {
+    fn name(&self) -> &'static str {
+        "me"
+    }
+    fn usage(&self) -> &'static str {
+        "/me <action> - describe what you are doing"
+    }
+    fn run(&self, ctx: &mut CommandContext, args: &str) -> CommandResult {
+        if args.is_empty() {
+            return Err("Usage: /me <action>".to_string());
+        }
+        Ok(vec![CommandEffect::Post(format!("* {} {}", ctx.sender_name(), args))])
+    }
+}
+
+struct Topic;
+
+impl SlashCommand for Topic {
+    fn name(&self) -> &'static str {
+        "topic"
+    }
+    fn usage(&self) -> &'static str {
+        "/topic [text] - show or (owner only) set the room topic"
+    }
+    fn run(&self, ctx: &mut CommandContext, args: &str) -> CommandResult {
+        use crate::schema::groups;
+        if args.is_empty() {
+            let group = ctx.require_group()?;
+            let topic = group.description.as_deref().unwrap_or("No topic set");
+            return Ok(vec![CommandEffect::Reply(format!("Topic: {}", topic))]);
+        }
+        let previous = ctx.require_owner()?;
+        let group_id = previous.id;
+        let mut entry = ctx.audit_entry("group.update", "group", group_id, Some(json!({"description": args})));
+        entry.before = Some(json!({"description": previous.description}));
+        ctx.conn
+            .transaction(|conn| {
+                let group = diesel::update(groups::table.find(group_id))
+                    .set(groups::description.eq(args))
+                    .returning(Group::as_returning())
+                    .get_result(conn)?;
+                audit::record(conn, entry)?;
+                webhooks::enqueue(conn, group_id, RoomEvent::Edit, json!(group))
+            })
+            .map_err(db_error)?;
+        Ok(vec![CommandEffect::Post(format!(
+            "{} changed the topic to: {}",
+            ctx.sender_name(),
+            args
+        ))])
+    }
+}
+
+struct Invite;
+
+impl SlashCommand for Invite {
+    fn name(&self) -> &'static str {
+        "invite"
+    }
+    fn usage(&self) -> &'static str {
+        "/invite <username> - add someone to this group"
+    }
+    fn run(&self, ctx: &mut CommandContext, args: &str) -> CommandResult {
+        use crate::schema::user_groups;
+        let group_id = ctx.require_group()?.id;
+        if !is_member(ctx.sender, group_id, ctx.conn).map_err(db_error)? {
+            return Err("Only members can invite others".to_string());
+        }
+        let user_id = ctx.find_user(args)?;
+        if is_banned(user_id, group_id, ctx.conn).map_err(db_error)? {
+            return Err(format!("{} is banned from this group", args));
+        }
+        let data = json!({"user_id": user_id, "actor_id": ctx.sender});
+        let entry = ctx.audit_entry("member.invite", "user", user_id, Some(json!({"role": "member"})));
+        let added = ctx
+            .conn
+            .transaction(|conn| {
+                let added = diesel::insert_into(user_groups::table)
+                    .values((user_groups::user_id.eq(user_id), user_groups::group_id.eq(group_id)))
+                    .on_conflict_do_nothing()
+                    .execute(conn)?;
+                if added > 0 {
+                    webhooks::enqueue(conn, group_id, RoomEvent::Join, data)?;
+                    audit::record(conn, entry)?;
+                }
+                QueryResult::Ok(added)
+            })
+            .map_err(db_error)?;
+        if added == 0 {
+            return Err(format!("{} is already a member", args));
+        }
+        Ok(vec![CommandEffect::Post(format!("{} invited {}", ctx.sender_name(), args))])
+    }
+}
+
+struct Kick;
+
+impl SlashCommand for Kick {
+    fn name(&self) -> &'static str {
+        "kick"
+    }
+    fn usage(&self) -> &'static str {
+        "/kick <username> - remove someone from this group (owner only)"
+    }
+    fn run(&self, ctx: &mut CommandContext, args: &str) -> CommandResult {
+        use crate::schema::user_groups;
+        let group_id = ctx.require_owner()?.id;
+        let user_id = ctx.find_user(args)?;
+        if user_id == ctx.sender {
+            return Err("You cannot kick yourself".to_string());
+        }
+        let data = json!({"user_id": user_id, "actor_id": ctx.sender});
+        let mut entry = ctx.audit_entry("member.kick", "user", user_id, None);
+        let removed = ctx
+            .conn
+            .transaction(|conn| {
+                let role = diesel::delete(user_groups::table.find((user_id, group_id)))
+                    .returning(user_groups::role)
+                    .get_result::<String>(conn)
+                    .optional()?;
+                let removed = usize::from(role.is_some());
+                if let Some(role) = role {
+                    webhooks::enqueue(conn, group_id, RoomEvent::Leave, data)?;
+                    entry.before = Some(json!({"role": role}));
+                    audit::record(conn, entry)?;
+                }
+                QueryResult::Ok(removed)
+            })
+            .map_err(db_error)?;
+        if removed == 0 {
+            return Err(format!("{} is not a member", args));
+        }
+        Ok(vec![
+            CommandEffect::Disconnect {
+                user_id,
+                reason: "You were removed from this group".to_string(),
+            },
+            CommandEffect::Post(format!("{} removed {}", ctx.sender_name(), args)),
+        ])
+    }
+}
+
+struct Mute;
+
+impl SlashCommand for Mute {
+    fn name(&self) -> &'static str {
+        "mute"
+    }
+    fn usage(&self) -> &'static str {
+        "/mute <username> [minutes] - stop someone posting here (owner only)"
+    }
+    fn run(&self, ctx: &mut CommandContext, args: &str) -> CommandResult {
+        let group_id = ctx.require_owner()?.id;
+        let mut parts = args.split_whitespace();
+        let username = parts.next().ok_or("Usage: /mute <username> [minutes]")?;
+        let minutes = match parts.next() {
+            Some(m) => Some(
+                m.parse::<i64>()
+                    .ok()
+                    .filter(|m| *m > 0)
+                    .ok_or("Minutes must be a positive number")?,
+            ),
+            None => None,
+        };
+        let user_id = ctx.find_user(username)?;
+        if user_id == ctx.sender {
+            return Err("You cannot mute yourself".to_string());
+        }
+        let muted_until = minutes.map(|m| chrono::Utc::now().naive_utc() + chrono::Duration::minutes(m));
+        let entry = ctx.audit_entry("member.mute", "user", user_id, Some(json!({"muted_until": muted_until})));
+        ctx.conn
+            .transaction(|conn| {
+                mute(group_id, user_id, ctx.sender, muted_until, conn)?;
+                audit::record(conn, entry)
+            })
+            .map_err(db_error)?;
+        let duration = minutes.map_or("until further notice".to_string(), |m| format!("for {} minutes", m));
+        Ok(vec![CommandEffect::Post(format!(
+            "{} muted {} {}",
+            ctx.sender_name(),
+            username,
+            duration
+        ))])
+    }
+}
+
+struct Unmute;
+
+impl SlashCommand for Unmute {
+    fn name(&self) -> &'static str {
+        "unmute"
+    }
+    fn usage(&self) -> &'static str {
+        "/unmute <username> - lift a mute (owner only)"
+    }
+    fn run(&self, ctx: &mut CommandContext, args: &str) -> CommandResult {
+        use crate::schema::group_mutes;
+        let group_id = ctx.require_owner()?.id;
+        let user_id = ctx.find_user(args)?;
+        let entry = ctx.audit_entry("member.unmute", "user", user_id, None);
+        let removed = ctx
+            .conn
+            .transaction(|conn| {
+                let removed = diesel::delete(
+                    group_mutes::table
+                        .filter(group_mutes::group_id.eq(group_id))
+                        .filter(group_mutes::user_id.eq(user_id)),
+                )
+                .execute(conn)?;
+                if removed > 0 {
+                    audit::record(conn, entry)?;
+                }
+                QueryResult::Ok(removed)
+            })
+            .map_err(db_error)?;
+        if removed == 0 {
+            return Err(format!("{} is not muted", args));
+        }
+        Ok(vec![CommandEffect::Post(format!("{} unmuted {}", ctx.sender_name(), args))])
+    }
+}
+
+struct Help;
+
+impl SlashCommand for Help {
+    fn name(&self) -> &'static str {
+        "help"
+    }
+    fn usage(&self) -> &'static str {
+        "/help - list available commands"
+    }
+    fn run(&self, ctx: &mut CommandContext, _args: &str) -> CommandResult {
+        let lines: Vec<&str> = ctx.registry.commands.values().map(|c| c.usage()).collect();
+        Ok(vec![CommandEffect::Reply(lines.join("\n"))])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::User;
+    use crate::test_support;
+
+    struct Echo;
+
+    impl SlashCommand for Echo {
+        fn name(&self) -> &'static str {
+            "me"
+        }
+        fn usage(&self) -> &'static str {
+            "/me - echo"
+        }
+        fn run(&self, _: &mut CommandContext, args: &str) -> CommandResult {
+            Ok(vec![CommandEffect::Reply(args.to_owned())])
+        }
+    }
+
+    fn run(registry: &CommandRegistry, sender: &User, group: Option<&Group>, line: &str, conn: &mut PgConnection) -> CommandResult {
+        let mut ctx = CommandContext {
+            sender: sender.id,
+            room: "room",
+            group,
+            conn,
+            registry,
+        };
+        registry.dispatch(&mut ctx, line)
+    }
+
+    fn text(effects: &[CommandEffect]) -> Vec<&str> {
+        effects
+            .iter()
+            .map(|effect| match effect {
+                CommandEffect::Reply(text) | CommandEffect::Post(text) => text.as_str(),
+                CommandEffect::Disconnect { reason, .. } => reason.as_str(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn commands_are_looked_up_by_name_and_can_be_replaced() {
+        let Some(mut conn) = test_support::connection() else { return };
+        let user = test_support::user(&mut conn);
+        let builtins = CommandRegistry::with_builtins();
+        let effects = run(&builtins, &user, None, "me  waves hello ", &mut conn).unwrap();
+        assert_eq!(text(&effects), [format!("* {} waves hello", user.username)]);
+        let unknown = run(&builtins, &user, None, "dance", &mut conn).unwrap_err();
+        assert_eq!(unknown, "Unknown command /dance. Try /help");
+        let help = run(&builtins, &user, None, "help", &mut conn).unwrap();
+        assert!(text(&help)[0].lines().any(|line| line.starts_with("/kick")));
+
+        let custom = CommandRegistry::with_builtins().register(Echo);
+        let effects = run(&custom, &user, None, "me hi", &mut conn).unwrap();
+        assert!(matches!(&effects[..], [CommandEffect::Reply(text)] if text == "hi"));
+    }
+
+    #[test]
+    fn group_commands_need_a_group_and_its_owner() {
+        let Some(mut conn) = test_support::connection() else { return };
+        let owner = test_support::user(&mut conn);
+        let member = test_support::user(&mut conn);
+        let group = test_support::group(owner.id, &mut conn);
+        test_support::join(member.id, group.id, "member", &mut conn);
+        let registry = CommandRegistry::with_builtins();
+
+        let line = format!("kick {}", owner.username);
+        assert!(run(&registry, &member, None, &line, &mut conn).unwrap_err().contains("not a group room"));
+        let refused = run(&registry, &member, Some(&group), &line, &mut conn).unwrap_err();
+        assert_eq!(refused, "Only the group owner can do that");
+
+        let effects = run(&registry, &owner, Some(&group), "topic Release day", &mut conn).unwrap();
+        assert_eq!(text(&effects), [format!("{} changed the topic to: Release day", owner.username)]);
+        let group = crate::schema::groups::table
+            .find(group.id)
+            .select(Group::as_select())
+            .first(&mut conn)
+            .unwrap();
+        let effects = run(&registry, &member, Some(&group), "topic", &mut conn).unwrap();
+        assert_eq!(text(&effects), ["Topic: Release day"]);
+    }
+
+    #[test]
+    fn owners_can_mute_and_kick_members() {
+        let Some(mut conn) = test_support::connection() else { return };
+        let owner = test_support::user(&mut conn);
+        let member = test_support::user(&mut conn);
+        let group = test_support::group(owner.id, &mut conn);
+        test_support::join(member.id, group.id, "member", &mut conn);
+        let registry = CommandRegistry::with_builtins();
+
+        let bad = run(&registry, &owner, Some(&group), &format!("mute {} soon", member.username), &mut conn);
+        assert_eq!(bad.unwrap_err(), "Minutes must be a positive number");
+        run(&registry, &owner, Some(&group), &format!("mute {} 5", member.username), &mut conn).unwrap();
+        assert!(is_muted(member.id, group.id, &mut conn).unwrap());
+        run(&registry, &owner, Some(&group), &format!("unmute {}", member.username), &mut conn).unwrap();
+        assert!(!is_muted(member.id, group.id, &mut conn).unwrap());
+
+        let effects = run(&registry, &owner, Some(&group), &format!("kick {}", member.username), &mut conn).unwrap();
+        assert!(matches!(effects[0], CommandEffect::Disconnect { user_id, .. } if user_id == member.id));
+        assert!(!is_member(member.id, group.id, &mut conn).unwrap());
+        let again = run(&registry, &owner, Some(&group), &format!("kick {}", member.username), &mut conn);
+        assert_eq!(again.unwrap_err(), format!("{} is not a member", member.username));
+    }
+
+    #[test]
+    fn banned_users_cannot_be_invited_back() {
+        let Some(mut conn) = test_support::connection() else { return };
+        let owner = test_support::user(&mut conn);
+        let banned = test_support::user(&mut conn);
+        let guest = test_support::user(&mut conn);
+        let group = test_support::group(owner.id, &mut conn);
+        test_support::ban(banned.id, group.id, &mut conn);
+        let registry = CommandRegistry::with_builtins();
+
+        let refused = run(&registry, &owner, Some(&group), &format!("invite {}", banned.username), &mut conn);
+        assert_eq!(refused.unwrap_err(), format!("{} is banned from this group", banned.username));
+        run(&registry, &owner, Some(&group), &format!("invite {}", guest.username), &mut conn).unwrap();
+        assert!(is_member(guest.id, group.id, &mut conn).unwrap());
+    }
+}
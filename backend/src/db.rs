@@ -1,13 +1,83 @@
+use crate::errors::ApiResult;
+use crate::metrics;
+use crate::settings::DatabaseSettings;
+use actix_web::web;
 use diesel::pg::PgConnection;
-use diesel::r2d2::{self, ConnectionManager};
-use dotenv::dotenv;
-use std::env;
+use diesel::r2d2::{self, ConnectionManager, PoolError, PooledConnection};
+use std::time::Instant;
 
 pub type DbPool = r2d2::Pool<ConnectionManager<PgConnection>>;
+pub type DbConnection = PooledConnection<ConnectionManager<PgConnection>>;
 
-pub fn establish_connection() -> DbPool {
-    dotenv().ok();
-    let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
-    let manager = ConnectionManager::<PgConnection>::new(database_url);
-    r2d2::Pool::builder().build(manager).expect("Failed to create database pool")
+pub fn establish_connection(settings: &DatabaseSettings) -> DbPool {
+    let manager = ConnectionManager::<PgConnection>::new(&settings.url);
+    r2d2::Pool::builder()
+        .max_size(settings.pool_max_size)
+        .min_idle(settings.pool_min_idle)
+        .connection_timeout(settings.connection_timeout())
+        .idle_timeout(settings.idle_timeout())
+        .build(manager)
+        .expect("Failed to create database pool")
+}
+
+/// Checks a connection out of `pool`, recording the wait for `/metrics`.
+pub fn connection(pool: &DbPool) -> Result<DbConnection, PoolError> {
+    let started = Instant::now();
+    let conn = pool.get();
+    metrics::DB_POOL_WAIT.observe(started.elapsed());
+    if let Err(e) = &conn {
+        metrics::DB_POOL_TIMEOUTS.inc();
+        tracing::warn!(error = %e, "database pool exhausted");
+    }
+    conn
+}
+
+/// Runs `f` with a pooled connection on the blocking thread pool, so Diesel
+/// queries and password hashing never stall the async workers.
+pub async fn run<T, F>(pool: &DbPool, f: F) -> ApiResult<T>
+where
+    F: FnOnce(&mut PgConnection) -> ApiResult<T> + Send + 'static,
+    T: Send + 'static,
+{
+    let pool = pool.clone();
+    // The blocking pool runs `f` on another thread; keep its logs under the caller's request
+    let span = tracing::Span::current();
+    web::block(move || {
+        let _entered = span.enter();
+        let mut conn = connection(&pool)?;
+        let started = Instant::now();
+        let result = f(&mut conn);
+        let elapsed = started.elapsed();
+        metrics::DB_QUERY_DURATION.observe(elapsed);
+        tracing::debug!(elapsed_us = elapsed.as_micros() as u64, ok = result.is_ok(), "database work finished");
+        result
+    })
+    .await?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::errors::ApiError;
+    use crate::test_support;
+    use std::time::Duration;
+
+    #[actix_web::test]
+    async fn work_runs_off_the_async_worker() {
+        let Some(pool) = test_support::pool() else { return };
+        let caller = std::thread::current().id();
+        let worker = run(&pool, |_| Ok(std::thread::current().id())).await.unwrap();
+        assert_ne!(worker, caller);
+    }
+
+    #[actix_web::test]
+    async fn an_exhausted_pool_is_unavailable() {
+        // Nothing listens on port 1, so no connection can be checked out
+        let pool = r2d2::Pool::builder()
+            .min_idle(Some(0))
+            .connection_timeout(Duration::from_millis(100))
+            .build_unchecked(ConnectionManager::new("postgres://localhost:1/none"));
+        let result = run(&pool, |_| Ok(())).await;
+        assert!(matches!(result, Err(ApiError::Unavailable)));
+    }
 }
@@ -0,0 +1,198 @@
+use actix_web::http::{header, StatusCode};
+use actix_web::{HttpResponse, ResponseError};
+use diesel::r2d2::PoolError;
+use diesel::result::{DatabaseErrorKind, Error as DieselError};
+use serde_json::json;
+use std::fmt;
+use std::time::Duration;
+
+pub type ApiResult<T = HttpResponse> = Result<T, ApiError>;
+
+/// Error returned by HTTP handlers.
+///
+/// Clients get `{"error": <message>, "code": <code>}`. Codes are stable and safe to
+/// match on; messages are for humans and may change.
+#[derive(Debug)]
+pub enum ApiError {
+    BadRequest(&'static str),
+    Unauthorized(&'static str),
+    Forbidden(&'static str),
+    NotFound(&'static str),
+    Conflict(&'static str),
+    // Over a rate limit; holds how long until the next request would be let through
+    RateLimited(Duration),
+    // No pooled connection became free in time
+    Unavailable,
+    // The detail is logged, never sent to the client
+    Internal(String),
+}
+
+impl ApiError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::BadRequest(_) => "bad_request",
+            ApiError::Unauthorized(_) => "unauthorized",
+            ApiError::Forbidden(_) => "forbidden",
+            ApiError::NotFound(_) => "not_found",
+            ApiError::Conflict(_) => "conflict",
+            ApiError::RateLimited(_) => "rate_limited",
+            ApiError::Unavailable => "service_unavailable",
+            ApiError::Internal(_) => "internal_error",
+        }
+    }
+
+    /// Maps a Diesel error, giving `NotFound` a resource-specific message.
+    pub fn db(not_found: &'static str) -> impl Fn(DieselError) -> ApiError {
+        move |e| match e {
+            DieselError::NotFound => ApiError::NotFound(not_found),
+            e => ApiError::from(e),
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiError::BadRequest(message)
+            | ApiError::Unauthorized(message)
+            | ApiError::Forbidden(message)
+            | ApiError::NotFound(message)
+            | ApiError::Conflict(message) => f.write_str(message),
+            ApiError::RateLimited(_) => f.write_str("Too many requests, slow down"),
+            ApiError::Unavailable => f.write_str("Service temporarily unavailable"),
+            ApiError::Internal(_) => f.write_str("Internal server error"),
+        }
+    }
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ApiError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            ApiError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            ApiError::Forbidden(_) => StatusCode::FORBIDDEN,
+            ApiError::NotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::Conflict(_) => StatusCode::CONFLICT,
+            ApiError::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
+            ApiError::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+            ApiError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        if let ApiError::Internal(detail) = self {
+            tracing::error!(%detail, "internal error");
+        }
+        if let ApiError::RateLimited(retry_after) = self {
+            // Whole seconds for the header, rounded up so an immediate retry is never invited
+            let secs = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+            return HttpResponse::build(self.status_code())
+                .insert_header((header::RETRY_AFTER, secs))
+                .json(json!({"error": self.to_string(), "code": self.code(), "retry_after_ms": retry_after.as_millis()}));
+        }
+        HttpResponse::build(self.status_code()).json(json!({"error": self.to_string(), "code": self.code()}))
+    }
+}
+
+impl From<DieselError> for ApiError {
+    fn from(e: DieselError) -> Self {
+        match e {
+            DieselError::NotFound => ApiError::NotFound("Not found"),
+            DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, _) => {
+                ApiError::Conflict("Resource already exists")
+            }
+            DieselError::DatabaseError(DatabaseErrorKind::ForeignKeyViolation, _) => {
+                ApiError::BadRequest("Referenced resource does not exist")
+            }
+            e => ApiError::Internal(format!("database: {:?}", e)),
+        }
+    }
+}
+
+impl From<PoolError> for ApiError {
+    fn from(_: PoolError) -> Self {
+        // db::connection has already logged it
+        ApiError::Unavailable
+    }
+}
+
+impl From<uuid::Error> for ApiError {
+    fn from(_: uuid::Error) -> Self {
+        ApiError::BadRequest("Invalid UUID")
+    }
+}
+
+impl From<actix_web::error::BlockingError> for ApiError {
+    fn from(e: actix_web::error::BlockingError) -> Self {
+        ApiError::Internal(format!("blocking task: {}", e))
+    }
+}
+
+impl From<bcrypt::BcryptError> for ApiError {
+    fn from(e: bcrypt::BcryptError) -> Self {
+        ApiError::Internal(format!("bcrypt: {:?}", e))
+    }
+}
+
+impl From<serde_json::Error> for ApiError {
+    fn from(e: serde_json::Error) -> Self {
+        ApiError::Internal(format!("json: {:?}", e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::body::to_bytes;
+    use serde_json::Value;
+
+    async fn body(error: &ApiError) -> Value {
+        let bytes = to_bytes(error.error_response().into_body()).await.unwrap();
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    #[actix_web::test]
+    async fn errors_carry_a_status_code_and_message() {
+        let cases = [
+            (ApiError::BadRequest("bad"), StatusCode::BAD_REQUEST, "bad_request"),
+            (ApiError::Unauthorized("who"), StatusCode::UNAUTHORIZED, "unauthorized"),
+            (ApiError::Forbidden("no"), StatusCode::FORBIDDEN, "forbidden"),
+            (ApiError::NotFound("gone"), StatusCode::NOT_FOUND, "not_found"),
+            (ApiError::Conflict("taken"), StatusCode::CONFLICT, "conflict"),
+            (ApiError::Unavailable, StatusCode::SERVICE_UNAVAILABLE, "service_unavailable"),
+        ];
+        for (error, status, code) in cases {
+            assert_eq!(error.status_code(), status);
+            assert_eq!(body(&error).await, json!({"error": error.to_string(), "code": code}));
+        }
+        assert_eq!(body(&ApiError::NotFound("Group not found")).await["error"], "Group not found");
+    }
+
+    #[actix_web::test]
+    async fn internal_details_are_not_sent_to_the_client() {
+        let error = ApiError::Internal("password=hunter2".to_owned());
+        assert_eq!(error.status_code(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(body(&error).await, json!({"error": "Internal server error", "code": "internal_error"}));
+    }
+
+    #[actix_web::test]
+    async fn rate_limits_round_retry_after_up() {
+        let error = ApiError::RateLimited(Duration::from_millis(1500));
+        let response = error.error_response();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers().get(header::RETRY_AFTER).unwrap(), "2");
+        assert_eq!(body(&error).await["retry_after_ms"], 1500);
+        let exact = ApiError::RateLimited(Duration::from_secs(3)).error_response();
+        assert_eq!(exact.headers().get(header::RETRY_AFTER).unwrap(), "3");
+    }
+
+    #[test]
+    fn database_errors_map_to_client_errors_where_they_can() {
+        let db_error = |kind| DieselError::DatabaseError(kind, Box::new(String::from("detail")));
+        assert!(matches!(ApiError::from(DieselError::NotFound), ApiError::NotFound(_)));
+        assert!(matches!(ApiError::from(db_error(DatabaseErrorKind::UniqueViolation)), ApiError::Conflict(_)));
+        assert!(matches!(ApiError::from(db_error(DatabaseErrorKind::ForeignKeyViolation)), ApiError::BadRequest(_)));
+        assert!(matches!(ApiError::from(db_error(DatabaseErrorKind::SerializationFailure)), ApiError::Internal(_)));
+        assert!(matches!(ApiError::db("Group not found")(DieselError::NotFound), ApiError::NotFound("Group not found")));
+    }
+}
@@ -0,0 +1,571 @@
+use crate::audit;
+use crate::db::{self, DbPool};
+use crate::errors::{ApiError, ApiResult};
+use crate::models::{Group, NewAuditEntry};
+use crate::moderation;
+use crate::ratelimit::RateLimits;
+use crate::sessions;
+use crate::settings::{FilterAction, FilterSettings};
+use crate::ws::{BannedWordsChanged, ChatServer};
+use actix::Addr;
+use actix_web::{web, HttpRequest, HttpResponse};
+use diesel::prelude::*;
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::{BTreeSet, HashSet};
+use std::ops::Range;
+use uuid::Uuid;
+
+/// Names accepted in `filters.chain`.
+pub const BUILTIN_FILTERS: [&str; 4] = ["banned_words", "links", "invites", "mentions"];
+
+// Most banned words one group can have
+const BANNED_WORDS_MAX: usize = 500;
+
+/// What one filter makes of a message.
+pub enum Verdict {
+    Allow,
+    /// Carry on down the chain with this text; the reason is shown to the sender
+    Rewrite { content: String, reason: String },
+    /// Keep the message back for the group's moderators
+    Hold(String),
+    Reject(String),
+}
+
+/// Everything a filter gets to work with.
+pub struct FilterContext<'a> {
+    pub sender: Option<Uuid>,
+    // None for rooms that are not backed by a group
+    pub group: Option<&'a Group>,
+    // None while the database is unavailable
+    pub conn: Option<&'a mut PgConnection>,
+    // The group's banned words as the room last loaded them; when None they
+    // are read from the database
+    pub banned_words: Option<&'a HashSet<String>>,
+}
+
+/// A check run on every chat message before it is stored or broadcast.
+/// Add custom ones with `FilterChain::push`.
+pub trait MessageFilter: Send + Sync {
+    fn name(&self) -> &'static str;
+    fn check(&self, ctx: &mut FilterContext, content: &str) -> Verdict;
+}
+
+/// Where a message ends up once the whole chain has seen it.
+pub enum Decision {
+    // `notices` tells the sender about every rewrite
+    Post { content: String, notices: Vec<String> },
+    Hold { content: String, reason: String },
+    Reject(String),
+}
+
+#[derive(Default)]
+pub struct FilterChain {
+    filters: Vec<Box<dyn MessageFilter>>,
+}
+
+impl FilterChain {
+    /// The built-in filters named in `filters.chain`, in that order.
+    pub fn from_settings(settings: &FilterSettings) -> Self {
+        let lowercase = |list: &[String]| list.iter().map(|item| item.trim().to_lowercase()).collect::<Vec<_>>();
+        let mut chain = FilterChain::default();
+        for name in &settings.chain {
+            chain = match name.as_str() {
+                "banned_words" => chain.push(BannedWords {
+                    action: settings.banned_words_action,
+                }),
+                "links" => chain.push(Links {
+                    allow: lowercase(&settings.link_allow),
+                    deny: lowercase(&settings.link_deny),
+                    action: settings.link_action,
+                }),
+                "invites" => chain.push(Invites {
+                    patterns: lowercase(&settings.invite_patterns),
+                    action: settings.invite_action,
+                }),
+                "mentions" => chain.push(Mentions {
+                    max: settings.max_mentions,
+                }),
+                // Settings::validate rejects anything else
+                _ => chain,
+            };
+        }
+        chain
+    }
+
+    pub fn push(mut self, filter: impl MessageFilter + 'static) -> Self {
+        self.filters.push(Box::new(filter));
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.filters.is_empty()
+    }
+
+    /// Runs every filter in order. Rewrites feed the next filter; the first
+    /// hold or reject ends the run.
+    pub fn run(&self, ctx: &mut FilterContext, content: &str) -> Decision {
+        let mut content = content.to_owned();
+        let mut notices = Vec::new();
+        for filter in &self.filters {
+            match filter.check(ctx, &content) {
+                Verdict::Allow => {}
+                Verdict::Rewrite {
+                    content: rewritten,
+                    reason,
+                } => {
+                    tracing::debug!(filter = filter.name(), "message rewritten");
+                    content = rewritten;
+                    notices.push(reason);
+                }
+                Verdict::Hold(reason) => {
+                    tracing::info!(filter = filter.name(), "message held for review");
+                    return Decision::Hold { content, reason };
+                }
+                Verdict::Reject(reason) => {
+                    tracing::info!(filter = filter.name(), "message rejected");
+                    return Decision::Reject(reason);
+                }
+            }
+        }
+        Decision::Post { content, notices }
+    }
+}
+
+// Hold or reject; `mask` only makes sense for banned words and falls back to rejecting
+fn act(action: FilterAction, reason: String) -> Verdict {
+    match action {
+        FilterAction::Hold => Verdict::Hold(reason),
+        FilterAction::Mask | FilterAction::Reject => Verdict::Reject(reason),
+    }
+}
+
+// Byte ranges of the runs of letters and digits in `text`
+fn word_ranges(text: &str) -> Vec<Range<usize>> {
+    let mut ranges = Vec::new();
+    let mut start = None;
+    for (i, c) in text.char_indices() {
+        match (c.is_alphanumeric(), start) {
+            (true, None) => start = Some(i),
+            (false, Some(s)) => {
+                ranges.push(s..i);
+                start = None;
+            }
+            _ => {}
+        }
+    }
+    if let Some(s) = start {
+        ranges.push(s..text.len());
+    }
+    ranges
+}
+
+/// The group's own banned words, matched as whole words whatever their case.
+struct BannedWords {
+    action: FilterAction,
+}
+
+impl MessageFilter for BannedWords {
+    fn name(&self) -> &'static str {
+        "banned_words"
+    }
+
+    fn check(&self, ctx: &mut FilterContext, content: &str) -> Verdict {
+        let loaded;
+        let banned = match ctx.banned_words {
+            Some(banned) => banned,
+            None => {
+                let (Some(group), Some(conn)) = (ctx.group, ctx.conn.as_deref_mut()) else {
+                    return Verdict::Allow;
+                };
+                loaded = match banned_words(group.id, conn) {
+                    Ok(words) => words.into_iter().collect::<HashSet<String>>(),
+                    Err(e) => {
+                        tracing::error!(error = ?e, "failed to load banned words");
+                        return Verdict::Allow;
+                    }
+                };
+                &loaded
+            }
+        };
+        let hits: Vec<Range<usize>> = word_ranges(content)
+            .into_iter()
+            .filter(|range| banned.contains(&content[range.clone()].to_lowercase()))
+            .collect();
+        if hits.is_empty() {
+            return Verdict::Allow;
+        }
+        if self.action != FilterAction::Mask {
+            return act(self.action, "Your message contains words this group does not allow".to_owned());
+        }
+        let mut masked = String::with_capacity(content.len());
+        let mut end = 0;
+        for range in hits {
+            masked.push_str(&content[end..range.start]);
+            masked.extend(content[range.clone()].chars().map(|_| '*'));
+            end = range.end;
+        }
+        masked.push_str(&content[end..]);
+        Verdict::Rewrite {
+            content: masked,
+            reason: "Words this group does not allow were starred out".to_owned(),
+        }
+    }
+}
+
+/// Links must not point at a denied domain and, when an allow list is set,
+/// must point at one of its domains. Subdomains count as their parent.
+struct Links {
+    allow: Vec<String>,
+    deny: Vec<String>,
+    action: FilterAction,
+}
+
+// Hosts of the `scheme://` and `www.` links in `text`
+fn link_hosts(text: &str) -> Vec<String> {
+    let mut hosts = Vec::new();
+    for token in text.split_whitespace() {
+        let token = token.to_lowercase();
+        let rest = match token.find("://") {
+            Some(i) => &token[i + 3..],
+            None if token.starts_with("www.") => &token[..],
+            None => continue,
+        };
+        let authority = rest.split(['/', '?', '#']).next().unwrap_or_default();
+        let host = authority.rsplit('@').next().unwrap_or_default();
+        let host = host.split(':').next().unwrap_or_default();
+        let host = host.trim_end_matches(|c: char| !c.is_alphanumeric());
+        if !host.is_empty() {
+            hosts.push(host.to_owned());
+        }
+    }
+    hosts
+}
+
+fn on_domain(host: &str, domains: &[String]) -> bool {
+    domains
+        .iter()
+        .any(|domain| host == domain || host.strip_suffix(domain.as_str()).is_some_and(|sub| sub.ends_with('.')))
+}
+
+impl MessageFilter for Links {
+    fn name(&self) -> &'static str {
+        "links"
+    }
+
+    fn check(&self, _: &mut FilterContext, content: &str) -> Verdict {
+        let blocked = link_hosts(content)
+            .into_iter()
+            .find(|host| on_domain(host, &self.deny) || (!self.allow.is_empty() && !on_domain(host, &self.allow)));
+        match blocked {
+            Some(host) => act(self.action, format!("Links to {} are not allowed here", host)),
+            None => Verdict::Allow,
+        }
+    }
+}
+
+/// Invite links to other chat services, a favourite of spam accounts.
+struct Invites {
+    patterns: Vec<String>,
+    action: FilterAction,
+}
+
+impl MessageFilter for Invites {
+    fn name(&self) -> &'static str {
+        "invites"
+    }
+
+    fn check(&self, _: &mut FilterContext, content: &str) -> Verdict {
+        let content = content.to_lowercase();
+        if self.patterns.iter().any(|pattern| content.contains(pattern.as_str())) {
+            act(self.action, "Your message contains an invite link to another chat service".to_owned())
+        } else {
+            Verdict::Allow
+        }
+    }
+}
+
+/// Caps how many different people one message can @mention.
+struct Mentions {
+    max: usize,
+}
+
+impl MessageFilter for Mentions {
+    fn name(&self) -> &'static str {
+        "mentions"
+    }
+
+    fn check(&self, _: &mut FilterContext, content: &str) -> Verdict {
+        if self.max == 0 {
+            return Verdict::Allow;
+        }
+        // Only at the start of a word, so email addresses are not counted
+        let mentioned: HashSet<String> = content
+            .split_whitespace()
+            .filter_map(|token| token.strip_prefix('@'))
+            .map(|name| name.trim_end_matches(|c: char| !c.is_alphanumeric() && c != '_').to_lowercase())
+            .filter(|name| !name.is_empty())
+            .collect();
+        if mentioned.len() > self.max {
+            Verdict::Reject(format!("Messages may mention at most {} people", self.max))
+        } else {
+            Verdict::Allow
+        }
+    }
+}
+
+pub fn banned_words(group_id: Uuid, conn: &mut PgConnection) -> QueryResult<Vec<String>> {
+    use crate::schema::group_banned_words;
+    group_banned_words::table
+        .filter(group_banned_words::group_id.eq(group_id))
+        .order(group_banned_words::word)
+        .select(group_banned_words::word)
+        .load(conn)
+}
+
+pub async fn list_banned_words(
+    req: HttpRequest,
+    path: web::Path<Uuid>,
+    pool: web::Data<DbPool>,
+) -> ApiResult {
+    let group_id = path.into_inner();
+    let caller = sessions::current_user(&req, &pool).await?;
+    let response = db::run(&pool, move |conn| {
+        moderation::require_moderator(caller.id, Some(group_id), conn)?;
+        Ok(json!({"group_id": group_id, "words": banned_words(group_id, conn)?}))
+    })
+    .await?;
+    Ok(HttpResponse::Ok().json(response))
+}
+
+#[derive(Deserialize)]
+pub struct BannedWordsRequest {
+    // Replaces the whole list
+    pub words: Vec<String>,
+}
+
+pub async fn set_banned_words(
+    req: HttpRequest,
+    path: web::Path<Uuid>,
+    form: web::Json<BannedWordsRequest>,
+    pool: web::Data<DbPool>,
+    rate_limits: web::Data<RateLimits>,
+    srv: web::Data<Addr<ChatServer>>,
+) -> ApiResult {
+    use crate::schema::group_banned_words;
+
+    let group_id = path.into_inner();
+    let actor_id = sessions::current_user(&req, &pool).await?.id;
+    let words: BTreeSet<String> = form
+        .words
+        .iter()
+        .map(|word| word.trim().to_lowercase())
+        .filter(|word| !word.is_empty())
+        .collect();
+    if words.iter().any(|word| !word.chars().all(char::is_alphanumeric)) {
+        return Err(ApiError::BadRequest("Banned words must be single words of letters and digits"));
+    }
+    if words.len() > BANNED_WORDS_MAX {
+        return Err(ApiError::BadRequest("Too many banned words"));
+    }
+    let ip = rate_limits.client_addr(&req);
+    let (room, response) = db::run(&pool, move |conn| {
+        conn.transaction(|conn| {
+            moderation::require_moderator(actor_id, Some(group_id), conn)?;
+            let room = crate::schema::groups::table
+                .find(group_id)
+                .select(crate::schema::groups::name)
+                .first::<String>(conn)?;
+            let previous = banned_words(group_id, conn)?;
+            diesel::delete(group_banned_words::table.filter(group_banned_words::group_id.eq(group_id)))
+                .execute(conn)?;
+            let rows: Vec<_> = words
+                .iter()
+                .map(|word| (group_banned_words::group_id.eq(group_id), group_banned_words::word.eq(word)))
+                .collect();
+            diesel::insert_into(group_banned_words::table).values(&rows).execute(conn)?;
+            audit::record(
+                conn,
+                NewAuditEntry {
+                    actor_id: Some(actor_id),
+                    action: "group.banned_words".to_owned(),
+                    group_id: Some(group_id),
+                    target_type: "group".to_owned(),
+                    target_id: Some(group_id),
+                    ip: Some(ip),
+                    before: Some(json!({"words": previous})),
+                    after: Some(json!({"words": words})),
+                },
+            )?;
+            Ok((room, json!({"group_id": group_id, "words": words})))
+        })
+    })
+    .await?;
+    // Rooms keep the list they loaded until told otherwise
+    srv.do_send(BannedWordsChanged { room });
+    Ok(HttpResponse::Ok().json(response))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::User;
+    use crate::test_support;
+    use actix_web::http::StatusCode;
+    use actix_web::test::{call_and_read_body_json, call_service, init_service, TestRequest};
+    use actix_web::App;
+    use serde_json::Value;
+
+    fn run(chain: &FilterChain, banned: &[&str], content: &str) -> Decision {
+        let banned: HashSet<String> = banned.iter().map(|word| word.to_string()).collect();
+        let mut ctx = FilterContext {
+            sender: None,
+            group: None,
+            conn: None,
+            banned_words: Some(&banned),
+        };
+        chain.run(&mut ctx, content)
+    }
+
+    fn posted(decision: Decision) -> String {
+        match decision {
+            Decision::Post { content, .. } => content,
+            Decision::Hold { reason, .. } | Decision::Reject(reason) => panic!("not posted: {}", reason),
+        }
+    }
+
+    #[test]
+    fn words_are_runs_of_letters_and_digits() {
+        let text = "héllo, wörld 42!";
+        let words: Vec<&str> = word_ranges(text).into_iter().map(|range| &text[range]).collect();
+        assert_eq!(words, ["héllo", "wörld", "42"]);
+    }
+
+    #[test]
+    fn banned_words_are_masked_as_whole_words() {
+        let chain = FilterChain::from_settings(&FilterSettings::default());
+        assert_eq!(posted(run(&chain, &["darn"], "Darn, darnation. DARN")), "****, darnation. ****");
+        assert!(matches!(
+            run(&chain, &["darn"], "darn"),
+            Decision::Post { notices, .. } if notices.len() == 1
+        ));
+    }
+
+    #[test]
+    fn banned_words_can_reject_instead() {
+        let settings = FilterSettings {
+            banned_words_action: FilterAction::Reject,
+            ..FilterSettings::default()
+        };
+        let chain = FilterChain::from_settings(&settings);
+        assert!(matches!(run(&chain, &["darn"], "oh darn"), Decision::Reject(_)));
+        assert_eq!(posted(run(&chain, &["darn"], "oh dear")), "oh dear");
+    }
+
+    #[test]
+    fn link_hosts_ignore_credentials_ports_and_paths() {
+        let hosts = link_hosts("see https://me:pw@Docs.Example.com:8080/a?b, www.test.org. or ftp://x.io");
+        assert_eq!(hosts, ["docs.example.com", "www.test.org", "x.io"]);
+    }
+
+    #[test]
+    fn subdomains_count_as_their_parent() {
+        let domains = ["example.com".to_owned()];
+        assert!(on_domain("example.com", &domains));
+        assert!(on_domain("docs.example.com", &domains));
+        assert!(!on_domain("badexample.com", &domains));
+    }
+
+    #[test]
+    fn links_follow_the_allow_and_deny_lists() {
+        let settings = FilterSettings {
+            link_allow: vec!["example.com".to_owned()],
+            link_deny: vec!["evil.example.com".to_owned()],
+            ..FilterSettings::default()
+        };
+        let chain = FilterChain::from_settings(&settings);
+        assert_eq!(posted(run(&chain, &[], "https://example.com/x")), "https://example.com/x");
+        assert!(matches!(run(&chain, &[], "https://evil.example.com"), Decision::Reject(_)));
+        assert!(matches!(run(&chain, &[], "https://other.org"), Decision::Reject(_)));
+    }
+
+    #[test]
+    fn invite_links_are_held_by_default() {
+        let chain = FilterChain::from_settings(&FilterSettings::default());
+        assert!(matches!(run(&chain, &[], "join DISCORD.gg/abc"), Decision::Hold { .. }));
+    }
+
+    #[test]
+    fn mentions_count_distinct_people() {
+        let settings = FilterSettings {
+            max_mentions: 2,
+            ..FilterSettings::default()
+        };
+        let chain = FilterChain::from_settings(&settings);
+        assert_eq!(posted(run(&chain, &[], "@ann @Ann, @bob me@mail.com")), "@ann @Ann, @bob me@mail.com");
+        assert!(matches!(run(&chain, &[], "@ann @bob @cy"), Decision::Reject(_)));
+    }
+
+    #[test]
+    fn rewrites_feed_the_next_filter_and_the_first_stop_ends_the_run() {
+        // Masking the word leaves no link for the links filter to reject
+        let settings = FilterSettings {
+            link_deny: vec!["spam.com".to_owned()],
+            ..FilterSettings::default()
+        };
+        let chain = FilterChain::from_settings(&settings);
+        assert_eq!(posted(run(&chain, &["spam"], "https://spam.com")), "https://****.com");
+
+        let settings = FilterSettings {
+            chain: vec!["invites".to_owned(), "mentions".to_owned()],
+            max_mentions: 1,
+            ..FilterSettings::default()
+        };
+        let chain = FilterChain::from_settings(&settings);
+        assert!(matches!(run(&chain, &[], "@a @b discord.gg/x"), Decision::Hold { .. }));
+    }
+
+    #[actix_web::test]
+    async fn banned_words_are_managed_by_signed_in_moderators() {
+        let Some(pool) = test_support::pool() else { return };
+        let (group, owner, member) = {
+            let mut conn = pool.get().unwrap();
+            let (owner, member) = (test_support::user(&mut conn), test_support::user(&mut conn));
+            let group = test_support::group(owner.id, &mut conn);
+            test_support::join(member.id, group.id, "member", &mut conn);
+            (group, owner, member)
+        };
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(pool.clone()))
+                .app_data(web::Data::new(RateLimits::new(&Default::default())))
+                .app_data(web::Data::new(test_support::chat_server(pool.clone(), FilterChain::default())))
+                .app_data(test_support::session_signer())
+                .route("/groups/{id}/banned-words", web::get().to(list_banned_words))
+                .route("/groups/{id}/banned-words", web::put().to(set_banned_words)),
+        )
+        .await;
+        let set = |session: Option<&User>| {
+            let mut req = TestRequest::put()
+                .uri(&format!("/groups/{}/banned-words", group.id))
+                .set_json(json!({"user_id": owner.id, "words": ["Spam"]}));
+            if let Some(user) = session {
+                req = req.insert_header(test_support::signed_in(user));
+            }
+            req.to_request()
+        };
+        let list = |user: &User| {
+            TestRequest::get()
+                .uri(&format!("/groups/{}/banned-words?user_id={}", group.id, owner.id))
+                .insert_header(test_support::signed_in(user))
+                .to_request()
+        };
+
+        assert_eq!(call_service(&app, set(None)).await.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(call_service(&app, set(Some(&member))).await.status(), StatusCode::FORBIDDEN);
+        assert_eq!(call_service(&app, list(&member)).await.status(), StatusCode::FORBIDDEN);
+        assert_eq!(call_service(&app, set(Some(&owner))).await.status(), StatusCode::OK);
+        let listed: Value = call_and_read_body_json(&app, list(&owner)).await;
+        assert_eq!(listed["words"], json!(["spam"]));
+    }
+}
@@ -1,9 +1,18 @@
-use crate::db::DbPool;
-use crate::models::Group;
-use actix_web::{web, HttpResponse, Responder};
+use crate::audit;
+use crate::db::{self, DbPool};
+use crate::errors::{ApiError, ApiResult};
+use crate::models::{Group, GroupMember, Membership, Message, NewAuditEntry};
+use crate::polls::{self, PollTally};
+use crate::ratelimit::RateLimits;
+use crate::sessions;
+use crate::settings::Settings;
+use crate::webhooks::{self, RoomEvent};
+use actix_web::{web, HttpRequest, HttpResponse};
+use chrono::NaiveDateTime;
 use diesel::prelude::*;
-use serde::Deserialize;
-use serde_json::{json, to_value};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::HashMap;
 use uuid::Uuid;
 
 #[derive(Deserialize)]
@@ -11,83 +20,666 @@ pub struct UpdateGroupRequest {
     pub id: String,
     pub name: String,
     pub description: Option<String>,
-    pub members: Vec<String>,
-    pub owner: String,
+    // Hands the group to this member; membership itself changes through join, leave, invite and kick
+    pub new_owner: Option<Uuid>,
 }
 
-pub async fn get_groups(pool: web::Data<DbPool>) -> impl Responder {
-    let mut conn = pool.get().expect("Failed to get DB connection");
-    let groups = crate::schema::groups::table
-        .load::<Group>(&mut conn)
-        .expect("Error loading groups");
-    HttpResponse::Ok().json(groups)
+#[derive(Serialize)]
+pub struct GroupSummary {
+    #[serde(flatten)]
+    pub group: Group,
+    pub member_count: i64,
 }
 
+/// The group, if `user_id` owns it.
+pub fn require_owner(group_id: Uuid, user_id: Uuid, conn: &mut PgConnection) -> ApiResult<Group> {
+    let group = crate::schema::groups::table
+        .find(group_id)
+        .select(Group::as_select())
+        .first(conn)
+        .map_err(ApiError::db("Group not found"))?;
+    if group.owner != user_id {
+        return Err(ApiError::Forbidden("Only the group owner can do that"));
+    }
+    Ok(group)
+}
+
+/// The member's role in the group, or `None` when they are not a member.
+pub fn member_role(user_id: Uuid, group_id: Uuid, conn: &mut PgConnection) -> QueryResult<Option<String>> {
+    use crate::schema::user_groups;
+    user_groups::table
+        .find((user_id, group_id))
+        .select(user_groups::role)
+        .first(conn)
+        .optional()
+}
+
+pub async fn get_groups(pool: web::Data<DbPool>) -> ApiResult {
+    use crate::schema::{groups, user_groups};
+
+    let response = db::run(&pool, move |conn| {
+        let groups = groups::table
+            .order(groups::name)
+            .select(Group::as_select())
+            .load(conn)?;
+        let counts: HashMap<Uuid, i64> = user_groups::table
+            .group_by(user_groups::group_id)
+            .select((user_groups::group_id, diesel::dsl::count_star()))
+            .load::<(Uuid, i64)>(conn)?
+            .into_iter()
+            .collect();
+        let summaries: Vec<GroupSummary> = groups
+            .into_iter()
+            .map(|group| GroupSummary {
+                member_count: counts.get(&group.id).copied().unwrap_or(0),
+                group,
+            })
+            .collect();
+        Ok(summaries)
+    })
+    .await?;
+    Ok(HttpResponse::Ok().json(response))
+}
+
+#[derive(Deserialize)]
+pub struct MembersQuery {
+    pub limit: Option<i64>,
+    pub offset: Option<i64>,
+}
+
+// Oldest members first
+pub async fn get_members(
+    path: web::Path<Uuid>,
+    query: web::Query<MembersQuery>,
+    pool: web::Data<DbPool>,
+    settings: web::Data<Settings>,
+) -> ApiResult {
+    use crate::schema::{groups, user_groups, users};
+
+    let group_id = path.into_inner();
+    let page_max = settings.limits.member_page_max;
+    let response = db::run(&pool, move |conn| {
+        groups::table
+            .find(group_id)
+            .select(groups::id)
+            .first::<Uuid>(conn)
+            .map_err(ApiError::db("Group not found"))?;
+        let total = user_groups::table
+            .filter(user_groups::group_id.eq(group_id))
+            .count()
+            .get_result::<i64>(conn)?;
+        let members = user_groups::table
+            .inner_join(users::table)
+            .filter(user_groups::group_id.eq(group_id))
+            .order((user_groups::joined_at, user_groups::user_id))
+            .limit(query.limit.unwrap_or(50).clamp(1, page_max))
+            .offset(query.offset.unwrap_or(0).max(0))
+            .select((user_groups::user_id, users::username, user_groups::role, user_groups::joined_at))
+            .load::<GroupMember>(conn)?;
+        Ok(json!({"group_id": group_id, "member_count": total, "members": members}))
+    })
+    .await?;
+    Ok(HttpResponse::Ok().json(response))
+}
+
+#[derive(Deserialize)]
+pub struct RoleRequest {
+    // "moderator" or "member"; ownership moves through update-group
+    pub role: String,
+}
+
+pub async fn set_member_role(
+    req: HttpRequest,
+    path: web::Path<(Uuid, Uuid)>,
+    form: web::Json<RoleRequest>,
+    pool: web::Data<DbPool>,
+    rate_limits: web::Data<RateLimits>,
+) -> ApiResult {
+    use crate::schema::user_groups;
+
+    let (group_id, member_id) = path.into_inner();
+    if !matches!(form.role.as_str(), "moderator" | "member") {
+        return Err(ApiError::BadRequest("Role must be moderator or member"));
+    }
+    let caller = sessions::current_user(&req, &pool).await?;
+    let ip = rate_limits.client_addr(&req);
+    db::run(&pool, move |conn| {
+        conn.transaction(|conn| {
+            require_owner(group_id, caller.id, conn)?;
+            let previous = member_role(member_id, group_id, conn)?.ok_or(ApiError::NotFound("Not a member of this group"))?;
+            if previous == "owner" {
+                return Err(ApiError::BadRequest("Transfer ownership to change the owner's role"));
+            }
+            diesel::update(user_groups::table.find((member_id, group_id)))
+                .set(user_groups::role.eq(&form.role))
+                .execute(conn)?;
+            audit::record(
+                conn,
+                NewAuditEntry {
+                    actor_id: Some(caller.id),
+                    action: "member.role".to_owned(),
+                    group_id: Some(group_id),
+                    target_type: "user".to_owned(),
+                    target_id: Some(member_id),
+                    ip: Some(ip),
+                    before: Some(json!({"role": previous})),
+                    after: Some(json!({"role": form.role})),
+                },
+            )?;
+            Ok(())
+        })
+    })
+    .await?;
+    Ok(HttpResponse::Ok().json(json!({"message": "Role updated"})))
+}
+
+// Only the signed-in owner may edit the group
 pub async fn update_group(
+    req: HttpRequest,
     pool: web::Data<DbPool>,
+    rate_limits: web::Data<RateLimits>,
     form: web::Json<UpdateGroupRequest>,
-) -> impl Responder {
-    let mut conn = pool.get().expect("Failed to get DB connection");
-    let group_id = Uuid::parse_str(&form.id).expect("Invalid UUID in form.id");
-    let owner_id = Uuid::parse_str(&form.owner).expect("Invalid UUID in form.owner");
-    let updated_members = to_value(&form.members).unwrap();
+) -> ApiResult {
+    let ip = rate_limits.client_addr(&req);
+    let caller = sessions::current_user(&req, &pool).await?;
+    let response = db::run(&pool, move |conn| {
+        let group_id = Uuid::parse_str(&form.id)?;
+        conn.transaction(|conn| update_group_rows(group_id, caller.id, &form, ip, conn))?;
+        Ok(json!({"message": "Group updated successfully"}))
+    })
+    .await?;
+    Ok(HttpResponse::Ok().json(response))
+}
 
-    let result = diesel::update(crate::schema::groups::table)
-        .filter(crate::schema::groups::id.eq(group_id))
-        .set((
-            crate::schema::groups::name.eq(&form.name),
-            crate::schema::groups::description.eq(&form.description),
-            crate::schema::groups::members.eq(updated_members),
-            crate::schema::groups::owner.eq(owner_id),
-        ))
-        .execute(&mut conn);
-
-    match result {
-        Ok(_) => HttpResponse::Ok().json(json!({"message": "Group updated successfully"})),
-        Err(e) => HttpResponse::BadRequest().json(json!({"error": format!("Failed to update group: {:?}", e)})),
+fn update_group_rows(
+    group_id: Uuid,
+    caller_id: Uuid,
+    form: &UpdateGroupRequest,
+    ip: String,
+    conn: &mut PgConnection,
+) -> ApiResult<()> {
+    use crate::schema::groups;
+
+    let previous = require_owner(group_id, caller_id, conn)?;
+    if let Some(new_owner) = form.new_owner {
+        transfer_ownership(group_id, new_owner, conn)?;
     }
+    let group = diesel::update(groups::table.find(group_id))
+        .set((groups::name.eq(&form.name), groups::description.eq(&form.description)))
+        .returning(Group::as_returning())
+        .get_result(conn)?;
+
+    webhooks::enqueue(conn, group_id, RoomEvent::Edit, json!(group))?;
+    audit::record(
+        conn,
+        NewAuditEntry {
+            actor_id: Some(caller_id),
+            action: "group.update".to_owned(),
+            group_id: Some(group_id),
+            target_type: "group".to_owned(),
+            target_id: Some(group_id),
+            ip: Some(ip),
+            before: Some(json!(previous)),
+            after: Some(json!(group)),
+        },
+    )?;
+    Ok(())
 }
 
+/// Makes `new_owner`, who must already be a member, the group's owner and the
+/// previous owner a plain member. Run it inside a transaction.
+pub fn transfer_ownership(group_id: Uuid, new_owner: Uuid, conn: &mut PgConnection) -> ApiResult<()> {
+    use crate::schema::{groups, user_groups};
 
-pub async fn delete_group(path: web::Path<Uuid>, pool: web::Data<DbPool>) -> impl Responder {
+    let previous_owner = groups::table
+        .find(group_id)
+        .select(groups::owner)
+        .first::<Uuid>(conn)
+        .map_err(ApiError::db("Group not found"))?;
+    if new_owner == previous_owner {
+        return Ok(());
+    }
+    let membership = user_groups::table
+        .find((new_owner, group_id))
+        .select(Membership::as_select())
+        .first(conn)
+        .optional()?;
+    if membership.is_none() {
+        return Err(ApiError::BadRequest("The new owner must be a member of the group"));
+    }
+    diesel::update(user_groups::table.find((previous_owner, group_id)))
+        .set(user_groups::role.eq("member"))
+        .execute(conn)?;
+    diesel::update(user_groups::table.find((new_owner, group_id)))
+        .set(user_groups::role.eq("owner"))
+        .execute(conn)?;
+    diesel::update(groups::table.find(group_id))
+        .set(groups::owner.eq(new_owner))
+        .execute(conn)?;
+    Ok(())
+}
+
+
+// Only the signed-in owner may delete a group; site admins use DELETE /admin/groups/{id}
+pub async fn delete_group(
+    req: HttpRequest,
+    path: web::Path<Uuid>,
+    pool: web::Data<DbPool>,
+    rate_limits: web::Data<RateLimits>,
+) -> ApiResult {
     let group_id = path.into_inner();
-    let mut conn = pool.get().expect("Failed to get DB connection");
+    let ip = rate_limits.client_addr(&req);
+    let caller = sessions::current_user(&req, &pool).await?;
+    let deleted = db::run(&pool, move |conn| {
+        conn.transaction(|conn| {
+            let group = require_owner(group_id, caller.id, conn)?;
+            let deleted = delete_group_rows(group_id, conn)?;
+            audit::record(conn, deletion_entry(&group, Some(caller.id), Some(ip)))?;
+            Ok(deleted)
+        })
+    })
+    .await?;
+    match deleted {
+        0 => Err(ApiError::NotFound("Group not found")),
+        _ => {
+            tracing::info!(%group_id, "group deleted");
+            Ok(HttpResponse::Ok().json(json!({"message": "Group deleted successfully"})))
+        }
+    }
+}
 
-    println!("Attempting to delete group: {}", group_id);
+/// The audit entry for deleting `group`, keeping what it looked like.
+pub fn deletion_entry(group: &Group, actor_id: Option<Uuid>, ip: Option<String>) -> NewAuditEntry {
+    NewAuditEntry {
+        actor_id,
+        action: "group.delete".to_owned(),
+        group_id: Some(group.id),
+        target_type: "group".to_owned(),
+        target_id: Some(group.id),
+        ip,
+        before: Some(json!(group)),
+        after: None,
+    }
+}
 
+/// Deletes a group and its memberships; everything else goes through `ON DELETE CASCADE`.
+pub fn delete_group_rows(group_id: Uuid, conn: &mut PgConnection) -> ApiResult<usize> {
     // Check if group exists
     let group_exists = crate::schema::groups::table
         .filter(crate::schema::groups::id.eq(group_id))
-        .first::<Group>(&mut conn)
+        .select(Group::as_select())
+        .first(conn)
         .is_ok();
     if !group_exists {
-        println!("Group not found: {}", group_id);
-        return HttpResponse::NotFound().json(json!({"error": "Group not found"}));
+        return Err(ApiError::NotFound("Group not found"));
     }
 
     // Delete user_groups entries
     let user_groups_deleted = diesel::delete(
         crate::schema::user_groups::table.filter(crate::schema::user_groups::group_id.eq(group_id))
     )
-    .execute(&mut conn)
-    .unwrap_or(0);
-    println!("Deleted {} user_groups rows", user_groups_deleted);
+    .execute(conn)?;
+    tracing::debug!(%group_id, memberships = user_groups_deleted, "group memberships deleted");
 
     // Delete the group
-    let result = diesel::delete(
+    let deleted = diesel::delete(
         crate::schema::groups::table.filter(crate::schema::groups::id.eq(group_id))
     )
-    .execute(&mut conn);
+    .execute(conn)?;
+    Ok(deleted)
+}
 
-    match result {
-        Ok(count) if count > 0 => {
-            println!("Deleted group with id: {}", group_id);
-            HttpResponse::Ok().json(json!({"message": "Group deleted successfully"}))
-        },
-        Ok(_) => HttpResponse::NotFound().json(json!({"error": "Group not found"})),
-        Err(e) => {
-            println!("Error deleting group: {:?}", e);
-            HttpResponse::InternalServerError().json(json!({"error": format!("Failed to delete group: {:?}", e)}))
-        },
+#[derive(Deserialize)]
+pub struct HistoryQuery {
+    pub before: Option<NaiveDateTime>,
+    // Only messages newer than this, e.g. the resume_after of a dropped socket
+    pub after: Option<NaiveDateTime>,
+    pub limit: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct HistoryEntry {
+    #[serde(flatten)]
+    pub message: Message,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub poll: Option<PollTally>,
+}
+
+// Newest messages first; page backwards with `before`, bound the page with `after`
+pub async fn get_messages(
+    req: HttpRequest,
+    path: web::Path<Uuid>,
+    query: web::Query<HistoryQuery>,
+    pool: web::Data<DbPool>,
+    settings: web::Data<Settings>,
+) -> ApiResult {
+    use crate::schema::messages;
+
+    let group_id = path.into_inner();
+    let page_max = settings.limits.history_page_max;
+    let reader = sessions::current_user(&req, &pool).await?;
+    let response = db::run(&pool, move |conn| {
+        if member_role(reader.id, group_id, conn)?.is_none() {
+            return Err(ApiError::Forbidden("Only members can read this group's history"));
+        }
+        let now = chrono::Utc::now().naive_utc();
+        let mut history = messages::table
+            .filter(messages::group_id.eq(group_id))
+            // Expired rows may still be waiting for the retention sweeper
+            .filter(messages::expires_at.is_null().or(messages::expires_at.gt(now)))
+            .order(messages::timestamp.desc())
+            .limit(query.limit.unwrap_or(50).clamp(1, page_max))
+            .into_boxed();
+        if let Some(before) = query.before {
+            history = history.filter(messages::timestamp.lt(before));
+        }
+        if let Some(after) = query.after {
+            history = history.filter(messages::timestamp.gt(after));
+        }
+        let rows = history.load::<Message>(conn)?;
+
+        // Poll rows share their id with the announcing message
+        let poll_ids: Vec<Uuid> = rows.iter().filter(|m| m.kind == "poll").map(|m| m.id).collect();
+        let mut tallies = polls::tallies(&poll_ids, conn)?;
+        let entries: Vec<HistoryEntry> = rows
+            .into_iter()
+            .map(|message| HistoryEntry {
+                poll: tallies.remove(&message.id),
+                message,
+            })
+            .collect();
+        Ok(entries)
+    })
+    .await?;
+    Ok(HttpResponse::Ok().json(response))
+}
+
+#[derive(Deserialize)]
+pub struct RetentionRequest {
+    // None keeps messages forever
+    pub retention_seconds: Option<i64>,
+}
+
+pub async fn update_retention(
+    req: HttpRequest,
+    path: web::Path<Uuid>,
+    pool: web::Data<DbPool>,
+    form: web::Json<RetentionRequest>,
+) -> ApiResult {
+    use crate::schema::groups;
+
+    let group_id = path.into_inner();
+    let caller = sessions::current_user(&req, &pool).await?;
+    let response = db::run(&pool, move |conn| {
+        if form.retention_seconds.is_some_and(|seconds| seconds < 60) {
+            return Err(ApiError::BadRequest("Retention must be at least 60 seconds"));
+        }
+        require_owner(group_id, caller.id, conn)?;
+
+        conn.transaction(|conn| {
+            let group = diesel::update(groups::table.find(group_id))
+                .set(groups::retention_seconds.eq(form.retention_seconds))
+                .returning(Group::as_returning())
+                .get_result(conn)?;
+            webhooks::enqueue(conn, group_id, RoomEvent::Edit, json!(group))
+        })?;
+        Ok(json!({"message": "Retention updated successfully"}))
+    })
+    .await?;
+    Ok(HttpResponse::Ok().json(response))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+    use crate::models::User;
+    use actix_web::http::StatusCode;
+    use actix_web::test::{call_and_read_body_json, call_service, init_service, TestRequest};
+    use actix_web::App;
+    use serde_json::Value;
+
+    #[actix_web::test]
+    async fn history_carries_the_tally_of_each_poll() {
+        let Some(pool) = test_support::pool() else { return };
+        let (group, owner, outsider, polls) = {
+            let mut conn = pool.get().unwrap();
+            let (owner, outsider) = (test_support::user(&mut conn), test_support::user(&mut conn));
+            let group = test_support::group(owner.id, &mut conn);
+            let polls: Vec<PollTally> = (0..2)
+                .map(|_| {
+                    let req = polls::CreatePollRequest {
+                        group_id: group.id,
+                        question: "Lunch?".to_owned(),
+                        options: vec!["Pizza".to_owned(), "Soup".to_owned()],
+                        multiple_choice: false,
+                        anonymous: false,
+                        closes_at: None,
+                    };
+                    polls::create_poll(&req, owner.id, &mut conn).unwrap()
+                })
+                .collect();
+            polls::cast_vote(polls[1].poll_id, owner.id, &[polls[1].options[0].id], &mut conn).unwrap();
+            (group, owner, outsider, polls)
+        };
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(pool.clone()))
+                .app_data(web::Data::new(Settings::default()))
+                .app_data(test_support::session_signer())
+                .route("/groups/{id}/messages", web::get().to(get_messages)),
+        )
+        .await;
+        let read = |user: &User| {
+            TestRequest::get()
+                .uri(&format!("/groups/{}/messages", group.id))
+                .insert_header(test_support::signed_in(user))
+                .to_request()
+        };
+
+        assert_eq!(call_service(&app, read(&outsider)).await.status(), StatusCode::FORBIDDEN);
+        let history: Vec<Value> = call_and_read_body_json(&app, read(&owner)).await;
+        assert_eq!(history.len(), 2);
+        for poll in &polls {
+            let entry = history.iter().find(|entry| entry["id"] == poll.poll_id.to_string()).unwrap();
+            assert_eq!(entry["poll"]["question"], "Lunch?");
+        }
+        let voted = history.iter().find(|entry| entry["id"] == polls[1].poll_id.to_string()).unwrap();
+        assert_eq!(voted["poll"]["total_voters"], 1);
+    }
+
+    #[test]
+    fn ownership_moves_between_members() {
+        let Some(mut conn) = test_support::connection() else { return };
+        let conn = &mut conn;
+        let (owner, member, outsider) = (test_support::user(conn), test_support::user(conn), test_support::user(conn));
+        let group = test_support::group(owner.id, conn);
+        test_support::join(member.id, group.id, "member", conn);
+
+        assert!(matches!(
+            transfer_ownership(group.id, outsider.id, conn),
+            Err(ApiError::BadRequest(_))
+        ));
+        transfer_ownership(group.id, member.id, conn).unwrap();
+        assert_eq!(member_role(member.id, group.id, conn).unwrap().as_deref(), Some("owner"));
+        assert_eq!(member_role(owner.id, group.id, conn).unwrap().as_deref(), Some("member"));
+        assert!(require_owner(group.id, member.id, conn).is_ok());
+        assert!(matches!(require_owner(group.id, owner.id, conn), Err(ApiError::Forbidden(_))));
+    }
+
+    #[actix_web::test]
+    async fn members_are_listed_oldest_first() {
+        let Some(pool) = test_support::pool() else { return };
+        let (group, owner, member) = {
+            let mut conn = pool.get().unwrap();
+            let (owner, member) = (test_support::user(&mut conn), test_support::user(&mut conn));
+            let group = test_support::group(owner.id, &mut conn);
+            test_support::join(member.id, group.id, "moderator", &mut conn);
+            // Both rows were stamped by the same transaction
+            diesel::update(crate::schema::user_groups::table.find((owner.id, group.id)))
+                .set(crate::schema::user_groups::joined_at.eq(diesel::dsl::sql("now() - interval '1 day'")))
+                .execute(&mut conn)
+                .unwrap();
+            (group, owner, member)
+        };
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(pool.clone()))
+                .app_data(web::Data::new(Settings::default()))
+                .route("/groups/{id}/members", web::get().to(get_members)),
+        )
+        .await;
+
+        let req = TestRequest::get().uri(&format!("/groups/{}/members", group.id)).to_request();
+        let listing: Value = call_and_read_body_json(&app, req).await;
+        assert_eq!(listing["member_count"], 2);
+        let members = listing["members"].as_array().unwrap();
+        assert_eq!(members[0]["username"], owner.username.as_str());
+        assert_eq!(members[0]["role"], "owner");
+        assert_eq!(members[1]["user_id"], member.id.to_string());
+        assert_eq!(members[1]["role"], "moderator");
+
+        let req = TestRequest::get().uri(&format!("/groups/{}/members?limit=1&offset=1", group.id)).to_request();
+        let page: Value = call_and_read_body_json(&app, req).await;
+        assert_eq!(page["members"].as_array().unwrap().len(), 1);
+        assert_eq!(page["members"][0]["user_id"], member.id.to_string());
+    }
+
+    #[actix_web::test]
+    async fn only_the_signed_in_owner_edits_or_deletes() {
+        let Some(pool) = test_support::pool() else { return };
+        let (group, owner, member) = {
+            let mut conn = pool.get().unwrap();
+            let (owner, member) = (test_support::user(&mut conn), test_support::user(&mut conn));
+            let group = test_support::group(owner.id, &mut conn);
+            test_support::join(member.id, group.id, "member", &mut conn);
+            (group, owner, member)
+        };
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(pool.clone()))
+                .app_data(web::Data::new(RateLimits::new(&Default::default())))
+                .app_data(test_support::session_signer())
+                .route("/update-group", web::put().to(update_group))
+                .route("/groups/{id}", web::delete().to(delete_group)),
+        )
+        .await;
+        let update = |session: Option<&User>| {
+            let mut req = TestRequest::put().uri("/update-group").set_json(json!({
+                "id": group.id,
+                "name": format!("{}-renamed", group.name),
+                "new_owner": member.id,
+                "owner": member.id.to_string(),
+                "user_id": owner.id,
+            }));
+            if let Some(user) = session {
+                req = req.insert_header(test_support::signed_in(user));
+            }
+            req.to_request()
+        };
+        let delete = |user: &User| {
+            TestRequest::delete()
+                .uri(&format!("/groups/{}?user_id={}", group.id, owner.id))
+                .insert_header(test_support::signed_in(user))
+                .to_request()
+        };
+
+        assert_eq!(call_service(&app, update(None)).await.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(call_service(&app, update(Some(&member))).await.status(), StatusCode::FORBIDDEN);
+        assert_eq!(call_service(&app, delete(&member)).await.status(), StatusCode::FORBIDDEN);
+
+        assert_eq!(call_service(&app, update(Some(&owner))).await.status(), StatusCode::OK);
+        {
+            let mut conn = pool.get().unwrap();
+            assert_eq!(member_role(member.id, group.id, &mut conn).unwrap().as_deref(), Some("owner"));
+        }
+        assert_eq!(call_service(&app, delete(&owner)).await.status(), StatusCode::FORBIDDEN);
+        assert_eq!(call_service(&app, delete(&member)).await.status(), StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn retention_is_set_by_the_signed_in_owner() {
+        let Some(pool) = test_support::pool() else { return };
+        let (group, owner, member) = {
+            let mut conn = pool.get().unwrap();
+            let (owner, member) = (test_support::user(&mut conn), test_support::user(&mut conn));
+            let group = test_support::group(owner.id, &mut conn);
+            test_support::join(member.id, group.id, "member", &mut conn);
+            (group, owner, member)
+        };
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(pool.clone()))
+                .app_data(test_support::session_signer())
+                .route("/groups/{id}/retention", web::put().to(update_retention)),
+        )
+        .await;
+        let retain = |session: Option<&User>, seconds: i64| {
+            let mut req = TestRequest::put()
+                .uri(&format!("/groups/{}/retention", group.id))
+                .set_json(json!({"user_id": owner.id, "retention_seconds": seconds}));
+            if let Some(user) = session {
+                req = req.insert_header(test_support::signed_in(user));
+            }
+            req.to_request()
+        };
+
+        assert_eq!(call_service(&app, retain(None, 3600)).await.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(call_service(&app, retain(Some(&member), 3600)).await.status(), StatusCode::FORBIDDEN);
+        assert_eq!(call_service(&app, retain(Some(&owner), 30)).await.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(call_service(&app, retain(Some(&owner), 3600)).await.status(), StatusCode::OK);
+
+        let mut conn = pool.get().unwrap();
+        let stored: Option<i64> = crate::schema::groups::table
+            .find(group.id)
+            .select(crate::schema::groups::retention_seconds)
+            .first(&mut conn)
+            .unwrap();
+        assert_eq!(stored, Some(3600));
+    }
+
+    #[actix_web::test]
+    async fn roles_are_set_by_the_signed_in_owner() {
+        use crate::schema::audit_log;
+
+        let Some(pool) = test_support::pool() else { return };
+        let (group, owner, member) = {
+            let mut conn = pool.get().unwrap();
+            let (owner, member) = (test_support::user(&mut conn), test_support::user(&mut conn));
+            let group = test_support::group(owner.id, &mut conn);
+            test_support::join(member.id, group.id, "member", &mut conn);
+            (group, owner, member)
+        };
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(pool.clone()))
+                .app_data(web::Data::new(RateLimits::new(&Default::default())))
+                .app_data(test_support::session_signer())
+                .route("/groups/{id}/members/{member_id}/role", web::put().to(set_member_role)),
+        )
+        .await;
+        let promote = |session: Option<&User>| {
+            let mut req = TestRequest::put()
+                .uri(&format!("/groups/{}/members/{}/role", group.id, member.id))
+                .set_json(json!({"user_id": owner.id, "role": "moderator"}));
+            if let Some(user) = session {
+                req = req.insert_header(test_support::signed_in(user));
+            }
+            req.to_request()
+        };
+
+        assert_eq!(call_service(&app, promote(None)).await.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(call_service(&app, promote(Some(&member))).await.status(), StatusCode::FORBIDDEN);
+        assert_eq!(call_service(&app, promote(Some(&owner))).await.status(), StatusCode::OK);
+
+        let mut conn = pool.get().unwrap();
+        assert_eq!(member_role(member.id, group.id, &mut conn).unwrap().as_deref(), Some("moderator"));
+        let actors: Vec<Option<Uuid>> = audit_log::table
+            .filter(audit_log::group_id.eq(group.id))
+            .filter(audit_log::action.eq("member.role"))
+            .select(audit_log::actor_id)
+            .load(&mut conn)
+            .unwrap();
+        assert_eq!(actors, vec![Some(owner.id)]);
     }
 }
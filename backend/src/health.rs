@@ -0,0 +1,165 @@
+use crate::db::DbPool;
+use crate::ws::{ChatServer, Ping, Shutdown};
+use actix::Addr;
+use actix_web::dev::ServerHandle;
+use actix_web::{web, HttpResponse};
+use diesel::prelude::*;
+use serde_json::json;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+// Probes are usually given a second or two; answer well inside that
+const CHECK_TIMEOUT: Duration = Duration::from_millis(1500);
+
+/// Whether this instance should still be sent traffic.
+#[derive(Default)]
+pub struct Readiness {
+    draining: AtomicBool,
+}
+
+/// Liveness: the process is up and its HTTP workers answer.
+pub async fn healthz() -> HttpResponse {
+    HttpResponse::Ok().json(json!({"status": "ok"}))
+}
+
+fn check_database(pool: &DbPool) -> bool {
+    let Ok(mut conn) = pool.get_timeout(CHECK_TIMEOUT) else {
+        return false;
+    };
+    diesel::sql_query("SELECT 1").execute(&mut conn).is_ok()
+}
+
+/// Readiness: a database connection can be had and the chat registry answers.
+/// Turns 503 as soon as a shutdown starts, so load balancers stop routing here.
+pub async fn readyz(
+    pool: web::Data<DbPool>,
+    srv: web::Data<Addr<ChatServer>>,
+    readiness: web::Data<Readiness>,
+) -> HttpResponse {
+    if readiness.draining.load(Ordering::Relaxed) {
+        return HttpResponse::ServiceUnavailable().json(json!({"status": "draining"}));
+    }
+    let pool = pool.get_ref().clone();
+    let database = web::block(move || check_database(&pool)).await.unwrap_or(false);
+    let chat_server = srv.send(Ping).timeout(CHECK_TIMEOUT).await.is_ok();
+    let state = |ok: bool| if ok { "ok" } else { "failing" };
+    let body = json!({
+        "status": if database && chat_server { "ready" } else { "not_ready" },
+        "checks": {"database": state(database), "chat_server": state(chat_server)},
+    });
+    if database && chat_server {
+        HttpResponse::Ok().json(body)
+    } else {
+        HttpResponse::ServiceUnavailable().json(body)
+    }
+}
+
+// Resolves with the name of the first SIGTERM or SIGINT
+async fn terminated() -> &'static str {
+    let (tx, mut rx) = mpsc::channel(2);
+    let ctrl_c = tx.clone();
+    actix_web::rt::spawn(async move {
+        if actix_web::rt::signal::ctrl_c().await.is_ok() {
+            let _ = ctrl_c.send("SIGINT").await;
+        }
+    });
+    #[cfg(unix)]
+    actix_web::rt::spawn(async move {
+        use actix_web::rt::signal::unix::{signal, SignalKind};
+        if let Ok(mut term) = signal(SignalKind::terminate()) {
+            term.recv().await;
+            let _ = tx.send("SIGTERM").await;
+        }
+    });
+    rx.recv().await.unwrap_or("SIGINT")
+}
+
+/// Takes over from actix's own signal handling. On SIGTERM or SIGINT it stops accepting
+/// connections, fails `/readyz`, closes every WebSocket with a "reconnect elsewhere" close
+/// frame, waits for the messages those sessions sent to be stored, and then lets in-flight
+/// requests finish before the server exits. Each wait is capped at `timeout`.
+pub async fn shutdown_on_signal(
+    server: ServerHandle,
+    chat_server: Addr<ChatServer>,
+    readiness: web::Data<Readiness>,
+    timeout: Duration,
+) {
+    let signal = terminated().await;
+    tracing::info!(signal, "shutting down");
+    readiness.draining.store(true, Ordering::Relaxed);
+    server.pause().await;
+    match chat_server.send(Shutdown).timeout(timeout).await {
+        Ok(()) => tracing::info!("sessions closed and their messages stored"),
+        Err(e) => tracing::warn!(error = %e, "gave up waiting for sessions to close"),
+    }
+    server.stop(true).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filters::FilterChain;
+    use crate::test_support;
+    use actix_web::http::StatusCode;
+    use actix_web::test::{call_service, init_service, read_body_json, TestRequest};
+    use actix_web::App;
+    use diesel::r2d2::{ConnectionManager, Pool};
+    use serde_json::Value;
+
+    async fn probe(pool: DbPool, readiness: Readiness) -> (StatusCode, Value) {
+        let srv = test_support::chat_server(pool.clone(), FilterChain::default());
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(pool))
+                .app_data(web::Data::new(srv))
+                .app_data(web::Data::new(readiness))
+                .route("/readyz", web::get().to(readyz)),
+        )
+        .await;
+        let res = call_service(&app, TestRequest::get().uri("/readyz").to_request()).await;
+        (res.status(), read_body_json(res).await)
+    }
+
+    // Nothing listens on port 1, so every checkout fails
+    fn unreachable_pool() -> DbPool {
+        Pool::builder()
+            .min_idle(Some(0))
+            .connection_timeout(Duration::from_millis(100))
+            .build_unchecked(ConnectionManager::new("postgres://localhost:1/none"))
+    }
+
+    #[actix_web::test]
+    async fn liveness_needs_nothing() {
+        let app = init_service(App::new().route("/healthz", web::get().to(healthz))).await;
+        let res = call_service(&app, TestRequest::get().uri("/healthz").to_request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn ready_with_a_database_and_a_chat_server() {
+        let Some(pool) = test_support::pool() else { return };
+        let (status, body) = probe(pool, Readiness::default()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["status"], "ready");
+        assert_eq!(body["checks"]["database"], "ok");
+    }
+
+    #[actix_web::test]
+    async fn not_ready_without_a_database() {
+        let (status, body) = probe(unreachable_pool(), Readiness::default()).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body["status"], "not_ready");
+        assert_eq!(body["checks"]["database"], "failing");
+        assert_eq!(body["checks"]["chat_server"], "ok");
+    }
+
+    #[actix_web::test]
+    async fn draining_instances_are_not_ready() {
+        let readiness = Readiness::default();
+        readiness.draining.store(true, Ordering::Relaxed);
+        let (status, body) = probe(unreachable_pool(), readiness).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body["status"], "draining");
+    }
+}
@@ -0,0 +1,284 @@
+use crate::bots::{hash_key, random_hex};
+use crate::db::{self, DbPool};
+use crate::errors::{ApiError, ApiResult};
+use crate::groups::require_owner;
+use crate::models::{IncomingWebhook, Message, NewMessage};
+use crate::sessions;
+use crate::webhooks::{self, RoomEvent};
+use crate::ws::{ChatServer, PostMessage};
+use actix::Addr;
+use actix_web::{web, HttpRequest, HttpResponse};
+use diesel::prelude::*;
+use serde::Deserialize;
+use serde_json::json;
+use uuid::Uuid;
+
+const MAX_NAME_LEN: usize = 64;
+
+#[derive(Deserialize)]
+pub struct CreateIncomingWebhookRequest {
+    pub name: String,
+}
+
+#[derive(Deserialize)]
+pub struct IncomingMessage {
+    pub text: String,
+}
+
+// Secret URL handed out to the integration; the token is never stored in plaintext
+fn hook_url(hook_id: Uuid, token: &str) -> String {
+    format!("/hooks/{}/{}", hook_id, token)
+}
+
+// Creating, listing, rotating and revoking are for the group's signed-in owner
+pub async fn create_incoming_webhook(
+    req: HttpRequest,
+    path: web::Path<Uuid>,
+    pool: web::Data<DbPool>,
+    form: web::Json<CreateIncomingWebhookRequest>,
+) -> ApiResult {
+    let group_id = path.into_inner();
+    let owner = sessions::current_user(&req, &pool).await?;
+    let response = db::run(&pool, move |conn| {
+        require_owner(group_id, owner.id, conn)?;
+        let name = form.name.trim();
+        if name.is_empty() || name.chars().count() > MAX_NAME_LEN {
+            return Err(ApiError::BadRequest("Integration name must be 1 to 64 characters"));
+        }
+
+        let token = random_hex(32);
+        let hook = IncomingWebhook {
+            id: Uuid::new_v4(),
+            group_id,
+            name: name.to_owned(),
+            token_hash: hash_key(&token),
+            created_by: Some(owner.id),
+            created_at: chrono::Utc::now().naive_utc(),
+            rotated_at: None,
+            last_used_at: None,
+            revoked_at: None,
+        };
+        diesel::insert_into(crate::schema::incoming_webhooks::table)
+            .values(&hook)
+            .execute(conn)?;
+        // The URL carries the token, so it is only shown here and on rotation
+        Ok(json!({"incoming_webhook": hook, "url": hook_url(hook.id, &token)}))
+    })
+    .await?;
+    Ok(HttpResponse::Ok().json(response))
+}
+
+pub async fn list_incoming_webhooks(
+    req: HttpRequest,
+    path: web::Path<Uuid>,
+    pool: web::Data<DbPool>,
+) -> ApiResult {
+    use crate::schema::incoming_webhooks;
+
+    let group_id = path.into_inner();
+    let owner = sessions::current_user(&req, &pool).await?;
+    let response = db::run(&pool, move |conn| {
+        require_owner(group_id, owner.id, conn)?;
+        let hooks = incoming_webhooks::table
+            .filter(incoming_webhooks::group_id.eq(group_id))
+            .order(incoming_webhooks::created_at.asc())
+            .load::<IncomingWebhook>(conn)?;
+        Ok(hooks)
+    })
+    .await?;
+    Ok(HttpResponse::Ok().json(response))
+}
+
+/// Replaces the token, so the old URL stops working immediately.
+pub async fn rotate_incoming_webhook(
+    req: HttpRequest,
+    path: web::Path<(Uuid, Uuid)>,
+    pool: web::Data<DbPool>,
+) -> ApiResult {
+    use crate::schema::incoming_webhooks;
+
+    let (group_id, hook_id) = path.into_inner();
+    let owner = sessions::current_user(&req, &pool).await?;
+    let response = db::run(&pool, move |conn| {
+        require_owner(group_id, owner.id, conn)?;
+        let token = random_hex(32);
+        let hook = diesel::update(
+            incoming_webhooks::table
+                .filter(incoming_webhooks::id.eq(hook_id))
+                .filter(incoming_webhooks::group_id.eq(group_id))
+                .filter(incoming_webhooks::revoked_at.is_null()),
+        )
+        .set((
+            incoming_webhooks::token_hash.eq(hash_key(&token)),
+            incoming_webhooks::rotated_at.eq(chrono::Utc::now().naive_utc()),
+        ))
+        .get_result::<IncomingWebhook>(conn)
+        .map_err(ApiError::db("Incoming webhook not found"))?;
+        Ok(json!({"incoming_webhook": hook, "url": hook_url(hook.id, &token)}))
+    })
+    .await?;
+    Ok(HttpResponse::Ok().json(response))
+}
+
+pub async fn revoke_incoming_webhook(
+    req: HttpRequest,
+    path: web::Path<(Uuid, Uuid)>,
+    pool: web::Data<DbPool>,
+) -> ApiResult {
+    use crate::schema::incoming_webhooks;
+
+    let (group_id, hook_id) = path.into_inner();
+    let owner = sessions::current_user(&req, &pool).await?;
+    let response = db::run(&pool, move |conn| {
+        require_owner(group_id, owner.id, conn)?;
+        let revoked = diesel::update(
+            incoming_webhooks::table
+                .filter(incoming_webhooks::id.eq(hook_id))
+                .filter(incoming_webhooks::group_id.eq(group_id))
+                .filter(incoming_webhooks::revoked_at.is_null()),
+        )
+        .set(incoming_webhooks::revoked_at.eq(chrono::Utc::now().naive_utc()))
+        .execute(conn)?;
+        if revoked == 0 {
+            return Err(ApiError::NotFound("Incoming webhook not found"));
+        }
+        Ok(json!({"message": "Incoming webhook revoked"}))
+    })
+    .await?;
+    Ok(HttpResponse::Ok().json(response))
+}
+
+/// Posts `{"text": ...}` into the hook's room, attributed to the integration.
+pub async fn receive(
+    path: web::Path<(Uuid, String)>,
+    pool: web::Data<DbPool>,
+    srv: web::Data<Addr<ChatServer>>,
+    form: web::Json<IncomingMessage>,
+) -> ApiResult {
+    use crate::schema::incoming_webhooks;
+
+    let (hook_id, token) = path.into_inner();
+    let response = db::run(&pool, move |conn| {
+        // Unknown, revoked and wrong-token URLs all look the same to the caller
+        let hook = incoming_webhooks::table
+            .find(hook_id)
+            .filter(incoming_webhooks::revoked_at.is_null())
+            .first::<IncomingWebhook>(conn)
+            .optional()?
+            .filter(|hook| hook.token_hash == hash_key(&token))
+            .ok_or(ApiError::NotFound("Incoming webhook not found"))?;
+        if form.text.trim().is_empty() {
+            return Err(ApiError::BadRequest("text must not be empty"));
+        }
+        let room = crate::polls::room_for_group(hook.group_id, conn).map_err(ApiError::db("Incoming webhook not found"))?;
+
+        let message = conn.transaction(|conn| {
+            let message = Message::create(
+                NewMessage {
+                    id: Uuid::new_v4(),
+                    group_id: Some(hook.group_id),
+                    sender_id: None,
+                    content: form.text.trim().to_owned(),
+                    kind: "text".to_owned(),
+                    expires_at: None,
+                    integration_id: Some(hook.id),
+                    sender_name: Some(hook.name.clone()),
+                },
+                conn,
+            )?;
+            webhooks::enqueue(conn, hook.group_id, RoomEvent::Message, json!(message))?;
+            QueryResult::Ok(message)
+        })?;
+        let _ = diesel::update(incoming_webhooks::table.find(hook.id))
+            .set(incoming_webhooks::last_used_at.eq(chrono::Utc::now().naive_utc()))
+            .execute(conn);
+        srv.do_send(PostMessage {
+            room,
+            message: message.clone(),
+            span: tracing::Span::current(),
+        });
+        Ok(message)
+    })
+    .await?;
+    Ok(HttpResponse::Ok().json(response))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filters::FilterChain;
+    use crate::models::User;
+    use crate::test_support;
+    use actix_web::http::StatusCode;
+    use actix_web::test::{call_and_read_body_json, call_service, init_service, TestRequest};
+    use actix_web::App;
+    use serde_json::Value;
+
+    #[actix_web::test]
+    async fn posts_need_the_current_token_of_a_live_hook() {
+        let Some(pool) = test_support::pool() else { return };
+        let (group, owner, member) = {
+            let mut conn = pool.get().unwrap();
+            let (owner, member) = (test_support::user(&mut conn), test_support::user(&mut conn));
+            let group = test_support::group(owner.id, &mut conn);
+            test_support::join(member.id, group.id, "member", &mut conn);
+            (group, owner, member)
+        };
+        let server = test_support::chat_server(pool.clone(), FilterChain::default());
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(pool.clone()))
+                .app_data(web::Data::new(server))
+                .app_data(test_support::session_signer())
+                .route("/groups/{id}/incoming-webhooks", web::post().to(create_incoming_webhook))
+                .route("/groups/{id}/incoming-webhooks/{hook_id}/rotate", web::post().to(rotate_incoming_webhook))
+                .route("/groups/{id}/incoming-webhooks/{hook_id}", web::delete().to(revoke_incoming_webhook))
+                .route("/hooks/{hook_id}/{token}", web::post().to(receive)),
+        )
+        .await;
+        let post = |url: &str, text: &str| TestRequest::post().uri(url).set_json(json!({"text": text})).to_request();
+
+        let create = |user: &User| {
+            TestRequest::post()
+                .uri(&format!("/groups/{}/incoming-webhooks", group.id))
+                .insert_header(test_support::signed_in(user))
+                .set_json(json!({"user_id": owner.id, "name": "CI"}))
+                .to_request()
+        };
+        assert_eq!(call_service(&app, create(&member)).await.status(), StatusCode::FORBIDDEN);
+        let created: Value = call_and_read_body_json(&app, create(&owner)).await;
+        assert_eq!(created["incoming_webhook"]["created_by"], owner.id.to_string());
+        let url = created["url"].as_str().unwrap().to_owned();
+        let hook_id = created["incoming_webhook"]["id"].as_str().unwrap().to_owned();
+        assert!(created["incoming_webhook"].get("token_hash").is_none());
+
+        let message: Value = call_and_read_body_json(&app, post(&url, "build passed")).await;
+        assert_eq!(message["content"], "build passed");
+        assert_eq!(message["sender_name"], "CI");
+        assert_eq!(call_service(&app, post(&url, " ")).await.status(), StatusCode::BAD_REQUEST);
+        let forged = format!("/hooks/{}/{}", hook_id, "0".repeat(64));
+        assert_eq!(call_service(&app, post(&forged, "hi")).await.status(), StatusCode::NOT_FOUND);
+
+        let rotate = |user: &User| {
+            TestRequest::post()
+                .uri(&format!("/groups/{}/incoming-webhooks/{}/rotate", group.id, hook_id))
+                .insert_header(test_support::signed_in(user))
+                .to_request()
+        };
+        assert_eq!(call_service(&app, rotate(&member)).await.status(), StatusCode::FORBIDDEN);
+        let rotated: Value = call_and_read_body_json(&app, rotate(&owner)).await;
+        let rotated_url = rotated["url"].as_str().unwrap().to_owned();
+        assert_eq!(call_service(&app, post(&url, "hi")).await.status(), StatusCode::NOT_FOUND);
+        assert_eq!(call_service(&app, post(&rotated_url, "hi")).await.status(), StatusCode::OK);
+
+        let revoke = |user: &User| {
+            TestRequest::delete()
+                .uri(&format!("/groups/{}/incoming-webhooks/{}?user_id={}", group.id, hook_id, owner.id))
+                .insert_header(test_support::signed_in(user))
+                .to_request()
+        };
+        assert_eq!(call_service(&app, revoke(&member)).await.status(), StatusCode::FORBIDDEN);
+        assert_eq!(call_service(&app, revoke(&owner)).await.status(), StatusCode::OK);
+        assert_eq!(call_service(&app, post(&rotated_url, "hi")).await.status(), StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,33 @@
+pub mod admin;
+pub mod audit;
+pub mod auth;
+pub mod bots;
+pub mod commands;
+pub mod db;
+pub mod errors;
+pub mod filters;
+pub mod groups;
+pub mod health;
+pub mod incoming_webhooks;
+pub mod logging;
+pub mod mail;
+pub mod metrics;
+pub mod migrations;
+pub mod moderation;
+pub mod models;
+pub mod outbox;
+pub mod password;
+pub mod polls;
+pub mod pubsub;
+pub mod ratelimit;
+pub mod retention;
+pub mod room;
+pub mod schema;
+pub mod sessions;
+pub mod settings;
+pub mod verification;
+pub mod webhooks;
+pub mod ws;
+
+#[cfg(test)]
+mod test_support;
@@ -0,0 +1,448 @@
+use crate::settings::{LogFormat, LoggingSettings};
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::middleware::Next;
+use serde_json::{Map, Value};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt::{self, Write as _};
+use std::io::Write as _;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::subscriber::{Interest, Subscriber};
+use tracing::{Event, Instrument, Level, Metadata};
+use tracing_core::span::Current;
+use tracing_core::LevelFilter;
+use uuid::Uuid;
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+// Longer ids from a client or proxy are replaced rather than logged
+const MAX_REQUEST_ID_LEN: usize = 64;
+
+struct SpanData {
+    metadata: &'static Metadata<'static>,
+    fields: Map<String, Value>,
+    parent: Option<u64>,
+    // Handles to the span plus its open children
+    refs: usize,
+}
+
+struct Inner {
+    max_level: Level,
+    format: LogFormat,
+    spans: Mutex<HashMap<u64, SpanData>>,
+    next_id: AtomicU64,
+}
+
+thread_local! {
+    // Spans entered on this thread, innermost last
+    static ENTERED: RefCell<Vec<u64>> = const { RefCell::new(Vec::new()) };
+}
+
+fn current_id() -> Option<u64> {
+    ENTERED.with(|entered| entered.borrow().last().copied())
+}
+
+struct FieldVisitor<'a>(&'a mut Map<String, Value>);
+
+impl Visit for FieldVisitor<'_> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_owned(), Value::from(value));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().to_owned(), Value::from(value));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().to_owned(), Value::from(value));
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.0.insert(field.name().to_owned(), Value::from(value));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().to_owned(), Value::from(value));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0.insert(field.name().to_owned(), Value::from(format!("{:?}", value)));
+    }
+}
+
+// Strings bare unless they need quoting, everything else as JSON
+fn write_text_value(out: &mut String, value: &Value) {
+    match value {
+        Value::String(s) if !s.is_empty() && !s.contains([' ', '"', '=']) => out.push_str(s),
+        value => {
+            let _ = write!(out, "{}", value);
+        }
+    }
+}
+
+impl Inner {
+    // Outermost first
+    fn span_chain(&self, spans: &HashMap<u64, SpanData>, leaf: Option<u64>) -> Vec<u64> {
+        let mut chain = Vec::new();
+        let mut next = leaf;
+        while let Some(id) = next {
+            chain.push(id);
+            next = spans.get(&id).and_then(|span| span.parent);
+        }
+        chain.reverse();
+        chain
+    }
+
+    fn write(&self, level: Level, target: &str, mut fields: Map<String, Value>, leaf: Option<u64>) {
+        let spans = self.spans.lock().unwrap();
+        let chain = self.span_chain(&spans, leaf);
+        let message = match fields.remove("message") {
+            Some(Value::String(message)) => message,
+            Some(other) => other.to_string(),
+            None => String::new(),
+        };
+        let timestamp = chrono::Utc::now().format("%Y-%m-%dT%H:%M:%S%.6fZ");
+
+        let mut line = String::new();
+        match self.format {
+            LogFormat::Json => {
+                let mut object = Map::new();
+                object.insert("timestamp".to_owned(), Value::from(timestamp.to_string()));
+                object.insert("level".to_owned(), Value::from(level.as_str()));
+                object.insert("target".to_owned(), Value::from(target));
+                object.insert("message".to_owned(), Value::from(message));
+                // The innermost correlation id, hoisted so one filter follows a request or session
+                if let Some(id) = chain.iter().rev().find_map(|id| spans[id].fields.get("correlation_id")) {
+                    object.insert("correlation_id".to_owned(), id.clone());
+                }
+                object.insert("fields".to_owned(), Value::Object(fields));
+                let span_objects = chain
+                    .iter()
+                    .map(|id| {
+                        let mut span = spans[id].fields.clone();
+                        span.insert("name".to_owned(), Value::from(spans[id].metadata.name()));
+                        Value::Object(span)
+                    })
+                    .collect();
+                object.insert("spans".to_owned(), Value::Array(span_objects));
+                line = Value::Object(object).to_string();
+            }
+            LogFormat::Text => {
+                let _ = write!(line, "{} {:>5} ", timestamp, level);
+                for id in &chain {
+                    let span = &spans[id];
+                    line.push_str(span.metadata.name());
+                    if !span.fields.is_empty() {
+                        line.push('{');
+                        for (i, (key, value)) in span.fields.iter().enumerate() {
+                            if i > 0 {
+                                line.push(' ');
+                            }
+                            let _ = write!(line, "{}=", key);
+                            write_text_value(&mut line, value);
+                        }
+                        line.push('}');
+                    }
+                    line.push(':');
+                }
+                if !chain.is_empty() {
+                    line.push(' ');
+                }
+                let _ = write!(line, "{}: {}", target, message);
+                for (key, value) in &fields {
+                    let _ = write!(line, " {}=", key);
+                    write_text_value(&mut line, value);
+                }
+            }
+        }
+        drop(spans);
+        line.push('\n');
+        let _ = std::io::stdout().lock().write_all(line.as_bytes());
+    }
+
+    // Drops one reference; a span that closes gives up its hold on its parent
+    fn release(&self, id: u64) -> bool {
+        let mut spans = self.spans.lock().unwrap();
+        let mut next = Some(id);
+        let mut closed = false;
+        while let Some(current) = next.take() {
+            let Some(span) = spans.get_mut(&current) else { break };
+            span.refs -= 1;
+            if span.refs > 0 {
+                break;
+            }
+            next = span.parent;
+            spans.remove(&current);
+            closed |= current == id;
+        }
+        closed
+    }
+}
+
+/// Writes `tracing` events, with the fields of every enclosing span, to stdout.
+#[derive(Clone)]
+pub struct Logger(Arc<Inner>);
+
+impl Subscriber for Logger {
+    fn register_callsite(&self, metadata: &'static Metadata<'static>) -> Interest {
+        if self.enabled(metadata) {
+            Interest::always()
+        } else {
+            Interest::never()
+        }
+    }
+
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        *metadata.level() <= self.0.max_level
+    }
+
+    fn max_level_hint(&self) -> Option<LevelFilter> {
+        Some(LevelFilter::from_level(self.0.max_level))
+    }
+
+    fn new_span(&self, attrs: &Attributes<'_>) -> Id {
+        let parent = if attrs.is_root() {
+            None
+        } else {
+            attrs.parent().map(Id::into_u64).or_else(current_id)
+        };
+        let mut fields = Map::new();
+        attrs.record(&mut FieldVisitor(&mut fields));
+        let id = self.0.next_id.fetch_add(1, Ordering::Relaxed);
+        let mut spans = self.0.spans.lock().unwrap();
+        let parent = parent.filter(|parent| match spans.get_mut(parent) {
+            Some(span) => {
+                span.refs += 1;
+                true
+            }
+            None => false,
+        });
+        spans.insert(
+            id,
+            SpanData {
+                metadata: attrs.metadata(),
+                fields,
+                parent,
+                refs: 1,
+            },
+        );
+        Id::from_u64(id)
+    }
+
+    fn record(&self, span: &Id, values: &Record<'_>) {
+        if let Some(span) = self.0.spans.lock().unwrap().get_mut(&span.into_u64()) {
+            values.record(&mut FieldVisitor(&mut span.fields));
+        }
+    }
+
+    fn record_follows_from(&self, _: &Id, _: &Id) {}
+
+    fn event(&self, event: &Event<'_>) {
+        let mut fields = Map::new();
+        event.record(&mut FieldVisitor(&mut fields));
+        let leaf = if event.is_root() {
+            None
+        } else {
+            event.parent().map(Id::into_u64).or_else(current_id)
+        };
+        let metadata = event.metadata();
+        self.0.write(*metadata.level(), metadata.target(), fields, leaf);
+    }
+
+    fn enter(&self, span: &Id) {
+        ENTERED.with(|entered| entered.borrow_mut().push(span.into_u64()));
+    }
+
+    fn exit(&self, span: &Id) {
+        let id = span.into_u64();
+        ENTERED.with(|entered| {
+            let mut entered = entered.borrow_mut();
+            if let Some(i) = entered.iter().rposition(|&entered| entered == id) {
+                entered.remove(i);
+            }
+        });
+    }
+
+    fn clone_span(&self, span: &Id) -> Id {
+        if let Some(data) = self.0.spans.lock().unwrap().get_mut(&span.into_u64()) {
+            data.refs += 1;
+        }
+        span.clone()
+    }
+
+    fn try_close(&self, span: Id) -> bool {
+        self.0.release(span.into_u64())
+    }
+
+    fn current_span(&self) -> Current {
+        let Some(id) = current_id() else { return Current::none() };
+        match self.0.spans.lock().unwrap().get(&id) {
+            Some(span) => Current::new(Id::from_u64(id), span.metadata),
+            None => Current::none(),
+        }
+    }
+}
+
+// Records from crates that use `log` (actix-server among them) go to the same output
+struct LogBridge(Logger);
+
+impl log::Log for LogBridge {
+    fn enabled(&self, metadata: &log::Metadata<'_>) -> bool {
+        from_log(metadata.level()) <= self.0 .0.max_level
+    }
+
+    fn log(&self, record: &log::Record<'_>) {
+        let level = from_log(record.level());
+        if level > self.0 .0.max_level {
+            return;
+        }
+        let mut fields = Map::new();
+        fields.insert("message".to_owned(), Value::from(record.args().to_string()));
+        self.0 .0.write(level, record.target(), fields, current_id());
+    }
+
+    fn flush(&self) {}
+}
+
+fn from_log(level: log::Level) -> Level {
+    match level {
+        log::Level::Error => Level::ERROR,
+        log::Level::Warn => Level::WARN,
+        log::Level::Info => Level::INFO,
+        log::Level::Debug => Level::DEBUG,
+        log::Level::Trace => Level::TRACE,
+    }
+}
+
+/// Installs the process-wide logger. Call once, before anything logs.
+pub fn init(settings: &LoggingSettings) {
+    let max_level = settings.level().unwrap_or(Level::INFO);
+    let logger = Logger(Arc::new(Inner {
+        max_level,
+        format: settings.format,
+        spans: Mutex::new(HashMap::new()),
+        // Span ids must be non-zero
+        next_id: AtomicU64::new(1),
+    }));
+    let bridge: &'static LogBridge = Box::leak(Box::new(LogBridge(logger.clone())));
+    if log::set_logger(bridge).is_ok() {
+        log::set_max_level(match max_level {
+            Level::ERROR => log::LevelFilter::Error,
+            Level::WARN => log::LevelFilter::Warn,
+            Level::INFO => log::LevelFilter::Info,
+            Level::DEBUG => log::LevelFilter::Debug,
+            Level::TRACE => log::LevelFilter::Trace,
+        });
+    }
+    let _ = tracing::subscriber::set_global_default(logger);
+}
+
+/// A fresh correlation id.
+pub fn correlation_id() -> String {
+    Uuid::new_v4().to_string()
+}
+
+// The request path with secrets carried in it starred out; incoming webhook
+// URLs end in the hook's token
+fn loggable_path(path: &str) -> Cow<'_, str> {
+    match path.strip_prefix("/hooks/").and_then(|rest| rest.split_once('/')) {
+        Some((hook_id, _)) => Cow::Owned(format!("/hooks/{}/***", hook_id)),
+        None => Cow::Borrowed(path),
+    }
+}
+
+/// Middleware giving every request a span with a correlation id and logging its outcome.
+///
+/// A sane `X-Request-Id` from the client or a proxy is reused, so one id can follow a
+/// request across services; the id is echoed back in the response either way.
+pub async fn trace_requests(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let correlation_id = req
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| {
+            !id.is_empty()
+                && id.len() <= MAX_REQUEST_ID_LEN
+                && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        })
+        .map_or_else(correlation_id, str::to_owned);
+    let span = tracing::info_span!(
+        "http_request",
+        correlation_id = %correlation_id,
+        method = %req.method(),
+        path = %loggable_path(req.path()),
+    );
+    let started = Instant::now();
+    let res = next.call(req).instrument(span.clone()).await;
+    let _entered = span.enter();
+    let elapsed_us = started.elapsed().as_micros() as u64;
+    match res {
+        Ok(mut res) => {
+            let status = res.status().as_u16();
+            if res.status().is_server_error() {
+                tracing::warn!(status, elapsed_us, "request failed");
+            } else {
+                tracing::info!(status, elapsed_us, "request finished");
+            }
+            if let Ok(value) = HeaderValue::from_str(&correlation_id) {
+                res.headers_mut().insert(HeaderName::from_static(REQUEST_ID_HEADER), value);
+            }
+            Ok(res)
+        }
+        Err(e) => {
+            let status = e.as_response_error().status_code().as_u16();
+            tracing::warn!(status, elapsed_us, error = %e, "request failed");
+            Err(e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::{middleware, web, App, HttpResponse};
+
+    #[test]
+    fn incoming_webhook_tokens_stay_out_of_request_paths() {
+        let hook_id = Uuid::new_v4();
+        let path = format!("/hooks/{}/{}", hook_id, "f".repeat(64));
+        assert_eq!(loggable_path(&path), format!("/hooks/{}/***", hook_id));
+        assert_eq!(loggable_path("/groups/1/messages"), "/groups/1/messages");
+        assert_eq!(loggable_path("/hooks/"), "/hooks/");
+    }
+
+    #[actix_web::test]
+    async fn sane_request_ids_are_reused_and_others_replaced() {
+        let app = init_service(
+            App::new()
+                .wrap(middleware::from_fn(trace_requests))
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        fn echoed<B>(res: ServiceResponse<B>) -> String {
+            res.headers().get(REQUEST_ID_HEADER).unwrap().to_str().unwrap().to_owned()
+        }
+
+        let req = TestRequest::get().insert_header((REQUEST_ID_HEADER, "edge-42_a")).to_request();
+        assert_eq!(echoed(call_service(&app, req).await), "edge-42_a");
+
+        let fresh = echoed(call_service(&app, TestRequest::get().to_request()).await);
+        assert!(Uuid::parse_str(&fresh).is_ok());
+
+        for unusable in ["a b", "<script>", &"x".repeat(MAX_REQUEST_ID_LEN + 1)] {
+            let req = TestRequest::get().insert_header((REQUEST_ID_HEADER, unusable)).to_request();
+            let replaced = echoed(call_service(&app, req).await);
+            assert!(Uuid::parse_str(&replaced).is_ok(), "{} was kept", unusable);
+        }
+    }
+}
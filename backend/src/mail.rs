@@ -0,0 +1,167 @@
+use crate::settings::{MailBackend, MailSettings, SmtpSecurity};
+use actix_web::web;
+use lettre::message::header::ContentType;
+use lettre::message::Mailbox;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{Message, SmtpTransport, Transport};
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use uuid::Uuid;
+
+/// A plain-text message to a single recipient.
+#[derive(Clone, Debug)]
+pub struct Mail {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+#[derive(Debug)]
+pub struct MailError(pub String);
+
+impl fmt::Display for MailError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "could not send mail: {}", self.0)
+    }
+}
+
+impl std::error::Error for MailError {}
+
+/// Delivers mail to users.
+///
+/// `send` blocks until the message is handed over, so call it from
+/// `web::block` rather than on an async worker.
+pub trait Mailer: Send + Sync {
+    fn send(&self, mail: &Mail) -> Result<(), MailError>;
+}
+
+/// Sends `mail` off the async workers without waiting for it. Failures are
+/// only logged, so callers must not depend on the mail arriving.
+pub fn send_in_background(mailer: &web::Data<dyn Mailer>, mail: Mail) {
+    let mailer = mailer.clone().into_inner();
+    actix_web::rt::spawn(async move {
+        let to = mail.to.clone();
+        match web::block(move || mailer.send(&mail)).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => tracing::error!(%to, "{}", e),
+            Err(e) => tracing::error!(%to, "could not send mail: {}", e),
+        }
+    });
+}
+
+fn message(from: &Mailbox, mail: &Mail) -> Result<Message, MailError> {
+    let to = mail
+        .to
+        .parse::<Mailbox>()
+        .map_err(|e| MailError(format!("bad recipient {:?}: {}", mail.to, e)))?;
+    Message::builder()
+        .from(from.clone())
+        .to(to)
+        .subject(&mail.subject)
+        .header(ContentType::TEXT_PLAIN)
+        .body(mail.body.clone())
+        .map_err(|e| MailError(e.to_string()))
+}
+
+/// Hands mail to an SMTP relay.
+pub struct SmtpMailer {
+    from: Mailbox,
+    transport: SmtpTransport,
+}
+
+impl SmtpMailer {
+    pub fn new(settings: &MailSettings) -> Result<Self, MailError> {
+        let host = settings.smtp_host.as_str();
+        let builder = match settings.smtp_security {
+            SmtpSecurity::Tls => SmtpTransport::relay(host),
+            SmtpSecurity::Starttls => SmtpTransport::starttls_relay(host),
+            SmtpSecurity::None => Ok(SmtpTransport::builder_dangerous(host)),
+        }
+        .map_err(|e| MailError(e.to_string()))?;
+        let mut builder = builder
+            .port(settings.smtp_port)
+            .timeout(Some(Duration::from_secs(settings.smtp_timeout_secs)));
+        if let (Some(username), Some(password)) = (&settings.smtp_username, &settings.smtp_password) {
+            builder = builder.credentials(Credentials::new(username.clone(), password.clone()));
+        }
+        Ok(SmtpMailer {
+            from: parse_from(settings)?,
+            transport: builder.build(),
+        })
+    }
+}
+
+impl Mailer for SmtpMailer {
+    fn send(&self, mail: &Mail) -> Result<(), MailError> {
+        self.transport
+            .send(&message(&self.from, mail)?)
+            .map_err(|e| MailError(e.to_string()))?;
+        Ok(())
+    }
+}
+
+/// Writes each message to its own `.eml` file, for local development.
+pub struct FileMailer {
+    from: Mailbox,
+    dir: PathBuf,
+}
+
+impl FileMailer {
+    pub fn new(settings: &MailSettings) -> Result<Self, MailError> {
+        std::fs::create_dir_all(&settings.file_dir)
+            .map_err(|e| MailError(format!("cannot create {:?}: {}", settings.file_dir, e)))?;
+        Ok(FileMailer {
+            from: parse_from(settings)?,
+            dir: PathBuf::from(&settings.file_dir),
+        })
+    }
+}
+
+impl Mailer for FileMailer {
+    fn send(&self, mail: &Mail) -> Result<(), MailError> {
+        // Sorts in the order the messages were sent
+        let name = format!("{}-{}.eml", chrono::Utc::now().format("%Y%m%dT%H%M%S%.6f"), Uuid::new_v4());
+        let path = self.dir.join(name);
+        std::fs::write(&path, message(&self.from, mail)?.formatted())
+            .map_err(|e| MailError(format!("cannot write {:?}: {}", path, e)))?;
+        tracing::info!(to = %mail.to, path = %path.display(), "mail written to file");
+        Ok(())
+    }
+}
+
+/// Keeps every message in memory, for tests.
+#[derive(Default)]
+pub struct MemoryMailer {
+    sent: Mutex<Vec<Mail>>,
+}
+
+impl MemoryMailer {
+    /// Everything sent so far, oldest first.
+    pub fn sent(&self) -> Vec<Mail> {
+        self.sent.lock().unwrap().clone()
+    }
+}
+
+impl Mailer for MemoryMailer {
+    fn send(&self, mail: &Mail) -> Result<(), MailError> {
+        self.sent.lock().unwrap().push(mail.clone());
+        Ok(())
+    }
+}
+
+fn parse_from(settings: &MailSettings) -> Result<Mailbox, MailError> {
+    settings
+        .from
+        .parse()
+        .map_err(|e| MailError(format!("bad mail.from {:?}: {}", settings.from, e)))
+}
+
+pub fn from_settings(settings: &MailSettings) -> Result<Arc<dyn Mailer>, MailError> {
+    Ok(match settings.backend {
+        MailBackend::Smtp => Arc::new(SmtpMailer::new(settings)?),
+        MailBackend::File => Arc::new(FileMailer::new(settings)?),
+        MailBackend::Memory => Arc::new(MemoryMailer::default()),
+    })
+}
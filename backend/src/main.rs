@@ -1,52 +1,187 @@
-mod auth;
-mod db;
-mod groups;
-mod models;
-mod schema;
-mod ws;
-
 use actix::Actor;
 use actix_cors::Cors;
-use actix_web::http::header;
+use actix_web::http::header::{self, HeaderName};
 use actix_web::{middleware, web, App, HttpServer};
-use auth::{create_group, join_group, login, profile, signup};
-use crate::groups::{get_groups, update_group, delete_group}; // Import endpoints
-use db::establish_connection;
-use ws::ChatServer;
-use uuid::Uuid;
+use backend::commands::CommandRegistry;
+use backend::filters::FilterChain;
+use backend::auth::{create_group, join_group, leave_group, login, profile, signup};
+use backend::groups::{get_groups, get_members, get_messages, set_member_role, update_group, update_retention, delete_group}; // Import endpoints
+use backend::polls::{create_poll_handler, get_poll, vote};
+use backend::db::establish_connection;
+use backend::retention::RetentionSweeper;
+use backend::sessions::SessionSigner;
+use backend::settings::Settings;
+use backend::webhooks::WebhookDispatcher;
+use backend::ws::ChatServer;
+use backend::health::{self, Readiness};
+use backend::ratelimit::RateLimits;
+use backend::verification::LinkSigner;
+use backend::{admin, audit, bots, filters, incoming_webhooks, logging, mail, metrics, migrations, moderation, password, pubsub, ratelimit, verification, webhooks, ws};
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    dotenv::dotenv().ok();
-    let pool = establish_connection();
-    println!("✅ Database connection established!");
+    let settings = Settings::load().unwrap_or_else(|e| {
+        eprintln!("❌ {}", e);
+        std::process::exit(1);
+    });
+    logging::init(&settings.logging);
+    let pool = establish_connection(&settings.database);
+    tracing::info!(pool_max_size = settings.database.pool_max_size, "database connection established");
+    if let Err(e) = migrations::prepare(&pool, &settings.database) {
+        tracing::error!("{}", e);
+        std::process::exit(1);
+    }
+
+    let chat_server = ChatServer::new(
+        pool.clone(),
+        CommandRegistry::with_builtins(),
+        FilterChain::from_settings(&settings.filters),
+        settings.limits.max_message_ttl_secs,
+        pubsub::from_settings(&settings),
+        &settings,
+    )
+    .start();
+    RetentionSweeper::new(pool.clone(), chat_server.clone(), settings.retention.sweep_interval_secs).start();
+    WebhookDispatcher::new(pool.clone(), &settings.webhooks).start();
 
-    let chat_server = ChatServer::new().start();
+    let mailer: web::Data<dyn mail::Mailer> = match mail::from_settings(&settings.mail) {
+        Ok(mailer) => web::Data::from(mailer),
+        Err(e) => {
+            tracing::error!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let link_signer = web::Data::new(LinkSigner::new(&settings.auth));
+    let session_signer = web::Data::new(SessionSigner::new(&settings.auth));
 
-    let server = HttpServer::new(move || {
+    // Shared by every worker, so a client's allowance does not depend on which one it lands on
+    let rate_limits = web::Data::new(RateLimits::new(&settings.rate_limit));
+    let readiness = web::Data::new(Readiness::default());
+    let app_readiness = readiness.clone();
+    let app_chat_server = chat_server.clone();
+    let app_settings = settings.clone();
+    let mut server = HttpServer::new(move || {
+        let cors = app_settings
+            .server
+            .allowed_origins
+            .iter()
+            .fold(Cors::default(), |cors, origin| cors.allowed_origin(origin))
+            .allowed_methods(vec!["GET", "POST", "PUT", "DELETE"])
+            .allowed_headers(vec![
+                header::CONTENT_TYPE,
+                header::AUTHORIZATION,
+                HeaderName::from_static(logging::REQUEST_ID_HEADER),
+            ])
+            .expose_headers(vec![HeaderName::from_static(logging::REQUEST_ID_HEADER)])
+            .supports_credentials();
         App::new()
-            .wrap(middleware::Logger::default())
-            .wrap(
-                Cors::default()
-                    .allowed_origin("http://localhost:3000")
-                    .allowed_methods(vec!["GET", "POST", "PUT", "DELETE"])
-                    .allowed_headers(vec![header::CONTENT_TYPE, header::AUTHORIZATION])
-                    .supports_credentials(),
-            )
+            .wrap(middleware::from_fn(ratelimit::limit_requests))
+            .wrap(middleware::from_fn(metrics::track_requests))
+            .wrap(middleware::from_fn(logging::trace_requests))
+            .wrap(cors)
+            .app_data(web::JsonConfig::default().limit(app_settings.limits.json_payload_bytes))
+            .app_data(web::Data::new(app_settings.clone()))
             .app_data(web::Data::new(pool.clone()))
-            .app_data(web::Data::new(chat_server.clone()))
+            .app_data(web::Data::new(app_chat_server.clone()))
+            .app_data(rate_limits.clone())
+            .app_data(mailer.clone())
+            .app_data(link_signer.clone())
+            .app_data(session_signer.clone())
+            .app_data(app_readiness.clone())
+            .route("/healthz", web::get().to(health::healthz))
+            .route("/readyz", web::get().to(health::readyz))
             .route("/signup", web::post().to(signup))
             .route("/login", web::post().to(login))
+            .route("/verify-email", web::get().to(verification::verify_email))
+            .route("/verify-email/resend", web::post().to(verification::resend_verification))
+            .route("/password/forgot", web::post().to(password::forgot_password))
+            .route("/password/reset", web::post().to(password::reset_password))
             .route("/profile", web::get().to(profile))
             .route("/create-group", web::post().to(create_group))
             .route("/join-group", web::post().to(join_group))
+            .route("/leave-group", web::post().to(leave_group))
             .route("/ws", web::get().to(ws::ws_index))
+            .route("/ws/stats", web::get().to(ws::outbox_stats))
+            .route("/metrics", web::get().to(metrics::metrics))
             .route("/groups", web::get().to(get_groups))
             .route("/update-group", web::put().to(update_group))
             .route("/groups/{id}", web::delete().to(delete_group))
-    })
-    .bind("127.0.0.1:8080")?;
-
-    println!("🚀 Server is running at http://127.0.0.1:8080");
-    server.run().await
+            .route("/groups/{id}/members", web::get().to(get_members))
+            .route("/groups/{id}/members/{member_id}/role", web::put().to(set_member_role))
+            .route("/groups/{id}/reports", web::get().to(moderation::list_group_reports))
+            .route("/groups/{id}/audit", web::get().to(audit::group_audit_log))
+            .route("/groups/{id}/banned-words", web::get().to(filters::list_banned_words))
+            .route("/groups/{id}/banned-words", web::put().to(filters::set_banned_words))
+            .route("/groups/{id}/messages", web::get().to(get_messages))
+            .route("/groups/{id}/retention", web::put().to(update_retention))
+            .route("/groups/{id}/webhooks", web::post().to(webhooks::create_webhook))
+            .route("/groups/{id}/webhooks", web::get().to(webhooks::list_webhooks))
+            .route("/groups/{id}/webhooks/{webhook_id}", web::delete().to(webhooks::delete_webhook))
+            .route(
+                "/groups/{id}/webhooks/{webhook_id}/deliveries",
+                web::get().to(webhooks::list_deliveries),
+            )
+            .route(
+                "/groups/{id}/webhooks/{webhook_id}/deliveries/{delivery_id}/retry",
+                web::post().to(webhooks::retry_delivery),
+            )
+            .route(
+                "/groups/{id}/incoming-webhooks",
+                web::post().to(incoming_webhooks::create_incoming_webhook),
+            )
+            .route(
+                "/groups/{id}/incoming-webhooks",
+                web::get().to(incoming_webhooks::list_incoming_webhooks),
+            )
+            .route(
+                "/groups/{id}/incoming-webhooks/{hook_id}/rotate",
+                web::post().to(incoming_webhooks::rotate_incoming_webhook),
+            )
+            .route(
+                "/groups/{id}/incoming-webhooks/{hook_id}",
+                web::delete().to(incoming_webhooks::revoke_incoming_webhook),
+            )
+            .route("/hooks/{hook_id}/{token}", web::post().to(incoming_webhooks::receive))
+            .route("/bots", web::post().to(bots::create_bot))
+            .route("/bots/messages", web::post().to(bots::post_message))
+            .route("/bots/{id}/keys", web::post().to(bots::create_key))
+            .route("/bots/{id}/keys", web::get().to(bots::list_keys))
+            .route("/bots/{id}/keys/{key_id}", web::delete().to(bots::revoke_key))
+            .route("/reports", web::post().to(moderation::create_report))
+            .route("/reports/{id}/claim", web::post().to(moderation::claim_report))
+            .route("/reports/{id}/resolve", web::post().to(moderation::resolve_report))
+            .route("/polls", web::post().to(create_poll_handler))
+            .route("/polls/{id}", web::get().to(get_poll))
+            .route("/polls/{id}/vote", web::post().to(vote))
+            .service(
+                web::scope("/admin")
+                    .route("/rooms", web::get().to(admin::list_rooms))
+                    .route("/users", web::get().to(admin::list_users))
+                    .route("/users/{id}/suspend", web::post().to(admin::suspend_user))
+                    .route("/users/{id}/unsuspend", web::post().to(admin::unsuspend_user))
+                    .route("/groups/{id}", web::delete().to(admin::delete_group))
+                    .route("/messages/{id}", web::delete().to(admin::delete_message))
+                    .route("/reports", web::get().to(admin::list_reports))
+                    .route("/audit", web::get().to(audit::audit_log)),
+            )
+    });
+    // Signals are handled by health::shutdown_on_signal, which drains sessions first
+    server = server
+        .disable_signals()
+        .shutdown_timeout(settings.server.shutdown_timeout_secs);
+    if let Some(workers) = settings.server.workers {
+        server = server.workers(workers);
+    }
+    for addr in &settings.server.bind {
+        server = server.bind(addr)?;
+        tracing::info!(%addr, "server listening");
+    }
+    let server = server.run();
+    actix_web::rt::spawn(health::shutdown_on_signal(
+        server.handle(),
+        chat_server,
+        readiness,
+        settings.server.shutdown_timeout(),
+    ));
+    server.await
 }
@@ -0,0 +1,356 @@
+use crate::db::DbPool;
+use crate::outbox;
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::{web, HttpResponse};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Upper bounds in seconds, from sub-millisecond fan-outs to slow queries
+const BUCKETS: [f64; 14] = [
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    const fn new() -> Self {
+        Counter(AtomicU64::new(0))
+    }
+
+    pub fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+pub struct Gauge(AtomicI64);
+
+impl Gauge {
+    const fn new() -> Self {
+        Gauge(AtomicI64::new(0))
+    }
+
+    pub fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn dec(&self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn set(&self, value: i64) {
+        self.0.store(value, Ordering::Relaxed);
+    }
+
+    fn get(&self) -> i64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+pub struct Histogram {
+    // Not cumulative; `render` adds them up
+    counts: [AtomicU64; BUCKETS.len() + 1],
+    sum_nanos: AtomicU64,
+}
+
+impl Histogram {
+    const fn new() -> Self {
+        Histogram {
+            counts: [const { AtomicU64::new(0) }; BUCKETS.len() + 1],
+            sum_nanos: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        let bucket = BUCKETS.iter().position(|&le| secs <= le).unwrap_or(BUCKETS.len());
+        self.counts[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_nanos.fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+    }
+}
+
+pub static DB_POOL_WAIT: Histogram = Histogram::new();
+pub static DB_POOL_TIMEOUTS: Counter = Counter::new();
+pub static DB_QUERY_DURATION: Histogram = Histogram::new();
+pub static WS_SESSIONS: Gauge = Gauge::new();
+pub static HEARTBEAT_DISCONNECTS: Counter = Counter::new();
+pub static ROOMS: Gauge = Gauge::new();
+pub static BROADCASTS: Counter = Counter::new();
+pub static BROADCAST_DELIVERIES: Counter = Counter::new();
+pub static FANOUT_DURATION: Histogram = Histogram::new();
+
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+struct RouteKey {
+    method: String,
+    route: String,
+}
+
+struct RouteStats {
+    statuses: BTreeMap<u16, u64>,
+    duration: Histogram,
+}
+
+static HTTP_ROUTES: Mutex<BTreeMap<RouteKey, RouteStats>> = Mutex::new(BTreeMap::new());
+
+/// Middleware counting requests per route pattern, method and status.
+pub async fn track_requests(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    // The pattern, not the path, so ids do not each get their own series
+    let route = req.match_pattern().unwrap_or_else(|| "unmatched".to_owned());
+    let method = req.method().to_string();
+    let started = Instant::now();
+    let res = next.call(req).await;
+    let status = match &res {
+        Ok(res) => res.status().as_u16(),
+        Err(e) => e.as_response_error().status_code().as_u16(),
+    };
+    let mut routes = HTTP_ROUTES.lock().unwrap();
+    let stats = routes.entry(RouteKey { method, route }).or_insert_with(|| RouteStats {
+        statuses: BTreeMap::new(),
+        duration: Histogram::new(),
+    });
+    *stats.statuses.entry(status).or_default() += 1;
+    stats.duration.observe(started.elapsed());
+    res
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn sample(out: &mut String, name: &str, labels: &str, value: impl std::fmt::Display) {
+    if labels.is_empty() {
+        let _ = writeln!(out, "{} {}", name, value);
+    } else {
+        let _ = writeln!(out, "{}{{{}}} {}", name, labels, value);
+    }
+}
+
+fn counter(out: &mut String, name: &str, help: &str, value: u64) {
+    header(out, name, "counter", help);
+    sample(out, name, "", value);
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, value: i64) {
+    header(out, name, "gauge", help);
+    sample(out, name, "", value);
+}
+
+fn histogram_samples(out: &mut String, name: &str, labels: &str, histogram: &Histogram) {
+    let sep = if labels.is_empty() { "" } else { "," };
+    let mut total = 0;
+    for (i, count) in histogram.counts.iter().enumerate() {
+        total += count.load(Ordering::Relaxed);
+        let le = BUCKETS.get(i).map_or("+Inf".to_owned(), |le| le.to_string());
+        sample(out, &format!("{}_bucket", name), &format!("{}{}le=\"{}\"", labels, sep, le), total);
+    }
+    let sum = histogram.sum_nanos.load(Ordering::Relaxed) as f64 / 1e9;
+    sample(out, &format!("{}_sum", name), labels, sum);
+    sample(out, &format!("{}_count", name), labels, total);
+}
+
+fn histogram(out: &mut String, name: &str, help: &str, histogram: &Histogram) {
+    header(out, name, "histogram", help);
+    histogram_samples(out, name, "", histogram);
+}
+
+/// Everything in the Prometheus text format.
+pub fn render(pool: &DbPool) -> String {
+    let mut out = String::new();
+
+    {
+        let routes = HTTP_ROUTES.lock().unwrap();
+        header(&mut out, "neurochat_http_requests_total", "counter", "HTTP requests by route, method and status.");
+        for (key, stats) in routes.iter() {
+            for (status, count) in &stats.statuses {
+                let labels = format!("method=\"{}\",route=\"{}\",status=\"{}\"", key.method, key.route, status);
+                sample(&mut out, "neurochat_http_requests_total", &labels, count);
+            }
+        }
+        header(
+            &mut out,
+            "neurochat_http_request_duration_seconds",
+            "histogram",
+            "Time to answer an HTTP request, by route and method.",
+        );
+        for (key, stats) in routes.iter() {
+            let labels = format!("method=\"{}\",route=\"{}\"", key.method, key.route);
+            histogram_samples(&mut out, "neurochat_http_request_duration_seconds", &labels, &stats.duration);
+        }
+    }
+
+    let state = pool.state();
+    gauge(
+        &mut out,
+        "neurochat_db_pool_max_connections",
+        "Configured size of the database pool.",
+        pool.max_size() as i64,
+    );
+    header(&mut out, "neurochat_db_pool_connections", "gauge", "Open pooled database connections by state.");
+    sample(&mut out, "neurochat_db_pool_connections", "state=\"idle\"", state.idle_connections);
+    sample(
+        &mut out,
+        "neurochat_db_pool_connections",
+        "state=\"in_use\"",
+        state.connections - state.idle_connections,
+    );
+    histogram(
+        &mut out,
+        "neurochat_db_pool_wait_seconds",
+        "Time spent waiting for a pooled database connection.",
+        &DB_POOL_WAIT,
+    );
+    counter(
+        &mut out,
+        "neurochat_db_pool_timeouts_total",
+        "Checkouts that gave up waiting for a free connection.",
+        DB_POOL_TIMEOUTS.get(),
+    );
+    histogram(
+        &mut out,
+        "neurochat_db_query_duration_seconds",
+        "Time a request handler spent on its database work, excluding the pool wait.",
+        &DB_QUERY_DURATION,
+    );
+
+    gauge(
+        &mut out,
+        "neurochat_ws_sessions",
+        "Open WebSocket sessions.",
+        WS_SESSIONS.get(),
+    );
+    counter(
+        &mut out,
+        "neurochat_ws_heartbeat_disconnects_total",
+        "Sessions dropped for not answering pings.",
+        HEARTBEAT_DISCONNECTS.get(),
+    );
+    gauge(&mut out, "neurochat_rooms", "Running room actors.", ROOMS.get());
+    counter(
+        &mut out,
+        "neurochat_broadcasts_total",
+        "Frames broadcast to a room, including ones from other instances.",
+        BROADCASTS.get(),
+    );
+    counter(
+        &mut out,
+        "neurochat_broadcast_deliveries_total",
+        "Broadcast frames queued for individual sessions.",
+        BROADCAST_DELIVERIES.get(),
+    );
+    histogram(
+        &mut out,
+        "neurochat_broadcast_fanout_seconds",
+        "Time to queue one broadcast frame for every session in its room.",
+        &FANOUT_DURATION,
+    );
+
+    let outbox = outbox::stats();
+    gauge(
+        &mut out,
+        "neurochat_outbox_queued_frames",
+        "Frames waiting in session outboxes.",
+        outbox.queued_frames as i64,
+    );
+    gauge(
+        &mut out,
+        "neurochat_outbox_queued_bytes",
+        "Bytes waiting in session outboxes.",
+        outbox.queued_bytes as i64,
+    );
+    gauge(
+        &mut out,
+        "neurochat_outbox_peak_depth",
+        "Deepest any single outbox has been since start-up.",
+        outbox.peak_depth as i64,
+    );
+    counter(
+        &mut out,
+        "neurochat_outbox_dropped_ephemeral_total",
+        "Presence, tally and notice frames dropped for slow sessions.",
+        outbox.dropped_ephemeral,
+    );
+    counter(
+        &mut out,
+        "neurochat_outbox_slow_consumer_disconnects_total",
+        "Sessions disconnected for falling too far behind.",
+        outbox.slow_consumer_disconnects,
+    );
+    out
+}
+
+pub async fn metrics(pool: web::Data<DbPool>) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(render(&pool))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::{middleware, App};
+    use diesel::r2d2::{ConnectionManager, Pool};
+
+    // Rendering only reads the pool's state, so it never has to connect
+    fn idle_pool() -> DbPool {
+        Pool::builder()
+            .max_size(3)
+            .min_idle(Some(0))
+            .build_unchecked(ConnectionManager::new("postgres://localhost/unused"))
+    }
+
+    #[test]
+    fn histogram_buckets_are_cumulative() {
+        let histogram = Histogram::new();
+        histogram.observe(Duration::from_micros(100));
+        histogram.observe(Duration::from_millis(30));
+        histogram.observe(Duration::from_secs(60));
+        let mut out = String::new();
+        histogram_samples(&mut out, "h", "route=\"/x\"", &histogram);
+
+        assert!(out.contains("h_bucket{route=\"/x\",le=\"0.0005\"} 1\n"));
+        assert!(out.contains("h_bucket{route=\"/x\",le=\"0.025\"} 1\n"));
+        assert!(out.contains("h_bucket{route=\"/x\",le=\"0.05\"} 2\n"));
+        assert!(out.contains("h_bucket{route=\"/x\",le=\"10\"} 2\n"));
+        assert!(out.contains("h_bucket{route=\"/x\",le=\"+Inf\"} 3\n"));
+        assert!(out.contains("h_sum{route=\"/x\"} 60.0301\n"));
+        assert!(out.contains("h_count{route=\"/x\"} 3\n"));
+    }
+
+    #[test]
+    fn unlabelled_samples_have_no_braces() {
+        let mut out = String::new();
+        counter(&mut out, "c_total", "Things.", 4);
+        assert_eq!(out, "# HELP c_total Things.\n# TYPE c_total counter\nc_total 4\n");
+    }
+
+    #[actix_web::test]
+    async fn requests_are_counted_by_route_pattern() {
+        let app = init_service(
+            App::new()
+                .wrap(middleware::from_fn(track_requests))
+                .route("/metrics-test/{id}", web::get().to(HttpResponse::NoContent)),
+        )
+        .await;
+        call_service(&app, TestRequest::get().uri("/metrics-test/1").to_request()).await;
+        call_service(&app, TestRequest::get().uri("/metrics-test/2").to_request()).await;
+
+        let out = render(&idle_pool());
+        assert!(out.contains("neurochat_http_requests_total{method=\"GET\",route=\"/metrics-test/{id}\",status=\"204\"} 2\n"));
+        assert!(out.contains("neurochat_http_request_duration_seconds_count{method=\"GET\",route=\"/metrics-test/{id}\"} 2\n"));
+        assert!(out.contains("neurochat_db_pool_max_connections 3\n"));
+        assert!(out.contains("# TYPE neurochat_outbox_queued_frames gauge\n"));
+    }
+}
@@ -0,0 +1,306 @@
+use crate::db::{self, DbPool};
+use crate::settings::DatabaseSettings;
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use diesel::r2d2::PoolError;
+use diesel::sql_types::Text;
+use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
+use std::collections::{BTreeMap, BTreeSet};
+use std::error::Error;
+use std::fmt;
+
+/// Every migration under `backend/migrations`, compiled into the binary.
+pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations");
+
+// The schema the code was compiled against, as written by `diesel print-schema`
+const SCHEMA_RS: &str = include_str!("schema.rs");
+
+// Diesel's own bookkeeping table is not part of schema.rs
+const MIGRATIONS_TABLE: &str = "__diesel_schema_migrations";
+
+#[derive(Debug)]
+pub enum MigrationError {
+    Connection(PoolError),
+    Migrate(Box<dyn Error + Send + Sync>),
+    Introspect(diesel::result::Error),
+    // Every difference between schema.rs and the live database
+    Drift(Vec<String>),
+}
+
+impl fmt::Display for MigrationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MigrationError::Connection(e) => write!(f, "could not connect to the database: {}", e),
+            MigrationError::Migrate(e) => write!(f, "could not apply migrations: {}", e),
+            MigrationError::Introspect(e) => write!(f, "could not read the database schema: {}", e),
+            MigrationError::Drift(problems) => {
+                write!(f, "the database schema does not match schema.rs:")?;
+                for problem in problems {
+                    write!(f, "\n  - {}", problem)?;
+                }
+                write!(f, "\nApply the pending migrations (database.run_migrations = true) or regenerate schema.rs")
+            }
+        }
+    }
+}
+
+impl From<PoolError> for MigrationError {
+    fn from(e: PoolError) -> Self {
+        MigrationError::Connection(e)
+    }
+}
+
+impl From<diesel::result::Error> for MigrationError {
+    fn from(e: diesel::result::Error) -> Self {
+        MigrationError::Introspect(e)
+    }
+}
+
+/// Brings the database up to date when `database.run_migrations` is set, then
+/// refuses to go on if its tables no longer match `schema.rs`.
+pub fn prepare(pool: &DbPool, settings: &DatabaseSettings) -> Result<(), MigrationError> {
+    let mut conn = db::connection(pool)?;
+    if settings.run_migrations {
+        run_pending(&mut conn)?;
+    } else {
+        let pending = conn.pending_migrations(MIGRATIONS).map_err(MigrationError::Migrate)?;
+        if !pending.is_empty() {
+            tracing::warn!(pending = pending.len(), "database has migrations that were not applied");
+        }
+    }
+    if settings.check_schema {
+        check_schema(&mut conn)?;
+    }
+    Ok(())
+}
+
+/// Applies every embedded migration the database has not seen yet.
+pub fn run_pending(conn: &mut PgConnection) -> Result<(), MigrationError> {
+    let applied = conn.run_pending_migrations(MIGRATIONS).map_err(MigrationError::Migrate)?;
+    for version in &applied {
+        tracing::info!(%version, "applied migration");
+    }
+    Ok(())
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct ColumnShape {
+    // Postgres type name as in pg_type, e.g. "uuid" or "_text" for Array<Text>
+    pub sql_type: String,
+    pub nullable: bool,
+}
+
+pub type TableShapes = BTreeMap<String, BTreeMap<String, ColumnShape>>;
+
+#[derive(QueryableByName)]
+struct LiveColumn {
+    #[diesel(sql_type = Text)]
+    table_name: String,
+    #[diesel(sql_type = Text)]
+    column_name: String,
+    #[diesel(sql_type = Text)]
+    udt_name: String,
+    #[diesel(sql_type = Text)]
+    is_nullable: String,
+}
+
+/// Compares the live tables with `schema.rs`, column by column.
+pub fn check_schema(conn: &mut PgConnection) -> Result<(), MigrationError> {
+    let problems = diff_schemas(&expected_schema(), &live_schema(conn)?);
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(MigrationError::Drift(problems))
+    }
+}
+
+/// The tables declared in `schema.rs`.
+pub fn expected_schema() -> TableShapes {
+    parse_schema(SCHEMA_RS)
+}
+
+fn live_schema(conn: &mut PgConnection) -> Result<TableShapes, diesel::result::Error> {
+    let columns = diesel::sql_query(
+        "SELECT table_name::text, column_name::text, udt_name::text, is_nullable::text \
+         FROM information_schema.columns \
+         WHERE table_schema = current_schema()",
+    )
+    .load::<LiveColumn>(conn)?;
+
+    let mut tables = TableShapes::new();
+    for column in columns {
+        if column.table_name == MIGRATIONS_TABLE {
+            continue;
+        }
+        tables.entry(column.table_name).or_default().insert(
+            column.column_name,
+            ColumnShape {
+                sql_type: column.udt_name,
+                nullable: column.is_nullable == "YES",
+            },
+        );
+    }
+    Ok(tables)
+}
+
+/// Reads the `diesel::table!` blocks of a `print-schema` file.
+pub fn parse_schema(source: &str) -> TableShapes {
+    let mut tables = TableShapes::new();
+    let mut current: Option<String> = None;
+    let mut in_table_macro = false;
+    for line in source.lines().map(str::trim) {
+        if line.starts_with("diesel::table!") {
+            in_table_macro = true;
+        } else if in_table_macro && current.is_none() && line.ends_with('{') {
+            // `users (id) {`, possibly with a schema prefix such as `public.users`
+            let name = line.split(|c: char| c.is_whitespace() || c == '(').next().unwrap_or_default();
+            let name = name.rsplit('.').next().unwrap_or(name).to_owned();
+            tables.entry(name.clone()).or_default();
+            current = Some(name);
+        } else if line == "}" {
+            if current.take().is_none() {
+                in_table_macro = false;
+            }
+        } else if let (Some(table), Some((column, sql_type))) = (&current, line.split_once("->")) {
+            let sql_type = sql_type.trim().trim_end_matches(',');
+            let (sql_type, nullable) = match sql_type.strip_prefix("Nullable<") {
+                Some(inner) => (inner.strip_suffix('>').unwrap_or(inner), true),
+                None => (sql_type, false),
+            };
+            tables.entry(table.clone()).or_default().insert(
+                column.trim().to_owned(),
+                ColumnShape {
+                    sql_type: pg_type_name(sql_type),
+                    nullable,
+                },
+            );
+        }
+    }
+    tables
+}
+
+// Diesel's SQL type names are Postgres' `udt_name`s in another case; arrays get a leading underscore
+fn pg_type_name(diesel_type: &str) -> String {
+    match diesel_type.strip_prefix("Array<").and_then(|inner| inner.strip_suffix('>')) {
+        Some(inner) => format!("_{}", pg_type_name(inner)),
+        None => diesel_type.to_lowercase(),
+    }
+}
+
+/// Lists every table and column that differs between `expected` and `live`.
+pub fn diff_schemas(expected: &TableShapes, live: &TableShapes) -> Vec<String> {
+    let mut problems = Vec::new();
+    let names: BTreeSet<&String> = expected.keys().chain(live.keys()).collect();
+    for table in names {
+        let (expected_columns, live_columns) = match (expected.get(table), live.get(table)) {
+            (Some(expected_columns), Some(live_columns)) => (expected_columns, live_columns),
+            (Some(_), None) => {
+                problems.push(format!("table {} is missing from the database", table));
+                continue;
+            }
+            (None, _) => {
+                problems.push(format!("table {} is not in schema.rs", table));
+                continue;
+            }
+        };
+        for (column, shape) in expected_columns {
+            match live_columns.get(column) {
+                None => problems.push(format!("column {}.{} is missing from the database", table, column)),
+                Some(live_shape) if live_shape.sql_type != shape.sql_type => problems.push(format!(
+                    "column {}.{} is {} in the database but {} in schema.rs",
+                    table, column, live_shape.sql_type, shape.sql_type
+                )),
+                Some(live_shape) if live_shape.nullable != shape.nullable => problems.push(format!(
+                    "column {}.{} is {} in the database but {} in schema.rs",
+                    table,
+                    column,
+                    nullability(live_shape.nullable),
+                    nullability(shape.nullable)
+                )),
+                Some(_) => {}
+            }
+        }
+        for column in live_columns.keys().filter(|column| !expected_columns.contains_key(*column)) {
+            problems.push(format!("column {}.{} is not in schema.rs", table, column));
+        }
+    }
+    problems
+}
+
+fn nullability(nullable: bool) -> &'static str {
+    if nullable {
+        "nullable"
+    } else {
+        "NOT NULL"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+
+    const SAMPLE: &str = r#"
+// @generated automatically by Diesel CLI.
+
+diesel::table! {
+    public.users (id) {
+        id -> Uuid,
+        email -> Nullable<Text>,
+        tags -> Array<Text>,
+    }
+}
+
+diesel::joinable!(users -> groups (id));
+"#;
+
+    fn column(sql_type: &str, nullable: bool) -> ColumnShape {
+        ColumnShape {
+            sql_type: sql_type.to_owned(),
+            nullable,
+        }
+    }
+
+    #[test]
+    fn table_macros_become_postgres_shapes() {
+        let tables = parse_schema(SAMPLE);
+        assert_eq!(tables.keys().collect::<Vec<_>>(), ["users"]);
+        let users = &tables["users"];
+        assert_eq!(users["id"], column("uuid", false));
+        assert_eq!(users["email"], column("text", true));
+        assert_eq!(users["tags"], column("_text", false));
+        assert_eq!(users.len(), 3);
+    }
+
+    #[test]
+    fn every_difference_is_listed() {
+        let expected = parse_schema(SAMPLE);
+        let mut live = parse_schema(SAMPLE);
+        assert!(diff_schemas(&expected, &live).is_empty());
+
+        let users = live.get_mut("users").unwrap();
+        users.remove("tags");
+        users.insert("email".to_owned(), column("text", false));
+        users.insert("id".to_owned(), column("int4", false));
+        users.insert("nickname".to_owned(), column("text", true));
+        live.insert("scratch".to_owned(), BTreeMap::new());
+        assert_eq!(
+            diff_schemas(&expected, &live),
+            [
+                "table scratch is not in schema.rs",
+                "column users.email is NOT NULL in the database but nullable in schema.rs",
+                "column users.id is int4 in the database but uuid in schema.rs",
+                "column users.tags is missing from the database",
+                "column users.nickname is not in schema.rs",
+            ]
+        );
+        assert_eq!(diff_schemas(&expected, &TableShapes::new()), ["table users is missing from the database"]);
+    }
+
+    #[test]
+    fn the_migrated_database_matches_schema_rs() {
+        let Some(mut conn) = test_support::connection() else { return };
+        run_pending(&mut conn).unwrap();
+        check_schema(&mut conn).unwrap();
+    }
+}
@@ -1,6 +1,7 @@
-use bcrypt::{hash, verify, DEFAULT_COST};
+use bcrypt::{hash, verify};
+use crate::errors::ApiError;
+use chrono::NaiveDateTime;
 use diesel::prelude::*;
-use diesel::sql_types::Jsonb; // needed for annotation
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use uuid::Uuid;
@@ -19,6 +20,14 @@ pub struct User {
     pub username: String,
     pub email: String,
     pub password_hash: String,
+    pub is_bot: bool,
+    pub owner_id: Option<Uuid>, // Set for bots only
+    pub disabled_at: Option<NaiveDateTime>,
+    pub is_admin: bool, // Site-wide; may use the /admin API
+    pub email_verified_at: Option<NaiveDateTime>,
+    // Session tokens issued under an older version no longer work
+    #[serde(skip)]
+    pub session_version: i32,
 }
 
 #[derive(Insertable, Serialize, Deserialize, Debug)]
@@ -31,27 +40,32 @@ pub struct NewUser {
 }
 
 impl User {
+    // bcrypt is deliberately slow; call this off the async workers and without a pooled connection
+    pub fn hash_password(password: &str, cost: u32) -> Result<String, ApiError> {
+        Ok(hash(password, cost)?)
+    }
+
     pub fn create(
         user_name: &str,
         user_email: &str,
-        user_password: &str,
+        user_password_hash: String,
         conn: &mut PgConnection,
-    ) -> Result<PublicUser, diesel::result::Error> {
+    ) -> Result<PublicUser, ApiError> {
         use crate::schema::users::dsl::*;
         let new_user = NewUser {
             id: Uuid::new_v4(),
             username: user_name.to_owned(),
             email: user_email.to_owned(),
-            password_hash: hash(user_password, DEFAULT_COST).unwrap(),
+            password_hash: user_password_hash,
         };
         diesel::insert_into(users)
             .values(&new_user)
             .execute(conn)?;
         // Return only public fields
-        users
+        Ok(users
             .filter(email.eq(&new_user.email))
             .select((id, username, email))
-            .first(conn)
+            .first(conn)?)
     }
 
     pub fn find_by_email(
@@ -62,6 +76,14 @@ impl User {
         users.filter(email.eq(search_email)).first(conn)
     }
 
+    pub fn is_disabled(&self) -> bool {
+        self.disabled_at.is_some()
+    }
+
+    pub fn is_verified(&self) -> bool {
+        self.email_verified_at.is_some()
+    }
+
     pub fn verify_password(&self, password: &str) -> bool {
         verify(password, &self.password_hash).unwrap_or(false)
     }
@@ -72,18 +94,219 @@ impl User {
 pub struct NewGroup {
     pub name: String,
     pub description: Option<String>,
-    #[diesel(sql_type = Jsonb)]
-    pub members: Value,
     pub owner: Uuid,
 }
 
-#[derive(Queryable, Serialize, Deserialize, Debug)]
+// Selected by column name, so the field order does not have to follow the table
+#[derive(Queryable, Selectable, Serialize, Deserialize, Debug, Clone)]
 #[diesel(table_name = crate::schema::groups)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
 pub struct Group {
     pub id: Uuid,
     pub name: String,
     pub description: Option<String>,
-    pub owner: Uuid, // Ensure order matches DB: (id, name, description, owner, members)
-    #[diesel(sql_type = Jsonb)]
-    pub members: Value,
+    pub owner: Uuid,
+    pub retention_seconds: Option<i64>, // None keeps messages forever
+}
+
+// A row of `user_groups`, the only record of who belongs to a group
+#[derive(Queryable, Selectable, Serialize, Debug, Clone)]
+#[diesel(table_name = crate::schema::user_groups)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct Membership {
+    pub user_id: Uuid,
+    pub group_id: Uuid,
+    pub joined_at: NaiveDateTime,
+    pub role: String, // "owner" or "member"
+}
+
+#[derive(Queryable, Serialize, Debug, Clone)]
+pub struct GroupMember {
+    pub user_id: Uuid,
+    pub username: String,
+    pub role: String,
+    pub joined_at: NaiveDateTime,
+}
+
+#[derive(Queryable, Serialize, Deserialize, Debug, Clone)]
+#[diesel(table_name = crate::schema::messages)]
+pub struct Message {
+    pub id: Uuid,
+    pub group_id: Option<Uuid>,
+    pub sender_id: Option<Uuid>,
+    pub content: String,
+    pub timestamp: Option<NaiveDateTime>,
+    pub kind: String, // "text" or "poll"
+    pub expires_at: Option<NaiveDateTime>,
+    pub integration_id: Option<Uuid>, // Set when posted through an incoming webhook
+    pub sender_name: Option<String>,
+}
+
+#[derive(Insertable, Debug)]
+#[diesel(table_name = crate::schema::messages)]
+pub struct NewMessage {
+    pub id: Uuid,
+    pub group_id: Option<Uuid>,
+    pub sender_id: Option<Uuid>,
+    pub content: String,
+    pub kind: String,
+    pub expires_at: Option<NaiveDateTime>,
+    pub integration_id: Option<Uuid>,
+    pub sender_name: Option<String>,
+}
+
+impl Message {
+    pub fn create(new_message: NewMessage, conn: &mut PgConnection) -> Result<Message, diesel::result::Error> {
+        diesel::insert_into(crate::schema::messages::table)
+            .values(&new_message)
+            .get_result(conn)
+    }
+}
+
+#[derive(Queryable, Insertable, Serialize, Deserialize, Debug, Clone)]
+#[diesel(table_name = crate::schema::polls)]
+pub struct Poll {
+    pub id: Uuid, // Same id as the announcing row in `messages`
+    pub group_id: Uuid,
+    pub creator_id: Uuid,
+    pub question: String,
+    pub multiple_choice: bool,
+    pub anonymous: bool,
+    pub closes_at: Option<NaiveDateTime>,
+    pub created_at: NaiveDateTime,
+}
+
+impl Poll {
+    pub fn is_closed(&self) -> bool {
+        self.closes_at
+            .is_some_and(|closes_at| closes_at <= chrono::Utc::now().naive_utc())
+    }
+}
+
+#[derive(Queryable, Insertable, Serialize, Deserialize, Debug, Clone)]
+#[diesel(table_name = crate::schema::poll_options)]
+pub struct PollOption {
+    pub id: Uuid,
+    pub poll_id: Uuid,
+    pub position: i32,
+    pub label: String,
+}
+
+#[derive(Queryable, Insertable, Serialize, Debug, Clone)]
+#[diesel(table_name = crate::schema::bot_api_keys)]
+pub struct BotApiKey {
+    pub id: Uuid,
+    pub bot_id: Uuid,
+    pub name: String,
+    pub key_prefix: String,
+    #[serde(skip_serializing)]
+    pub key_hash: String,
+    pub created_at: NaiveDateTime,
+    pub last_used_at: Option<NaiveDateTime>,
+    pub revoked_at: Option<NaiveDateTime>,
+}
+
+#[derive(Queryable, Insertable, Serialize, Debug, Clone)]
+#[diesel(table_name = crate::schema::webhooks)]
+pub struct Webhook {
+    pub id: Uuid,
+    pub group_id: Uuid,
+    pub url: String,
+    #[serde(skip_serializing)]
+    pub secret: String,
+    pub events: Vec<String>,
+    pub created_by: Option<Uuid>,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Queryable, Serialize, Debug, Clone)]
+#[diesel(table_name = crate::schema::webhook_deliveries)]
+pub struct WebhookDelivery {
+    pub id: Uuid,
+    pub webhook_id: Uuid,
+    pub event: String,
+    pub payload: Value,
+    pub status: String, // "pending", "delivered" or "dead"
+    pub attempts: i32,
+    pub next_attempt_at: NaiveDateTime,
+    pub last_status_code: Option<i32>,
+    pub last_error: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub delivered_at: Option<NaiveDateTime>,
+}
+
+#[derive(Queryable, Insertable, Serialize, Debug, Clone)]
+#[diesel(table_name = crate::schema::incoming_webhooks)]
+pub struct IncomingWebhook {
+    pub id: Uuid,
+    pub group_id: Uuid,
+    pub name: String,
+    #[serde(skip_serializing)]
+    pub token_hash: String,
+    pub created_by: Option<Uuid>,
+    pub created_at: NaiveDateTime,
+    pub rotated_at: Option<NaiveDateTime>,
+    pub last_used_at: Option<NaiveDateTime>,
+    pub revoked_at: Option<NaiveDateTime>,
+}
+
+#[derive(Queryable, Serialize, Debug, Clone)]
+#[diesel(table_name = crate::schema::reports)]
+pub struct Report {
+    pub id: Uuid,
+    pub reporter_id: Option<Uuid>,
+    pub group_id: Option<Uuid>,
+    pub message_id: Option<Uuid>,
+    pub reported_user_id: Option<Uuid>,
+    pub reason: String,
+    pub content_snapshot: Option<String>, // The message as it was when reported
+    pub status: String, // "open", "claimed" or "resolved"
+    pub created_at: NaiveDateTime,
+    pub claimed_by: Option<Uuid>,
+    pub claimed_at: Option<NaiveDateTime>,
+    pub resolved_by: Option<Uuid>,
+    pub resolved_at: Option<NaiveDateTime>,
+    pub outcome: Option<String>, // "dismiss", "delete_message", "mute", "ban" or "approve"
+    pub resolution_note: Option<String>,
+    pub kind: String, // "report", or "held" for a message a content filter kept back
+}
+
+#[derive(Queryable, Serialize, Debug, Clone)]
+#[diesel(table_name = crate::schema::audit_log)]
+pub struct AuditEntry {
+    pub id: i64,
+    pub actor_id: Option<Uuid>,
+    pub action: String,
+    pub group_id: Option<Uuid>,
+    pub target_type: String,
+    pub target_id: Option<Uuid>,
+    pub ip: Option<String>,
+    pub before: Option<Value>,
+    pub after: Option<Value>,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Insertable, Debug, Default)]
+#[diesel(table_name = crate::schema::audit_log)]
+pub struct NewAuditEntry {
+    pub actor_id: Option<Uuid>,
+    pub action: String,
+    pub group_id: Option<Uuid>,
+    pub target_type: String,
+    pub target_id: Option<Uuid>,
+    pub ip: Option<String>,
+    pub before: Option<Value>,
+    pub after: Option<Value>,
+}
+
+#[derive(Queryable, Debug, Clone)]
+#[diesel(table_name = crate::schema::password_reset_tokens)]
+pub struct PasswordResetToken {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub token_hash: String,
+    pub created_at: NaiveDateTime,
+    pub expires_at: NaiveDateTime,
+    pub used_at: Option<NaiveDateTime>,
+    pub requested_ip: Option<String>,
 }
@@ -0,0 +1,761 @@
+use crate::admin;
+use crate::audit;
+use crate::commands;
+use crate::db::{self, DbPool};
+use crate::errors::{ApiError, ApiResult};
+use crate::groups;
+use crate::models::{Message, NewAuditEntry, NewMessage, Report};
+use crate::polls;
+use crate::ratelimit::RateLimits;
+use crate::sessions;
+use crate::settings::Settings;
+use crate::verification;
+use crate::webhooks::{self, RoomEvent};
+use crate::ws::{ChatServer, DisconnectUser, MessagesDeleted, PostMessage};
+use actix::Addr;
+use actix_web::{web, HttpRequest, HttpResponse};
+use diesel::prelude::*;
+use serde::Deserialize;
+use serde_json::json;
+use uuid::Uuid;
+
+// Longest report reason or resolution note, in characters
+const TEXT_MAX: usize = 1000;
+
+// Largest page of a group's queue
+const PAGE_MAX: i64 = 200;
+
+#[derive(Deserialize)]
+pub struct CreateReportRequest {
+    // A message, or else a user, optionally within a group
+    pub message_id: Option<Uuid>,
+    pub user_id: Option<Uuid>,
+    pub group_id: Option<Uuid>,
+    pub reason: String,
+}
+
+/// Files a report about a message or a user for the signed-in user. Reports about a
+/// message keep a copy of its content, so the queue still shows it after an edit or delete.
+pub async fn create_report(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
+    settings: web::Data<Settings>,
+    form: web::Json<CreateReportRequest>,
+) -> ApiResult {
+    use crate::schema::{messages, reports, users};
+
+    let reason = form.reason.trim().to_owned();
+    if reason.is_empty() {
+        return Err(ApiError::BadRequest("A reason is required"));
+    }
+    if reason.chars().count() > TEXT_MAX {
+        return Err(ApiError::BadRequest("The reason is too long"));
+    }
+    let reporter = sessions::current_user(&req, &pool).await?;
+    let report = db::run(&pool, move |conn| {
+        verification::require_verified(reporter.id, &settings.auth, conn)?;
+        let (group_id, reported_user_id, content_snapshot) = match form.message_id {
+            Some(message_id) => {
+                let (group_id, sender_id, content) = messages::table
+                    .find(message_id)
+                    .select((messages::group_id, messages::sender_id, messages::content))
+                    .first::<(Option<Uuid>, Option<Uuid>, String)>(conn)
+                    .map_err(ApiError::db("Message not found"))?;
+                (group_id, sender_id, Some(content))
+            }
+            None => {
+                let user_id = form
+                    .user_id
+                    .ok_or(ApiError::BadRequest("Report a message_id or a user_id"))?;
+                users::table
+                    .find(user_id)
+                    .select(users::id)
+                    .first::<Uuid>(conn)
+                    .map_err(ApiError::db("User not found"))?;
+                (form.group_id, Some(user_id), None)
+            }
+        };
+        if reported_user_id == Some(reporter.id) {
+            return Err(ApiError::BadRequest("You cannot report yourself"));
+        }
+        if let Some(group_id) = group_id {
+            if groups::member_role(reporter.id, group_id, conn)?.is_none() {
+                return Err(ApiError::Forbidden("Only members can report in this group"));
+            }
+        }
+        Ok(diesel::insert_into(reports::table)
+            .values((
+                reports::reporter_id.eq(reporter.id),
+                reports::group_id.eq(group_id),
+                reports::message_id.eq(form.message_id),
+                reports::reported_user_id.eq(reported_user_id),
+                reports::reason.eq(&reason),
+                reports::content_snapshot.eq(content_snapshot),
+            ))
+            .get_result::<Report>(conn)?)
+    })
+    .await?;
+    tracing::info!(report_id = %report.id, group_id = ?report.group_id, "report filed");
+    Ok(HttpResponse::Ok().json(report))
+}
+
+/// Puts a message a content filter held back into the group's queue.
+pub fn hold_message(
+    group_id: Uuid,
+    sender_id: Uuid,
+    content: &str,
+    reason: &str,
+    conn: &mut PgConnection,
+) -> QueryResult<Report> {
+    use crate::schema::reports;
+    diesel::insert_into(reports::table)
+        .values((
+            reports::kind.eq("held"),
+            reports::group_id.eq(group_id),
+            reports::reported_user_id.eq(sender_id),
+            reports::reason.eq(reason),
+            reports::content_snapshot.eq(content),
+        ))
+        .get_result(conn)
+}
+
+/// Lets site admins through, and the owner and moderators of `group_id`.
+/// Returns whether the user is a site admin. `user_id` must be the signed-in caller.
+pub fn require_moderator(user_id: Uuid, group_id: Option<Uuid>, conn: &mut PgConnection) -> ApiResult<bool> {
+    if admin::is_site_admin(user_id, conn)? {
+        return Ok(true);
+    }
+    let role = match group_id {
+        Some(group_id) => groups::member_role(user_id, group_id, conn)?,
+        None => None,
+    };
+    match role.as_deref() {
+        Some("owner" | "moderator") => Ok(false),
+        _ => Err(ApiError::Forbidden("Only the group's moderators can do that")),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct GroupReportQuery {
+    // "open", "claimed" or "resolved"; everything not yet resolved by default
+    pub status: Option<String>,
+    pub limit: Option<i64>,
+    pub offset: Option<i64>,
+}
+
+// Oldest first, so the queue is worked in order
+pub async fn list_group_reports(
+    req: HttpRequest,
+    path: web::Path<Uuid>,
+    query: web::Query<GroupReportQuery>,
+    pool: web::Data<DbPool>,
+) -> ApiResult {
+    use crate::schema::reports;
+
+    let group_id = path.into_inner();
+    let caller = sessions::current_user(&req, &pool).await?;
+    let response = db::run(&pool, move |conn| {
+        require_moderator(caller.id, Some(group_id), conn)?;
+        let mut found = reports::table
+            .filter(reports::group_id.eq(group_id))
+            .order(reports::created_at)
+            .limit(query.limit.unwrap_or(50).clamp(1, PAGE_MAX))
+            .offset(query.offset.unwrap_or(0).max(0))
+            .into_boxed();
+        found = match &query.status {
+            Some(status) => found.filter(reports::status.eq(status.clone())),
+            None => found.filter(reports::status.ne("resolved")),
+        };
+        Ok(found.load::<Report>(conn)?)
+    })
+    .await?;
+    Ok(HttpResponse::Ok().json(response))
+}
+
+// Locked until the transaction ends, so two moderators cannot act on it at once
+fn lock_report(report_id: Uuid, conn: &mut PgConnection) -> ApiResult<Report> {
+    crate::schema::reports::table
+        .find(report_id)
+        .for_update()
+        .first::<Report>(conn)
+        .map_err(ApiError::db("Report not found"))
+}
+
+// A claimed report belongs to whoever claimed it; site admins may take it over
+fn check_claim(report: &Report, user_id: Uuid, is_admin: bool) -> ApiResult<()> {
+    if report.status == "resolved" {
+        return Err(ApiError::Conflict("Report is already resolved"));
+    }
+    match report.claimed_by {
+        Some(claimed_by) if claimed_by != user_id && !is_admin => {
+            Err(ApiError::Conflict("Another moderator has claimed this report"))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Marks the report as being handled by the signed-in moderator.
+pub async fn claim_report(
+    req: HttpRequest,
+    path: web::Path<Uuid>,
+    pool: web::Data<DbPool>,
+) -> ApiResult {
+    use crate::schema::reports;
+
+    let report_id = path.into_inner();
+    let caller = sessions::current_user(&req, &pool).await?;
+    let report = db::run(&pool, move |conn| {
+        conn.transaction(|conn| {
+            let report = lock_report(report_id, conn)?;
+            let is_admin = require_moderator(caller.id, report.group_id, conn)?;
+            check_claim(&report, caller.id, is_admin)?;
+            Ok(diesel::update(reports::table.find(report_id))
+                .set((
+                    reports::status.eq("claimed"),
+                    reports::claimed_by.eq(caller.id),
+                    reports::claimed_at.eq(chrono::Utc::now().naive_utc()),
+                ))
+                .get_result::<Report>(conn)?)
+        })
+    })
+    .await?;
+    Ok(HttpResponse::Ok().json(report))
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Dismiss,
+    DeleteMessage,
+    // Mutes the reported user in the report's group
+    Mute,
+    // Bans the reported user from the report's group, or suspends the account
+    // when the report is not about a group
+    Ban,
+    // Posts a message a content filter held back
+    Approve,
+}
+
+impl Outcome {
+    fn as_str(self) -> &'static str {
+        match self {
+            Outcome::Dismiss => "dismiss",
+            Outcome::DeleteMessage => "delete_message",
+            Outcome::Mute => "mute",
+            Outcome::Ban => "ban",
+            Outcome::Approve => "approve",
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ResolveRequest {
+    pub outcome: Outcome,
+    pub note: Option<String>,
+    // For a mute; without it the mute lasts until /unmute
+    pub mute_minutes: Option<i64>,
+}
+
+// What the chat server has to hear about once the decision is stored
+#[derive(Default)]
+struct Notices {
+    posted: Option<(String, Message)>,
+    deleted: Option<MessagesDeleted>,
+    disconnect: Option<DisconnectUser>,
+}
+
+/// Applies the signed-in moderator's outcome, closes the report and writes the
+/// decision to the audit log, all in one transaction.
+pub async fn resolve_report(
+    req: HttpRequest,
+    path: web::Path<Uuid>,
+    form: web::Json<ResolveRequest>,
+    pool: web::Data<DbPool>,
+    settings: web::Data<Settings>,
+    rate_limits: web::Data<RateLimits>,
+    srv: web::Data<Addr<ChatServer>>,
+) -> ApiResult {
+    let report_id = path.into_inner();
+    let note = form.note.as_deref().map(str::trim).filter(|note| !note.is_empty()).map(str::to_owned);
+    if note.as_ref().is_some_and(|note| note.chars().count() > TEXT_MAX) {
+        return Err(ApiError::BadRequest("The note is too long"));
+    }
+    if form.mute_minutes.is_some_and(|minutes| minutes <= 0) {
+        return Err(ApiError::BadRequest("mute_minutes must be a positive number"));
+    }
+    let caller = sessions::current_user(&req, &pool).await?;
+    // Banning outside a group suspends the account, which only the admin API may do
+    let may_suspend = match form.outcome {
+        Outcome::Ban => match admin::require_admin(&req, &settings, &pool).await {
+            Ok(_) => true,
+            Err(ApiError::Unauthorized(_) | ApiError::Forbidden(_)) => false,
+            Err(e) => return Err(e),
+        },
+        _ => false,
+    };
+    let ip = rate_limits.client_addr(&req);
+    let resolver = Resolver {
+        user_id: caller.id,
+        may_suspend,
+    };
+    let (report, notices) = db::run(&pool, move |conn| {
+        conn.transaction(|conn| resolve_rows(report_id, resolver, &form, note, ip, conn))
+    })
+    .await?;
+    if let Some((room, message)) = notices.posted {
+        srv.do_send(PostMessage {
+            room,
+            message,
+            span: tracing::Span::current(),
+        });
+    }
+    if let Some(deleted) = notices.deleted {
+        srv.do_send(deleted);
+    }
+    if let Some(disconnect) = notices.disconnect {
+        srv.do_send(disconnect);
+    }
+    tracing::info!(
+        report_id = %report.id,
+        outcome = report.outcome.as_deref().unwrap_or_default(),
+        "report resolved"
+    );
+    Ok(HttpResponse::Ok().json(report))
+}
+
+// The signed-in moderator resolving a report
+#[derive(Clone, Copy)]
+struct Resolver {
+    user_id: Uuid,
+    // Passed require_admin, so a ban outside a group may suspend the account
+    may_suspend: bool,
+}
+
+fn resolve_rows(
+    report_id: Uuid,
+    resolver: Resolver,
+    form: &ResolveRequest,
+    note: Option<String>,
+    ip: String,
+    conn: &mut PgConnection,
+) -> ApiResult<(Report, Notices)> {
+    use crate::schema::{group_bans, messages, reports, user_groups};
+
+    let report = lock_report(report_id, conn)?;
+    let is_admin = require_moderator(resolver.user_id, report.group_id, conn)?;
+    check_claim(&report, resolver.user_id, is_admin)?;
+    if report.reported_user_id == Some(resolver.user_id) {
+        return Err(ApiError::Forbidden("You cannot resolve a report about yourself"));
+    }
+
+    let mut notices = Notices::default();
+    match form.outcome {
+        Outcome::Dismiss => {}
+        Outcome::DeleteMessage => {
+            let message_id = report
+                .message_id
+                .ok_or(ApiError::BadRequest("The report is not about a message"))?;
+            // Someone may have deleted it already; the report still closes
+            let group_id = diesel::delete(messages::table.find(message_id))
+                .returning(messages::group_id)
+                .get_result::<Option<Uuid>>(conn)
+                .optional()?
+                .flatten();
+            if let Some(group_id) = group_id {
+                notices.deleted = Some(MessagesDeleted {
+                    room: polls::room_for_group(group_id, conn)?,
+                    message_ids: vec![message_id],
+                });
+            }
+        }
+        Outcome::Mute => {
+            let (group_id, user_id) = report
+                .group_id
+                .zip(report.reported_user_id)
+                .ok_or(ApiError::BadRequest("Only a user in a group can be muted"))?;
+            let muted_until = form
+                .mute_minutes
+                .map(|minutes| chrono::Utc::now().naive_utc() + chrono::Duration::minutes(minutes));
+            commands::mute(group_id, user_id, resolver.user_id, muted_until, conn)?;
+        }
+        Outcome::Ban => {
+            let user_id = report
+                .reported_user_id
+                .ok_or(ApiError::BadRequest("The report does not name a user"))?;
+            match report.group_id {
+                Some(group_id) => {
+                    if groups::member_role(user_id, group_id, conn)?.as_deref() == Some("owner") {
+                        return Err(ApiError::BadRequest("The group owner cannot be banned"));
+                    }
+                    diesel::insert_into(group_bans::table)
+                        .values((
+                            group_bans::group_id.eq(group_id),
+                            group_bans::user_id.eq(user_id),
+                            group_bans::banned_by.eq(resolver.user_id),
+                            group_bans::reason.eq(&report.reason),
+                        ))
+                        .on_conflict_do_nothing()
+                        .execute(conn)?;
+                    let removed = diesel::delete(user_groups::table.find((user_id, group_id))).execute(conn)?;
+                    if removed > 0 {
+                        webhooks::enqueue(
+                            conn,
+                            group_id,
+                            RoomEvent::Leave,
+                            json!({"user_id": user_id, "actor_id": resolver.user_id}),
+                        )?;
+                    }
+                    notices.disconnect = Some(DisconnectUser {
+                        user_id,
+                        room: Some(polls::room_for_group(group_id, conn)?),
+                        reason: "You were banned from this group".to_owned(),
+                    });
+                }
+                None => {
+                    if !resolver.may_suspend {
+                        return Err(ApiError::Forbidden("Only site admins can ban outside a group"));
+                    }
+                    admin::set_disabled(user_id, true, conn)?;
+                    notices.disconnect = Some(DisconnectUser {
+                        user_id,
+                        room: None,
+                        reason: "Your account has been suspended".to_owned(),
+                    });
+                }
+            }
+        }
+        Outcome::Approve => {
+            if report.kind != "held" {
+                return Err(ApiError::BadRequest("Only held messages can be approved"));
+            }
+            let (Some(group_id), Some(sender_id), Some(content)) =
+                (report.group_id, report.reported_user_id, report.content_snapshot.clone())
+            else {
+                return Err(ApiError::BadRequest("The held message no longer has a group or sender"));
+            };
+            let message = Message::create(
+                NewMessage {
+                    id: Uuid::new_v4(),
+                    group_id: Some(group_id),
+                    sender_id: Some(sender_id),
+                    content,
+                    kind: "text".to_owned(),
+                    expires_at: None,
+                    integration_id: None,
+                    sender_name: None,
+                },
+                conn,
+            )?;
+            webhooks::enqueue(conn, group_id, RoomEvent::Message, json!(message))?;
+            notices.posted = Some((polls::room_for_group(group_id, conn)?, message));
+        }
+    }
+
+    let now = chrono::Utc::now().naive_utc();
+    let resolved = diesel::update(reports::table.find(report_id))
+        .set((
+            reports::status.eq("resolved"),
+            reports::claimed_by.eq(report.claimed_by.unwrap_or(resolver.user_id)),
+            reports::claimed_at.eq(report.claimed_at.unwrap_or(now)),
+            reports::resolved_by.eq(resolver.user_id),
+            reports::resolved_at.eq(now),
+            reports::outcome.eq(form.outcome.as_str()),
+            reports::resolution_note.eq(&note),
+        ))
+        .get_result::<Report>(conn)?;
+    audit::record(
+        conn,
+        NewAuditEntry {
+            actor_id: Some(resolver.user_id),
+            action: format!("report.{}", form.outcome.as_str()),
+            group_id: report.group_id,
+            target_type: "report".to_owned(),
+            target_id: Some(report_id),
+            ip: Some(ip),
+            before: Some(json!({"status": report.status, "claimed_by": report.claimed_by})),
+            after: Some(json!({
+                "status": resolved.status,
+                "outcome": resolved.outcome,
+                "note": resolved.resolution_note,
+                "message_id": report.message_id,
+                "reported_user_id": report.reported_user_id,
+                "mute_minutes": form.mute_minutes,
+                "kind": report.kind,
+            })),
+        },
+    )?;
+    Ok((resolved, notices))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::User;
+    use crate::test_support;
+    use actix_web::http::StatusCode;
+    use actix_web::test::{call_and_read_body_json, call_service, init_service, TestRequest};
+    use actix_web::App;
+    use serde_json::Value;
+
+    fn resolve_as(report: &Report, user_id: Uuid, outcome: Outcome, conn: &mut PgConnection) -> ApiResult<(Report, Notices)> {
+        let resolver = Resolver {
+            user_id,
+            may_suspend: false,
+        };
+        let form = ResolveRequest {
+            outcome,
+            note: None,
+            mute_minutes: None,
+        };
+        resolve_rows(report.id, resolver, &form, Some("checked".to_owned()), "127.0.0.1".to_owned(), conn)
+    }
+
+    #[test]
+    fn owners_and_moderators_moderate() {
+        let Some(mut conn) = test_support::connection() else { return };
+        let conn = &mut conn;
+        let (owner, moderator, member) = (test_support::user(conn), test_support::user(conn), test_support::user(conn));
+        let group = test_support::group(owner.id, conn);
+        test_support::join(moderator.id, group.id, "moderator", conn);
+        test_support::join(member.id, group.id, "member", conn);
+
+        assert!(!require_moderator(owner.id, Some(group.id), conn).unwrap());
+        assert!(!require_moderator(moderator.id, Some(group.id), conn).unwrap());
+        assert!(matches!(require_moderator(member.id, Some(group.id), conn), Err(ApiError::Forbidden(_))));
+        assert!(matches!(require_moderator(owner.id, None, conn), Err(ApiError::Forbidden(_))));
+    }
+
+    #[test]
+    fn approving_a_held_message_posts_it() {
+        let Some(mut conn) = test_support::connection() else { return };
+        let conn = &mut conn;
+        let (owner, sender) = (test_support::user(conn), test_support::user(conn));
+        let group = test_support::group(owner.id, conn);
+        test_support::join(sender.id, group.id, "member", conn);
+        let held = hold_message(group.id, sender.id, "see example.com", "links", conn).unwrap();
+        assert_eq!(held.kind, "held");
+
+        let (resolved, notices) = resolve_as(&held, owner.id, Outcome::Approve, conn).unwrap();
+        assert_eq!(resolved.status, "resolved");
+        assert_eq!(resolved.outcome.as_deref(), Some("approve"));
+        assert_eq!(resolved.claimed_by, Some(owner.id));
+        let (room, message) = notices.posted.unwrap();
+        assert_eq!(room, group.name);
+        assert_eq!(message.content, "see example.com");
+        assert_eq!(message.sender_id, Some(sender.id));
+
+        // A decision is final
+        let again = resolve_as(&resolved, owner.id, Outcome::Dismiss, conn);
+        assert!(matches!(again, Err(ApiError::Conflict(_))));
+    }
+
+    #[test]
+    fn banning_removes_the_member_and_disconnects_them() {
+        let Some(mut conn) = test_support::connection() else { return };
+        let conn = &mut conn;
+        let (owner, member) = (test_support::user(conn), test_support::user(conn));
+        let group = test_support::group(owner.id, conn);
+        test_support::join(member.id, group.id, "member", conn);
+        let report = hold_message(group.id, member.id, "spam", "spam", conn).unwrap();
+
+        let (_, notices) = resolve_as(&report, owner.id, Outcome::Ban, conn).unwrap();
+        assert!(commands::is_banned(member.id, group.id, conn).unwrap());
+        assert_eq!(groups::member_role(member.id, group.id, conn).unwrap(), None);
+        let disconnect = notices.disconnect.unwrap();
+        assert_eq!(disconnect.user_id, member.id);
+        assert_eq!(disconnect.room.as_deref(), Some(group.name.as_str()));
+    }
+
+    #[test]
+    fn claims_and_self_reports_are_respected() {
+        let Some(mut conn) = test_support::connection() else { return };
+        let conn = &mut conn;
+        let (owner, moderator, member) = (test_support::user(conn), test_support::user(conn), test_support::user(conn));
+        let group = test_support::group(owner.id, conn);
+        test_support::join(moderator.id, group.id, "moderator", conn);
+        test_support::join(member.id, group.id, "member", conn);
+
+        let about_moderator = hold_message(group.id, moderator.id, "hm", "words", conn).unwrap();
+        let own = resolve_as(&about_moderator, moderator.id, Outcome::Dismiss, conn);
+        assert!(matches!(own, Err(ApiError::Forbidden(_))));
+
+        let claimed = hold_message(group.id, member.id, "hm", "words", conn).unwrap();
+        diesel::update(crate::schema::reports::table.find(claimed.id))
+            .set((
+                crate::schema::reports::status.eq("claimed"),
+                crate::schema::reports::claimed_by.eq(moderator.id),
+            ))
+            .execute(conn)
+            .unwrap();
+        let taken = resolve_as(&claimed, owner.id, Outcome::Dismiss, conn);
+        assert!(matches!(taken, Err(ApiError::Conflict(_))));
+        let (resolved, _) = resolve_as(&claimed, moderator.id, Outcome::Dismiss, conn).unwrap();
+        assert_eq!(resolved.resolved_by, Some(moderator.id));
+    }
+
+    #[test]
+    fn suspending_outside_a_group_needs_the_admin_api() {
+        let Some(mut conn) = test_support::connection() else { return };
+        let conn = &mut conn;
+        let (admin, target) = (test_support::user(conn), test_support::user(conn));
+        admin::set_admin(admin.id, true, conn).unwrap();
+        let report = user_report(admin.id, target.id, conn);
+
+        let refused = resolve_as(&report, admin.id, Outcome::Ban, conn);
+        assert!(matches!(refused, Err(ApiError::Forbidden(_))));
+        let resolver = Resolver {
+            user_id: admin.id,
+            may_suspend: true,
+        };
+        let form = ResolveRequest {
+            outcome: Outcome::Ban,
+            note: None,
+            mute_minutes: None,
+        };
+        let (_, notices) = resolve_rows(report.id, resolver, &form, None, "127.0.0.1".to_owned(), conn).unwrap();
+        assert_eq!(notices.disconnect.unwrap().room, None);
+        assert!(!admin::is_site_admin(target.id, conn).unwrap());
+    }
+
+    // A report about a user outside any group
+    fn user_report(reporter_id: Uuid, user_id: Uuid, conn: &mut PgConnection) -> Report {
+        use crate::schema::reports;
+        diesel::insert_into(reports::table)
+            .values((
+                reports::reporter_id.eq(reporter_id),
+                reports::reported_user_id.eq(user_id),
+                reports::reason.eq("spam"),
+            ))
+            .get_result(conn)
+            .unwrap()
+    }
+
+    fn app_with_sessions(pool: &DbPool) -> App<
+        impl actix_web::dev::ServiceFactory<
+            actix_web::dev::ServiceRequest,
+            Config = (),
+            Response = actix_web::dev::ServiceResponse,
+            Error = actix_web::Error,
+            InitError = (),
+        >,
+    > {
+        let settings = Settings {
+            auth: test_support::auth_settings(),
+            ..Settings::default()
+        };
+        App::new()
+            .app_data(web::Data::new(pool.clone()))
+            .app_data(web::Data::new(settings))
+            .app_data(web::Data::new(RateLimits::new(&Default::default())))
+            .app_data(web::Data::new(test_support::chat_server(pool.clone(), Default::default())))
+            .app_data(test_support::session_signer())
+            .route("/reports", web::post().to(create_report))
+            .route("/reports/{id}/claim", web::post().to(claim_report))
+            .route("/reports/{id}/resolve", web::post().to(resolve_report))
+            .route("/groups/{id}/reports", web::get().to(list_group_reports))
+    }
+
+    #[actix_web::test]
+    async fn reports_keep_the_message_and_reach_the_queue() {
+        let Some(pool) = test_support::pool() else { return };
+        let (group, owner, reporter, message) = {
+            let mut conn = pool.get().unwrap();
+            let (owner, reporter) = (test_support::user(&mut conn), test_support::user(&mut conn));
+            let group = test_support::group(owner.id, &mut conn);
+            test_support::join(reporter.id, group.id, "member", &mut conn);
+            let message = Message::create(
+                NewMessage {
+                    id: Uuid::new_v4(),
+                    group_id: Some(group.id),
+                    sender_id: Some(owner.id),
+                    content: "rude".to_owned(),
+                    kind: "text".to_owned(),
+                    expires_at: None,
+                    integration_id: None,
+                    sender_name: None,
+                },
+                &mut conn,
+            )
+            .unwrap();
+            (group, owner, reporter, message)
+        };
+        let app = init_service(app_with_sessions(&pool)).await;
+
+        let req = TestRequest::post()
+            .uri("/reports")
+            .insert_header(test_support::signed_in(&reporter))
+            .set_json(json!({"message_id": message.id, "reason": "  insult "}))
+            .to_request();
+        let report: Value = call_and_read_body_json(&app, req).await;
+        assert_eq!(report["reason"], "insult");
+        assert_eq!(report["reporter_id"], reporter.id.to_string());
+        assert_eq!(report["content_snapshot"], "rude");
+        assert_eq!(report["reported_user_id"], owner.id.to_string());
+
+        let req = TestRequest::post()
+            .uri("/reports")
+            .insert_header(test_support::signed_in(&reporter))
+            .set_json(json!({"user_id": reporter.id, "reason": "me"}))
+            .to_request();
+        assert_eq!(call_service(&app, req).await.status(), StatusCode::BAD_REQUEST);
+        let req = TestRequest::post()
+            .uri("/reports")
+            .set_json(json!({"message_id": message.id, "reason": "unsigned"}))
+            .to_request();
+        assert_eq!(call_service(&app, req).await.status(), StatusCode::UNAUTHORIZED);
+
+        let queue_for = |user: &User| {
+            TestRequest::get()
+                .uri(&format!("/groups/{}/reports", group.id))
+                .insert_header(test_support::signed_in(user))
+                .to_request()
+        };
+        let queue: Vec<Value> = call_and_read_body_json(&app, queue_for(&owner)).await;
+        assert_eq!(queue.len(), 1);
+        assert_eq!(queue[0]["id"], report["id"]);
+        assert_eq!(call_service(&app, queue_for(&reporter)).await.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[actix_web::test]
+    async fn admin_ids_in_the_body_are_not_credentials() {
+        let Some(pool) = test_support::pool() else { return };
+        let (admin, member, target, report) = {
+            let mut conn = pool.get().unwrap();
+            let (admin, member, target) = (
+                test_support::user(&mut conn),
+                test_support::user(&mut conn),
+                test_support::user(&mut conn),
+            );
+            admin::set_admin(admin.id, true, &mut conn).unwrap();
+            let report = user_report(member.id, target.id, &mut conn);
+            (admin, member, target, report)
+        };
+        let app = init_service(app_with_sessions(&pool)).await;
+
+        let resolve = |session: Option<&User>| {
+            let mut req = TestRequest::post()
+                .uri(&format!("/reports/{}/resolve", report.id))
+                .set_json(json!({"user_id": admin.id, "outcome": "ban"}));
+            if let Some(user) = session {
+                req = req.insert_header(test_support::signed_in(user));
+            }
+            req.to_request()
+        };
+        assert_eq!(call_service(&app, resolve(None)).await.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(call_service(&app, resolve(Some(&member))).await.status(), StatusCode::FORBIDDEN);
+        let claim = TestRequest::post()
+            .uri(&format!("/reports/{}/claim", report.id))
+            .insert_header(test_support::signed_in(&member))
+            .set_json(json!({"user_id": admin.id}))
+            .to_request();
+        assert_eq!(call_service(&app, claim).await.status(), StatusCode::FORBIDDEN);
+        let target_user = crate::schema::users::table
+            .find(target.id)
+            .first::<User>(&mut pool.get().unwrap())
+            .unwrap();
+        assert!(!target_user.is_disabled());
+
+        // The admin's own session suspends through require_admin
+        assert_eq!(call_service(&app, resolve(Some(&admin))).await.status(), StatusCode::OK);
+    }
+}
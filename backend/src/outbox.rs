@@ -0,0 +1,287 @@
+use crate::settings::WebSocketSettings;
+use actix::{Message as ActixMessage, Recipient};
+use chrono::NaiveDateTime;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+// Process-wide queue figures, reported by `stats()`
+static QUEUED_FRAMES: AtomicU64 = AtomicU64::new(0);
+static QUEUED_BYTES: AtomicU64 = AtomicU64::new(0);
+static PEAK_DEPTH: AtomicU64 = AtomicU64::new(0);
+static DROPPED_EPHEMERAL: AtomicU64 = AtomicU64::new(0);
+static SLOW_CONSUMER_DISCONNECTS: AtomicU64 = AtomicU64::new(0);
+
+/// One text frame for a session. Rooms share a frame between all of their
+/// sessions, so a broadcast is serialized and allocated once.
+#[derive(Serialize, Deserialize)]
+pub struct Frame {
+    pub text: String,
+    // Superseded by later frames (presence, tallies, notices); safe to drop under pressure
+    #[serde(default)]
+    pub ephemeral: bool,
+    // Set for stored chat messages, so a dropped client knows where to resume
+    #[serde(default)]
+    pub message_at: Option<NaiveDateTime>,
+}
+
+impl Frame {
+    pub fn text(text: impl Into<String>) -> Self {
+        Frame {
+            text: text.into(),
+            ephemeral: false,
+            message_at: None,
+        }
+    }
+}
+
+// Tells a session that its outbox has frames waiting
+#[derive(ActixMessage)]
+#[rtype(result = "()")]
+pub struct Flush;
+
+#[derive(Clone, Copy)]
+pub struct OutboxLimits {
+    pub max_frames: usize,
+    pub max_bytes: usize,
+}
+
+impl OutboxLimits {
+    pub fn from_settings(settings: &WebSocketSettings) -> Self {
+        OutboxLimits {
+            max_frames: settings.outbox_max_frames,
+            max_bytes: settings.outbox_max_bytes,
+        }
+    }
+}
+
+#[derive(Default)]
+struct State {
+    frames: VecDeque<Arc<Frame>>,
+    bytes: usize,
+    // A Flush is already in the session's mailbox
+    wake_pending: bool,
+    // Over the limit with nothing left to drop; the session must go
+    overflowed: bool,
+    closed: bool,
+}
+
+impl State {
+    fn remove(&mut self, index: usize) {
+        if let Some(frame) = self.frames.remove(index) {
+            self.bytes -= frame.text.len();
+            QUEUED_FRAMES.fetch_sub(1, Ordering::Relaxed);
+            QUEUED_BYTES.fetch_sub(frame.text.len() as u64, Ordering::Relaxed);
+        }
+    }
+
+    fn clear(&mut self) {
+        QUEUED_FRAMES.fetch_sub(self.frames.len() as u64, Ordering::Relaxed);
+        QUEUED_BYTES.fetch_sub(self.bytes as u64, Ordering::Relaxed);
+        self.frames.clear();
+        self.bytes = 0;
+    }
+}
+
+/// What a session takes out of its outbox in one go.
+pub struct Drained {
+    pub frames: Vec<Arc<Frame>>,
+    // The client fell too far behind and has to reconnect
+    pub overflowed: bool,
+}
+
+/// Frames waiting to be written to one WebSocket.
+///
+/// Rooms push, the session drains whenever its actor runs. A session actor is only
+/// polled while its socket keeps up, so a slow client's backlog collects here, where
+/// it is bounded, rather than in an unbounded mailbox. Over the limit the oldest
+/// ephemeral frames go first; if only chat frames are left the outbox is emptied and
+/// the session is told to disconnect.
+pub struct Outbox {
+    state: Mutex<State>,
+    limits: OutboxLimits,
+    session: Recipient<Flush>,
+}
+
+impl Outbox {
+    pub fn new(session: Recipient<Flush>, limits: OutboxLimits) -> Self {
+        Outbox {
+            state: Mutex::new(State::default()),
+            limits,
+            session,
+        }
+    }
+
+    /// Queues `frame`; false once the session is gone or has overflowed.
+    pub fn push(&self, frame: Arc<Frame>) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.closed || state.overflowed {
+            return false;
+        }
+        state.bytes += frame.text.len();
+        QUEUED_FRAMES.fetch_add(1, Ordering::Relaxed);
+        QUEUED_BYTES.fetch_add(frame.text.len() as u64, Ordering::Relaxed);
+        state.frames.push_back(frame);
+
+        while state.frames.len() > self.limits.max_frames || state.bytes > self.limits.max_bytes {
+            match state.frames.iter().position(|frame| frame.ephemeral) {
+                Some(index) => {
+                    state.remove(index);
+                    DROPPED_EPHEMERAL.fetch_add(1, Ordering::Relaxed);
+                }
+                None => {
+                    state.clear();
+                    state.overflowed = true;
+                    SLOW_CONSUMER_DISCONNECTS.fetch_add(1, Ordering::Relaxed);
+                    break;
+                }
+            }
+        }
+        PEAK_DEPTH.fetch_max(state.frames.len() as u64, Ordering::Relaxed);
+
+        let wake = !state.wake_pending;
+        state.wake_pending = true;
+        let accepted = !state.overflowed;
+        drop(state);
+        if wake {
+            self.session.do_send(Flush);
+        }
+        accepted
+    }
+
+    pub fn drain(&self) -> Drained {
+        let mut state = self.state.lock().unwrap();
+        state.wake_pending = false;
+        let frames = state.frames.iter().cloned().collect();
+        state.clear();
+        Drained {
+            frames,
+            overflowed: state.overflowed,
+        }
+    }
+
+    /// Drops whatever is queued and refuses further frames.
+    pub fn close(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.clear();
+        state.closed = true;
+    }
+}
+
+#[derive(Serialize)]
+pub struct OutboxStats {
+    pub queued_frames: u64,
+    pub queued_bytes: u64,
+    // Deepest any single outbox has been since start-up
+    pub peak_depth: u64,
+    pub dropped_ephemeral: u64,
+    pub slow_consumer_disconnects: u64,
+}
+
+pub fn stats() -> OutboxStats {
+    OutboxStats {
+        queued_frames: QUEUED_FRAMES.load(Ordering::Relaxed),
+        queued_bytes: QUEUED_BYTES.load(Ordering::Relaxed),
+        peak_depth: PEAK_DEPTH.load(Ordering::Relaxed),
+        dropped_ephemeral: DROPPED_EPHEMERAL.load(Ordering::Relaxed),
+        slow_consumer_disconnects: SLOW_CONSUMER_DISCONNECTS.load(Ordering::Relaxed),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix::{Actor, Context, Handler};
+    use std::time::Duration;
+
+    // Counts the wake-ups an outbox sends
+    struct Session(Arc<AtomicU64>);
+
+    impl Actor for Session {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<Flush> for Session {
+        type Result = ();
+        fn handle(&mut self, _: Flush, _: &mut Context<Self>) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn outbox(max_frames: usize, max_bytes: usize) -> (Outbox, Arc<AtomicU64>) {
+        let flushes = Arc::new(AtomicU64::new(0));
+        let session = Session(flushes.clone()).start().recipient();
+        (Outbox::new(session, OutboxLimits { max_frames, max_bytes }), flushes)
+    }
+
+    fn ephemeral(text: &str) -> Arc<Frame> {
+        Arc::new(Frame {
+            ephemeral: true,
+            ..Frame::text(text)
+        })
+    }
+
+    fn texts(drained: &Drained) -> Vec<&str> {
+        drained.frames.iter().map(|frame| frame.text.as_str()).collect()
+    }
+
+    #[actix_web::test]
+    async fn one_wake_up_per_drain() {
+        let (outbox, flushes) = outbox(10, 1024);
+        assert!(outbox.push(Arc::new(Frame::text("a"))));
+        assert!(outbox.push(Arc::new(Frame::text("b"))));
+        actix_web::rt::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(flushes.load(Ordering::Relaxed), 1);
+
+        let drained = outbox.drain();
+        assert_eq!(texts(&drained), ["a", "b"]);
+        assert!(!drained.overflowed);
+        assert!(outbox.drain().frames.is_empty());
+
+        assert!(outbox.push(Arc::new(Frame::text("c"))));
+        actix_web::rt::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(flushes.load(Ordering::Relaxed), 2);
+    }
+
+    #[actix_web::test]
+    async fn oldest_ephemeral_frames_go_first() {
+        let (outbox, _) = outbox(3, 1024);
+        assert!(outbox.push(ephemeral("presence 1")));
+        assert!(outbox.push(Arc::new(Frame::text("hello"))));
+        assert!(outbox.push(ephemeral("presence 2")));
+        assert!(outbox.push(Arc::new(Frame::text("world"))));
+        let drained = outbox.drain();
+        assert_eq!(texts(&drained), ["hello", "presence 2", "world"]);
+        assert!(!drained.overflowed);
+    }
+
+    #[actix_web::test]
+    async fn the_byte_limit_counts_too() {
+        let (outbox, _) = outbox(100, 10);
+        assert!(outbox.push(ephemeral("12345")));
+        assert!(outbox.push(Arc::new(Frame::text("123456"))));
+        assert_eq!(texts(&outbox.drain()), ["123456"]);
+    }
+
+    #[actix_web::test]
+    async fn chat_frames_over_the_limit_overflow_the_outbox() {
+        let (outbox, _) = outbox(2, 1024);
+        assert!(outbox.push(Arc::new(Frame::text("a"))));
+        assert!(outbox.push(Arc::new(Frame::text("b"))));
+        assert!(!outbox.push(Arc::new(Frame::text("c"))));
+        let drained = outbox.drain();
+        assert!(drained.frames.is_empty());
+        assert!(drained.overflowed);
+        assert!(!outbox.push(Arc::new(Frame::text("d"))));
+    }
+
+    #[actix_web::test]
+    async fn closed_outboxes_refuse_frames() {
+        let (outbox, _) = outbox(10, 1024);
+        assert!(outbox.push(Arc::new(Frame::text("a"))));
+        outbox.close();
+        assert!(outbox.drain().frames.is_empty());
+        assert!(!outbox.push(Arc::new(Frame::text("b"))));
+    }
+}
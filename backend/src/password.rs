@@ -0,0 +1,365 @@
+use crate::admin;
+use crate::audit;
+use crate::bots;
+use crate::db::{self, DbPool};
+use crate::errors::{ApiError, ApiResult};
+use crate::mail::{self, Mail, Mailer};
+use crate::models::{NewAuditEntry, PasswordResetToken, User};
+use crate::ratelimit::RateLimits;
+use crate::settings::Settings;
+use crate::ws::{ChatServer, DisconnectUser};
+use actix::Addr;
+use actix_web::{web, HttpRequest, HttpResponse};
+use diesel::prelude::*;
+use serde::Deserialize;
+use serde_json::json;
+use uuid::Uuid;
+
+// One reset mail per account in this window, however many addresses ask for it
+const REQUEST_INTERVAL_SECS: i64 = 60;
+
+#[derive(Deserialize)]
+pub struct ForgotRequest {
+    pub email: String,
+}
+
+#[derive(Deserialize)]
+pub struct ResetRequest {
+    pub token: String,
+    pub password: String,
+}
+
+// A new token replaces any the account still had. None while the last one is too recent.
+fn issue_token(user_id: Uuid, ttl_secs: u64, ip: &str, conn: &mut PgConnection) -> ApiResult<Option<String>> {
+    use crate::schema::password_reset_tokens as tokens;
+
+    conn.transaction(|conn| {
+        // Serializes requests for the same account
+        crate::schema::users::table
+            .find(user_id)
+            .select(crate::schema::users::id)
+            .for_update()
+            .first::<Uuid>(conn)?;
+        let now = chrono::Utc::now().naive_utc();
+        let recent = diesel::select(diesel::dsl::exists(
+            tokens::table
+                .filter(tokens::user_id.eq(user_id))
+                .filter(tokens::created_at.gt(now - chrono::Duration::seconds(REQUEST_INTERVAL_SECS))),
+        ))
+        .get_result::<bool>(conn)?;
+        if recent {
+            return Ok(None);
+        }
+        diesel::delete(tokens::table.filter(tokens::user_id.eq(user_id))).execute(conn)?;
+
+        let token = bots::random_hex(32);
+        diesel::insert_into(tokens::table)
+            .values((
+                tokens::user_id.eq(user_id),
+                tokens::token_hash.eq(bots::hash_key(&token)),
+                tokens::expires_at.eq(now + chrono::Duration::seconds(ttl_secs as i64)),
+                tokens::requested_ip.eq(ip),
+            ))
+            .execute(conn)?;
+        audit::record(
+            conn,
+            NewAuditEntry {
+                action: "user.password_reset_request".to_owned(),
+                target_type: "user".to_owned(),
+                target_id: Some(user_id),
+                ip: Some(ip.to_owned()),
+                ..Default::default()
+            },
+        )?;
+        Ok(Some(token))
+    })
+}
+
+/// Mails a one-time reset link. The answer is the same whether or not the
+/// address belongs to an account, so it cannot be used to look up addresses.
+pub async fn forgot_password(
+    req: HttpRequest,
+    form: web::Json<ForgotRequest>,
+    mailer: web::Data<dyn Mailer>,
+    settings: web::Data<Settings>,
+    pool: web::Data<DbPool>,
+    rate_limits: web::Data<RateLimits>,
+) -> ApiResult {
+    let ip = rate_limits.client_addr(&req);
+    let email = form.into_inner().email;
+    let ttl_secs = settings.auth.password_reset_ttl_secs;
+    let issued = db::run(&pool, move |conn| {
+        let Some(user) = User::find_by_email(&email, conn).optional()? else {
+            return Ok(None);
+        };
+        if user.is_bot || user.is_disabled() {
+            return Ok(None);
+        }
+        Ok(issue_token(user.id, ttl_secs, &ip, conn)?.map(|token| (user, token)))
+    })
+    .await?;
+
+    if let Some((user, token)) = issued {
+        let link = format!("{}?token={}", settings.mail.password_reset_url, token);
+        mail::send_in_background(
+            &mailer,
+            Mail {
+                to: user.email,
+                subject: "Reset your NeuroChat password".to_owned(),
+                body: format!(
+                    "Hi {},\n\nSomeone asked to reset the password of your NeuroChat account. \
+                     To choose a new one, open this link:\n\n{}\n\n\
+                     The link works once and expires in {} minutes. If you did not ask for this, \
+                     ignore this message; your password stays the same.\n",
+                    user.username,
+                    link,
+                    (ttl_secs / 60).max(1),
+                ),
+            },
+        );
+    }
+    Ok(HttpResponse::Ok().json(json!({
+        "message": "If that address belongs to an account, a reset link is on its way"
+    })))
+}
+
+// The unused, unexpired reset token with this plaintext
+fn find_token(token: &str, conn: &mut PgConnection, lock: bool) -> ApiResult<PasswordResetToken> {
+    use crate::schema::password_reset_tokens as tokens;
+
+    let now = chrono::Utc::now().naive_utc();
+    let query = tokens::table
+        .filter(tokens::token_hash.eq(bots::hash_key(token)))
+        .filter(tokens::used_at.is_null())
+        .filter(tokens::expires_at.gt(now));
+    let reset = if lock {
+        query.for_update().first::<PasswordResetToken>(conn).optional()?
+    } else {
+        query.first::<PasswordResetToken>(conn).optional()?
+    };
+    reset.ok_or(ApiError::BadRequest("This reset link is invalid or has expired"))
+}
+
+/// Sets a new password with a token from `forgot_password`. Every other reset
+/// token of the account stops working, and so do its session tokens, whose
+/// holders have to sign in again. Bot API keys are left alone.
+pub async fn reset_password(
+    req: HttpRequest,
+    form: web::Json<ResetRequest>,
+    settings: web::Data<Settings>,
+    pool: web::Data<DbPool>,
+    rate_limits: web::Data<RateLimits>,
+    srv: web::Data<Addr<ChatServer>>,
+) -> ApiResult {
+    use crate::schema::password_reset_tokens as tokens;
+
+    let ResetRequest { token, password } = form.into_inner();
+    if password.is_empty() {
+        return Err(ApiError::BadRequest("The password must not be empty"));
+    }
+    // Hashing is slow on purpose, so only do it for a token that can still be used
+    let lookup = token.clone();
+    db::run(&pool, move |conn| find_token(&lookup, conn, false)).await?;
+    let ip = rate_limits.client_addr(&req);
+    let cost = settings.auth.bcrypt_cost;
+    let password_hash = web::block(move || User::hash_password(&password, cost)).await??;
+    let user_id = db::run(&pool, move |conn| {
+        conn.transaction(|conn| {
+            // Checked again under the lock; the token may have been used meanwhile
+            let reset = find_token(&token, conn, true)?;
+            diesel::update(tokens::table.find(reset.id))
+                .set(tokens::used_at.eq(chrono::Utc::now().naive_utc()))
+                .execute(conn)?;
+            diesel::delete(
+                tokens::table
+                    .filter(tokens::user_id.eq(reset.user_id))
+                    .filter(tokens::used_at.is_null()),
+            )
+            .execute(conn)?;
+            admin::set_password(reset.user_id, &password_hash, conn)?;
+            audit::record(
+                conn,
+                NewAuditEntry {
+                    actor_id: Some(reset.user_id),
+                    action: "user.password".to_owned(),
+                    target_type: "user".to_owned(),
+                    target_id: Some(reset.user_id),
+                    ip: Some(ip),
+                    after: Some(json!({"via": "reset"})),
+                    ..Default::default()
+                },
+            )?;
+            Ok(reset.user_id)
+        })
+    })
+    .await?;
+
+    // Their tokens no longer work, so close the sockets they opened with them
+    srv.do_send(DisconnectUser {
+        user_id,
+        room: None,
+        reason: "Your password was changed; sign in again".to_owned(),
+    });
+    tracing::info!(%user_id, "password reset");
+    Ok(HttpResponse::Ok().json(json!({"message": "Password changed"})))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mail::MemoryMailer;
+    use crate::models::BotApiKey;
+    use crate::sessions::SessionSigner;
+    use crate::test_support;
+    use actix_web::http::StatusCode;
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::App;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    fn settings(bcrypt_cost: u32) -> Settings {
+        let mut settings = Settings::default();
+        settings.auth.jwt_secret = Some("a-secret-of-at-least-thirty-two-chars".to_owned());
+        settings.auth.bcrypt_cost = bcrypt_cost;
+        settings
+    }
+
+    fn unverified_user(conn: &mut PgConnection) -> User {
+        let user = test_support::user(conn);
+        diesel::update(crate::schema::users::table.find(user.id))
+            .set(crate::schema::users::email_verified_at.eq(None::<chrono::NaiveDateTime>))
+            .get_result(conn)
+            .unwrap()
+    }
+
+    fn reset(token: &str, password: &str) -> TestRequest {
+        TestRequest::post()
+            .uri("/password/reset")
+            .set_json(json!({"token": token, "password": password}))
+    }
+
+    #[test]
+    fn tokens_are_throttled_per_account() {
+        let Some(mut conn) = test_support::connection() else { return };
+        let user = test_support::user(&mut conn);
+        assert!(issue_token(user.id, 60, "127.0.0.1", &mut conn).unwrap().is_some());
+        assert!(issue_token(user.id, 60, "127.0.0.1", &mut conn).unwrap().is_none());
+    }
+
+    #[test]
+    fn unknown_and_expired_tokens_are_not_found() {
+        use crate::schema::password_reset_tokens as tokens;
+
+        let Some(mut conn) = test_support::connection() else { return };
+        let user = test_support::user(&mut conn);
+        let token = issue_token(user.id, 60, "127.0.0.1", &mut conn).unwrap().unwrap();
+        assert_eq!(find_token(&token, &mut conn, false).unwrap().user_id, user.id);
+        assert!(find_token("junk", &mut conn, false).is_err());
+
+        let past = chrono::Utc::now().naive_utc() - chrono::Duration::seconds(1);
+        diesel::update(tokens::table.filter(tokens::user_id.eq(user.id)))
+            .set(tokens::expires_at.eq(past))
+            .execute(&mut conn)
+            .unwrap();
+        assert!(find_token(&token, &mut conn, false).is_err());
+    }
+
+    #[actix_web::test]
+    async fn reset_links_work_once_and_sign_the_account_out() {
+        let Some(pool) = test_support::pool() else { return };
+        let settings = settings(4);
+        let signer = SessionSigner::new(&settings.auth);
+        let (user, key) = {
+            let mut conn = pool.get().unwrap();
+            let user = unverified_user(&mut conn);
+            let bot = test_support::user(&mut conn);
+            diesel::update(crate::schema::users::table.find(bot.id))
+                .set((
+                    crate::schema::users::is_bot.eq(true),
+                    crate::schema::users::owner_id.eq(user.id),
+                ))
+                .execute(&mut conn)
+                .unwrap();
+            let key = diesel::insert_into(crate::schema::bot_api_keys::table)
+                .values(BotApiKey {
+                    id: Uuid::new_v4(),
+                    bot_id: bot.id,
+                    name: "ci".to_owned(),
+                    key_prefix: bots::random_hex(6),
+                    key_hash: bots::hash_key("unused"),
+                    created_at: chrono::Utc::now().naive_utc(),
+                    last_used_at: None,
+                    revoked_at: None,
+                })
+                .get_result::<BotApiKey>(&mut conn)
+                .unwrap();
+            (user, key)
+        };
+        let session = signer.issue(user.id, user.session_version).unwrap();
+        let mailer = Arc::new(MemoryMailer::default());
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(pool.clone()))
+                .app_data(web::Data::from(mailer.clone() as Arc<dyn Mailer>))
+                .app_data(web::Data::new(RateLimits::new(&settings.rate_limit)))
+                .app_data(web::Data::new(test_support::chat_server(pool.clone(), Default::default())))
+                .app_data(web::Data::new(settings.clone()))
+                .route("/password/forgot", web::post().to(forgot_password))
+                .route("/password/reset", web::post().to(reset_password)),
+        )
+        .await;
+
+        let req = TestRequest::post()
+            .uri("/password/forgot")
+            .set_json(json!({"email": user.email}))
+            .to_request();
+        assert_eq!(call_service(&app, req).await.status(), StatusCode::OK);
+        let mut mail = None;
+        for _ in 0..100 {
+            mail = mailer.sent().pop();
+            if mail.is_some() {
+                break;
+            }
+            actix_web::rt::time::sleep(Duration::from_millis(20)).await;
+        }
+        let mail = mail.expect("a reset mail was sent");
+        let start = mail.body.find("token=").unwrap() + "token=".len();
+        let token = mail.body[start..].split_whitespace().next().unwrap().to_owned();
+
+        let status = call_service(&app, reset(&token, "new password").to_request()).await.status();
+        assert_eq!(status, StatusCode::OK);
+        let status = call_service(&app, reset(&token, "another one").to_request()).await.status();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let mut conn = pool.get().unwrap();
+        let after = crate::schema::users::table.find(user.id).first::<User>(&mut conn).unwrap();
+        assert!(after.verify_password("new password"));
+        assert!(signer.authenticate(&session, &mut conn).is_err());
+        // Neither a side door to verification nor a surprise for the user's integrations
+        assert!(!after.is_verified());
+        let key = crate::schema::bot_api_keys::table
+            .find(key.id)
+            .first::<BotApiKey>(&mut conn)
+            .unwrap();
+        assert!(key.revoked_at.is_none());
+    }
+
+    #[actix_web::test]
+    async fn junk_tokens_are_turned_away_before_hashing() {
+        let Some(pool) = test_support::pool() else { return };
+        // Hashing at this cost would take far longer than the test allows
+        let settings = settings(20);
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(pool.clone()))
+                .app_data(web::Data::new(RateLimits::new(&settings.rate_limit)))
+                .app_data(web::Data::new(test_support::chat_server(pool.clone(), Default::default())))
+                .app_data(web::Data::new(settings))
+                .route("/password/reset", web::post().to(reset_password)),
+        )
+        .await;
+        let answer = call_service(&app, reset("junk", "new password").to_request());
+        let response = actix_web::rt::time::timeout(Duration::from_secs(5), answer).await;
+        assert_eq!(response.expect("answered without hashing").status(), StatusCode::BAD_REQUEST);
+    }
+}
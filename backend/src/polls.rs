@@ -0,0 +1,482 @@
+use crate::db::{self, DbPool};
+use crate::errors::{ApiError, ApiResult};
+use crate::models::{Message, NewMessage, Poll, PollOption};
+use crate::sessions;
+use crate::webhooks::{self, RoomEvent};
+use crate::ws::{ChatServer, PollEvent};
+use actix::Addr;
+use actix_web::{web, HttpRequest, HttpResponse};
+use chrono::NaiveDateTime;
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::{BTreeSet, HashMap};
+use uuid::Uuid;
+
+const MAX_POLL_OPTIONS: usize = 20;
+
+#[derive(Deserialize)]
+pub struct CreatePollRequest {
+    pub group_id: Uuid,
+    pub question: String,
+    pub options: Vec<String>,
+    #[serde(default)]
+    pub multiple_choice: bool,
+    #[serde(default)]
+    pub anonymous: bool,
+    pub closes_at: Option<NaiveDateTime>,
+}
+
+#[derive(Deserialize)]
+pub struct VoteRequest {
+    pub option_ids: Vec<Uuid>,
+}
+
+/// Current state of a poll as sent to clients, both live and in history.
+#[derive(Serialize, Clone, Debug)]
+pub struct PollTally {
+    pub poll_id: Uuid,
+    pub group_id: Uuid,
+    pub question: String,
+    pub multiple_choice: bool,
+    pub anonymous: bool,
+    pub closes_at: Option<NaiveDateTime>,
+    pub closed: bool,
+    pub total_voters: usize,
+    pub options: Vec<OptionTally>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct OptionTally {
+    pub id: Uuid,
+    pub label: String,
+    pub votes: usize,
+    // Only filled in for public polls
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub voters: Option<Vec<Uuid>>,
+}
+
+#[derive(Debug)]
+pub enum PollError {
+    Invalid(&'static str),
+    NotFound,
+    Closed,
+    NotMember,
+    Db(diesel::result::Error),
+}
+
+impl From<diesel::result::Error> for PollError {
+    fn from(e: diesel::result::Error) -> Self {
+        match e {
+            diesel::result::Error::NotFound => PollError::NotFound,
+            e => PollError::Db(e),
+        }
+    }
+}
+
+impl PollError {
+    pub fn message(&self) -> String {
+        match self {
+            PollError::Invalid(reason) => reason.to_string(),
+            PollError::NotFound => "Poll not found".to_string(),
+            PollError::Closed => "Poll is closed".to_string(),
+            PollError::NotMember => "Only group members can take part in polls".to_string(),
+            PollError::Db(e) => {
+                tracing::error!(error = ?e, "poll query failed");
+                "Poll query failed".to_string()
+            }
+        }
+    }
+}
+
+impl From<PollError> for ApiError {
+    fn from(e: PollError) -> Self {
+        match e {
+            PollError::Invalid(reason) => ApiError::BadRequest(reason),
+            PollError::NotFound => ApiError::NotFound("Poll not found"),
+            PollError::Closed => ApiError::Conflict("Poll is closed"),
+            PollError::NotMember => ApiError::Forbidden("Only group members can take part in polls"),
+            PollError::Db(e) => ApiError::from(e),
+        }
+    }
+}
+
+fn is_member(user_id: Uuid, group_id: Uuid, conn: &mut PgConnection) -> Result<bool, diesel::result::Error> {
+    use crate::schema::user_groups;
+    diesel::select(diesel::dsl::exists(
+        user_groups::table
+            .filter(user_groups::user_id.eq(user_id))
+            .filter(user_groups::group_id.eq(group_id)),
+    ))
+    .get_result(conn)
+}
+
+/// Room name used by `ChatServer` for a group.
+pub fn room_for_group(group_id: Uuid, conn: &mut PgConnection) -> Result<String, diesel::result::Error> {
+    crate::schema::groups::table
+        .find(group_id)
+        .select(crate::schema::groups::name)
+        .first(conn)
+}
+
+/// Creates the poll together with the `messages` row that places it in the room history.
+pub fn create_poll(req: &CreatePollRequest, creator_id: Uuid, conn: &mut PgConnection) -> Result<PollTally, PollError> {
+    let question = req.question.trim();
+    if question.is_empty() {
+        return Err(PollError::Invalid("Poll question must not be empty"));
+    }
+    let labels: Vec<&str> = req.options.iter().map(|o| o.trim()).collect();
+    if labels.len() < 2 || labels.len() > MAX_POLL_OPTIONS {
+        return Err(PollError::Invalid("Polls need between 2 and 20 options"));
+    }
+    if labels.iter().any(|l| l.is_empty()) {
+        return Err(PollError::Invalid("Poll options must not be empty"));
+    }
+    let now = chrono::Utc::now().naive_utc();
+    if req.closes_at.is_some_and(|closes_at| closes_at <= now) {
+        return Err(PollError::Invalid("Poll close time must be in the future"));
+    }
+    if !is_member(creator_id, req.group_id, conn)? {
+        return Err(PollError::NotMember);
+    }
+
+    let message = conn.transaction::<_, diesel::result::Error, _>(|conn| {
+        let message = Message::create(
+            NewMessage {
+                id: Uuid::new_v4(),
+                group_id: Some(req.group_id),
+                sender_id: Some(creator_id),
+                content: question.to_owned(),
+                kind: "poll".to_owned(),
+                expires_at: None,
+                integration_id: None,
+                sender_name: None,
+            },
+            conn,
+        )?;
+        diesel::insert_into(crate::schema::polls::table)
+            .values(Poll {
+                id: message.id,
+                group_id: req.group_id,
+                creator_id,
+                question: question.to_owned(),
+                multiple_choice: req.multiple_choice,
+                anonymous: req.anonymous,
+                closes_at: req.closes_at,
+                created_at: now,
+            })
+            .execute(conn)?;
+        let options: Vec<PollOption> = labels
+            .iter()
+            .enumerate()
+            .map(|(position, label)| PollOption {
+                id: Uuid::new_v4(),
+                poll_id: message.id,
+                position: position as i32,
+                label: (*label).to_owned(),
+            })
+            .collect();
+        diesel::insert_into(crate::schema::poll_options::table)
+            .values(&options)
+            .execute(conn)?;
+        webhooks::enqueue(conn, req.group_id, RoomEvent::Message, json!(message))?;
+        Ok(message)
+    })?;
+    tally(message.id, conn)
+}
+
+/// Replaces the user's previous ballot on the poll with `option_ids`.
+pub fn cast_vote(
+    poll_id: Uuid,
+    user_id: Uuid,
+    option_ids: &[Uuid],
+    conn: &mut PgConnection,
+) -> Result<PollTally, PollError> {
+    use crate::schema::{poll_options, poll_votes, polls};
+
+    let poll = polls::table.find(poll_id).first::<Poll>(conn)?;
+    if poll.is_closed() {
+        return Err(PollError::Closed);
+    }
+    if !is_member(user_id, poll.group_id, conn)? {
+        return Err(PollError::NotMember);
+    }
+    let chosen: BTreeSet<Uuid> = option_ids.iter().copied().collect();
+    if chosen.is_empty() {
+        return Err(PollError::Invalid("Pick at least one option"));
+    }
+    if !poll.multiple_choice && chosen.len() > 1 {
+        return Err(PollError::Invalid("This poll only allows a single choice"));
+    }
+    let valid: BTreeSet<Uuid> = poll_options::table
+        .filter(poll_options::poll_id.eq(poll_id))
+        .select(poll_options::id)
+        .load::<Uuid>(conn)?
+        .into_iter()
+        .collect();
+    if !chosen.is_subset(&valid) {
+        return Err(PollError::Invalid("Unknown poll option"));
+    }
+
+    conn.transaction::<_, diesel::result::Error, _>(|conn| {
+        diesel::delete(
+            poll_votes::table
+                .filter(poll_votes::poll_id.eq(poll_id))
+                .filter(poll_votes::user_id.eq(user_id)),
+        )
+        .execute(conn)?;
+        let rows: Vec<_> = chosen
+            .iter()
+            .map(|option_id| {
+                (
+                    poll_votes::poll_id.eq(poll_id),
+                    poll_votes::option_id.eq(*option_id),
+                    poll_votes::user_id.eq(user_id),
+                )
+            })
+            .collect();
+        diesel::insert_into(poll_votes::table).values(&rows).execute(conn)?;
+        Ok(())
+    })?;
+
+    tally(poll_id, conn)
+}
+
+pub fn tally(poll_id: Uuid, conn: &mut PgConnection) -> Result<PollTally, PollError> {
+    tallies(&[poll_id], conn)?.remove(&poll_id).ok_or(PollError::NotFound)
+}
+
+/// Tallies for every poll in `poll_ids` that exists, in three queries however many there are.
+pub fn tallies(poll_ids: &[Uuid], conn: &mut PgConnection) -> QueryResult<HashMap<Uuid, PollTally>> {
+    use crate::schema::{poll_options, poll_votes, polls};
+
+    if poll_ids.is_empty() {
+        return Ok(HashMap::new());
+    }
+    let polls = polls::table.filter(polls::id.eq_any(poll_ids)).load::<Poll>(conn)?;
+    let options = poll_options::table
+        .filter(poll_options::poll_id.eq_any(poll_ids))
+        .order((poll_options::poll_id, poll_options::position.asc()))
+        .load::<PollOption>(conn)?;
+    let votes = poll_votes::table
+        .filter(poll_votes::poll_id.eq_any(poll_ids))
+        .order(poll_votes::created_at.asc())
+        .select((poll_votes::poll_id, poll_votes::option_id, poll_votes::user_id))
+        .load::<(Uuid, Uuid, Uuid)>(conn)?;
+
+    let mut options_by_poll: HashMap<Uuid, Vec<PollOption>> = HashMap::new();
+    for option in options {
+        options_by_poll.entry(option.poll_id).or_default().push(option);
+    }
+    let mut voters_by_option: HashMap<Uuid, Vec<Uuid>> = HashMap::new();
+    let mut voters_by_poll: HashMap<Uuid, BTreeSet<Uuid>> = HashMap::new();
+    for (poll_id, option_id, user_id) in votes {
+        voters_by_option.entry(option_id).or_default().push(user_id);
+        voters_by_poll.entry(poll_id).or_default().insert(user_id);
+    }
+
+    Ok(polls
+        .into_iter()
+        .map(|poll| {
+            let options = options_by_poll
+                .remove(&poll.id)
+                .unwrap_or_default()
+                .into_iter()
+                .map(|option| {
+                    let option_voters = voters_by_option.remove(&option.id).unwrap_or_default();
+                    OptionTally {
+                        id: option.id,
+                        label: option.label,
+                        votes: option_voters.len(),
+                        voters: (!poll.anonymous).then_some(option_voters),
+                    }
+                })
+                .collect();
+            let tally = PollTally {
+                poll_id: poll.id,
+                group_id: poll.group_id,
+                question: poll.question.clone(),
+                multiple_choice: poll.multiple_choice,
+                anonymous: poll.anonymous,
+                closes_at: poll.closes_at,
+                closed: poll.is_closed(),
+                total_voters: voters_by_poll.get(&poll.id).map_or(0, BTreeSet::len),
+                options,
+            };
+            (poll.id, tally)
+        })
+        .collect())
+}
+
+pub async fn create_poll_handler(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
+    srv: web::Data<Addr<ChatServer>>,
+    form: web::Json<CreatePollRequest>,
+) -> ApiResult {
+    let creator = sessions::current_user(&req, &pool).await?;
+    let response = db::run(&pool, move |conn| {
+        let tally = create_poll(&form, creator.id, conn)?;
+        if let Ok(room) = room_for_group(tally.group_id, conn) {
+            srv.do_send(PollEvent {
+                room,
+                tally: tally.clone(),
+                created: true,
+                span: tracing::Span::current(),
+            });
+        }
+        Ok(tally)
+    })
+    .await?;
+    Ok(HttpResponse::Ok().json(response))
+}
+
+pub async fn vote(
+    req: HttpRequest,
+    path: web::Path<Uuid>,
+    pool: web::Data<DbPool>,
+    srv: web::Data<Addr<ChatServer>>,
+    form: web::Json<VoteRequest>,
+) -> ApiResult {
+    let poll_id = path.into_inner();
+    let voter = sessions::current_user(&req, &pool).await?;
+    let response = db::run(&pool, move |conn| {
+        let tally = cast_vote(poll_id, voter.id, &form.option_ids, conn)?;
+        if let Ok(room) = room_for_group(tally.group_id, conn) {
+            srv.do_send(PollEvent {
+                room,
+                tally: tally.clone(),
+                created: false,
+                span: tracing::Span::current(),
+            });
+        }
+        Ok(tally)
+    })
+    .await?;
+    Ok(HttpResponse::Ok().json(response))
+}
+
+pub async fn get_poll(path: web::Path<Uuid>, pool: web::Data<DbPool>) -> ApiResult {
+    let response = db::run(&pool, move |conn| Ok(tally(path.into_inner(), conn)?)).await?;
+    Ok(HttpResponse::Ok().json(response))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filters::FilterChain;
+    use crate::test_support;
+    use actix_web::http::StatusCode;
+    use actix_web::test::{call_and_read_body_json, call_service, init_service, TestRequest};
+    use actix_web::App;
+
+    fn request(group_id: Uuid, anonymous: bool) -> CreatePollRequest {
+        CreatePollRequest {
+            group_id,
+            question: "Lunch?".to_owned(),
+            options: vec!["Pizza".to_owned(), "Soup".to_owned()],
+            multiple_choice: false,
+            anonymous,
+            closes_at: None,
+        }
+    }
+
+    #[test]
+    fn votes_replace_the_previous_ballot() {
+        let Some(mut conn) = test_support::connection() else { return };
+        let owner = test_support::user(&mut conn);
+        let group = test_support::group(owner.id, &mut conn);
+        let poll = create_poll(&request(group.id, false), owner.id, &mut conn).unwrap();
+        let (pizza, soup) = (poll.options[0].id, poll.options[1].id);
+
+        cast_vote(poll.poll_id, owner.id, &[pizza], &mut conn).unwrap();
+        let tally = cast_vote(poll.poll_id, owner.id, &[soup], &mut conn).unwrap();
+        assert_eq!(tally.total_voters, 1);
+        assert_eq!((tally.options[0].votes, tally.options[1].votes), (0, 1));
+        assert_eq!(tally.options[1].voters, Some(vec![owner.id]));
+
+        let both = cast_vote(poll.poll_id, owner.id, &[pizza, soup], &mut conn);
+        assert!(matches!(both, Err(PollError::Invalid(_))));
+        let outsider = test_support::user(&mut conn);
+        assert!(matches!(cast_vote(poll.poll_id, outsider.id, &[pizza], &mut conn), Err(PollError::NotMember)));
+    }
+
+    #[test]
+    fn polls_need_a_member_and_two_options() {
+        let Some(mut conn) = test_support::connection() else { return };
+        let owner = test_support::user(&mut conn);
+        let group = test_support::group(owner.id, &mut conn);
+        let mut one_option = request(group.id, false);
+        one_option.options.truncate(1);
+        assert!(matches!(create_poll(&one_option, owner.id, &mut conn), Err(PollError::Invalid(_))));
+        let outsider = test_support::user(&mut conn);
+        assert!(matches!(create_poll(&request(group.id, false), outsider.id, &mut conn), Err(PollError::NotMember)));
+    }
+
+    #[test]
+    fn tallies_match_one_by_one_tallies() {
+        let Some(mut conn) = test_support::connection() else { return };
+        let owner = test_support::user(&mut conn);
+        let voter = test_support::user(&mut conn);
+        let group = test_support::group(owner.id, &mut conn);
+        test_support::join(voter.id, group.id, "member", &mut conn);
+        let public = create_poll(&request(group.id, false), owner.id, &mut conn).unwrap();
+        let secret = create_poll(&request(group.id, true), owner.id, &mut conn).unwrap();
+        cast_vote(public.poll_id, voter.id, &[public.options[0].id], &mut conn).unwrap();
+        cast_vote(secret.poll_id, voter.id, &[secret.options[1].id], &mut conn).unwrap();
+        cast_vote(secret.poll_id, owner.id, &[secret.options[1].id], &mut conn).unwrap();
+
+        let missing = Uuid::new_v4();
+        let tallies = tallies(&[public.poll_id, secret.poll_id, missing], &mut conn).unwrap();
+        assert_eq!(tallies.len(), 2);
+        for poll_id in [public.poll_id, secret.poll_id] {
+            let one = tally(poll_id, &mut conn).unwrap();
+            assert_eq!(serde_json::to_value(&tallies[&poll_id]).unwrap(), serde_json::to_value(&one).unwrap());
+        }
+        assert_eq!(tallies[&secret.poll_id].total_voters, 2);
+        assert_eq!(tallies[&secret.poll_id].options[1].voters, None);
+        assert!(matches!(tally(missing, &mut conn), Err(PollError::NotFound)));
+    }
+
+    #[actix_web::test]
+    async fn creators_and_voters_come_from_the_session() {
+        let Some(pool) = test_support::pool() else { return };
+        let (group, owner, outsider) = {
+            let mut conn = pool.get().unwrap();
+            let (owner, outsider) = (test_support::user(&mut conn), test_support::user(&mut conn));
+            (test_support::group(owner.id, &mut conn), owner, outsider)
+        };
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(pool.clone()))
+                .app_data(web::Data::new(test_support::chat_server(pool.clone(), FilterChain::default())))
+                .app_data(test_support::session_signer())
+                .route("/polls", web::post().to(create_poll_handler))
+                .route("/polls/{id}/vote", web::post().to(vote)),
+        )
+        .await;
+        let create = |user: &crate::models::User| {
+            TestRequest::post()
+                .uri("/polls")
+                .insert_header(test_support::signed_in(user))
+                .set_json(json!({"group_id": group.id, "creator_id": owner.id, "question": "Lunch?", "options": ["Pizza", "Soup"]}))
+                .to_request()
+        };
+
+        assert_eq!(call_service(&app, create(&outsider)).await.status(), StatusCode::FORBIDDEN);
+        let poll: serde_json::Value = call_and_read_body_json(&app, create(&owner)).await;
+        let ballot = |session: Option<&crate::models::User>| {
+            let mut req = TestRequest::post()
+                .uri(&format!("/polls/{}/vote", poll["poll_id"].as_str().unwrap()))
+                .set_json(json!({"user_id": owner.id, "option_ids": [poll["options"][0]["id"]]}));
+            if let Some(user) = session {
+                req = req.insert_header(test_support::signed_in(user));
+            }
+            req.to_request()
+        };
+        assert_eq!(call_service(&app, ballot(None)).await.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(call_service(&app, ballot(Some(&outsider))).await.status(), StatusCode::FORBIDDEN);
+        let tally: serde_json::Value = call_and_read_body_json(&app, ballot(Some(&owner))).await;
+        assert_eq!(tally["options"][0]["voters"], json!([owner.id]));
+    }
+}
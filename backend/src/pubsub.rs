@@ -0,0 +1,286 @@
+use crate::outbox::Frame;
+use crate::settings::{PubSubBackend, Settings};
+use actix::{Message as ActixMessage, Recipient};
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use diesel::sql_types::Text;
+use serde::{Deserialize, Serialize};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Duration;
+use uuid::Uuid;
+
+// NOTIFY rejects payloads of 8000 bytes or more
+const MAX_NOTIFY_PAYLOAD: usize = 7900;
+// Spilled payloads only need to outlive delivery to the listeners
+const SPILL_RETENTION_MINUTES: i64 = 5;
+// How often the listener checks its socket for notifications
+const LISTEN_POLL_INTERVAL: Duration = Duration::from_millis(20);
+const RECONNECT_DELAY: Duration = Duration::from_secs(2);
+
+/// A room-wide effect that every instance must apply to its own sessions.
+/// Replies meant for a single session never leave the instance that owns it.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClusterEvent {
+    // A frame for every session in the room
+    Broadcast { room: String, frame: Arc<Frame> },
+    // Close a user's sessions in a room, e.g. after /kick
+    Disconnect { room: String, user_id: Uuid, reason: String },
+    // Close a user's sessions in every room, e.g. after a suspension
+    DisconnectUser { user_id: Uuid, reason: String },
+    // A group's banned words were replaced
+    BannedWordsChanged { room: String },
+    // Every user the sending instance has connected to the room; empty once it has none
+    Presence { room: String, user_ids: Vec<Uuid> },
+    // Lets peers notice an instance that died without clearing its presence
+    Heartbeat,
+}
+
+#[derive(Serialize, Deserialize, ActixMessage)]
+#[rtype(result = "()")]
+pub struct Envelope {
+    pub origin: Uuid,
+    pub event: ClusterEvent,
+}
+
+/// Carries room events between backend instances.
+///
+/// Delivery is best effort: an instance that is reconnecting misses live events,
+/// which is fine for chat frames (history has them) and self-heals for presence
+/// through the heartbeat.
+pub trait PubSub: Send + Sync {
+    /// Queues `envelope` for the other instances without blocking the caller.
+    fn publish(&self, envelope: &Envelope);
+    /// Starts forwarding envelopes from instances other than `origin` to `inbox`.
+    fn subscribe(&self, origin: Uuid, inbox: Recipient<Envelope>);
+}
+
+/// A single instance has nobody to fan out to.
+pub struct LocalOnly;
+
+impl PubSub for LocalOnly {
+    fn publish(&self, _: &Envelope) {}
+    fn subscribe(&self, _: Uuid, _: Recipient<Envelope>) {}
+}
+
+/// Fans out over Postgres `LISTEN/NOTIFY` using two dedicated connections, one
+/// publishing and one listening, so chat traffic never waits on the request pool.
+pub struct PgNotify {
+    url: String,
+    channel: String,
+    outbox: mpsc::Sender<String>,
+}
+
+impl PgNotify {
+    pub fn new(url: &str, channel: &str) -> Self {
+        let (outbox, queue) = mpsc::channel();
+        let (publish_url, publish_channel) = (url.to_owned(), channel.to_owned());
+        thread::spawn(move || run_publisher(&publish_url, &publish_channel, queue));
+        PgNotify {
+            url: url.to_owned(),
+            channel: channel.to_owned(),
+            outbox,
+        }
+    }
+}
+
+impl PubSub for PgNotify {
+    fn publish(&self, envelope: &Envelope) {
+        match serde_json::to_string(envelope) {
+            Ok(payload) => {
+                let _ = self.outbox.send(payload);
+            }
+            Err(e) => tracing::error!(error = ?e, "could not encode cluster event"),
+        }
+    }
+
+    fn subscribe(&self, origin: Uuid, inbox: Recipient<Envelope>) {
+        let (url, channel) = (self.url.clone(), self.channel.clone());
+        thread::spawn(move || run_listener(&url, &channel, origin, inbox));
+    }
+}
+
+pub fn from_settings(settings: &Settings) -> Box<dyn PubSub> {
+    match settings.pubsub.backend {
+        PubSubBackend::Local => Box::new(LocalOnly),
+        PubSubBackend::Postgres => Box::new(PgNotify::new(&settings.database.url, &settings.pubsub.channel)),
+    }
+}
+
+fn notify(conn: &mut PgConnection, channel: &str, payload: &str) -> QueryResult<()> {
+    use crate::schema::pubsub_payloads;
+
+    let payload = if payload.len() < MAX_NOTIFY_PAYLOAD {
+        payload.to_owned()
+    } else {
+        let cutoff = chrono::Utc::now().naive_utc() - chrono::Duration::minutes(SPILL_RETENTION_MINUTES);
+        diesel::delete(pubsub_payloads::table.filter(pubsub_payloads::created_at.lt(cutoff))).execute(conn)?;
+        let id = diesel::insert_into(pubsub_payloads::table)
+            .values(pubsub_payloads::payload.eq(payload))
+            .returning(pubsub_payloads::id)
+            .get_result::<i64>(conn)?;
+        format!("@{}", id)
+    };
+    diesel::sql_query("SELECT pg_notify($1, $2)")
+        .bind::<Text, _>(channel)
+        .bind::<Text, _>(payload)
+        .execute(conn)?;
+    Ok(())
+}
+
+fn run_publisher(url: &str, channel: &str, queue: mpsc::Receiver<String>) {
+    let mut conn: Option<PgConnection> = None;
+    // Ends once the chat server, and with it the sender, is gone
+    for payload in queue {
+        // One retry on a fresh connection covers a dropped socket
+        for _ in 0..2 {
+            if conn.is_none() {
+                conn = PgConnection::establish(url)
+                    .map_err(|e| tracing::warn!(error = %e, "pub/sub publisher could not connect"))
+                    .ok();
+            }
+            let Some(c) = conn.as_mut() else { break };
+            match notify(c, channel, &payload) {
+                Ok(()) => break,
+                Err(e) => {
+                    tracing::warn!(error = ?e, "pub/sub publish failed");
+                    conn = None;
+                }
+            }
+        }
+    }
+}
+
+fn run_listener(url: &str, channel: &str, origin: Uuid, inbox: Recipient<Envelope>) {
+    while inbox.connected() {
+        match listen(url, channel, origin, &inbox) {
+            Ok(()) => return,
+            Err(e) => {
+                tracing::warn!(error = %e, "pub/sub listener lost its connection, retrying");
+                thread::sleep(RECONNECT_DELAY);
+            }
+        }
+    }
+}
+
+// Returns Ok once the inbox is gone, or the error that broke the connection
+fn listen(url: &str, channel: &str, origin: Uuid, inbox: &Recipient<Envelope>) -> Result<(), String> {
+    use crate::schema::pubsub_payloads;
+
+    let mut conn = PgConnection::establish(url).map_err(|e| e.to_string())?;
+    diesel::sql_query(format!("LISTEN \"{}\"", channel))
+        .execute(&mut conn)
+        .map_err(|e| e.to_string())?;
+    tracing::info!(channel, "listening for other instances");
+
+    while inbox.connected() {
+        let notifications = conn
+            .notifications_iter()
+            .collect::<QueryResult<Vec<_>>>()
+            .map_err(|e| e.to_string())?;
+        if notifications.is_empty() {
+            thread::sleep(LISTEN_POLL_INTERVAL);
+            continue;
+        }
+        for notification in notifications {
+            let payload = match notification.payload.strip_prefix('@') {
+                Some(id) => {
+                    let Ok(id) = id.parse::<i64>() else { continue };
+                    match pubsub_payloads::table
+                        .find(id)
+                        .select(pubsub_payloads::payload)
+                        .first::<String>(&mut conn)
+                        .optional()
+                    {
+                        Ok(Some(payload)) => payload,
+                        Ok(None) => continue,
+                        Err(e) => return Err(e.to_string()),
+                    }
+                }
+                None => notification.payload,
+            };
+            match serde_json::from_str::<Envelope>(&payload) {
+                // Our own NOTIFYs come back to us too
+                Ok(envelope) if envelope.origin == origin => {}
+                Ok(envelope) => inbox.do_send(envelope),
+                Err(e) => tracing::warn!(error = ?e, "ignoring malformed cluster event"),
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+    use actix::{Actor, Context, Handler};
+    use std::sync::Mutex;
+
+    // Collects what the listener delivers
+    struct Inbox(Arc<Mutex<Vec<Envelope>>>);
+
+    impl Actor for Inbox {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<Envelope> for Inbox {
+        type Result = ();
+        fn handle(&mut self, envelope: Envelope, _: &mut Context<Self>) {
+            self.0.lock().unwrap().push(envelope);
+        }
+    }
+
+    #[test]
+    fn events_are_tagged_json() {
+        let envelope = Envelope {
+            origin: Uuid::nil(),
+            event: ClusterEvent::Broadcast {
+                room: "lobby".to_owned(),
+                frame: Arc::new(Frame::text("hi")),
+            },
+        };
+        let json = serde_json::to_value(&envelope).unwrap();
+        assert_eq!(json["event"]["type"], "broadcast");
+        assert_eq!(json["event"]["frame"]["text"], "hi");
+        let back: Envelope = serde_json::from_value(json).unwrap();
+        assert!(matches!(back.event, ClusterEvent::Broadcast { room, frame } if room == "lobby" && frame.text == "hi"));
+    }
+
+    #[actix_web::test]
+    async fn instances_hear_each_other_but_not_themselves() {
+        let Some(url) = test_support::database_url() else { return };
+        // A channel of its own keeps other test runs out of the way
+        let channel = test_support::unique("test").replace('-', "_");
+        let (this, other) = (Uuid::new_v4(), Uuid::new_v4());
+        let pubsub = PgNotify::new(&url, &channel);
+        let received = Arc::new(Mutex::new(Vec::new()));
+        pubsub.subscribe(this, Inbox(received.clone()).start().recipient());
+
+        // The listener may not be listening yet, so keep publishing until something arrives
+        for _ in 0..250 {
+            pubsub.publish(&Envelope {
+                origin: this,
+                event: ClusterEvent::Heartbeat,
+            });
+            pubsub.publish(&Envelope {
+                origin: other,
+                event: ClusterEvent::Presence {
+                    room: "lobby".to_owned(),
+                    user_ids: vec![this],
+                },
+            });
+            actix_web::rt::time::sleep(Duration::from_millis(20)).await;
+            if !received.lock().unwrap().is_empty() {
+                break;
+            }
+        }
+        let received = received.lock().unwrap();
+        assert!(!received.is_empty());
+        for envelope in received.iter() {
+            assert_eq!(envelope.origin, other);
+            assert!(matches!(&envelope.event, ClusterEvent::Presence { user_ids, .. } if user_ids == &[this]));
+        }
+    }
+}
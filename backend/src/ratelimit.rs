@@ -0,0 +1,213 @@
+use crate::errors::ApiError;
+use crate::settings::{RateLimitRule, RateLimitSettings};
+use actix_web::body::{EitherBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::{web, HttpRequest, ResponseError};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Past this many tracked clients, buckets that have refilled are forgotten
+const PRUNE_THRESHOLD: usize = 10_000;
+
+struct Bucket {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+/// Token buckets for one rule, keyed by client.
+pub struct RateLimiter {
+    rule: RateLimitRule,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(rule: RateLimitRule) -> Self {
+        RateLimiter {
+            rule,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes a token for `key`, or says how long until the next one.
+    pub fn check(&self, key: &str) -> Result<(), Duration> {
+        if self.rule.per_minute == 0 {
+            return Ok(());
+        }
+        let (burst, per_second) = (self.rule.burst as f64, self.rule.per_minute as f64 / 60.0);
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= PRUNE_THRESHOLD && !buckets.contains_key(key) {
+            buckets.retain(|_, bucket| {
+                bucket.tokens + now.duration_since(bucket.refilled_at).as_secs_f64() * per_second < burst
+            });
+        }
+        let bucket = buckets.entry(key.to_owned()).or_insert(Bucket {
+            tokens: burst,
+            refilled_at: now,
+        });
+        let refill = now.duration_since(bucket.refilled_at).as_secs_f64() * per_second;
+        bucket.tokens = (bucket.tokens + refill).min(burst);
+        bucket.refilled_at = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / per_second))
+        }
+    }
+}
+
+/// Every limiter on this instance. Limits are per instance; behind a load balancer
+/// a client gets each allowance once per backend.
+pub struct RateLimits {
+    trust_forwarded_for: bool,
+    login: RateLimiter,
+    signup: RateLimiter,
+    create_group: RateLimiter,
+    mail: RateLimiter,
+    password_reset: RateLimiter,
+    api: RateLimiter,
+    pub messages: RateLimiter,
+}
+
+impl RateLimits {
+    pub fn new(settings: &RateLimitSettings) -> Self {
+        RateLimits {
+            trust_forwarded_for: settings.trust_forwarded_for,
+            login: RateLimiter::new(settings.login),
+            signup: RateLimiter::new(settings.signup),
+            create_group: RateLimiter::new(settings.create_group),
+            mail: RateLimiter::new(settings.mail),
+            password_reset: RateLimiter::new(settings.password_reset),
+            api: RateLimiter::new(settings.api),
+            messages: RateLimiter::new(settings.messages),
+        }
+    }
+
+    /// The address requests from `req` are counted against.
+    pub fn client_addr(&self, req: &HttpRequest) -> String {
+        let addr = if self.trust_forwarded_for {
+            // Falls back to the peer, which comes with a port
+            req.connection_info().realip_remote_addr().map(|addr| match addr.parse::<SocketAddr>() {
+                Ok(addr) => addr.ip().to_string(),
+                Err(_) => addr.to_owned(),
+            })
+        } else {
+            req.peer_addr().map(|addr| addr.ip().to_string())
+        };
+        addr.unwrap_or_else(|| "unknown".to_owned())
+    }
+}
+
+/// Middleware applying the REST limits; rejected requests get a 429 with `Retry-After`.
+pub async fn limit_requests(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, actix_web::Error> {
+    let Some(limits) = req.app_data::<web::Data<RateLimits>>().cloned() else {
+        return next.call(req).await.map(ServiceResponse::map_into_left_body);
+    };
+    // Counted per address: nothing in a request proves who sent it before the handler runs,
+    // and keying on an unchecked API key would let a client mint fresh allowances
+    let limiter = match req.path() {
+        "/login" => &limits.login,
+        "/signup" => &limits.signup,
+        "/create-group" => &limits.create_group,
+        "/verify-email/resend" | "/password/forgot" => &limits.mail,
+        "/password/reset" => &limits.password_reset,
+        _ => &limits.api,
+    };
+    let checked = limiter.check(&limits.client_addr(req.request()));
+    match checked {
+        Ok(()) => next.call(req).await.map(ServiceResponse::map_into_left_body),
+        Err(retry_after) => {
+            let response = ApiError::RateLimited(retry_after).error_response();
+            Ok(req.into_response(response).map_into_right_body())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::StatusCode;
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::{middleware, App, HttpResponse};
+
+    fn rule(per_minute: u32, burst: u32) -> RateLimitRule {
+        RateLimitRule { per_minute, burst }
+    }
+
+    #[test]
+    fn buckets_allow_a_burst_then_refuse() {
+        let limiter = RateLimiter::new(rule(60, 3));
+        for _ in 0..3 {
+            assert!(limiter.check("a").is_ok());
+        }
+        let retry_after = limiter.check("a").unwrap_err();
+        assert!(retry_after > Duration::ZERO && retry_after <= Duration::from_secs(1));
+        // Each client has a bucket of its own
+        assert!(limiter.check("b").is_ok());
+    }
+
+    #[test]
+    fn buckets_refill_over_time() {
+        let limiter = RateLimiter::new(rule(6000, 1));
+        assert!(limiter.check("a").is_ok());
+        assert!(limiter.check("a").is_err());
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(limiter.check("a").is_ok());
+    }
+
+    #[test]
+    fn zero_per_minute_turns_the_limit_off() {
+        let limiter = RateLimiter::new(rule(0, 1));
+        for _ in 0..100 {
+            assert!(limiter.check("a").is_ok());
+        }
+    }
+
+    #[test]
+    fn forwarded_addresses_count_only_when_trusted() {
+        let req = TestRequest::default()
+            .peer_addr("10.0.0.1:4000".parse().unwrap())
+            .insert_header(("X-Forwarded-For", "203.0.113.7, 10.0.0.1"))
+            .to_http_request();
+        let mut settings = RateLimitSettings::default();
+        assert_eq!(RateLimits::new(&settings).client_addr(&req), "10.0.0.1");
+        settings.trust_forwarded_for = true;
+        assert_eq!(RateLimits::new(&settings).client_addr(&req), "203.0.113.7");
+
+        let direct = TestRequest::default().peer_addr("10.0.0.2:4000".parse().unwrap()).to_http_request();
+        assert_eq!(RateLimits::new(&settings).client_addr(&direct), "10.0.0.2");
+    }
+
+    #[actix_web::test]
+    async fn requests_over_the_limit_get_429() {
+        let settings = RateLimitSettings {
+            login: rule(60, 1),
+            ..RateLimitSettings::default()
+        };
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(RateLimits::new(&settings)))
+                .wrap(middleware::from_fn(limit_requests))
+                .route("/login", web::post().to(HttpResponse::Ok))
+                .route("/groups", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let peer = "192.0.2.1:1234".parse().unwrap();
+
+        let res = call_service(&app, TestRequest::post().uri("/login").peer_addr(peer).to_request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = call_service(&app, TestRequest::post().uri("/login").peer_addr(peer).to_request()).await;
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(res.headers().get("Retry-After").is_some());
+        // Other routes draw on the general allowance
+        let res = call_service(&app, TestRequest::get().uri("/groups").peer_addr(peer).to_request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+}
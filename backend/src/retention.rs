@@ -0,0 +1,150 @@
+use crate::db::{self, DbPool};
+use crate::ws::{ChatServer, MessagesDeleted};
+use actix::{Actor, Addr, AsyncContext, Context};
+use diesel::prelude::*;
+use std::collections::HashMap;
+use std::time::Duration;
+use uuid::Uuid;
+
+/// Periodically hard-deletes messages past their TTL or their group's retention
+/// window and tells the affected rooms which message ids are gone.
+pub struct RetentionSweeper {
+    pool: DbPool,
+    server: Addr<ChatServer>,
+    interval: Duration,
+}
+
+impl RetentionSweeper {
+    pub fn new(pool: DbPool, server: Addr<ChatServer>, interval_secs: u64) -> Self {
+        RetentionSweeper {
+            pool,
+            server,
+            interval: Duration::from_secs(interval_secs),
+        }
+    }
+
+    fn sweep(&self) {
+        let mut conn = match db::connection(&self.pool) {
+            Ok(conn) => conn,
+            Err(_) => return tracing::warn!("retention sweep skipped, no database connection"),
+        };
+        let deleted = match sweep_expired(&mut conn) {
+            Ok(deleted) => deleted,
+            Err(e) => return tracing::error!(error = ?e, "retention sweep failed"),
+        };
+
+        let mut by_group: HashMap<Uuid, Vec<Uuid>> = HashMap::new();
+        for (message_id, group_id) in deleted {
+            if let Some(group_id) = group_id {
+                by_group.entry(group_id).or_default().push(message_id);
+            }
+        }
+        if by_group.is_empty() {
+            return;
+        }
+        let rooms = crate::schema::groups::table
+            .filter(crate::schema::groups::id.eq_any(by_group.keys().copied().collect::<Vec<_>>()))
+            .select((crate::schema::groups::id, crate::schema::groups::name))
+            .load::<(Uuid, String)>(&mut conn)
+            .unwrap_or_default();
+        for (group_id, room) in rooms {
+            if let Some(message_ids) = by_group.remove(&group_id) {
+                self.server.do_send(MessagesDeleted { room, message_ids });
+            }
+        }
+    }
+}
+
+impl Actor for RetentionSweeper {
+    type Context = Context<Self>;
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_interval(self.interval, |act, _| act.sweep());
+    }
+}
+
+/// Deletes expired messages and returns `(message id, group id)` for each removed row.
+/// Polls and their options and votes go with their message through `ON DELETE CASCADE`.
+pub fn sweep_expired(conn: &mut PgConnection) -> Result<Vec<(Uuid, Option<Uuid>)>, diesel::result::Error> {
+    use crate::schema::{groups, messages};
+
+    let now = chrono::Utc::now().naive_utc();
+    conn.transaction(|conn| {
+        let mut deleted = diesel::delete(messages::table.filter(messages::expires_at.le(now)))
+            .returning((messages::id, messages::group_id))
+            .get_results::<(Uuid, Option<Uuid>)>(conn)?;
+
+        let policies = groups::table
+            .filter(groups::retention_seconds.is_not_null())
+            .select((groups::id, groups::retention_seconds))
+            .load::<(Uuid, Option<i64>)>(conn)?;
+        for (group_id, retention_seconds) in policies {
+            let Some(retention_seconds) = retention_seconds else { continue };
+            let cutoff = now - chrono::Duration::seconds(retention_seconds);
+            deleted.extend(
+                diesel::delete(
+                    messages::table
+                        .filter(messages::group_id.eq(group_id))
+                        .filter(messages::timestamp.lt(cutoff)),
+                )
+                .returning((messages::id, messages::group_id))
+                .get_results::<(Uuid, Option<Uuid>)>(conn)?,
+            );
+        }
+        Ok(deleted)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{Message, NewMessage};
+    use crate::test_support;
+
+    fn message(group_id: Uuid, sender_id: Uuid, expires_at: Option<chrono::NaiveDateTime>, conn: &mut PgConnection) -> Uuid {
+        let new = NewMessage {
+            id: Uuid::new_v4(),
+            group_id: Some(group_id),
+            sender_id: Some(sender_id),
+            content: "hello".to_owned(),
+            kind: "text".to_owned(),
+            expires_at,
+            integration_id: None,
+            sender_name: None,
+        };
+        Message::create(new, conn).unwrap().id
+    }
+
+    #[test]
+    fn expired_messages_and_those_past_retention_are_deleted() {
+        use crate::schema::{groups, messages};
+        let Some(mut conn) = test_support::connection() else { return };
+        let owner = test_support::user(&mut conn);
+        let group = test_support::group(owner.id, &mut conn);
+        let kept_group = test_support::group(owner.id, &mut conn);
+        let now = chrono::Utc::now().naive_utc();
+
+        let expired = message(group.id, owner.id, Some(now - chrono::Duration::seconds(1)), &mut conn);
+        let not_yet = message(group.id, owner.id, Some(now + chrono::Duration::hours(1)), &mut conn);
+        let old = message(group.id, owner.id, None, &mut conn);
+        let old_elsewhere = message(kept_group.id, owner.id, None, &mut conn);
+        diesel::update(messages::table.filter(messages::id.eq_any([old, old_elsewhere])))
+            .set(messages::timestamp.eq(now - chrono::Duration::hours(2)))
+            .execute(&mut conn)
+            .unwrap();
+        diesel::update(groups::table.find(group.id))
+            .set(groups::retention_seconds.eq(Some(3600)))
+            .execute(&mut conn)
+            .unwrap();
+
+        let deleted = sweep_expired(&mut conn).unwrap();
+        assert!(deleted.contains(&(expired, Some(group.id))));
+        assert!(deleted.contains(&(old, Some(group.id))));
+        let left: Vec<Uuid> = messages::table
+            .filter(messages::group_id.eq_any([group.id, kept_group.id]))
+            .select(messages::id)
+            .load(&mut conn)
+            .unwrap();
+        assert_eq!(left.len(), 2);
+        assert!(left.contains(&not_yet) && left.contains(&old_elsewhere));
+    }
+}
@@ -0,0 +1,876 @@
+use crate::commands::{self, CommandContext, CommandEffect, CommandRegistry};
+use crate::db::{self, DbConnection, DbPool};
+use crate::filters::{self, Decision, FilterChain, FilterContext};
+use crate::metrics;
+use crate::moderation;
+use crate::models::{Group, Message, NewMessage};
+use crate::outbox::{Frame, Outbox};
+use crate::polls::{self, PollTally};
+use crate::pubsub::{ClusterEvent, Envelope, PubSub};
+use crate::webhooks::{self, RoomEvent};
+use crate::ws::{ChatServer, ClientFrame, ClientMessage, CloseSession, Disconnect, RoomIdle, ServerEvent};
+use actix::prelude::*;
+use actix::Message as ActixMessage;
+use chrono::NaiveDateTime;
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::oneshot;
+use tracing::Span;
+use uuid::Uuid;
+
+/// State every room actor shares, whichever arbiter it runs on.
+pub struct RoomShared {
+    pub pool: DbPool,
+    pub commands: CommandRegistry,
+    pub filters: FilterChain,
+    pub max_ttl_seconds: i64,
+    pub instance_id: Uuid,
+    pub pubsub: Box<dyn PubSub>,
+}
+
+impl RoomShared {
+    pub fn publish(&self, event: ClusterEvent) {
+        self.pubsub.publish(&Envelope {
+            origin: self.instance_id,
+            event,
+        });
+    }
+}
+
+const SHUTDOWN_REASON: &str = "Server is restarting, reconnect elsewhere";
+
+// How long a room trusts its idea of which group it belongs to
+const GROUP_REFRESH: Duration = Duration::from_secs(60);
+
+/// Checks a connection out of the pool the first time a frame needs one, so
+/// handling a frame never holds more than one.
+struct LazyConn {
+    pool: DbPool,
+    conn: Option<DbConnection>,
+    // Set once the pool has failed us; the frame goes on without a database
+    failed: bool,
+}
+
+impl LazyConn {
+    fn new(pool: &DbPool) -> Self {
+        LazyConn {
+            pool: pool.clone(),
+            conn: None,
+            failed: false,
+        }
+    }
+
+    fn get(&mut self) -> Option<&mut PgConnection> {
+        if self.conn.is_none() && !self.failed {
+            match db::connection(&self.pool) {
+                Ok(conn) => self.conn = Some(conn),
+                Err(_) => self.failed = true,
+            }
+        }
+        self.conn.as_deref_mut()
+    }
+}
+
+/// Work the registry hands to a room. The room counts these so the registry can
+/// tell whether anything is still in flight before letting an idle room stop.
+pub enum RoomOp {
+    Join {
+        id: usize,
+        outbox: Arc<Outbox>,
+        close: Recipient<CloseSession>,
+        user_id: Option<Uuid>,
+    },
+    Post(Message),
+    Deleted(Vec<Uuid>),
+    Poll { tally: PollTally, created: bool },
+    // From another instance; delivered here only
+    RemoteBroadcast(Arc<Frame>),
+    RemoteDisconnect { user_id: Uuid, reason: String },
+    // Everyone other instances have connected to this room
+    RemotePresence(HashSet<Uuid>),
+    // Re-announce local presence, e.g. for an instance that just appeared
+    PublishPresence,
+    // Forget the group's banned words so the next message loads them again
+    BannedWordsChanged,
+    // The instance is going away: close every session and answer once all of them are gone
+    Shutdown(oneshot::Sender<()>),
+    // Answered straight away with the room's current figures
+    Stats(oneshot::Sender<RoomStats>),
+}
+
+/// One room on this instance, as reported by `GET /admin/rooms`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RoomStats {
+    pub name: String,
+    pub sessions: usize,
+    // Distinct signed-in users with a session here
+    pub users: usize,
+    // Users connected to this room through other instances
+    pub remote_users: usize,
+}
+
+/// A `RoomOp` with the span it was issued under, so the room logs under the
+/// caller's correlation id.
+#[derive(ActixMessage)]
+#[rtype(result = "()")]
+pub struct Routed {
+    pub op: RoomOp,
+    pub span: Span,
+}
+
+struct Session {
+    outbox: Arc<Outbox>,
+    close: Recipient<CloseSession>,
+    user_id: Option<Uuid>,
+}
+
+/// One chat room: its sessions, presence and everything posted to it.
+/// Rooms run on the registry's arbiters, so a busy room only slows rooms
+/// that share its thread.
+pub struct Room {
+    name: String,
+    shared: Arc<RoomShared>,
+    registry: Addr<ChatServer>,
+    sessions: HashMap<usize, Session>,
+    // Open sessions per user on this instance
+    users: HashMap<Uuid, usize>,
+    remote_users: HashSet<Uuid>,
+    // The room's group, None for rooms without one, and when it was looked up
+    group: Option<(Option<Group>, Instant)>,
+    // The banned words of the group with this id, loaded on first use
+    banned_words: Option<(Uuid, HashSet<String>)>,
+    // RoomOps received so far, compared against what the registry sent
+    received: u64,
+    // Poll-close timers that still need this actor alive
+    pending_timers: usize,
+    idle_timeout: Duration,
+    idle: bool,
+    // Set while shutting down; fired once the last session has left
+    drained: Option<oneshot::Sender<()>>,
+    shutting_down: bool,
+}
+
+impl Room {
+    pub fn new(
+        name: String,
+        shared: Arc<RoomShared>,
+        registry: Addr<ChatServer>,
+        remote_users: HashSet<Uuid>,
+        idle_timeout: Duration,
+    ) -> Self {
+        Room {
+            name,
+            shared,
+            registry,
+            sessions: HashMap::new(),
+            users: HashMap::new(),
+            remote_users,
+            group: None,
+            banned_words: None,
+            received: 0,
+            pending_timers: 0,
+            idle_timeout,
+            idle: false,
+            drained: None,
+            shutting_down: false,
+        }
+    }
+
+    fn broadcast(&self, frame: Frame) {
+        metrics::BROADCASTS.inc();
+        let frame = Arc::new(frame);
+        self.deliver(&frame);
+        self.shared.publish(ClusterEvent::Broadcast {
+            room: self.name.clone(),
+            frame,
+        });
+    }
+    // Sessions on this instance only
+    fn deliver(&self, frame: &Arc<Frame>) {
+        let started = Instant::now();
+        for session in self.sessions.values() {
+            session.outbox.push(frame.clone());
+            metrics::BROADCAST_DELIVERIES.inc();
+        }
+        let elapsed = started.elapsed();
+        metrics::FANOUT_DURATION.observe(elapsed);
+        tracing::debug!(
+            room = %self.name,
+            sessions = self.sessions.len(),
+            elapsed_us = elapsed.as_micros() as u64,
+            "frame fanned out"
+        );
+    }
+    fn broadcast_event(&self, event: &ServerEvent) {
+        if let Some(frame) = event.frame() {
+            self.broadcast(frame);
+        }
+    }
+    // Reply to a single session only
+    fn send_event(&self, id: usize, event: &ServerEvent) {
+        if let (Some(session), Some(frame)) = (self.sessions.get(&id), event.frame()) {
+            session.outbox.push(Arc::new(frame));
+        }
+    }
+
+    // The group behind this room, looked up again after GROUP_REFRESH so that
+    // groups created, renamed or deleted since are noticed
+    fn group(&mut self, conn: &mut LazyConn) -> Option<Group> {
+        if let Some((group, resolved_at)) = &self.group {
+            if resolved_at.elapsed() < GROUP_REFRESH {
+                return group.clone();
+            }
+        }
+        let group = crate::schema::groups::table
+            .filter(crate::schema::groups::name.eq(&self.name))
+            .select(Group::as_select())
+            .first(conn.get()?)
+            .optional()
+            .map_err(|e| tracing::error!(error = ?e, "failed to look up the room's group"))
+            .ok()?;
+        self.group = Some((group.clone(), Instant::now()));
+        group
+    }
+
+    // Chat text is stored when both the sender and the room's group are known
+    fn persist_text(
+        &mut self,
+        msg: &ClientMessage,
+        content: &str,
+        ttl_seconds: Option<i64>,
+        conn: &mut LazyConn,
+    ) -> Option<Message> {
+        let user_id = msg.user_id?;
+        let group = self.group(conn)?;
+        let conn = conn.get()?;
+        let expires_at = ttl_seconds.map(|ttl| chrono::Utc::now().naive_utc() + chrono::Duration::seconds(ttl));
+        let stored = conn.transaction(|conn| {
+            let message = Message::create(
+                NewMessage {
+                    id: Uuid::new_v4(),
+                    group_id: Some(group.id),
+                    sender_id: Some(user_id),
+                    content: content.to_owned(),
+                    kind: "text".to_owned(),
+                    expires_at,
+                    integration_id: None,
+                    sender_name: None,
+                },
+                conn,
+            )?;
+            webhooks::enqueue(conn, group.id, RoomEvent::Message, json!(message))?;
+            QueryResult::Ok(message)
+        });
+        match stored {
+            Ok(message) => {
+                tracing::debug!(message_id = %message.id, "message stored");
+                Some(message)
+            }
+            Err(e) => {
+                tracing::error!(error = ?e, "failed to store message");
+                None
+            }
+        }
+    }
+
+    fn send_error(&self, msg: &ClientMessage, error: &str) {
+        self.send_event(msg.id, &ServerEvent::Error { error });
+    }
+
+    fn disconnect_user(&self, user_id: Uuid, reason: &str) {
+        self.close_local_sessions(user_id, reason);
+        self.shared.publish(ClusterEvent::Disconnect {
+            room: self.name.clone(),
+            user_id,
+            reason: reason.to_owned(),
+        });
+    }
+
+    fn shut_down(&mut self, drained: oneshot::Sender<()>) {
+        self.shutting_down = true;
+        self.drained = Some(drained);
+        for session in self.sessions.values() {
+            session.close.do_send(CloseSession {
+                reason: SHUTDOWN_REASON.to_owned(),
+                restart: true,
+            });
+        }
+        self.check_drained();
+    }
+
+    // A session's Disconnect comes after every frame it sent, so once all sessions
+    // have left, everything they posted has been stored
+    fn check_drained(&mut self) {
+        if self.sessions.is_empty() {
+            if let Some(drained) = self.drained.take() {
+                let _ = drained.send(());
+            }
+        }
+    }
+
+    fn close_local_sessions(&self, user_id: Uuid, reason: &str) {
+        for session in self.sessions.values().filter(|session| session.user_id == Some(user_id)) {
+            session.close.do_send(CloseSession {
+                reason: reason.to_owned(),
+                restart: false,
+            });
+        }
+    }
+
+    fn presence(&self) -> Vec<Uuid> {
+        let mut users: Vec<Uuid> = self.users.keys().chain(&self.remote_users).copied().collect();
+        users.sort();
+        users.dedup();
+        users
+    }
+
+    fn deliver_presence(&self) {
+        let user_ids = self.presence();
+        if let Some(frame) = (ServerEvent::Presence { user_ids: &user_ids }).frame() {
+            self.deliver(&Arc::new(frame));
+        }
+    }
+
+    fn publish_presence(&self) {
+        self.shared.publish(ClusterEvent::Presence {
+            room: self.name.clone(),
+            user_ids: self.users.keys().copied().collect(),
+        });
+    }
+
+    // Called whenever this instance gains or loses the last session of a user
+    fn presence_changed(&self) {
+        self.deliver_presence();
+        self.publish_presence();
+    }
+
+    fn join(
+        &mut self,
+        id: usize,
+        outbox: Arc<Outbox>,
+        close: Recipient<CloseSession>,
+        user_id: Option<Uuid>,
+    ) {
+        self.idle = false;
+        if self.shutting_down {
+            close.do_send(CloseSession {
+                reason: SHUTDOWN_REASON.to_owned(),
+                restart: true,
+            });
+        }
+        self.sessions.insert(id, Session { outbox, close, user_id });
+        let Some(user_id) = user_id else { return };
+        let open = self.users.entry(user_id).or_default();
+        *open += 1;
+        if *open == 1 {
+            self.presence_changed();
+        } else {
+            let user_ids = self.presence();
+            self.send_event(id, &ServerEvent::Presence { user_ids: &user_ids });
+        }
+    }
+
+    // Runs `/name args` through the command registry instead of broadcasting it
+    fn run_command(&mut self, msg: &ClientMessage, line: &str, conn: &mut LazyConn) {
+        let Some(sender) = msg.user_id else {
+            return self.send_error(msg, "Sign in to use commands");
+        };
+        let group = self.group(conn);
+        let Some(conn) = conn.get() else {
+            return self.send_error(msg, "Database unavailable");
+        };
+        let mut ctx = CommandContext {
+            sender,
+            room: &self.name,
+            group: group.as_ref(),
+            conn,
+            registry: &self.shared.commands,
+        };
+        match self.shared.commands.dispatch(&mut ctx, line) {
+            Ok(effects) => {
+                for effect in effects {
+                    match effect {
+                        CommandEffect::Reply(text) => self.send_event(msg.id, &ServerEvent::Notice { text: &text }),
+                        CommandEffect::Post(text) => self.broadcast_event(&ServerEvent::Notice { text: &text }),
+                        CommandEffect::Disconnect { user_id, reason } => self.disconnect_user(user_id, &reason),
+                    }
+                }
+            }
+            Err(error) => self.send_error(msg, &error),
+        }
+    }
+
+    // Why the sender may not post here right now, if they may not
+    fn silenced(&mut self, msg: &ClientMessage, conn: &mut LazyConn) -> Option<&'static str> {
+        let user_id = msg.user_id?;
+        let group = self.group(conn)?;
+        let conn = conn.get()?;
+        if commands::is_muted(user_id, group.id, conn).unwrap_or(false) {
+            return Some("You are muted in this group");
+        }
+        // A ban can land while the session is still open
+        if commands::is_banned(user_id, group.id, conn).unwrap_or(false) {
+            return Some("You are banned from this group");
+        }
+        None
+    }
+
+    // Returns the text to post, or None when a command, mute or ban consumed it
+    fn accept_text<'a>(&mut self, msg: &ClientMessage, content: &'a str, conn: &mut LazyConn) -> Option<&'a str> {
+        let content = match content.strip_prefix('/') {
+            // "//text" escapes a message that should start with a slash
+            Some(escaped) if escaped.starts_with('/') => escaped,
+            Some(line) => {
+                self.run_command(msg, line, conn);
+                return None;
+            }
+            None => content,
+        };
+        if let Some(error) = self.silenced(msg, conn) {
+            self.send_error(msg, error);
+            return None;
+        }
+        Some(content)
+    }
+
+    // Loads the group's banned words unless the room already has them
+    fn load_banned_words(&mut self, group: &Group, conn: &mut LazyConn) {
+        if self.banned_words.as_ref().is_some_and(|(group_id, _)| *group_id == group.id) {
+            return;
+        }
+        let Some(conn) = conn.get() else { return };
+        match filters::banned_words(group.id, conn) {
+            Ok(words) => self.banned_words = Some((group.id, words.into_iter().collect())),
+            Err(e) => tracing::error!(error = ?e, "failed to load banned words"),
+        }
+    }
+
+    // Returns the text to post, or None when a filter held or rejected it
+    fn filter_text(&mut self, msg: &ClientMessage, content: &str, conn: &mut LazyConn) -> Option<String> {
+        if self.shared.filters.is_empty() {
+            return Some(content.to_owned());
+        }
+        let group = self.group(conn);
+        if let Some(group) = &group {
+            self.load_banned_words(group, conn);
+        }
+        let mut ctx = FilterContext {
+            sender: msg.user_id,
+            group: group.as_ref(),
+            conn: conn.get(),
+            banned_words: self.banned_words.as_ref().map(|(_, words)| words),
+        };
+        match self.shared.filters.run(&mut ctx, content) {
+            Decision::Post { content, notices } => {
+                for text in &notices {
+                    self.send_event(msg.id, &ServerEvent::Notice { text });
+                }
+                Some(content)
+            }
+            Decision::Hold { content, reason } => {
+                // Only a known sender in a group room has a queue to wait in
+                let held = match (msg.user_id, &group, conn.get()) {
+                    (Some(sender), Some(group), Some(conn)) => {
+                        moderation::hold_message(group.id, sender, &content, &reason, conn)
+                            .map_err(|e| tracing::error!(error = ?e, "failed to hold message"))
+                            .ok()
+                    }
+                    _ => None,
+                };
+                match held {
+                    Some(report) => {
+                        tracing::debug!(report_id = %report.id, "message queued for review");
+                        let text = format!("Your message is waiting for a moderator: {}", reason);
+                        self.send_event(msg.id, &ServerEvent::Notice { text: &text });
+                    }
+                    None => self.send_error(msg, &reason),
+                }
+                None
+            }
+            Decision::Reject(reason) => {
+                self.send_error(msg, &reason);
+                None
+            }
+        }
+    }
+
+    fn send_text(&mut self, msg: &ClientMessage, content: &str, ttl_seconds: Option<i64>, conn: &mut LazyConn) {
+        let Some(content) = self.accept_text(msg, content, conn) else { return };
+        let max_ttl_seconds = self.shared.max_ttl_seconds;
+        if ttl_seconds.is_some_and(|ttl| !(1..=max_ttl_seconds).contains(&ttl)) {
+            let error = format!("ttl_seconds must be between 1 and {}", max_ttl_seconds);
+            return self.send_error(msg, &error);
+        }
+        // Only stored messages can expire, so anything else would outlive its ttl
+        if ttl_seconds.is_some() && msg.user_id.is_none() {
+            return self.send_error(msg, "Sign in to send self-destructing messages");
+        }
+        let Some(content) = self.filter_text(msg, content, conn) else { return };
+        match self.persist_text(msg, &content, ttl_seconds, conn) {
+            Some(message) => self.broadcast_event(&ServerEvent::Message { message: &message }),
+            None if ttl_seconds.is_some() => {
+                self.send_error(msg, "Self-destructing messages can only be sent in group rooms")
+            }
+            None => self.broadcast(Frame::text(content)),
+        }
+    }
+
+    // Polls need a known sender and a database connection
+    fn with_poll_conn<T>(
+        &self,
+        msg: &ClientMessage,
+        conn: &mut LazyConn,
+        f: impl FnOnce(Uuid, &mut PgConnection) -> Result<T, polls::PollError>,
+    ) -> Result<T, polls::PollError> {
+        let user_id = msg.user_id.ok_or(polls::PollError::Invalid("Sign in to use polls"))?;
+        let conn = conn.get().ok_or(polls::PollError::Invalid("Database unavailable"))?;
+        f(user_id, conn)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn create_poll(
+        &mut self,
+        msg: &ClientMessage,
+        question: String,
+        options: Vec<String>,
+        multiple_choice: bool,
+        anonymous: bool,
+        closes_at: Option<NaiveDateTime>,
+        conn: &mut LazyConn,
+    ) -> Result<PollTally, polls::PollError> {
+        let group = self.group(conn);
+        self.with_poll_conn(msg, conn, |user_id, conn| {
+            let group = group.ok_or(polls::PollError::Invalid("Polls are only available in group rooms"))?;
+            let req = polls::CreatePollRequest {
+                group_id: group.id,
+                question,
+                options,
+                multiple_choice,
+                anonymous,
+                closes_at,
+            };
+            polls::create_poll(&req, user_id, conn)
+        })
+    }
+
+    fn handle_frame(&mut self, msg: &ClientMessage, frame: ClientFrame, conn: &mut LazyConn, ctx: &mut Context<Self>) {
+        let result = match frame {
+            ClientFrame::Message { content, ttl_seconds } => return self.send_text(msg, &content, ttl_seconds, conn),
+            ClientFrame::CreatePoll {
+                question,
+                options,
+                multiple_choice,
+                anonymous,
+                closes_at,
+            } => self
+                .create_poll(msg, question, options, multiple_choice, anonymous, closes_at, conn)
+                .map(|tally| (tally, true)),
+            ClientFrame::Vote { poll_id, option_ids } => self.with_poll_conn(msg, conn, |user_id, conn| {
+                polls::cast_vote(poll_id, user_id, &option_ids, conn).map(|tally| (tally, false))
+            }),
+        };
+        match result {
+            Ok((tally, created)) => self.publish_poll(&tally, created, ctx),
+            Err(e) => {
+                let error = e.message();
+                self.send_event(msg.id, &ServerEvent::Error { error: &error });
+            }
+        }
+    }
+
+    fn publish_poll(&mut self, tally: &PollTally, created: bool, ctx: &mut Context<Self>) {
+        if !created {
+            return self.broadcast_event(&ServerEvent::PollTally { poll: tally });
+        }
+        self.broadcast_event(&ServerEvent::Poll { poll: tally });
+        // Push the final tally once the poll closes
+        if let Some(closes_at) = tally.closes_at {
+            let delay = (closes_at - chrono::Utc::now().naive_utc())
+                .to_std()
+                .unwrap_or_default();
+            let poll_id = tally.poll_id;
+            self.pending_timers += 1;
+            ctx.run_later(delay, move |act, _| {
+                act.pending_timers -= 1;
+                let Ok(mut conn) = db::connection(&act.shared.pool) else { return };
+                if let Ok(tally) = polls::tally(poll_id, &mut conn) {
+                    act.broadcast_event(&ServerEvent::PollTally { poll: &tally });
+                }
+            });
+        }
+    }
+
+    // A room stops once it has had no sessions and no timers for a whole idle period
+    fn check_idle(&mut self, ctx: &mut Context<Self>) {
+        if !self.sessions.is_empty() || self.pending_timers > 0 {
+            self.idle = false;
+            return;
+        }
+        if !self.idle {
+            self.idle = true;
+            return;
+        }
+        self.registry
+            .send(RoomIdle {
+                room: self.name.clone(),
+                received: self.received,
+            })
+            .into_actor(self)
+            .then(|res, _, ctx| {
+                if let Ok(true) = res {
+                    ctx.stop();
+                }
+                actix::fut::ready(())
+            })
+            // Nothing else is handled until the registry has answered
+            .wait(ctx);
+    }
+}
+
+impl Actor for Room {
+    type Context = Context<Self>;
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_interval(self.idle_timeout, |act, ctx| act.check_idle(ctx));
+    }
+}
+
+impl Handler<Routed> for Room {
+    type Result = ();
+    fn handle(&mut self, msg: Routed, ctx: &mut Context<Self>) {
+        self.received += 1;
+        let _entered = msg.span.enter();
+        match msg.op {
+            RoomOp::Join {
+                id,
+                outbox,
+                close,
+                user_id,
+            } => self.join(id, outbox, close, user_id),
+            RoomOp::Post(message) => self.broadcast_event(&ServerEvent::Message { message: &message }),
+            RoomOp::Deleted(message_ids) => {
+                self.broadcast_event(&ServerEvent::MessagesDeleted { message_ids: &message_ids })
+            }
+            RoomOp::Poll { tally, created } => self.publish_poll(&tally, created, ctx),
+            RoomOp::RemoteBroadcast(frame) => {
+                metrics::BROADCASTS.inc();
+                self.deliver(&frame);
+            }
+            RoomOp::RemoteDisconnect { user_id, reason } => self.close_local_sessions(user_id, &reason),
+            RoomOp::RemotePresence(user_ids) => {
+                if user_ids != self.remote_users {
+                    self.remote_users = user_ids;
+                    self.deliver_presence();
+                }
+            }
+            RoomOp::BannedWordsChanged => self.banned_words = None,
+            RoomOp::PublishPresence => {
+                if !self.users.is_empty() {
+                    self.publish_presence();
+                }
+            }
+            RoomOp::Shutdown(drained) => self.shut_down(drained),
+            RoomOp::Stats(reply) => {
+                let _ = reply.send(RoomStats {
+                    name: self.name.clone(),
+                    sessions: self.sessions.len(),
+                    users: self.users.len(),
+                    remote_users: self.remote_users.len(),
+                });
+            }
+        }
+    }
+}
+
+impl Handler<ClientMessage> for Room {
+    type Result = ();
+    fn handle(&mut self, msg: ClientMessage, ctx: &mut Context<Self>) {
+        let span = msg.span.clone();
+        let _entered = span.enter();
+        tracing::debug!(bytes = msg.message.len(), "frame received");
+        // Everything this frame needs from the database goes over one connection
+        let mut conn = LazyConn::new(&self.shared.pool);
+        match serde_json::from_str::<ClientFrame>(&msg.message) {
+            Ok(frame) => self.handle_frame(&msg, frame, &mut conn, ctx),
+            Err(_) => {
+                let Some(content) = self.accept_text(&msg, &msg.message, &mut conn) else { return };
+                let Some(content) = self.filter_text(&msg, content, &mut conn) else { return };
+                self.persist_text(&msg, &content, None, &mut conn);
+                self.broadcast(Frame::text(content));
+            }
+        }
+    }
+}
+
+impl Handler<Disconnect> for Room {
+    type Result = ();
+    fn handle(&mut self, msg: Disconnect, _: &mut Context<Self>) {
+        let user_id = self.sessions.remove(&msg.id).and_then(|session| session.user_id);
+        if let Some(user_id) = user_id {
+            if let Some(open) = self.users.get_mut(&user_id) {
+                *open -= 1;
+                if *open == 0 {
+                    self.users.remove(&user_id);
+                    self.presence_changed();
+                }
+            }
+        }
+        self.check_drained();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{self, TestSession};
+
+    fn stored_messages(group_id: Uuid, conn: &mut PgConnection) -> Vec<String> {
+        use crate::schema::messages;
+        messages::table
+            .filter(messages::group_id.eq(group_id))
+            .order(messages::timestamp.asc())
+            .select(messages::content)
+            .load(conn)
+            .unwrap()
+    }
+
+    #[actix_web::test]
+    async fn banned_members_cannot_post_from_an_open_session() {
+        let Some(pool) = test_support::pool() else { return };
+        let (member, group) = {
+            let mut conn = pool.get().unwrap();
+            let owner = test_support::user(&mut conn);
+            let member = test_support::user(&mut conn);
+            let group = test_support::group(owner.id, &mut conn);
+            test_support::join(member.id, group.id, "member", &mut conn);
+            (member, group)
+        };
+        let server = test_support::chat_server(pool.clone(), FilterChain::default());
+        let session = TestSession::connect(&server, &group.name, Some(member.id)).await;
+        session.send("before the ban");
+        session.frames().await;
+
+        test_support::ban(member.id, group.id, &mut pool.get().unwrap());
+        session.send("after the ban");
+        let frames = session.frames().await;
+        assert_eq!(frames.last().unwrap()["error"], "You are banned from this group");
+        assert_eq!(stored_messages(group.id, &mut pool.get().unwrap()), ["before the ban"]);
+    }
+
+    #[actix_web::test]
+    async fn frames_are_handled_over_a_single_connection() {
+        // The test pool holds one connection, so a frame that checked out a
+        // second one would wait on itself
+        let Some(pool) = test_support::pool() else { return };
+        let (member, group) = {
+            let mut conn = pool.get().unwrap();
+            let owner = test_support::user(&mut conn);
+            let member = test_support::user(&mut conn);
+            let group = test_support::group(owner.id, &mut conn);
+            test_support::join(member.id, group.id, "member", &mut conn);
+            (member, group)
+        };
+        let server = test_support::chat_server(pool.clone(), FilterChain::default());
+        let session = TestSession::connect(&server, &group.name, Some(member.id)).await;
+        session.frames().await;
+
+        session.send("/me waves");
+        session.send(r#"{"type":"create_poll","question":"Lunch?","options":["Yes","No"]}"#);
+        let frames = session.frames().await;
+        assert!(frames[0]["text"].as_str().unwrap().ends_with("waves"));
+        let poll = &frames[1]["poll"];
+        assert_eq!(frames[1]["type"], "poll");
+
+        let vote = json!({"type": "vote", "poll_id": poll["poll_id"], "option_ids": [poll["options"][0]["id"]]});
+        session.send(&vote.to_string());
+        session.send(r#"{"type":"message","content":"hello"}"#);
+        let frames = session.frames().await;
+        assert_eq!(frames[0]["poll"]["total_voters"], 1);
+        assert_eq!(frames[1]["message"]["content"], "hello");
+        assert_eq!(stored_messages(group.id, &mut pool.get().unwrap()), ["Lunch?", "hello"]);
+    }
+
+    fn set_banned_words(group_id: Uuid, words: &[&str], conn: &mut PgConnection) {
+        use crate::schema::group_banned_words;
+        diesel::delete(group_banned_words::table.filter(group_banned_words::group_id.eq(group_id)))
+            .execute(conn)
+            .unwrap();
+        let rows: Vec<_> = words
+            .iter()
+            .map(|word| (group_banned_words::group_id.eq(group_id), group_banned_words::word.eq(*word)))
+            .collect();
+        diesel::insert_into(group_banned_words::table).values(&rows).execute(conn).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn plain_text_frames_go_through_the_filters() {
+        let Some(pool) = test_support::pool() else { return };
+        let group = {
+            let mut conn = pool.get().unwrap();
+            let owner = test_support::user(&mut conn);
+            let group = test_support::group(owner.id, &mut conn);
+            set_banned_words(group.id, &["darn"], &mut conn);
+            group
+        };
+        let filters = FilterChain::from_settings(&crate::settings::FilterSettings::default());
+        let server = test_support::chat_server(pool.clone(), filters);
+        let session = TestSession::connect(&server, &group.name, Some(group.owner)).await;
+        session.frames().await;
+
+        session.send("well Darn it");
+        let frames = session.frames().await;
+        assert!(frames.iter().any(|frame| frame["type"] == "notice"));
+        assert_eq!(frames.last().unwrap(), "well **** it");
+        assert_eq!(stored_messages(group.id, &mut pool.get().unwrap()), ["well **** it"]);
+    }
+
+    #[actix_web::test]
+    async fn rooms_reload_banned_words_once_told_they_changed() {
+        let Some(pool) = test_support::pool() else { return };
+        let group = {
+            let mut conn = pool.get().unwrap();
+            let owner = test_support::user(&mut conn);
+            test_support::group(owner.id, &mut conn)
+        };
+        let filters = FilterChain::from_settings(&crate::settings::FilterSettings::default());
+        let server = test_support::chat_server(pool.clone(), filters);
+        let session = TestSession::connect(&server, &group.name, Some(group.owner)).await;
+        session.send("heck");
+        session.frames().await;
+
+        // The room still has the empty list it loaded for the first message
+        set_banned_words(group.id, &["heck"], &mut pool.get().unwrap());
+        session.send("heck again");
+        assert_eq!(session.frames().await.last().unwrap(), "heck again");
+
+        // Once the server has forwarded the change it is queued ahead of the next frame
+        let changed = crate::ws::BannedWordsChanged { room: group.name.clone() };
+        server.send(changed).await.unwrap();
+        session.send("heck once more");
+        assert_eq!(session.frames().await.last().unwrap(), "**** once more");
+    }
+
+    #[actix_web::test]
+    async fn self_destructing_messages_are_refused_when_they_cannot_be_stored() {
+        let Some(pool) = test_support::pool() else { return };
+        let user = test_support::user(&mut pool.get().unwrap());
+        let server = test_support::chat_server(pool.clone(), FilterChain::default());
+        let frame = r#"{"type":"message","content":"gone soon","ttl_seconds":60}"#;
+
+        let anonymous = TestSession::connect(&server, "lobby", None).await;
+        anonymous.send(frame);
+        let frames = anonymous.frames().await;
+        assert_eq!(frames.last().unwrap()["error"], "Sign in to send self-destructing messages");
+
+        // No group backs this room, so there is nowhere to store the message
+        let member = TestSession::connect(&server, "lobby", Some(user.id)).await;
+        member.send(frame);
+        let frames = member.frames().await;
+        assert_eq!(frames.last().unwrap()["error"], "Self-destructing messages can only be sent in group rooms");
+        let seen = anonymous.frames().await;
+        assert!(!seen.iter().any(|frame| frame.to_string().contains("gone soon")));
+    }
+}
@@ -1,12 +1,81 @@
 // @generated automatically by Diesel CLI.
 
+diesel::table! {
+    audit_log (id) {
+        id -> Int8,
+        actor_id -> Nullable<Uuid>,
+        action -> Text,
+        group_id -> Nullable<Uuid>,
+        target_type -> Text,
+        target_id -> Nullable<Uuid>,
+        ip -> Nullable<Text>,
+        before -> Nullable<Jsonb>,
+        after -> Nullable<Jsonb>,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    bot_api_keys (id) {
+        id -> Uuid,
+        bot_id -> Uuid,
+        name -> Text,
+        key_prefix -> Text,
+        key_hash -> Text,
+        created_at -> Timestamp,
+        last_used_at -> Nullable<Timestamp>,
+        revoked_at -> Nullable<Timestamp>,
+    }
+}
+
+diesel::table! {
+    group_bans (group_id, user_id) {
+        group_id -> Uuid,
+        user_id -> Uuid,
+        banned_by -> Nullable<Uuid>,
+        reason -> Nullable<Text>,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    group_banned_words (group_id, word) {
+        group_id -> Uuid,
+        word -> Text,
+    }
+}
+
+diesel::table! {
+    group_mutes (group_id, user_id) {
+        group_id -> Uuid,
+        user_id -> Uuid,
+        muted_by -> Nullable<Uuid>,
+        muted_until -> Nullable<Timestamp>,
+        created_at -> Timestamp,
+    }
+}
+
 diesel::table! {
     groups (id) {
         id -> Uuid,
         name -> Text,
         description -> Nullable<Text>,
         owner -> Uuid,
-        members -> Jsonb,
+        retention_seconds -> Nullable<Int8>,
+    }
+}
+
+diesel::table! {
+    incoming_webhooks (id) {
+        id -> Uuid,
+        group_id -> Uuid,
+        name -> Text,
+        token_hash -> Text,
+        created_by -> Nullable<Uuid>,
+        created_at -> Timestamp,
+        rotated_at -> Nullable<Timestamp>,
+        last_used_at -> Nullable<Timestamp>,
+        revoked_at -> Nullable<Timestamp>,
     }
 }
 
@@ -17,6 +86,82 @@ diesel::table! {
         sender_id -> Nullable<Uuid>,
         content -> Text,
         timestamp -> Nullable<Timestamp>,
+        kind -> Text,
+        expires_at -> Nullable<Timestamp>,
+        integration_id -> Nullable<Uuid>,
+        sender_name -> Nullable<Text>,
+    }
+}
+
+diesel::table! {
+    password_reset_tokens (id) {
+        id -> Uuid,
+        user_id -> Uuid,
+        token_hash -> Text,
+        created_at -> Timestamp,
+        expires_at -> Timestamp,
+        used_at -> Nullable<Timestamp>,
+        requested_ip -> Nullable<Text>,
+    }
+}
+
+diesel::table! {
+    poll_options (id) {
+        id -> Uuid,
+        poll_id -> Uuid,
+        position -> Int4,
+        label -> Text,
+    }
+}
+
+diesel::table! {
+    poll_votes (poll_id, option_id, user_id) {
+        poll_id -> Uuid,
+        option_id -> Uuid,
+        user_id -> Uuid,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    polls (id) {
+        id -> Uuid,
+        group_id -> Uuid,
+        creator_id -> Uuid,
+        question -> Text,
+        multiple_choice -> Bool,
+        anonymous -> Bool,
+        closes_at -> Nullable<Timestamp>,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    pubsub_payloads (id) {
+        id -> Int8,
+        payload -> Text,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    reports (id) {
+        id -> Uuid,
+        reporter_id -> Nullable<Uuid>,
+        group_id -> Nullable<Uuid>,
+        message_id -> Nullable<Uuid>,
+        reported_user_id -> Nullable<Uuid>,
+        reason -> Text,
+        content_snapshot -> Nullable<Text>,
+        status -> Text,
+        created_at -> Timestamp,
+        claimed_by -> Nullable<Uuid>,
+        claimed_at -> Nullable<Timestamp>,
+        resolved_by -> Nullable<Uuid>,
+        resolved_at -> Nullable<Timestamp>,
+        outcome -> Nullable<Text>,
+        resolution_note -> Nullable<Text>,
+        kind -> Text,
     }
 }
 
@@ -24,6 +169,8 @@ diesel::table! {
     user_groups (user_id, group_id) {
         user_id -> Uuid,
         group_id -> Uuid,
+        joined_at -> Timestamp,
+        role -> Text,
     }
 }
 
@@ -33,17 +180,85 @@ diesel::table! {
         username -> Text,
         email -> Text,
         password_hash -> Text,
+        is_bot -> Bool,
+        owner_id -> Nullable<Uuid>,
+        disabled_at -> Nullable<Timestamp>,
+        is_admin -> Bool,
+        email_verified_at -> Nullable<Timestamp>,
+        session_version -> Int4,
+    }
+}
+
+diesel::table! {
+    webhook_deliveries (id) {
+        id -> Uuid,
+        webhook_id -> Uuid,
+        event -> Text,
+        payload -> Jsonb,
+        status -> Text,
+        attempts -> Int4,
+        next_attempt_at -> Timestamp,
+        last_status_code -> Nullable<Int4>,
+        last_error -> Nullable<Text>,
+        created_at -> Timestamp,
+        delivered_at -> Nullable<Timestamp>,
+    }
+}
+
+diesel::table! {
+    webhooks (id) {
+        id -> Uuid,
+        group_id -> Uuid,
+        url -> Text,
+        secret -> Text,
+        events -> Array<Text>,
+        created_by -> Nullable<Uuid>,
+        created_at -> Timestamp,
     }
 }
 
+diesel::joinable!(bot_api_keys -> users (bot_id));
+diesel::joinable!(group_banned_words -> groups (group_id));
+diesel::joinable!(group_bans -> groups (group_id));
+diesel::joinable!(group_mutes -> groups (group_id));
+diesel::joinable!(incoming_webhooks -> groups (group_id));
+diesel::joinable!(incoming_webhooks -> users (created_by));
 diesel::joinable!(messages -> groups (group_id));
+diesel::joinable!(messages -> incoming_webhooks (integration_id));
 diesel::joinable!(messages -> users (sender_id));
+diesel::joinable!(password_reset_tokens -> users (user_id));
+diesel::joinable!(poll_options -> polls (poll_id));
+diesel::joinable!(poll_votes -> poll_options (option_id));
+diesel::joinable!(poll_votes -> polls (poll_id));
+diesel::joinable!(poll_votes -> users (user_id));
+diesel::joinable!(polls -> groups (group_id));
+diesel::joinable!(polls -> messages (id));
+diesel::joinable!(polls -> users (creator_id));
+diesel::joinable!(reports -> groups (group_id));
+diesel::joinable!(reports -> messages (message_id));
 diesel::joinable!(user_groups -> groups (group_id));
 diesel::joinable!(user_groups -> users (user_id));
+diesel::joinable!(webhook_deliveries -> webhooks (webhook_id));
+diesel::joinable!(webhooks -> groups (group_id));
+diesel::joinable!(webhooks -> users (created_by));
 
 diesel::allow_tables_to_appear_in_same_query!(
+    audit_log,
+    bot_api_keys,
+    group_banned_words,
+    group_bans,
+    group_mutes,
     groups,
+    incoming_webhooks,
     messages,
+    password_reset_tokens,
+    poll_options,
+    poll_votes,
+    polls,
+    pubsub_payloads,
+    reports,
     user_groups,
     users,
+    webhook_deliveries,
+    webhooks,
 );
@@ -0,0 +1,163 @@
+use crate::bots;
+use crate::db::{self, DbPool};
+use crate::errors::{ApiError, ApiResult};
+use crate::models::User;
+use crate::settings::AuthSettings;
+use actix_web::{web, HttpRequest};
+use diesel::prelude::*;
+use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+// Keeps a session token from being accepted anywhere else jwt_secret signs tokens
+const PURPOSE: &str = "session";
+
+#[derive(Serialize, Deserialize)]
+struct Claims {
+    sub: Uuid,
+    // The account's session_version when the token was issued
+    ver: i32,
+    purpose: String,
+    exp: i64,
+}
+
+/// Signs and checks the session tokens `/login` hands out.
+pub struct SessionSigner {
+    secret: Vec<u8>,
+    ttl: chrono::Duration,
+}
+
+impl SessionSigner {
+    pub fn new(settings: &AuthSettings) -> Self {
+        let secret = match &settings.jwt_secret {
+            Some(secret) => secret.as_bytes().to_vec(),
+            None => {
+                tracing::warn!(
+                    "auth.jwt_secret is not set; sessions only work on this instance until it restarts"
+                );
+                let mut secret = vec![0u8; 32];
+                rand::thread_rng().fill_bytes(&mut secret);
+                secret
+            }
+        };
+        SessionSigner {
+            secret,
+            ttl: chrono::Duration::seconds(settings.session_ttl_secs as i64),
+        }
+    }
+
+    pub fn issue(&self, user_id: Uuid, session_version: i32) -> ApiResult<String> {
+        let claims = Claims {
+            sub: user_id,
+            ver: session_version,
+            purpose: PURPOSE.to_owned(),
+            exp: (chrono::Utc::now() + self.ttl).timestamp(),
+        };
+        encode(&Header::default(), &claims, &EncodingKey::from_secret(&self.secret))
+            .map_err(|e| ApiError::Internal(format!("signing session token: {}", e)))
+    }
+
+    // None for forged, expired and foreign tokens alike
+    fn check(&self, token: &str) -> Option<Claims> {
+        let claims = decode::<Claims>(token, &DecodingKey::from_secret(&self.secret), &Validation::default())
+            .ok()?
+            .claims;
+        (claims.purpose == PURPOSE).then_some(claims)
+    }
+
+    /// The account `token` was issued to, while it is still enabled and has not
+    /// been signed out everywhere since.
+    pub fn authenticate(&self, token: &str, conn: &mut PgConnection) -> ApiResult<User> {
+        let claims = self.check(token).ok_or(ApiError::Unauthorized("Invalid or expired session"))?;
+        let user = crate::schema::users::table
+            .find(claims.sub)
+            .first::<User>(conn)
+            .optional()?
+            .filter(|user| user.session_version == claims.ver)
+            .ok_or(ApiError::Unauthorized("Invalid or expired session"))?;
+        if user.is_disabled() {
+            return Err(ApiError::Forbidden("This account has been disabled"));
+        }
+        Ok(user)
+    }
+}
+
+/// The signed-in user behind `Authorization: Bearer <session token>`.
+pub async fn current_user(req: &HttpRequest, pool: &DbPool) -> ApiResult<User> {
+    let token = bots::bearer_token(req)
+        .ok_or(ApiError::Unauthorized("Sign in first"))?
+        .to_owned();
+    let signer = req
+        .app_data::<web::Data<SessionSigner>>()
+        .ok_or_else(|| ApiError::Internal("no session signer registered".to_owned()))?
+        .clone();
+    db::run(pool, move |conn| signer.authenticate(&token, conn)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+
+    fn signer(secret: &str, ttl_secs: i64) -> SessionSigner {
+        SessionSigner {
+            secret: secret.as_bytes().to_vec(),
+            ttl: chrono::Duration::seconds(ttl_secs),
+        }
+    }
+
+    #[test]
+    fn issued_tokens_check_out() {
+        let signer = signer("a-secret-of-at-least-thirty-two-chars", 60);
+        let user_id = Uuid::new_v4();
+        let claims = signer.check(&signer.issue(user_id, 0).unwrap()).unwrap();
+        assert_eq!(claims.sub, user_id);
+    }
+
+    #[test]
+    fn rejects_expired_forged_and_foreign_tokens() {
+        let signer = signer("a-secret-of-at-least-thirty-two-chars", 60);
+        // Past the validator's leeway
+        let expired = self::signer("a-secret-of-at-least-thirty-two-chars", -120);
+        assert!(signer.check(&expired.issue(Uuid::new_v4(), 0).unwrap()).is_none());
+        let forged = self::signer("some-other-secret-of-thirty-two-chars", 60);
+        assert!(signer.check(&forged.issue(Uuid::new_v4(), 0).unwrap()).is_none());
+        let foreign = Claims {
+            sub: Uuid::new_v4(),
+            ver: 0,
+            purpose: "verify_email".to_owned(),
+            exp: (chrono::Utc::now() + chrono::Duration::hours(1)).timestamp(),
+        };
+        let token = encode(&Header::default(), &foreign, &EncodingKey::from_secret(&signer.secret)).unwrap();
+        assert!(signer.check(&token).is_none());
+        assert!(signer.check("not-a-token").is_none());
+    }
+
+    #[test]
+    fn disabled_accounts_cannot_use_their_sessions() {
+        let Some(mut conn) = test_support::connection() else { return };
+        let signer = signer("a-secret-of-at-least-thirty-two-chars", 60);
+        let user = test_support::user(&mut conn);
+        let token = signer.issue(user.id, 0).unwrap();
+        assert_eq!(signer.authenticate(&token, &mut conn).unwrap().id, user.id);
+
+        crate::admin::set_disabled(user.id, true, &mut conn).unwrap();
+        assert!(matches!(signer.authenticate(&token, &mut conn), Err(ApiError::Forbidden(_))));
+        let unknown = signer.issue(Uuid::new_v4(), 0).unwrap();
+        assert!(matches!(signer.authenticate(&unknown, &mut conn), Err(ApiError::Unauthorized(_))));
+    }
+
+    #[test]
+    fn password_changes_sign_the_account_out_everywhere() {
+        let Some(mut conn) = test_support::connection() else { return };
+        let signer = signer("a-secret-of-at-least-thirty-two-chars", 60);
+        let user = test_support::user(&mut conn);
+        let before = signer.issue(user.id, user.session_version).unwrap();
+        crate::admin::set_password(user.id, "-", &mut conn).unwrap();
+        assert!(matches!(signer.authenticate(&before, &mut conn), Err(ApiError::Unauthorized(_))));
+
+        let user = signer.authenticate(&signer.issue(user.id, user.session_version + 1).unwrap(), &mut conn);
+        assert!(user.is_ok());
+    }
+}
@@ -0,0 +1,764 @@
+use config::{Config, Environment, File};
+use serde::Deserialize;
+use std::fmt;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+// Used when NEUROCHAT_CONFIG is not set; a missing default file is not an error
+const DEFAULT_CONFIG_FILE: &str = "neurochat.toml";
+
+/// Typed server configuration.
+///
+/// Values come from the built-in defaults, then the TOML file named by
+/// `NEUROCHAT_CONFIG` (or `neurochat.toml`), then environment variables such as
+/// `NEUROCHAT_SERVER__BIND=0.0.0.0:8080` or
+/// `NEUROCHAT_SERVER__ALLOWED_ORIGINS=https://a.example,https://b.example`.
+/// `DATABASE_URL` is still honoured for the database URL.
+#[derive(Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct Settings {
+    pub server: ServerSettings,
+    pub database: DatabaseSettings,
+    pub auth: AuthSettings,
+    pub limits: LimitSettings,
+    pub websocket: WebSocketSettings,
+    pub retention: RetentionSettings,
+    pub webhooks: WebhookSettings,
+    pub pubsub: PubSubSettings,
+    pub rate_limit: RateLimitSettings,
+    pub logging: LoggingSettings,
+    pub admin: AdminSettings,
+    pub filters: FilterSettings,
+    pub mail: MailSettings,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct ServerSettings {
+    pub bind: Vec<String>,
+    // None lets actix pick one worker per core
+    pub workers: Option<usize>,
+    pub allowed_origins: Vec<String>,
+    // How long a SIGTERM waits for sessions to close and requests to finish
+    pub shutdown_timeout_secs: u64,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct DatabaseSettings {
+    pub url: String,
+    pub pool_max_size: u32,
+    pub pool_min_idle: Option<u32>,
+    // How long a handler waits for a free connection before giving up with a 503
+    pub connection_timeout_secs: u64,
+    // 0 keeps idle connections open forever
+    pub idle_timeout_secs: u64,
+    // Apply pending embedded migrations before serving
+    pub run_migrations: bool,
+    // Refuse to start when the live tables differ from schema.rs
+    pub check_schema: bool,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct AuthSettings {
+    // Signs session tokens and email verification links; when unset a random one is
+    // made at startup, so both stop working after a restart
+    pub jwt_secret: Option<String>,
+    pub bcrypt_cost: u32,
+    // How long a session token from /login stays valid
+    pub session_ttl_secs: u64,
+    // Unverified accounts cannot create or join groups, add bots, file reports or connect
+    // to chat, and /ws turns anonymous sessions away
+    pub require_email_verification: bool,
+    // How long a verification link stays valid
+    pub verification_ttl_secs: u64,
+    // How long a password reset token from /password/forgot stays valid
+    pub password_reset_ttl_secs: u64,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct LimitSettings {
+    pub json_payload_bytes: usize,
+    pub max_message_ttl_secs: i64,
+    pub history_page_max: i64,
+    pub member_page_max: i64,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct WebSocketSettings {
+    pub heartbeat_interval_secs: u64,
+    // A session that has not answered a ping for this long is dropped
+    pub client_timeout_secs: u64,
+    // Threads that room actors are spread over; 0 means one per CPU core
+    pub room_threads: usize,
+    // A room with no sessions is stopped after this long
+    pub room_idle_secs: u64,
+    // Per-session backlog before ephemeral frames are dropped and, failing that,
+    // the client is disconnected with a resume hint
+    pub outbox_max_frames: usize,
+    pub outbox_max_bytes: usize,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct RetentionSettings {
+    pub sweep_interval_secs: u64,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct WebhookSettings {
+    pub dispatch_interval_secs: u64,
+    pub request_timeout_secs: u64,
+    pub batch_size: i64,
+    pub max_attempts: i32,
+    // Lets webhooks point at loopback and private addresses; for local testing only
+    pub allow_private_destinations: bool,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PubSubBackend {
+    // One instance; room events never leave the process
+    Local,
+    // Fan out through LISTEN/NOTIFY on the main database
+    Postgres,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct PubSubSettings {
+    pub backend: PubSubBackend,
+    pub channel: String,
+    // Peers that stay silent for three intervals are dropped from presence
+    pub heartbeat_interval_secs: u64,
+}
+
+/// A token bucket: `burst` requests at once, refilled at `per_minute`.
+/// `per_minute = 0` turns the limit off.
+#[derive(Deserialize, Clone, Copy)]
+#[serde(default)]
+pub struct RateLimitRule {
+    pub per_minute: u32,
+    pub burst: u32,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct RateLimitSettings {
+    // Key clients by the left-most X-Forwarded-For address; only safe behind a proxy that sets it
+    pub trust_forwarded_for: bool,
+    // REST limits are per client address
+    pub login: RateLimitRule,
+    pub signup: RateLimitRule,
+    pub create_group: RateLimitRule,
+    // Routes that send mail to an address given in the request
+    pub mail: RateLimitRule,
+    // POST /password/reset
+    pub password_reset: RateLimitRule,
+    // Every other REST route
+    pub api: RateLimitRule,
+    // WebSocket chat frames, per user across their sessions, or per address for anonymous ones
+    pub messages: RateLimitRule,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    // One human-readable line per event
+    Text,
+    // One JSON object per line, for log shippers
+    Json,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct LoggingSettings {
+    // error, warn, info, debug or trace
+    pub level: String,
+    pub format: LogFormat,
+}
+
+#[derive(Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct AdminSettings {
+    // Bearer token for /admin/* used by operators and neurochat-admin; site admins
+    // identify with their user_id instead
+    pub api_token: Option<String>,
+}
+
+/// What a content filter does with a message it objects to.
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum FilterAction {
+    // Post it with the offending words starred out; banned words only
+    Mask,
+    // Keep it back for the group's moderators to approve or drop
+    Hold,
+    Reject,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct FilterSettings {
+    // Run in this order on every chat message: banned_words, links, invites, mentions
+    pub chain: Vec<String>,
+    // The words themselves are set per group through /groups/{id}/banned-words
+    pub banned_words_action: FilterAction,
+    // When not empty, links may only point at these domains and their subdomains
+    pub link_allow: Vec<String>,
+    pub link_deny: Vec<String>,
+    pub link_action: FilterAction,
+    // Case-insensitive fragments of invite links to other chat services
+    pub invite_patterns: Vec<String>,
+    pub invite_action: FilterAction,
+    // 0 turns the mention limit off
+    pub max_mentions: usize,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum MailBackend {
+    // Hand mail to an SMTP relay
+    Smtp,
+    // Write each message to an .eml file under mail.file_dir
+    File,
+    // Keep mail in the process; for tests
+    Memory,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpSecurity {
+    // TLS from the first byte, usually port 465
+    Tls,
+    // Upgrade a plain connection, usually port 587
+    Starttls,
+    // Plain text; only for a relay on the same host
+    None,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct MailSettings {
+    pub backend: MailBackend,
+    // Sender, e.g. "NeuroChat <noreply@chat.example>"
+    pub from: String,
+    // Where users reach this server; links in mail start with it
+    pub public_url: String,
+    // Page of the web app that asks for a new password; reset mail links to it with ?token=
+    pub password_reset_url: String,
+    pub file_dir: String,
+    pub smtp_host: String,
+    pub smtp_port: u16,
+    pub smtp_security: SmtpSecurity,
+    pub smtp_username: Option<String>,
+    pub smtp_password: Option<String>,
+    pub smtp_timeout_secs: u64,
+}
+
+impl Default for ServerSettings {
+    fn default() -> Self {
+        ServerSettings {
+            bind: vec!["127.0.0.1:8080".to_owned()],
+            workers: None,
+            allowed_origins: vec!["http://localhost:3000".to_owned()],
+            shutdown_timeout_secs: 30,
+        }
+    }
+}
+
+impl Default for DatabaseSettings {
+    fn default() -> Self {
+        DatabaseSettings {
+            url: String::new(),
+            pool_max_size: 10,
+            pool_min_idle: None,
+            connection_timeout_secs: 5,
+            idle_timeout_secs: 600,
+            run_migrations: false,
+            check_schema: true,
+        }
+    }
+}
+
+impl Default for AuthSettings {
+    fn default() -> Self {
+        AuthSettings {
+            jwt_secret: None,
+            bcrypt_cost: bcrypt::DEFAULT_COST,
+            session_ttl_secs: 7 * 24 * 60 * 60,
+            require_email_verification: true,
+            verification_ttl_secs: 24 * 60 * 60,
+            password_reset_ttl_secs: 60 * 60,
+        }
+    }
+}
+
+impl Default for LimitSettings {
+    fn default() -> Self {
+        LimitSettings {
+            json_payload_bytes: 64 * 1024,
+            max_message_ttl_secs: 30 * 24 * 60 * 60,
+            history_page_max: 200,
+            member_page_max: 200,
+        }
+    }
+}
+
+impl Default for WebSocketSettings {
+    fn default() -> Self {
+        WebSocketSettings {
+            heartbeat_interval_secs: 5,
+            client_timeout_secs: 10,
+            room_threads: 0,
+            room_idle_secs: 60,
+            outbox_max_frames: 256,
+            outbox_max_bytes: 1024 * 1024,
+        }
+    }
+}
+
+impl Default for RetentionSettings {
+    fn default() -> Self {
+        RetentionSettings { sweep_interval_secs: 30 }
+    }
+}
+
+impl Default for WebhookSettings {
+    fn default() -> Self {
+        WebhookSettings {
+            dispatch_interval_secs: 5,
+            request_timeout_secs: 10,
+            batch_size: 20,
+            max_attempts: 8,
+            allow_private_destinations: false,
+        }
+    }
+}
+
+impl Default for PubSubSettings {
+    fn default() -> Self {
+        PubSubSettings {
+            backend: PubSubBackend::Local,
+            channel: "neurochat_events".to_owned(),
+            heartbeat_interval_secs: 10,
+        }
+    }
+}
+
+impl Default for RateLimitRule {
+    fn default() -> Self {
+        RateLimitRule { per_minute: 0, burst: 1 }
+    }
+}
+
+impl Default for RateLimitSettings {
+    fn default() -> Self {
+        let rule = |per_minute, burst| RateLimitRule { per_minute, burst };
+        RateLimitSettings {
+            trust_forwarded_for: false,
+            login: rule(10, 5),
+            signup: rule(5, 3),
+            create_group: rule(10, 5),
+            mail: rule(5, 3),
+            password_reset: rule(10, 5),
+            api: rule(600, 100),
+            messages: rule(120, 20),
+        }
+    }
+}
+
+impl Default for LoggingSettings {
+    fn default() -> Self {
+        LoggingSettings {
+            level: "info".to_owned(),
+            format: LogFormat::Text,
+        }
+    }
+}
+
+impl Default for FilterSettings {
+    fn default() -> Self {
+        FilterSettings {
+            chain: ["banned_words", "links", "invites", "mentions"].map(str::to_owned).to_vec(),
+            banned_words_action: FilterAction::Mask,
+            link_allow: Vec::new(),
+            link_deny: Vec::new(),
+            link_action: FilterAction::Reject,
+            invite_patterns: ["discord.gg/", "discord.com/invite/", "t.me/joinchat", "chat.whatsapp.com/"]
+                .map(str::to_owned)
+                .to_vec(),
+            invite_action: FilterAction::Hold,
+            max_mentions: 10,
+        }
+    }
+}
+
+impl Default for MailSettings {
+    fn default() -> Self {
+        MailSettings {
+            backend: MailBackend::File,
+            from: "NeuroChat <noreply@localhost>".to_owned(),
+            public_url: "http://localhost:8080".to_owned(),
+            password_reset_url: "http://localhost:3000/reset-password".to_owned(),
+            file_dir: "mail".to_owned(),
+            smtp_host: String::new(),
+            smtp_port: 587,
+            smtp_security: SmtpSecurity::Starttls,
+            smtp_username: None,
+            smtp_password: None,
+            smtp_timeout_secs: 10,
+        }
+    }
+}
+
+impl ServerSettings {
+    pub fn shutdown_timeout(&self) -> Duration {
+        Duration::from_secs(self.shutdown_timeout_secs)
+    }
+}
+
+impl DatabaseSettings {
+    pub fn connection_timeout(&self) -> Duration {
+        Duration::from_secs(self.connection_timeout_secs)
+    }
+
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        (self.idle_timeout_secs > 0).then(|| Duration::from_secs(self.idle_timeout_secs))
+    }
+}
+
+impl PubSubSettings {
+    pub fn heartbeat_interval(&self) -> Duration {
+        Duration::from_secs(self.heartbeat_interval_secs)
+    }
+}
+
+impl LoggingSettings {
+    pub fn level(&self) -> Option<tracing::Level> {
+        self.level.parse().ok()
+    }
+}
+
+impl WebSocketSettings {
+    pub fn heartbeat_interval(&self) -> Duration {
+        Duration::from_secs(self.heartbeat_interval_secs)
+    }
+
+    pub fn client_timeout(&self) -> Duration {
+        Duration::from_secs(self.client_timeout_secs)
+    }
+
+    pub fn room_threads(&self) -> usize {
+        match self.room_threads {
+            0 => std::thread::available_parallelism().map_or(1, |n| n.get()),
+            n => n,
+        }
+    }
+
+    pub fn room_idle_timeout(&self) -> Duration {
+        Duration::from_secs(self.room_idle_secs)
+    }
+}
+
+#[derive(Debug)]
+pub enum SettingsError {
+    Load(config::ConfigError),
+    Invalid(Vec<String>),
+}
+
+impl fmt::Display for SettingsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SettingsError::Load(e) => write!(f, "could not load configuration: {}", e),
+            SettingsError::Invalid(problems) => {
+                write!(f, "invalid configuration:")?;
+                for problem in problems {
+                    write!(f, "\n  - {}", problem)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl From<config::ConfigError> for SettingsError {
+    fn from(e: config::ConfigError) -> Self {
+        SettingsError::Load(e)
+    }
+}
+
+impl Settings {
+    pub fn load() -> Result<Settings, SettingsError> {
+        dotenv::dotenv().ok();
+        let file = match std::env::var("NEUROCHAT_CONFIG") {
+            Ok(path) => File::with_name(&path).required(true),
+            Err(_) => File::with_name(DEFAULT_CONFIG_FILE).required(false),
+        };
+        let settings: Settings = Config::builder()
+            .add_source(file)
+            .add_source(
+                Environment::with_prefix("NEUROCHAT")
+                    .prefix_separator("_")
+                    .separator("__")
+                    .list_separator(",")
+                    .with_list_parse_key("server.bind")
+                    .with_list_parse_key("server.allowed_origins")
+                    .with_list_parse_key("filters.chain")
+                    .with_list_parse_key("filters.link_allow")
+                    .with_list_parse_key("filters.link_deny")
+                    .with_list_parse_key("filters.invite_patterns")
+                    .try_parsing(true),
+            )
+            .set_override_option("database.url", std::env::var("DATABASE_URL").ok())?
+            .build()?
+            .try_deserialize()?;
+        settings.validate()?;
+        Ok(settings)
+    }
+
+    /// Collects every problem at once so a bad deploy can be fixed in one go.
+    pub fn validate(&self) -> Result<(), SettingsError> {
+        let mut problems = Vec::new();
+        let mut check = |ok: bool, problem: String| {
+            if !ok {
+                problems.push(problem);
+            }
+        };
+
+        check(!self.server.bind.is_empty(), "server.bind needs at least one address".to_owned());
+        for addr in &self.server.bind {
+            check(
+                addr.parse::<SocketAddr>().is_ok(),
+                format!("server.bind: {:?} is not a host:port socket address", addr),
+            );
+        }
+        check(self.server.workers != Some(0), "server.workers must be at least 1".to_owned());
+        check(
+            self.server.shutdown_timeout_secs > 0,
+            "server.shutdown_timeout_secs must be at least 1".to_owned(),
+        );
+        for origin in &self.server.allowed_origins {
+            check(
+                origin.starts_with("http://") || origin.starts_with("https://"),
+                format!("server.allowed_origins: {:?} must start with http:// or https://", origin),
+            );
+        }
+
+        check(
+            !self.database.url.is_empty(),
+            "database.url is not set (use DATABASE_URL or NEUROCHAT_DATABASE__URL)".to_owned(),
+        );
+        check(self.database.pool_max_size > 0, "database.pool_max_size must be at least 1".to_owned());
+        check(
+            self.database.pool_min_idle.unwrap_or(0) <= self.database.pool_max_size,
+            "database.pool_min_idle must not exceed database.pool_max_size".to_owned(),
+        );
+        check(
+            self.database.connection_timeout_secs > 0,
+            "database.connection_timeout_secs must be at least 1".to_owned(),
+        );
+
+        if let Some(secret) = &self.auth.jwt_secret {
+            check(secret.len() >= 32, "auth.jwt_secret must be at least 32 characters".to_owned());
+        }
+        check(
+            (4..=31).contains(&self.auth.bcrypt_cost),
+            format!("auth.bcrypt_cost must be between 4 and 31, got {}", self.auth.bcrypt_cost),
+        );
+        check(
+            self.auth.session_ttl_secs > 0,
+            "auth.session_ttl_secs must be at least 1".to_owned(),
+        );
+        check(
+            self.auth.verification_ttl_secs > 0,
+            "auth.verification_ttl_secs must be at least 1".to_owned(),
+        );
+        check(
+            self.auth.password_reset_ttl_secs > 0,
+            "auth.password_reset_ttl_secs must be at least 1".to_owned(),
+        );
+
+        check(self.limits.json_payload_bytes > 0, "limits.json_payload_bytes must be positive".to_owned());
+        check(self.limits.max_message_ttl_secs > 0, "limits.max_message_ttl_secs must be positive".to_owned());
+        check(self.limits.history_page_max > 0, "limits.history_page_max must be positive".to_owned());
+        check(self.limits.member_page_max > 0, "limits.member_page_max must be positive".to_owned());
+
+        check(
+            self.websocket.heartbeat_interval_secs > 0,
+            "websocket.heartbeat_interval_secs must be at least 1".to_owned(),
+        );
+        check(
+            self.websocket.client_timeout_secs > self.websocket.heartbeat_interval_secs,
+            "websocket.client_timeout_secs must be longer than websocket.heartbeat_interval_secs".to_owned(),
+        );
+        check(self.websocket.room_idle_secs > 0, "websocket.room_idle_secs must be at least 1".to_owned());
+        check(self.websocket.outbox_max_frames > 0, "websocket.outbox_max_frames must be at least 1".to_owned());
+        // A single frame over the byte limit would disconnect every session in the room
+        check(
+            self.websocket.outbox_max_bytes >= self.limits.json_payload_bytes,
+            "websocket.outbox_max_bytes must be at least limits.json_payload_bytes".to_owned(),
+        );
+
+        check(
+            self.retention.sweep_interval_secs > 0,
+            "retention.sweep_interval_secs must be at least 1".to_owned(),
+        );
+        check(
+            self.webhooks.dispatch_interval_secs > 0,
+            "webhooks.dispatch_interval_secs must be at least 1".to_owned(),
+        );
+        check(
+            self.webhooks.request_timeout_secs > 0,
+            "webhooks.request_timeout_secs must be at least 1".to_owned(),
+        );
+        check(self.webhooks.batch_size > 0, "webhooks.batch_size must be at least 1".to_owned());
+        check(self.webhooks.max_attempts > 0, "webhooks.max_attempts must be at least 1".to_owned());
+
+        // The channel is spliced into LISTEN, so keep it to a plain identifier
+        check(
+            !self.pubsub.channel.is_empty()
+                && self.pubsub.channel.len() <= 63
+                && self.pubsub.channel.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'),
+            format!("pubsub.channel: {:?} must be 1 to 63 letters, digits or underscores", self.pubsub.channel),
+        );
+        check(
+            self.pubsub.heartbeat_interval_secs > 0,
+            "pubsub.heartbeat_interval_secs must be at least 1".to_owned(),
+        );
+
+        for (name, rule) in [
+            ("login", self.rate_limit.login),
+            ("signup", self.rate_limit.signup),
+            ("create_group", self.rate_limit.create_group),
+            ("mail", self.rate_limit.mail),
+            ("password_reset", self.rate_limit.password_reset),
+            ("api", self.rate_limit.api),
+            ("messages", self.rate_limit.messages),
+        ] {
+            check(
+                rule.per_minute == 0 || rule.burst > 0,
+                format!("rate_limit.{}.burst must be at least 1 while the limit is on", name),
+            );
+        }
+
+        check(
+            self.logging.level().is_some(),
+            format!("logging.level: {:?} must be one of error, warn, info, debug or trace", self.logging.level),
+        );
+
+        if let Some(token) = &self.admin.api_token {
+            check(token.len() >= 32, "admin.api_token must be at least 32 characters".to_owned());
+        }
+
+        for name in &self.filters.chain {
+            check(
+                crate::filters::BUILTIN_FILTERS.contains(&name.as_str()),
+                format!(
+                    "filters.chain: {:?} is not one of {}",
+                    name,
+                    crate::filters::BUILTIN_FILTERS.join(", ")
+                ),
+            );
+        }
+        for (name, action) in [("link_action", self.filters.link_action), ("invite_action", self.filters.invite_action)] {
+            check(
+                action != FilterAction::Mask,
+                format!("filters.{} must be hold or reject", name),
+            );
+        }
+
+        check(
+            self.mail.from.parse::<lettre::message::Mailbox>().is_ok(),
+            format!("mail.from: {:?} is not a mailbox such as \"Name <user@host>\"", self.mail.from),
+        );
+        for (name, url) in [
+            ("public_url", &self.mail.public_url),
+            ("password_reset_url", &self.mail.password_reset_url),
+        ] {
+            check(
+                url.starts_with("http://") || url.starts_with("https://"),
+                format!("mail.{}: {:?} must start with http:// or https://", name, url),
+            );
+        }
+        match self.mail.backend {
+            MailBackend::Smtp => {
+                check(!self.mail.smtp_host.is_empty(), "mail.smtp_host is required for the smtp backend".to_owned());
+                check(
+                    self.mail.smtp_username.is_some() == self.mail.smtp_password.is_some(),
+                    "mail.smtp_username and mail.smtp_password must be set together".to_owned(),
+                );
+                check(self.mail.smtp_timeout_secs > 0, "mail.smtp_timeout_secs must be at least 1".to_owned());
+            }
+            MailBackend::File => {
+                check(!self.mail.file_dir.is_empty(), "mail.file_dir is required for the file backend".to_owned())
+            }
+            MailBackend::Memory => {}
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(SettingsError::Invalid(problems))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::FileFormat;
+
+    fn problems(settings: &Settings) -> Vec<String> {
+        match settings.validate() {
+            Ok(()) => Vec::new(),
+            Err(SettingsError::Invalid(problems)) => problems,
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    fn with_database() -> Settings {
+        let mut settings = Settings::default();
+        settings.database.url = "postgres://localhost/neurochat".to_owned();
+        settings
+    }
+
+    #[test]
+    fn the_defaults_only_lack_a_database_url() {
+        assert_eq!(problems(&Settings::default()).len(), 1);
+        assert!(problems(&Settings::default())[0].starts_with("database.url"));
+        assert!(problems(&with_database()).is_empty());
+    }
+
+    #[test]
+    fn every_problem_is_reported_at_once() {
+        let mut settings = with_database();
+        settings.server.bind = vec!["localhost".to_owned()];
+        settings.auth.jwt_secret = Some("short".to_owned());
+        settings.auth.bcrypt_cost = 3;
+        settings.pubsub.channel = "events; DROP TABLE users".to_owned();
+        settings.filters.chain.push("profanity".to_owned());
+        settings.filters.link_action = FilterAction::Mask;
+        let problems = problems(&settings);
+        assert_eq!(problems.len(), 6, "{:?}", problems);
+        let error = SettingsError::Invalid(problems).to_string();
+        assert!(error.starts_with("invalid configuration:\n  - server.bind"));
+    }
+
+    #[test]
+    fn the_example_file_is_valid() {
+        let settings: Settings = Config::builder()
+            .add_source(File::from_str(include_str!("../neurochat.example.toml"), FileFormat::Toml))
+            .set_override("database.url", "postgres://localhost/neurochat")
+            .unwrap()
+            .build()
+            .unwrap()
+            .try_deserialize()
+            .unwrap();
+        assert!(problems(&settings).is_empty(), "{:?}", problems(&settings));
+    }
+}
@@ -0,0 +1,217 @@
+//! Fixtures for tests that need Postgres. They connect to `TEST_DATABASE_URL`,
+//! or `DATABASE_URL`, and are skipped when neither is set. Everything a test
+//! writes is rolled back when its connection or pool is dropped.
+
+use crate::commands::CommandRegistry;
+use crate::db::DbPool;
+use crate::filters::FilterChain;
+use crate::models::{Group, NewGroup, User};
+use crate::outbox::{Flush, Outbox, OutboxLimits};
+use crate::pubsub::LocalOnly;
+use crate::sessions::SessionSigner;
+use crate::settings::{AuthSettings, Settings};
+use crate::ws::{ChatServer, ClientMessage, CloseSession, Connect, Joined, ListRooms};
+use actix::prelude::*;
+use actix_web::http::header;
+use actix_web::web;
+use diesel::prelude::*;
+use diesel::r2d2::{ConnectionManager, Pool, TestCustomizer};
+use std::sync::{Arc, Once};
+use tracing::Span;
+use uuid::Uuid;
+
+static MIGRATE: Once = Once::new();
+
+const SESSION_SECRET: &str = "test-session-secret-of-at-least-32-chars";
+
+/// The test database, migrated on first use.
+pub fn database_url() -> Option<String> {
+    let url = std::env::var("TEST_DATABASE_URL")
+        .or_else(|_| std::env::var("DATABASE_URL"))
+        .ok()?;
+    MIGRATE.call_once(|| {
+        let mut conn = PgConnection::establish(&url).expect("connecting to the test database");
+        crate::migrations::run_pending(&mut conn).expect("migrating the test database");
+    });
+    Some(url)
+}
+
+/// A connection inside a transaction that is never committed.
+pub fn connection() -> Option<PgConnection> {
+    let mut conn = PgConnection::establish(&database_url()?).expect("connecting to the test database");
+    conn.begin_test_transaction().expect("starting the test transaction");
+    Some(conn)
+}
+
+/// A pool of one such connection, for handlers that check connections out themselves.
+pub fn pool() -> Option<DbPool> {
+    let manager = ConnectionManager::<PgConnection>::new(database_url()?);
+    let pool = Pool::builder()
+        .max_size(1)
+        .connection_customizer(Box::new(TestCustomizer))
+        .build(manager)
+        .expect("building the test pool");
+    Some(pool)
+}
+
+// Names are unique so tests running side by side never wait on each other's rows
+pub fn unique(prefix: &str) -> String {
+    format!("{}-{}", prefix, &Uuid::new_v4().simple().to_string()[..12])
+}
+
+/// A verified account; its password hash is not a real one.
+pub fn user(conn: &mut PgConnection) -> User {
+    use crate::schema::users;
+    let name = unique("user");
+    diesel::insert_into(users::table)
+        .values((
+            users::id.eq(Uuid::new_v4()),
+            users::username.eq(&name),
+            users::email.eq(format!("{}@example.com", name)),
+            users::password_hash.eq("-"),
+            users::email_verified_at.eq(chrono::Utc::now().naive_utc()),
+        ))
+        .get_result(conn)
+        .expect("creating a test user")
+}
+
+/// A group owned by `owner`, who is also its first member.
+pub fn group(owner: Uuid, conn: &mut PgConnection) -> Group {
+    use crate::schema::groups;
+    let group = diesel::insert_into(groups::table)
+        .values(NewGroup {
+            name: unique("group"),
+            description: None,
+            owner,
+        })
+        .returning(Group::as_returning())
+        .get_result(conn)
+        .expect("creating a test group");
+    join(owner, group.id, "owner", conn);
+    group
+}
+
+/// Settings whose session tokens `signed_in` can forge.
+pub fn auth_settings() -> AuthSettings {
+    AuthSettings {
+        jwt_secret: Some(SESSION_SECRET.to_owned()),
+        ..Default::default()
+    }
+}
+
+/// The session signer for test apps, registered with `.app_data(...)`.
+pub fn session_signer() -> web::Data<SessionSigner> {
+    web::Data::new(SessionSigner::new(&auth_settings()))
+}
+
+/// An `Authorization` header carrying a session for `user`.
+pub fn signed_in(user: &User) -> (header::HeaderName, String) {
+    let token = SessionSigner::new(&auth_settings())
+        .issue(user.id, user.session_version)
+        .expect("signing a test session");
+    (header::AUTHORIZATION, format!("Bearer {}", token))
+}
+
+pub fn join(user_id: Uuid, group_id: Uuid, role: &str, conn: &mut PgConnection) {
+    use crate::schema::user_groups;
+    diesel::insert_into(user_groups::table)
+        .values((
+            user_groups::user_id.eq(user_id),
+            user_groups::group_id.eq(group_id),
+            user_groups::role.eq(role),
+        ))
+        .execute(conn)
+        .expect("adding a test member");
+}
+
+pub fn ban(user_id: Uuid, group_id: Uuid, conn: &mut PgConnection) {
+    use crate::schema::group_bans;
+    diesel::insert_into(group_bans::table)
+        .values((group_bans::group_id.eq(group_id), group_bans::user_id.eq(user_id)))
+        .execute(conn)
+        .expect("banning a test member");
+}
+
+/// Stands in for a WebSocket session: rooms queue frames in its outbox.
+pub struct Member;
+
+impl Actor for Member {
+    type Context = Context<Self>;
+}
+
+impl Handler<Flush> for Member {
+    type Result = ();
+    fn handle(&mut self, _: Flush, _: &mut Context<Self>) {}
+}
+
+impl Handler<CloseSession> for Member {
+    type Result = ();
+    fn handle(&mut self, _: CloseSession, _: &mut Context<Self>) {}
+}
+
+/// A member's session in a room of a running `ChatServer`.
+pub struct TestSession {
+    pub joined: Joined,
+    pub outbox: Arc<Outbox>,
+    pub user_id: Option<Uuid>,
+    server: Addr<ChatServer>,
+}
+
+impl TestSession {
+    pub async fn connect(server: &Addr<ChatServer>, room: &str, user_id: Option<Uuid>) -> Self {
+        let member = Member.start();
+        let limits = OutboxLimits::from_settings(&Settings::default().websocket);
+        let outbox = Arc::new(Outbox::new(member.clone().recipient(), limits));
+        let joined = server
+            .send(Connect {
+                outbox: outbox.clone(),
+                close: member.recipient(),
+                user_id,
+                room: room.to_owned(),
+                span: Span::none(),
+            })
+            .await
+            .expect("chat server is running");
+        TestSession {
+            joined,
+            outbox,
+            user_id,
+            server: server.clone(),
+        }
+    }
+
+    pub fn send(&self, message: &str) {
+        self.joined.room.do_send(ClientMessage {
+            id: self.joined.id,
+            user_id: self.user_id,
+            message: message.to_owned(),
+            span: Span::none(),
+        });
+    }
+
+    /// Every frame queued for this session once its room has handled what came before.
+    pub async fn frames(&self) -> Vec<serde_json::Value> {
+        // Rooms answer in order, so this waits for everything sent so far
+        self.server.send(ListRooms).await.expect("chat server is running");
+        self.outbox
+            .drain()
+            .frames
+            .iter()
+            .map(|frame| serde_json::from_str(&frame.text).unwrap_or_else(|_| serde_json::Value::from(frame.text.as_str())))
+            .collect()
+    }
+}
+
+/// A chat server over `pool` with the built-in commands and `filters`.
+pub fn chat_server(pool: DbPool, filters: FilterChain) -> Addr<ChatServer> {
+    let settings = Settings::default();
+    ChatServer::new(
+        pool,
+        CommandRegistry::with_builtins(),
+        filters,
+        settings.limits.max_message_ttl_secs,
+        Box::new(LocalOnly),
+        &settings,
+    )
+    .start()
+}
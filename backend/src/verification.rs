@@ -0,0 +1,322 @@
+use crate::audit;
+use crate::db::{self, DbPool};
+use crate::errors::{ApiError, ApiResult};
+use crate::mail::{self, Mail, Mailer};
+use crate::models::{NewAuditEntry, User};
+use crate::ratelimit::RateLimits;
+use crate::settings::{AuthSettings, Settings};
+use actix_web::{web, HttpRequest, HttpResponse};
+use diesel::prelude::*;
+use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use uuid::Uuid;
+
+// Keeps a verification token from being accepted anywhere else jwt_secret signs tokens
+const PURPOSE: &str = "verify_email";
+
+#[derive(Serialize, Deserialize)]
+struct Claims {
+    sub: Uuid,
+    // A link stops working once the account's address changes
+    email: String,
+    purpose: String,
+    exp: i64,
+}
+
+/// Signs and checks the tokens in verification links.
+pub struct LinkSigner {
+    secret: Vec<u8>,
+    ttl: chrono::Duration,
+}
+
+impl LinkSigner {
+    pub fn new(settings: &AuthSettings) -> Self {
+        let secret = match &settings.jwt_secret {
+            Some(secret) => secret.as_bytes().to_vec(),
+            None => {
+                tracing::warn!(
+                    "auth.jwt_secret is not set; verification links only work on this instance until it restarts"
+                );
+                let mut secret = vec![0u8; 32];
+                rand::thread_rng().fill_bytes(&mut secret);
+                secret
+            }
+        };
+        LinkSigner {
+            secret,
+            ttl: chrono::Duration::seconds(settings.verification_ttl_secs as i64),
+        }
+    }
+
+    fn sign(&self, user_id: Uuid, email: &str) -> ApiResult<String> {
+        let claims = Claims {
+            sub: user_id,
+            email: email.to_owned(),
+            purpose: PURPOSE.to_owned(),
+            exp: (chrono::Utc::now() + self.ttl).timestamp(),
+        };
+        encode(&Header::default(), &claims, &EncodingKey::from_secret(&self.secret))
+            .map_err(|e| ApiError::Internal(format!("signing verification token: {}", e)))
+    }
+
+    // None for forged, expired and foreign tokens alike
+    fn check(&self, token: &str) -> Option<Claims> {
+        let claims = decode::<Claims>(token, &DecodingKey::from_secret(&self.secret), &Validation::default())
+            .ok()?
+            .claims;
+        (claims.purpose == PURPOSE).then_some(claims)
+    }
+}
+
+/// Fails while `auth.require_email_verification` is on and the account has not
+/// confirmed its address. Unknown users pass, so callers keep their own
+/// not-found errors.
+pub fn require_verified(user_id: Uuid, settings: &AuthSettings, conn: &mut PgConnection) -> ApiResult<()> {
+    use crate::schema::users;
+
+    if !settings.require_email_verification {
+        return Ok(());
+    }
+    let verified_at = users::table
+        .find(user_id)
+        .select(users::email_verified_at)
+        .first::<Option<chrono::NaiveDateTime>>(conn)
+        .optional()?;
+    match verified_at {
+        Some(None) => Err(ApiError::Forbidden("Verify your email address first")),
+        _ => Ok(()),
+    }
+}
+
+/// Mails `email` a fresh verification link in the background. Failures are
+/// logged; the user can ask for another link.
+pub fn send_link(
+    user_id: Uuid,
+    username: &str,
+    email: &str,
+    signer: &LinkSigner,
+    mailer: &web::Data<dyn Mailer>,
+    settings: &Settings,
+) -> ApiResult<()> {
+    let token = signer.sign(user_id, email)?;
+    let link = format!("{}/verify-email?token={}", settings.mail.public_url.trim_end_matches('/'), token);
+    let mail = Mail {
+        to: email.to_owned(),
+        subject: "Confirm your NeuroChat email address".to_owned(),
+        body: format!(
+            "Hi {},\n\nConfirm your email address by opening this link:\n\n{}\n\n\
+             The link expires in {} hours. If you did not sign up for NeuroChat, ignore this message.\n",
+            username,
+            link,
+            signer.ttl.num_hours().max(1),
+        ),
+    };
+    mail::send_in_background(mailer, mail);
+    Ok(())
+}
+
+#[derive(Deserialize)]
+pub struct VerifyQuery {
+    pub token: String,
+}
+
+/// Follows a verification link. Following it again is harmless.
+pub async fn verify_email(
+    req: HttpRequest,
+    query: web::Query<VerifyQuery>,
+    signer: web::Data<LinkSigner>,
+    pool: web::Data<DbPool>,
+    rate_limits: web::Data<RateLimits>,
+) -> ApiResult {
+    use crate::schema::users;
+
+    let claims = signer
+        .check(&query.token)
+        .ok_or(ApiError::BadRequest("This link is invalid or has expired"))?;
+    let ip = rate_limits.client_addr(&req);
+    db::run(&pool, move |conn| {
+        conn.transaction(|conn| {
+            let user = users::table
+                .find(claims.sub)
+                .for_update()
+                .first::<User>(conn)
+                .optional()?
+                .filter(|user| user.email == claims.email)
+                .ok_or(ApiError::BadRequest("This link is invalid or has expired"))?;
+            if user.is_verified() {
+                return Ok(());
+            }
+            diesel::update(users::table.find(user.id))
+                .set(users::email_verified_at.eq(chrono::Utc::now().naive_utc()))
+                .execute(conn)?;
+            audit::record(
+                conn,
+                NewAuditEntry {
+                    actor_id: Some(user.id),
+                    action: "user.verify_email".to_owned(),
+                    target_type: "user".to_owned(),
+                    target_id: Some(user.id),
+                    ip: Some(ip),
+                    after: Some(json!({"email": user.email})),
+                    ..Default::default()
+                },
+            )?;
+            Ok(())
+        })
+    })
+    .await?;
+    Ok(HttpResponse::Ok().json(json!({"message": "Email address verified"})))
+}
+
+#[derive(Deserialize)]
+pub struct ResendRequest {
+    pub email: String,
+}
+
+/// Mails a new link to an unverified account. The answer is the same whether
+/// or not one exists, so it cannot be used to look up addresses.
+pub async fn resend_verification(
+    form: web::Json<ResendRequest>,
+    signer: web::Data<LinkSigner>,
+    mailer: web::Data<dyn Mailer>,
+    settings: web::Data<Settings>,
+    pool: web::Data<DbPool>,
+) -> ApiResult {
+    let email = form.into_inner().email;
+    let user = db::run(&pool, move |conn| Ok(User::find_by_email(&email, conn).optional()?)).await?;
+    if let Some(user) = user.filter(|user| !user.is_verified() && !user.is_bot && !user.is_disabled()) {
+        send_link(user.id, &user.username, &user.email, &signer, &mailer, &settings)?;
+    }
+    Ok(HttpResponse::Ok().json(json!({
+        "message": "If that address belongs to an unverified account, a new link is on its way"
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mail::MemoryMailer;
+    use crate::test_support;
+    use actix_web::http::StatusCode;
+    use actix_web::test::{call_and_read_body_json, call_service, init_service, TestRequest};
+    use actix_web::App;
+    use std::sync::Arc;
+
+    fn settings() -> Settings {
+        let mut settings = Settings::default();
+        settings.auth.jwt_secret = Some("a-secret-of-at-least-thirty-two-chars".to_owned());
+        settings.auth.bcrypt_cost = 4;
+        settings
+    }
+
+    // Mail goes out in the background; wait for it
+    async fn next_mail(mailer: &MemoryMailer, count: usize) -> Mail {
+        for _ in 0..100 {
+            if let Some(mail) = mailer.sent().get(count) {
+                return mail.clone();
+            }
+            actix_web::rt::time::sleep(std::time::Duration::from_millis(20)).await;
+        }
+        panic!("no mail was sent");
+    }
+
+    fn link_token(mail: &Mail) -> String {
+        let start = mail.body.find("token=").expect("mail has a link") + "token=".len();
+        mail.body[start..].split_whitespace().next().unwrap().to_owned()
+    }
+
+    #[test]
+    fn links_expire_and_are_bound_to_their_purpose() {
+        let auth = settings().auth;
+        let signer = LinkSigner::new(&auth);
+        let user_id = Uuid::new_v4();
+        let claims = signer.check(&signer.sign(user_id, "a@example.com").unwrap()).unwrap();
+        assert_eq!((claims.sub, claims.email.as_str()), (user_id, "a@example.com"));
+
+        let expired = LinkSigner {
+            ttl: chrono::Duration::seconds(-120),
+            ..LinkSigner::new(&auth)
+        };
+        assert!(signer.check(&expired.sign(user_id, "a@example.com").unwrap()).is_none());
+        let session = crate::sessions::SessionSigner::new(&auth).issue(user_id, 0).unwrap();
+        assert!(signer.check(&session).is_none());
+    }
+
+    #[actix_web::test]
+    async fn signup_mails_a_link_that_unlocks_the_account() {
+        let Some(pool) = test_support::pool() else { return };
+        let settings = settings();
+        let mailer = Arc::new(MemoryMailer::default());
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(pool.clone()))
+                .app_data(web::Data::new(LinkSigner::new(&settings.auth)))
+                .app_data(web::Data::from(mailer.clone() as Arc<dyn Mailer>))
+                .app_data(web::Data::new(RateLimits::new(&settings.rate_limit)))
+                .app_data(web::Data::new(settings.clone()))
+                .route("/signup", web::post().to(crate::auth::signup))
+                .route("/verify-email", web::get().to(verify_email)),
+        )
+        .await;
+
+        let name = test_support::unique("signup");
+        let email = format!("{}@example.com", name);
+        let req = TestRequest::post()
+            .uri("/signup")
+            .set_json(json!({"username": name, "email": email, "password": "correct horse"}))
+            .to_request();
+        let user: crate::models::PublicUser = call_and_read_body_json(&app, req).await;
+        let mail = next_mail(&mailer, 0).await;
+        assert_eq!(mail.to, email);
+
+        // Unverified accounts are kept out until they follow the link
+        let gate = |conn: &mut PgConnection| require_verified(user.id, &settings.auth, conn);
+        assert!(matches!(gate(&mut pool.get().unwrap()), Err(ApiError::Forbidden(_))));
+
+        let req = TestRequest::get().uri("/verify-email?token=forged").to_request();
+        assert_eq!(call_service(&app, req).await.status(), StatusCode::BAD_REQUEST);
+        let uri = format!("/verify-email?token={}", link_token(&mail));
+        let req = TestRequest::get().uri(&uri).to_request();
+        assert_eq!(call_service(&app, req).await.status(), StatusCode::OK);
+        assert!(gate(&mut pool.get().unwrap()).is_ok());
+        // Following the link twice is harmless
+        let req = TestRequest::get().uri(&uri).to_request();
+        assert_eq!(call_service(&app, req).await.status(), StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn links_stop_working_once_the_address_changes() {
+        let Some(pool) = test_support::pool() else { return };
+        let settings = settings();
+        let signer = LinkSigner::new(&settings.auth);
+        let user = {
+            let mut conn = pool.get().unwrap();
+            let user = test_support::user(&mut conn);
+            diesel::update(crate::schema::users::table.find(user.id))
+                .set((
+                    crate::schema::users::email.eq(format!("new-{}", user.email)),
+                    crate::schema::users::email_verified_at.eq(None::<chrono::NaiveDateTime>),
+                ))
+                .execute(&mut conn)
+                .unwrap();
+            user
+        };
+        let token = signer.sign(user.id, &user.email).unwrap();
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(pool.clone()))
+                .app_data(web::Data::new(signer))
+                .app_data(web::Data::new(RateLimits::new(&settings.rate_limit)))
+                .route("/verify-email", web::get().to(verify_email)),
+        )
+        .await;
+        let req = TestRequest::get()
+            .uri(&format!("/verify-email?token={}", token))
+            .to_request();
+        assert_eq!(call_service(&app, req).await.status(), StatusCode::BAD_REQUEST);
+        let result = require_verified(user.id, &settings.auth, &mut pool.get().unwrap());
+        assert!(matches!(result, Err(ApiError::Forbidden(_))));
+    }
+}
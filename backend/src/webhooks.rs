@@ -0,0 +1,708 @@
+use crate::db::{self, DbPool};
+use crate::errors::{ApiError, ApiResult};
+use crate::groups::require_owner;
+use crate::sessions;
+use crate::settings::{Settings, WebhookSettings};
+use crate::models::{Webhook, WebhookDelivery};
+use actix::{Actor, ActorFutureExt, AsyncContext, Context, WrapFuture};
+use actix_web::{web, HttpRequest, HttpResponse};
+use diesel::prelude::*;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sha2::Sha256;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use uuid::Uuid;
+
+// A claimed delivery is retried after this long if the dispatcher never reports back
+const CLAIM_LEASE_SECONDS: i64 = 300;
+const MAX_BACKOFF_SECONDS: i64 = 3600;
+const INTERNAL_DESTINATION: &str = "Webhooks cannot be sent to loopback, private or link-local addresses";
+
+/// Room events a webhook can subscribe to.
+#[derive(Clone, Copy, Debug)]
+pub enum RoomEvent {
+    Message,
+    Join,
+    Leave,
+    // Messages cannot be edited yet, so this covers edits to the group itself
+    Edit,
+}
+
+impl RoomEvent {
+    pub const ALL: [&'static str; 4] = ["message", "join", "leave", "edit"];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            RoomEvent::Message => "message",
+            RoomEvent::Join => "join",
+            RoomEvent::Leave => "leave",
+            RoomEvent::Edit => "edit",
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct CreateWebhookRequest {
+    pub url: String,
+    pub events: Vec<String>,
+}
+
+
+/// Adds an outbox row for every webhook in the group subscribed to `event`.
+/// Call it in the transaction that makes the change, so the event is queued
+/// exactly when the change commits.
+pub fn enqueue(conn: &mut PgConnection, group_id: Uuid, event: RoomEvent, data: Value) -> QueryResult<()> {
+    use crate::schema::{webhook_deliveries, webhooks};
+
+    let hook_ids = webhooks::table
+        .filter(webhooks::group_id.eq(group_id))
+        .filter(webhooks::events.contains(vec![event.as_str()]))
+        .select(webhooks::id)
+        .load::<Uuid>(conn)?;
+    let payload = json!({
+        "event": event.as_str(),
+        "group_id": group_id,
+        "occurred_at": chrono::Utc::now().naive_utc(),
+        "data": data,
+    });
+    let rows: Vec<_> = hook_ids
+        .into_iter()
+        .map(|hook_id| {
+            (
+                webhook_deliveries::webhook_id.eq(hook_id),
+                webhook_deliveries::event.eq(event.as_str()),
+                webhook_deliveries::payload.eq(payload.clone()),
+            )
+        })
+        .collect();
+    diesel::insert_into(webhook_deliveries::table)
+        .values(&rows)
+        .execute(conn)?;
+    Ok(())
+}
+
+/// Whether `ip` is somewhere webhooks must not reach: this host, the networks
+/// it sits on, or addresses that are not routable on the internet at all.
+fn is_internal(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                || ip.is_documentation()
+                // "This network", 0.0.0.0/8
+                || a == 0
+                // Carrier-grade NAT, 100.64.0.0/10
+                || (a == 100 && (64..128).contains(&b))
+                // IETF protocol assignments, 192.0.0.0/24
+                || (a == 192 && b == 0 && c == 0)
+                // Benchmarking, 198.18.0.0/15
+                || (a == 198 && (b == 18 || b == 19))
+                // Reserved, 240.0.0.0/4
+                || a >= 240
+        }
+        IpAddr::V6(ip) => {
+            if let Some(ip) = ip.to_ipv4_mapped() {
+                return is_internal(IpAddr::V4(ip));
+            }
+            let segments = ip.segments();
+            // NAT64, 64:ff9b::/96, carries an IPv4 address in its last 32 bits
+            if segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0] {
+                let embedded = (u32::from(segments[6]) << 16) | u32::from(segments[7]);
+                return is_internal(IpAddr::V4(Ipv4Addr::from(embedded)));
+            }
+            ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_multicast()
+                // Unique local, fc00::/7
+                || (segments[0] & 0xfe00) == 0xfc00
+                // Link-local, fe80::/10, and the retired site-local fec0::/10
+                || (segments[0] & 0xffc0) == 0xfe80
+                || (segments[0] & 0xffc0) == 0xfec0
+                // Documentation, 2001:db8::/32
+                || (segments[0] == 0x2001 && segments[1] == 0xdb8)
+        }
+    }
+}
+
+// Host and port of an http or https URL, refusing hosts that are internal addresses
+fn destination(url: &str) -> Result<(String, u16), &'static str> {
+    let url = reqwest::Url::parse(url).map_err(|_| "Webhook URL is not a valid URL")?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err("Webhook URL must be http or https");
+    }
+    let host = url.host_str().ok_or("Webhook URL must name a host")?;
+    // IPv6 hosts keep their brackets in URLs
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    if host.parse::<IpAddr>().is_ok_and(is_internal) {
+        return Err(INTERNAL_DESTINATION);
+    }
+    Ok((host.to_owned(), url.port_or_known_default().unwrap_or(80)))
+}
+
+// Every address `host` resolves to, as long as none of them is internal
+async fn resolve_public(host: &str, port: u16) -> Result<Vec<SocketAddr>, &'static str> {
+    let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host, port))
+        .await
+        .map_err(|_| "Webhook host could not be resolved")?
+        .collect();
+    if addrs.is_empty() {
+        return Err("Webhook host could not be resolved");
+    }
+    if addrs.iter().any(|addr| is_internal(addr.ip())) {
+        return Err(INTERNAL_DESTINATION);
+    }
+    Ok(addrs)
+}
+
+/// Refuses webhook URLs that point into the server's own network, either
+/// directly or through a name that resolves there.
+pub async fn check_destination(url: &str) -> Result<(), &'static str> {
+    let (host, port) = destination(url)?;
+    resolve_public(&host, port).await.map(drop)
+}
+
+// Looks receiver hosts up again for every delivery, so a name pointed into the
+// network after the webhook was created is still refused
+struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let addrs = resolve_public(name.as_str(), 0).await?;
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// Hex HMAC-SHA256 of `"{timestamp}.{body}"`, sent as `X-NeuroChat-Signature: sha256=<hex>`.
+pub fn sign(secret: &str, timestamp: i64, body: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(format!("{}.{}", timestamp, body).as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+fn backoff(attempts: i32) -> chrono::Duration {
+    let seconds = 10i64.saturating_mul(1 << attempts.clamp(0, 16)).min(MAX_BACKOFF_SECONDS);
+    chrono::Duration::seconds(seconds)
+}
+
+// Outcome of one HTTP attempt: status code if a response came back, error text otherwise
+type AttemptResult = (Option<u16>, Option<String>);
+
+/// Drains the `webhook_deliveries` outbox, retrying with exponential backoff until
+/// a delivery succeeds or runs out of attempts and is marked dead.
+pub struct WebhookDispatcher {
+    pool: DbPool,
+    client: reqwest::Client,
+    busy: bool,
+    interval: Duration,
+    batch_size: i64,
+    max_attempts: i32,
+    allow_private: bool,
+}
+
+impl WebhookDispatcher {
+    pub fn new(pool: DbPool, settings: &WebhookSettings) -> Self {
+        // A redirect could lead anywhere, including back inside the network
+        let mut builder = reqwest::Client::builder()
+            .timeout(Duration::from_secs(settings.request_timeout_secs))
+            .redirect(reqwest::redirect::Policy::none());
+        if !settings.allow_private_destinations {
+            builder = builder.dns_resolver(Arc::new(PublicResolver));
+        }
+        let client = builder.build().expect("Failed to build webhook HTTP client");
+        WebhookDispatcher {
+            pool,
+            client,
+            busy: false,
+            interval: Duration::from_secs(settings.dispatch_interval_secs),
+            batch_size: settings.batch_size,
+            max_attempts: settings.max_attempts,
+            allow_private: settings.allow_private_destinations,
+        }
+    }
+
+    // Locks due rows and pushes their next attempt out by the lease, so that
+    // another dispatcher (or this one after a crash) does not send them twice
+    fn claim_due(&self, conn: &mut PgConnection) -> QueryResult<Vec<(WebhookDelivery, Webhook)>> {
+        use crate::schema::{webhook_deliveries, webhooks};
+
+        let now = chrono::Utc::now().naive_utc();
+        conn.transaction(|conn| {
+            let due = webhook_deliveries::table
+                .filter(webhook_deliveries::status.eq("pending"))
+                .filter(webhook_deliveries::next_attempt_at.le(now))
+                .order(webhook_deliveries::next_attempt_at.asc())
+                .limit(self.batch_size)
+                .for_update()
+                .skip_locked()
+                .load::<WebhookDelivery>(conn)?;
+            let ids: Vec<Uuid> = due.iter().map(|d| d.id).collect();
+            diesel::update(webhook_deliveries::table.filter(webhook_deliveries::id.eq_any(&ids)))
+                .set(webhook_deliveries::next_attempt_at.eq(now + chrono::Duration::seconds(CLAIM_LEASE_SECONDS)))
+                .execute(conn)?;
+            let hooks = webhooks::table
+                .filter(webhooks::id.eq_any(due.iter().map(|d| d.webhook_id).collect::<Vec<_>>()))
+                .load::<Webhook>(conn)?;
+            Ok(due
+                .into_iter()
+                .filter_map(|delivery| {
+                    let hook = hooks.iter().find(|h| h.id == delivery.webhook_id)?.clone();
+                    Some((delivery, hook))
+                })
+                .collect())
+        })
+    }
+
+    fn record(&self, delivery: &WebhookDelivery, (status_code, error): AttemptResult) {
+        use crate::schema::webhook_deliveries::dsl::*;
+
+        let Ok(mut conn) = db::connection(&self.pool) else { return };
+        let now = chrono::Utc::now().naive_utc();
+        let tries = delivery.attempts + 1;
+        let succeeded = status_code.is_some_and(|code| (200..300).contains(&code));
+        let (new_status, next_attempt) = if succeeded {
+            ("delivered", now)
+        } else if tries >= self.max_attempts {
+            ("dead", now)
+        } else {
+            ("pending", now + backoff(delivery.attempts))
+        };
+        let result = diesel::update(webhook_deliveries.find(delivery.id))
+            .set((
+                status.eq(new_status),
+                attempts.eq(tries),
+                next_attempt_at.eq(next_attempt),
+                last_status_code.eq(status_code.map(i32::from)),
+                last_error.eq(error),
+                delivered_at.eq(succeeded.then_some(now)),
+            ))
+            .execute(&mut conn);
+        if let Err(e) = result {
+            tracing::error!(delivery_id = %delivery.id, error = ?e, "failed to record webhook delivery");
+        }
+    }
+
+    fn dispatch(&mut self, ctx: &mut Context<Self>) {
+        if self.busy {
+            return;
+        }
+        let Ok(mut conn) = db::connection(&self.pool) else { return };
+        let due = match self.claim_due(&mut conn) {
+            Ok(due) if !due.is_empty() => due,
+            Ok(_) => return,
+            Err(e) => return tracing::error!(error = ?e, "failed to claim webhook deliveries"),
+        };
+        self.busy = true;
+
+        let client = self.client.clone();
+        let allow_private = self.allow_private;
+        let sends = async move {
+            let mut results = Vec::with_capacity(due.len());
+            for (delivery, hook) in due {
+                let outcome = send(&client, &delivery, &hook, allow_private).await;
+                results.push((delivery, outcome));
+            }
+            results
+        };
+        ctx.spawn(sends.into_actor(self).map(|results, act, _| {
+            for (delivery, outcome) in &results {
+                act.record(delivery, outcome.clone());
+            }
+            act.busy = false;
+        }));
+    }
+}
+
+async fn send(client: &reqwest::Client, delivery: &WebhookDelivery, hook: &Webhook, allow_private: bool) -> AttemptResult {
+    // Names are checked by the client's resolver; literal addresses never reach it
+    if !allow_private {
+        if let Err(reason) = destination(&hook.url) {
+            return (None, Some(reason.to_owned()));
+        }
+    }
+    let body = delivery.payload.to_string();
+    let timestamp = chrono::Utc::now().timestamp();
+    let response = client
+        .post(&hook.url)
+        .header("Content-Type", "application/json")
+        .header("X-NeuroChat-Event", &delivery.event)
+        .header("X-NeuroChat-Delivery", delivery.id.to_string())
+        .header("X-NeuroChat-Timestamp", timestamp.to_string())
+        .header("X-NeuroChat-Signature", format!("sha256={}", sign(&hook.secret, timestamp, &body)))
+        .body(body)
+        .send()
+        .await;
+    match response {
+        Ok(response) if response.status().is_success() => (Some(response.status().as_u16()), None),
+        Ok(response) => (
+            Some(response.status().as_u16()),
+            Some(format!("Receiver responded with {}", response.status())),
+        ),
+        Err(e) => (None, Some(e.to_string())),
+    }
+}
+
+impl Actor for WebhookDispatcher {
+    type Context = Context<Self>;
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_interval(self.interval, |act, ctx| act.dispatch(ctx));
+    }
+}
+
+// Every webhook endpoint is for the group's signed-in owner
+pub async fn create_webhook(
+    req: HttpRequest,
+    path: web::Path<Uuid>,
+    pool: web::Data<DbPool>,
+    settings: web::Data<Settings>,
+    form: web::Json<CreateWebhookRequest>,
+) -> ApiResult {
+    let group_id = path.into_inner();
+    let owner = sessions::current_user(&req, &pool).await?;
+    if !settings.webhooks.allow_private_destinations {
+        check_destination(&form.url).await.map_err(ApiError::BadRequest)?;
+    }
+    let response = db::run(&pool, move |conn| {
+        require_owner(group_id, owner.id, conn)?;
+        if !(form.url.starts_with("http://") || form.url.starts_with("https://")) {
+            return Err(ApiError::BadRequest("Webhook URL must be http or https"));
+        }
+        if form.events.is_empty() || form.events.iter().any(|e| !RoomEvent::ALL.contains(&e.as_str())) {
+            return Err(ApiError::BadRequest(
+                "events must be a non-empty list of: message, join, leave, edit",
+            ));
+        }
+
+        let mut secret = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut secret);
+        let hook = Webhook {
+            id: Uuid::new_v4(),
+            group_id,
+            url: form.url.clone(),
+            secret: hex::encode(secret),
+            events: form.events.clone(),
+            created_by: Some(owner.id),
+            created_at: chrono::Utc::now().naive_utc(),
+        };
+        diesel::insert_into(crate::schema::webhooks::table)
+            .values(&hook)
+            .execute(conn)?;
+        // The signing secret is only returned once, at creation
+        Ok(json!({"webhook": hook, "secret": hook.secret}))
+    })
+    .await?;
+    Ok(HttpResponse::Ok().json(response))
+}
+
+pub async fn list_webhooks(
+    req: HttpRequest,
+    path: web::Path<Uuid>,
+    pool: web::Data<DbPool>,
+) -> ApiResult {
+    use crate::schema::webhooks;
+
+    let group_id = path.into_inner();
+    let owner = sessions::current_user(&req, &pool).await?;
+    let response = db::run(&pool, move |conn| {
+        require_owner(group_id, owner.id, conn)?;
+        let hooks = webhooks::table
+            .filter(webhooks::group_id.eq(group_id))
+            .order(webhooks::created_at.asc())
+            .load::<Webhook>(conn)?;
+        Ok(hooks)
+    })
+    .await?;
+    Ok(HttpResponse::Ok().json(response))
+}
+
+pub async fn delete_webhook(
+    req: HttpRequest,
+    path: web::Path<(Uuid, Uuid)>,
+    pool: web::Data<DbPool>,
+) -> ApiResult {
+    use crate::schema::webhooks;
+
+    let (group_id, webhook_id) = path.into_inner();
+    let owner = sessions::current_user(&req, &pool).await?;
+    let response = db::run(&pool, move |conn| {
+        require_owner(group_id, owner.id, conn)?;
+        let deleted = diesel::delete(
+            webhooks::table
+                .filter(webhooks::id.eq(webhook_id))
+                .filter(webhooks::group_id.eq(group_id)),
+        )
+        .execute(conn)?;
+        if deleted == 0 {
+            return Err(ApiError::NotFound("Webhook not found"));
+        }
+        Ok(json!({"message": "Webhook deleted successfully"}))
+    })
+    .await?;
+    Ok(HttpResponse::Ok().json(response))
+}
+
+#[derive(Deserialize)]
+pub struct DeliveryLogQuery {
+    pub status: Option<String>,
+}
+
+/// Most recent deliveries for a webhook, newest first.
+pub async fn list_deliveries(
+    req: HttpRequest,
+    path: web::Path<(Uuid, Uuid)>,
+    query: web::Query<DeliveryLogQuery>,
+    pool: web::Data<DbPool>,
+) -> ApiResult {
+    use crate::schema::{webhook_deliveries, webhooks};
+
+    let (group_id, webhook_id) = path.into_inner();
+    let owner = sessions::current_user(&req, &pool).await?;
+    let response = db::run(&pool, move |conn| {
+        require_owner(group_id, owner.id, conn)?;
+        let mut deliveries = webhook_deliveries::table
+            .inner_join(webhooks::table)
+            .filter(webhooks::group_id.eq(group_id))
+            .filter(webhook_deliveries::webhook_id.eq(webhook_id))
+            .select(webhook_deliveries::all_columns)
+            .order(webhook_deliveries::created_at.desc())
+            .limit(100)
+            .into_boxed();
+        if let Some(status) = &query.status {
+            deliveries = deliveries.filter(webhook_deliveries::status.eq(status));
+        }
+        Ok(deliveries.load::<WebhookDelivery>(conn)?)
+    })
+    .await?;
+    Ok(HttpResponse::Ok().json(response))
+}
+
+/// Puts a dead delivery back in the outbox with a fresh set of attempts.
+pub async fn retry_delivery(
+    req: HttpRequest,
+    path: web::Path<(Uuid, Uuid, Uuid)>,
+    pool: web::Data<DbPool>,
+) -> ApiResult {
+    use crate::schema::{webhook_deliveries, webhooks};
+
+    let (group_id, webhook_id, delivery_id) = path.into_inner();
+    let owner = sessions::current_user(&req, &pool).await?;
+    let response = db::run(&pool, move |conn| {
+        require_owner(group_id, owner.id, conn)?;
+        let in_group = webhooks::table
+            .filter(webhooks::id.eq(webhook_id))
+            .filter(webhooks::group_id.eq(group_id))
+            .select(webhooks::id);
+        let queued = diesel::update(
+            webhook_deliveries::table
+                .filter(webhook_deliveries::id.eq(delivery_id))
+                .filter(webhook_deliveries::webhook_id.eq_any(in_group))
+                .filter(webhook_deliveries::status.eq("dead")),
+        )
+        .set((
+            webhook_deliveries::status.eq("pending"),
+            webhook_deliveries::attempts.eq(0),
+            webhook_deliveries::next_attempt_at.eq(chrono::Utc::now().naive_utc()),
+        ))
+        .execute(conn)?;
+        if queued == 0 {
+            return Err(ApiError::NotFound("No dead delivery with that id"));
+        }
+        Ok(json!({"message": "Delivery queued for retry"}))
+    })
+    .await?;
+    Ok(HttpResponse::Ok().json(response))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::User;
+    use crate::test_support;
+    use actix_web::http::StatusCode;
+    use actix_web::test::{call_and_read_body_json, call_service, init_service, TestRequest};
+    use actix_web::App;
+
+    fn hook(group_id: Uuid, url: &str, conn: &mut PgConnection) -> Webhook {
+        let hook = Webhook {
+            id: Uuid::new_v4(),
+            group_id,
+            url: url.to_owned(),
+            secret: "secret".to_owned(),
+            events: vec!["message".to_owned()],
+            created_by: None,
+            created_at: chrono::Utc::now().naive_utc(),
+        };
+        diesel::insert_into(crate::schema::webhooks::table)
+            .values(&hook)
+            .execute(conn)
+            .unwrap();
+        hook
+    }
+
+    fn queued(hook_id: Uuid, conn: &mut PgConnection) -> Vec<WebhookDelivery> {
+        use crate::schema::webhook_deliveries;
+        webhook_deliveries::table
+            .filter(webhook_deliveries::webhook_id.eq(hook_id))
+            .load(conn)
+            .unwrap()
+    }
+
+    #[test]
+    fn deliveries_are_queued_only_if_the_change_commits() {
+        let Some(mut conn) = test_support::connection() else { return };
+        let owner = test_support::user(&mut conn);
+        let group = test_support::group(owner.id, &mut conn);
+        let hook = hook(group.id, "https://example.com/hook", &mut conn);
+
+        let rolled_back = conn.transaction(|conn| {
+            enqueue(conn, group.id, RoomEvent::Message, json!({"n": 1}))?;
+            Err::<(), _>(diesel::result::Error::RollbackTransaction)
+        });
+        assert!(rolled_back.is_err());
+        assert!(queued(hook.id, &mut conn).is_empty());
+
+        conn.transaction(|conn| enqueue(conn, group.id, RoomEvent::Message, json!({"n": 2})))
+            .unwrap();
+        // Events the hook did not subscribe to are not queued
+        enqueue(&mut conn, group.id, RoomEvent::Join, json!({})).unwrap();
+        let deliveries = queued(hook.id, &mut conn);
+        assert_eq!(deliveries.len(), 1);
+        assert_eq!(deliveries[0].payload["data"]["n"], 2);
+        assert_eq!(deliveries[0].status, "pending");
+    }
+
+    #[test]
+    fn internal_addresses_are_recognised() {
+        for ip in [
+            "127.0.0.1", "10.1.2.3", "172.16.0.1", "192.168.1.1", "169.254.169.254", "0.0.0.0",
+            "100.64.0.1", "255.255.255.255", "224.0.0.1", "::1", "::", "fd00::1", "fe80::1",
+            "::ffff:127.0.0.1", "64:ff9b::a9fe:a9fe",
+        ] {
+            assert!(is_internal(ip.parse().unwrap()), "{} should be internal", ip);
+        }
+        for ip in ["93.184.216.34", "8.8.8.8", "2606:4700::1111", "::ffff:8.8.8.8"] {
+            assert!(!is_internal(ip.parse().unwrap()), "{} should be public", ip);
+        }
+    }
+
+    #[test]
+    fn destinations_must_be_public_http_urls() {
+        assert_eq!(destination("https://example.com/hook").unwrap(), ("example.com".to_owned(), 443));
+        assert_eq!(destination("http://example.com:8080").unwrap(), ("example.com".to_owned(), 8080));
+        assert_eq!(destination("ftp://example.com"), Err("Webhook URL must be http or https"));
+        assert_eq!(destination("not a url"), Err("Webhook URL is not a valid URL"));
+        assert_eq!(destination("http://127.0.0.1:9000/hook"), Err(INTERNAL_DESTINATION));
+        assert_eq!(destination("http://[::1]/hook"), Err(INTERNAL_DESTINATION));
+        assert_eq!(destination("http://169.254.169.254/latest/meta-data"), Err(INTERNAL_DESTINATION));
+    }
+
+    #[actix_web::test]
+    async fn names_that_resolve_inside_the_network_are_refused() {
+        assert_eq!(check_destination("http://localhost:9000/hook").await, Err(INTERNAL_DESTINATION));
+        let client = reqwest::Client::builder()
+            .dns_resolver(Arc::new(PublicResolver))
+            .build()
+            .unwrap();
+        assert!(client.get("http://localhost:9/").send().await.is_err());
+    }
+
+    #[actix_web::test]
+    async fn deliveries_to_internal_addresses_fail_without_a_request() {
+        let client = reqwest::Client::new();
+        let delivery = WebhookDelivery {
+            id: Uuid::new_v4(),
+            webhook_id: Uuid::new_v4(),
+            event: "message".to_owned(),
+            payload: json!({}),
+            status: "pending".to_owned(),
+            attempts: 0,
+            next_attempt_at: chrono::Utc::now().naive_utc(),
+            last_status_code: None,
+            last_error: None,
+            created_at: chrono::Utc::now().naive_utc(),
+            delivered_at: None,
+        };
+        let hook = Webhook {
+            id: delivery.webhook_id,
+            group_id: Uuid::new_v4(),
+            url: "http://10.0.0.1/hook".to_owned(),
+            secret: "secret".to_owned(),
+            events: vec!["message".to_owned()],
+            created_by: None,
+            created_at: chrono::Utc::now().naive_utc(),
+        };
+        let (status, error) = send(&client, &delivery, &hook, false).await;
+        assert_eq!(status, None);
+        assert_eq!(error.as_deref(), Some(INTERNAL_DESTINATION));
+    }
+
+    #[test]
+    fn signatures_cover_the_timestamp_and_body() {
+        let signature = sign("secret", 1700000000, "{}");
+        assert_eq!(signature.len(), 64);
+        assert_eq!(signature, sign("secret", 1700000000, "{}"));
+        assert_ne!(signature, sign("secret", 1700000001, "{}"));
+        assert_ne!(signature, sign("other", 1700000000, "{}"));
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_an_hour() {
+        assert_eq!(backoff(0).num_seconds(), 10);
+        assert_eq!(backoff(3).num_seconds(), 80);
+        assert_eq!(backoff(20).num_seconds(), MAX_BACKOFF_SECONDS);
+    }
+
+    #[actix_web::test]
+    async fn only_the_signed_in_owner_manages_webhooks() {
+        let Some(pool) = test_support::pool() else { return };
+        let (group, owner, member) = {
+            let mut conn = pool.get().unwrap();
+            let (owner, member) = (test_support::user(&mut conn), test_support::user(&mut conn));
+            let group = test_support::group(owner.id, &mut conn);
+            test_support::join(member.id, group.id, "member", &mut conn);
+            (group, owner, member)
+        };
+        let mut settings = Settings::default();
+        settings.webhooks.allow_private_destinations = true;
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(pool.clone()))
+                .app_data(web::Data::new(settings))
+                .app_data(test_support::session_signer())
+                .route("/groups/{id}/webhooks", web::post().to(create_webhook))
+                .route("/groups/{id}/webhooks", web::get().to(list_webhooks)),
+        )
+        .await;
+        let create = |user: &User| {
+            TestRequest::post()
+                .uri(&format!("/groups/{}/webhooks", group.id))
+                .insert_header(test_support::signed_in(user))
+                .set_json(json!({"user_id": owner.id, "url": "http://127.0.0.1:9/hook", "events": ["message"]}))
+                .to_request()
+        };
+        let list = |user: &User| {
+            TestRequest::get()
+                .uri(&format!("/groups/{}/webhooks?user_id={}", group.id, owner.id))
+                .insert_header(test_support::signed_in(user))
+                .to_request()
+        };
+
+        assert_eq!(call_service(&app, create(&member)).await.status(), StatusCode::FORBIDDEN);
+        assert_eq!(call_service(&app, list(&member)).await.status(), StatusCode::FORBIDDEN);
+        let created: Value = call_and_read_body_json(&app, create(&owner)).await;
+        assert_eq!(created["webhook"]["created_by"], owner.id.to_string());
+        let hooks: Vec<Value> = call_and_read_body_json(&app, list(&owner)).await;
+        assert_eq!(hooks.len(), 1);
+    }
+}
@@ -1,64 +1,368 @@
-use actix::{Actor, Addr, Context, Handler, Message as ActixMessage, Recipient, StreamHandler, AsyncContext};
+use crate::bots;
+use crate::commands::{self, CommandRegistry};
+use crate::filters::FilterChain;
+use crate::db::{self, DbPool};
+use crate::errors::ApiError;
+use crate::groups;
+use crate::metrics;
+use crate::models::Message;
+use crate::outbox::{self, Flush, Frame, Outbox, OutboxLimits};
+use crate::polls::PollTally;
+use crate::pubsub::{ClusterEvent, Envelope, PubSub};
+use crate::ratelimit::RateLimits;
+use crate::room::{Room, RoomOp, RoomShared, RoomStats, Routed};
+use crate::sessions::SessionSigner;
+use crate::settings::{AuthSettings, Settings, WebSocketSettings};
+use crate::verification;
+use actix::prelude::*;
+use actix::Message as ActixMessage;
+use actix::ActorContext;
 use actix_web::{HttpRequest, HttpResponse, web};
 use actix_web_actors::ws;
-use std::collections::HashMap;
+use chrono::NaiveDateTime;
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
 use std::time::{Duration, Instant};
-use actix::prelude::*;
-use actix::ActorContext;
+use tokio::sync::oneshot;
+use tracing::Span;
+use uuid::Uuid;
 
-// Message to broadcast to sessions
+// Message from client to server
 #[derive(ActixMessage)]
 #[rtype(result = "()")]
-pub struct BroadcastMessage {
-    pub room: String,
+pub struct ClientMessage {
+    pub id: usize,
+    pub user_id: Option<Uuid>,
     pub message: String,
+    // The frame's span, under its session's
+    pub span: Span,
 }
 
-// Message from client to server
+// Message stored outside a WebSocket session, e.g. by a bot over REST
 #[derive(ActixMessage)]
 #[rtype(result = "()")]
-pub struct ClientMessage {
+pub struct PostMessage {
     pub room: String,
-    pub message: String,
+    pub message: Message,
+    pub span: Span,
+}
+
+/// Closes the sessions of `user_id` in `room`, or in every room when `None`,
+/// on this instance and on every other one.
+#[derive(ActixMessage)]
+#[rtype(result = "()")]
+pub struct DisconnectUser {
+    pub user_id: Uuid,
+    pub room: Option<String>,
+    pub reason: String,
+}
+
+// A group's banned words were replaced; rooms drop the list they loaded
+#[derive(ActixMessage)]
+#[rtype(result = "()")]
+pub struct BannedWordsChanged {
+    pub room: String,
+}
+
+// Messages removed by the retention sweeper
+#[derive(ActixMessage)]
+#[rtype(result = "()")]
+pub struct MessagesDeleted {
+    pub room: String,
+    pub message_ids: Vec<Uuid>,
+}
+
+// Poll created or voted on through REST
+#[derive(ActixMessage)]
+#[rtype(result = "()")]
+pub struct PollEvent {
+    pub room: String,
+    pub tally: PollTally,
+    pub created: bool,
+    pub span: Span,
+}
+
+/// Structured frames a client can send instead of plain chat text.
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientFrame {
+    Message {
+        content: String,
+        // Self-destruct this many seconds after sending
+        ttl_seconds: Option<i64>,
+    },
+    CreatePoll {
+        question: String,
+        options: Vec<String>,
+        #[serde(default)]
+        multiple_choice: bool,
+        #[serde(default)]
+        anonymous: bool,
+        closes_at: Option<NaiveDateTime>,
+    },
+    Vote {
+        poll_id: Uuid,
+        option_ids: Vec<Uuid>,
+    },
+}
+
+/// Structured events pushed to sessions, tagged by `type`.
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerEvent<'a> {
+    Poll { poll: &'a PollTally },
+    PollTally { poll: &'a PollTally },
+    Message { message: &'a Message },
+    MessagesDeleted { message_ids: &'a [Uuid] },
+    // Everyone connected to the room on any instance
+    Presence { user_ids: &'a [Uuid] },
+    Notice { text: &'a str },
+    Error { error: &'a str },
+    // The frame was dropped; wait this long before sending another
+    RateLimited { retry_after_ms: u64 },
+}
+
+impl ServerEvent<'_> {
+    pub fn frame(&self) -> Option<Frame> {
+        let text = serde_json::to_string(self).ok()?;
+        Some(Frame {
+            text,
+            ephemeral: matches!(
+                self,
+                ServerEvent::PollTally { .. } | ServerEvent::Presence { .. } | ServerEvent::Notice { .. }
+            ),
+            message_at: match self {
+                ServerEvent::Message { message } => message.timestamp,
+                _ => None,
+            },
+        })
+    }
+}
+
+// Asks a session to close its socket
+#[derive(ActixMessage)]
+#[rtype(result = "()")]
+pub struct CloseSession {
+    pub reason: String,
+    // This instance is going away; the client should reconnect, likely to another one
+    pub restart: bool,
 }
 
+
+// Answered straight away; lets /readyz tell a wedged registry from a busy one
+#[derive(ActixMessage)]
+#[rtype(result = "()")]
+pub struct Ping;
+
+/// Closes every session on this instance with a "reconnect elsewhere" close frame.
+/// Resolves once every room has seen all of its sessions leave, so the messages
+/// they sent before closing have been stored.
 #[derive(ActixMessage)]
-#[rtype(result = "usize")]
+#[rtype(result = "()")]
+pub struct Shutdown;
+
+/// Figures for every room running on this instance, busiest first.
+#[derive(ActixMessage)]
+#[rtype(result = "Vec<RoomStats>")]
+pub struct ListRooms;
+
+/// A session's place in a room: its id and the room actor to talk to.
+pub struct Joined {
+    pub id: usize,
+    pub room: Addr<Room>,
+}
+
+#[derive(ActixMessage)]
+#[rtype(result = "Joined")]
 pub struct Connect {
-    pub addr: Recipient<BroadcastMessage>,
+    pub outbox: Arc<Outbox>,
+    pub close: Recipient<CloseSession>,
+    pub user_id: Option<Uuid>,
     pub room: String,
+    pub span: Span,
 }
 
+// Sent by a session to its room when it closes
 #[derive(ActixMessage)]
 #[rtype(result = "()")]
 pub struct Disconnect {
     pub id: usize,
+}
+
+// A room with no sessions asks to be removed; true means it may stop
+#[derive(ActixMessage)]
+#[rtype(result = "bool")]
+pub struct RoomIdle {
     pub room: String,
+    pub received: u64,
+}
+
+struct RoomHandle {
+    addr: Addr<Room>,
+    // RoomOps sent so far; see RoomIdle
+    sent: u64,
 }
 
+// Another backend instance, as last heard over pub/sub
+struct Peer {
+    last_seen: Instant,
+    rooms: HashMap<String, HashSet<Uuid>>,
+}
+
+/// Registry of room actors. It starts a `Room` on first use, spreads rooms over
+/// a fixed set of arbiters and removes rooms that have gone idle. It also tracks
+/// the other instances seen over pub/sub and forwards their events.
 pub struct ChatServer {
-    sessions: HashMap<usize, Recipient<BroadcastMessage>>,
-    rooms: HashMap<String, Vec<usize>>,
+    shared: Arc<RoomShared>,
+    rooms: HashMap<String, RoomHandle>,
     counter: usize,
+    arbiters: Vec<ArbiterHandle>,
+    next_arbiter: usize,
+    room_threads: usize,
+    room_idle_timeout: Duration,
+    peers: HashMap<Uuid, Peer>,
+    heartbeat_interval: Duration,
 }
 
 impl ChatServer {
-    pub fn new() -> Self {
+    pub fn new(
+        pool: DbPool,
+        commands: CommandRegistry,
+        filters: FilterChain,
+        max_ttl_seconds: i64,
+        pubsub: Box<dyn PubSub>,
+        settings: &Settings,
+    ) -> Self {
         ChatServer {
-            sessions: HashMap::new(),
+            shared: Arc::new(RoomShared {
+                pool,
+                commands,
+                filters,
+                max_ttl_seconds,
+                instance_id: Uuid::new_v4(),
+                pubsub,
+            }),
             rooms: HashMap::new(),
             counter: 0,
+            arbiters: Vec::new(),
+            next_arbiter: 0,
+            room_threads: settings.websocket.room_threads(),
+            room_idle_timeout: settings.websocket.room_idle_timeout(),
+            peers: HashMap::new(),
+            heartbeat_interval: settings.pubsub.heartbeat_interval(),
         }
     }
-    pub fn broadcast(&self, room: &str, message: &str) {
-        if let Some(session_ids) = self.rooms.get(room) {
-            for id in session_ids {
-                if let Some(addr) = self.sessions.get(id) {
-                    let _ = addr.do_send(BroadcastMessage {
-                        room: room.to_owned(),
-                        message: message.to_owned(),
-                    });
+
+    // Starts the room on the next arbiter if it is not running yet
+    fn room(&mut self, name: &str, ctx: &mut Context<Self>) -> &mut RoomHandle {
+        if !self.rooms.contains_key(name) {
+            let arbiter = &self.arbiters[self.next_arbiter % self.arbiters.len()];
+            self.next_arbiter += 1;
+            let room = Room::new(
+                name.to_owned(),
+                self.shared.clone(),
+                ctx.address(),
+                self.remote_users(name),
+                self.room_idle_timeout,
+            );
+            let addr = Room::start_in_arbiter(arbiter, move |_| room);
+            self.rooms.insert(name.to_owned(), RoomHandle { addr, sent: 0 });
+            metrics::ROOMS.set(self.rooms.len() as i64);
+        }
+        self.rooms.get_mut(name).expect("room was just inserted")
+    }
+
+    // Rooms log under whatever span the registry is handling
+    fn send(handle: &mut RoomHandle, op: RoomOp) {
+        handle.sent += 1;
+        handle.addr.do_send(Routed {
+            op,
+            span: Span::current(),
+        });
+    }
+
+    fn forward(&mut self, name: &str, op: RoomOp, ctx: &mut Context<Self>) {
+        Self::send(self.room(name, ctx), op);
+    }
+
+    // Remote events only matter to rooms with sessions here
+    fn forward_existing(&mut self, name: &str, op: RoomOp) {
+        if let Some(handle) = self.rooms.get_mut(name) {
+            Self::send(handle, op);
+        }
+    }
+
+    fn remote_users(&self, room: &str) -> HashSet<Uuid> {
+        self.peers
+            .values()
+            .flat_map(|peer| peer.rooms.get(room).into_iter().flatten().copied())
+            .collect()
+    }
+
+    fn remote_presence_changed(&mut self, room: &str) {
+        let user_ids = self.remote_users(room);
+        self.forward_existing(room, RoomOp::RemotePresence(user_ids));
+    }
+
+    fn expire_peers(&mut self) {
+        let deadline = self.heartbeat_interval * 3;
+        let (expired, alive): (HashMap<_, _>, HashMap<_, _>) = std::mem::take(&mut self.peers)
+            .into_iter()
+            .partition(|(_, peer)| peer.last_seen.elapsed() > deadline);
+        self.peers = alive;
+        for (instance_id, peer) in expired {
+            tracing::warn!(%instance_id, "instance stopped sending heartbeats; dropping its presence");
+            for room in peer.rooms.keys() {
+                self.remote_presence_changed(room);
+            }
+        }
+    }
+
+    // Every room on this instance; the user may be in any of them
+    fn disconnect_everywhere(&mut self, user_id: Uuid, reason: &str) {
+        for handle in self.rooms.values_mut() {
+            Self::send(
+                handle,
+                RoomOp::RemoteDisconnect {
+                    user_id,
+                    reason: reason.to_owned(),
+                },
+            );
+        }
+    }
+
+    fn handle_remote(&mut self, origin: Uuid, event: ClusterEvent) {
+        let is_new = !self.peers.contains_key(&origin);
+        let peer = self.peers.entry(origin).or_insert_with(|| Peer {
+            last_seen: Instant::now(),
+            rooms: HashMap::new(),
+        });
+        peer.last_seen = Instant::now();
+        match event {
+            ClusterEvent::Broadcast { room, frame } => self.forward_existing(&room, RoomOp::RemoteBroadcast(frame)),
+            ClusterEvent::Disconnect { room, user_id, reason } => {
+                self.forward_existing(&room, RoomOp::RemoteDisconnect { user_id, reason })
+            }
+            ClusterEvent::Presence { room, user_ids } => {
+                if user_ids.is_empty() {
+                    peer.rooms.remove(&room);
+                } else {
+                    peer.rooms.insert(room.clone(), user_ids.into_iter().collect());
                 }
+                self.remote_presence_changed(&room);
+            }
+            ClusterEvent::DisconnectUser { user_id, reason } => self.disconnect_everywhere(user_id, &reason),
+            ClusterEvent::BannedWordsChanged { room } => self.forward_existing(&room, RoomOp::BannedWordsChanged),
+            ClusterEvent::Heartbeat => {}
+        }
+        // Full local presence, so a peer that just appeared does not wait for changes
+        if is_new {
+            let rooms: Vec<String> = self.rooms.keys().cloned().collect();
+            for room in rooms {
+                self.forward_existing(&room, RoomOp::PublishPresence);
             }
         }
     }
@@ -66,55 +370,248 @@ impl ChatServer {
 
 impl Actor for ChatServer {
     type Context = Context<Self>;
+    fn started(&mut self, ctx: &mut Self::Context) {
+        self.arbiters = (0..self.room_threads).map(|_| Arbiter::new().handle()).collect();
+        self.shared.pubsub.subscribe(self.shared.instance_id, ctx.address().recipient());
+        ctx.run_interval(self.heartbeat_interval, |act, _| {
+            act.shared.publish(ClusterEvent::Heartbeat);
+            act.expire_peers();
+        });
+    }
 }
 
 impl Handler<Connect> for ChatServer {
-    type Result = usize;
-    fn handle(&mut self, msg: Connect, _: &mut Context<Self>) -> Self::Result {
+    type Result = MessageResult<Connect>;
+    fn handle(&mut self, msg: Connect, ctx: &mut Context<Self>) -> Self::Result {
+        let _entered = msg.span.enter();
         let id = self.counter;
         self.counter += 1;
-        self.sessions.insert(id, msg.addr);
-        self.rooms.entry(msg.room.clone()).or_insert_with(Vec::new).push(id);
-        id
+        let handle = self.room(&msg.room, ctx);
+        Self::send(
+            handle,
+            RoomOp::Join {
+                id,
+                outbox: msg.outbox,
+                close: msg.close,
+                user_id: msg.user_id,
+            },
+        );
+        MessageResult(Joined {
+            id,
+            room: handle.addr.clone(),
+        })
+    }
+}
+
+impl Handler<Ping> for ChatServer {
+    type Result = ();
+    fn handle(&mut self, _: Ping, _: &mut Context<Self>) {}
+}
+
+impl Handler<Shutdown> for ChatServer {
+    type Result = ResponseFuture<()>;
+    fn handle(&mut self, _: Shutdown, _: &mut Context<Self>) -> Self::Result {
+        tracing::info!(rooms = self.rooms.len(), "closing every session");
+        let drained: Vec<_> = self
+            .rooms
+            .values_mut()
+            .map(|handle| {
+                let (done, drained) = oneshot::channel();
+                Self::send(handle, RoomOp::Shutdown(done));
+                drained
+            })
+            .collect();
+        Box::pin(async move {
+            for room in drained {
+                // A room that stopped in the meantime had nothing left to drain
+                let _ = room.await;
+            }
+        })
+    }
+}
+
+impl Handler<ListRooms> for ChatServer {
+    type Result = ResponseFuture<Vec<RoomStats>>;
+    fn handle(&mut self, _: ListRooms, _: &mut Context<Self>) -> Self::Result {
+        let replies: Vec<_> = self
+            .rooms
+            .values_mut()
+            .map(|handle| {
+                let (reply, stats) = oneshot::channel();
+                Self::send(handle, RoomOp::Stats(reply));
+                stats
+            })
+            .collect();
+        Box::pin(async move {
+            let mut rooms = Vec::with_capacity(replies.len());
+            for stats in replies {
+                // A room that stopped in the meantime has nothing to report
+                if let Ok(stats) = stats.await {
+                    rooms.push(stats);
+                }
+            }
+            rooms.sort_by(|a, b| b.sessions.cmp(&a.sessions).then_with(|| a.name.cmp(&b.name)));
+            rooms
+        })
+    }
+}
+
+impl Handler<RoomIdle> for ChatServer {
+    type Result = bool;
+    fn handle(&mut self, msg: RoomIdle, _: &mut Context<Self>) -> bool {
+        // Anything sent after the room's last check is still in its mailbox
+        let drained = self.rooms.get(&msg.room).is_some_and(|handle| handle.sent == msg.received);
+        if drained {
+            self.rooms.remove(&msg.room);
+            metrics::ROOMS.set(self.rooms.len() as i64);
+        }
+        drained
+    }
+}
+
+impl Handler<Envelope> for ChatServer {
+    type Result = ();
+    fn handle(&mut self, msg: Envelope, _: &mut Context<Self>) {
+        self.handle_remote(msg.origin, msg.event);
+    }
+}
+
+impl Handler<PostMessage> for ChatServer {
+    type Result = ();
+    fn handle(&mut self, msg: PostMessage, ctx: &mut Context<Self>) {
+        let _entered = msg.span.enter();
+        self.forward(&msg.room, RoomOp::Post(msg.message), ctx);
     }
 }
 
-impl Handler<Disconnect> for ChatServer {
+impl Handler<DisconnectUser> for ChatServer {
     type Result = ();
-    fn handle(&mut self, msg: Disconnect, _: &mut Context<Self>) {
-        self.sessions.remove(&msg.id);
-        if let Some(ids) = self.rooms.get_mut(&msg.room) {
-            ids.retain(|&x| x != msg.id);
+    fn handle(&mut self, msg: DisconnectUser, _: &mut Context<Self>) {
+        match msg.room {
+            Some(room) => {
+                self.forward_existing(
+                    &room,
+                    RoomOp::RemoteDisconnect {
+                        user_id: msg.user_id,
+                        reason: msg.reason.clone(),
+                    },
+                );
+                self.shared.publish(ClusterEvent::Disconnect {
+                    room,
+                    user_id: msg.user_id,
+                    reason: msg.reason,
+                });
+            }
+            None => {
+                self.disconnect_everywhere(msg.user_id, &msg.reason);
+                self.shared.publish(ClusterEvent::DisconnectUser {
+                    user_id: msg.user_id,
+                    reason: msg.reason,
+                });
+            }
         }
     }
 }
 
-impl Handler<ClientMessage> for ChatServer {
+impl Handler<BannedWordsChanged> for ChatServer {
+    type Result = ();
+    fn handle(&mut self, msg: BannedWordsChanged, _: &mut Context<Self>) {
+        // A room that is not running loads the new list when it starts
+        self.forward_existing(&msg.room, RoomOp::BannedWordsChanged);
+        self.shared.publish(ClusterEvent::BannedWordsChanged { room: msg.room });
+    }
+}
+
+impl Handler<MessagesDeleted> for ChatServer {
     type Result = ();
-    fn handle(&mut self, msg: ClientMessage, _: &mut Context<Self>) {
-        self.broadcast(&msg.room, &msg.message);
+    fn handle(&mut self, msg: MessagesDeleted, ctx: &mut Context<Self>) {
+        self.forward(&msg.room, RoomOp::Deleted(msg.message_ids), ctx);
+    }
+}
+
+impl Handler<PollEvent> for ChatServer {
+    type Result = ();
+    fn handle(&mut self, msg: PollEvent, ctx: &mut Context<Self>) {
+        let _entered = msg.span.enter();
+        self.forward(
+            &msg.room,
+            RoomOp::Poll {
+                tally: msg.tally,
+                created: msg.created,
+            },
+            ctx,
+        );
     }
 }
 
 pub struct ChatSession {
     pub id: usize,
+    pub user_id: Option<Uuid>,
     pub room: String,
     pub server: Addr<ChatServer>,
     pub hb: Instant,
+    // Set once the registry has placed the session in its room
+    room_addr: Option<Addr<Room>>,
+    outbox: Option<Arc<Outbox>>,
+    outbox_limits: OutboxLimits,
+    // Newest chat message written to the socket; where a dropped client resumes
+    resume_after: NaiveDateTime,
+    heartbeat_interval: Duration,
+    client_timeout: Duration,
+    rate_limits: web::Data<RateLimits>,
+    // Users share one allowance across their sessions; anonymous sessions go by address
+    rate_key: String,
+    // Child of the upgrade request's span, so it carries that request's correlation id
+    span: Span,
+    frames_received: u64,
 }
 
 impl ChatSession {
-    pub fn new(room: String, server: Addr<ChatServer>) -> Self {
+    pub fn new(
+        room: String,
+        user_id: Option<Uuid>,
+        server: Addr<ChatServer>,
+        settings: &WebSocketSettings,
+        rate_limits: web::Data<RateLimits>,
+        client_addr: String,
+    ) -> Self {
+        let span = tracing::info_span!("ws_session", room = %room, user_id = user_id.map(tracing::field::display));
         ChatSession {
             id: 0,
+            user_id,
             room,
             server,
             hb: Instant::now(),
+            room_addr: None,
+            outbox: None,
+            outbox_limits: OutboxLimits::from_settings(settings),
+            resume_after: chrono::Utc::now().naive_utc(),
+            heartbeat_interval: settings.heartbeat_interval(),
+            client_timeout: settings.client_timeout(),
+            rate_limits,
+            rate_key: user_id.map_or(client_addr, |id| id.to_string()),
+            span,
+            frames_received: 0,
         }
     }
+    // Writes whatever the outbox holds; true if the client fell too far behind
+    fn write_queued(&mut self, ctx: &mut ws::WebsocketContext<Self>) -> bool {
+        let Some(outbox) = &self.outbox else { return false };
+        let drained = outbox.drain();
+        for frame in drained.frames {
+            if let Some(message_at) = frame.message_at {
+                self.resume_after = message_at;
+            }
+            ctx.text(frame.text.as_str());
+        }
+        drained.overflowed
+    }
+
     fn start_heartbeat(&self, ctx: &mut ws::WebsocketContext<Self>) {
-        ctx.run_interval(Duration::from_secs(5), |act, ctx| {
-            if Instant::now().duration_since(act.hb) > Duration::from_secs(10) {
+        ctx.run_interval(self.heartbeat_interval, |act, ctx| {
+            if Instant::now().duration_since(act.hb) > act.client_timeout {
+                metrics::HEARTBEAT_DISCONNECTS.inc();
+                act.span.in_scope(|| tracing::info!("client stopped answering pings"));
                 ctx.stop();
                 return;
             }
@@ -126,17 +623,27 @@ impl ChatSession {
 impl Actor for ChatSession {
     type Context = ws::WebsocketContext<Self>;
     fn started(&mut self, ctx: &mut Self::Context) {
+        metrics::WS_SESSIONS.inc();
+        self.span.in_scope(|| tracing::info!("session opened"));
         self.start_heartbeat(ctx);
-        let addr = ctx.address().recipient();
+        let outbox = Arc::new(Outbox::new(ctx.address().recipient(), self.outbox_limits));
+        self.outbox = Some(outbox.clone());
+        let close = ctx.address().recipient();
         self.server
             .send(Connect {
-                addr,
+                outbox,
+                close,
+                user_id: self.user_id,
                 room: self.room.clone(),
+                span: self.span.clone(),
             })
             .into_actor(self)
             .then(|res, act, ctx| {
                 match res {
-                    Ok(id) => act.id = id,
+                    Ok(joined) => {
+                        act.id = joined.id;
+                        act.room_addr = Some(joined.room);
+                    }
                     Err(_) => ctx.stop(),
                 }
                 actix::fut::ready(())
@@ -144,17 +651,55 @@ impl Actor for ChatSession {
             .wait(ctx);
     }
     fn stopped(&mut self, _: &mut Self::Context) {
-        self.server.do_send(Disconnect {
-            id: self.id,
-            room: self.room.clone(),
-        });
+        metrics::WS_SESSIONS.dec();
+        self.span
+            .in_scope(|| tracing::info!(frames = self.frames_received, "session closed"));
+        if let Some(outbox) = &self.outbox {
+            outbox.close();
+        }
+        if let Some(room) = &self.room_addr {
+            room.do_send(Disconnect { id: self.id });
+        }
     }
 }
 
-impl Handler<BroadcastMessage> for ChatSession {
+impl Handler<Flush> for ChatSession {
     type Result = ();
-    fn handle(&mut self, msg: BroadcastMessage, ctx: &mut Self::Context) {
-        ctx.text(msg.message);
+    fn handle(&mut self, _: Flush, ctx: &mut Self::Context) {
+        if self.write_queued(ctx) {
+            self.span
+                .in_scope(|| tracing::warn!(resume_after = %self.resume_after, "disconnecting slow consumer"));
+            // Fetch GET /groups/{id}/messages?after=<resume_after> after reconnecting
+            let reason = json!({"code": "slow_consumer", "resume_after": self.resume_after});
+            ctx.close(Some(ws::CloseReason {
+                code: ws::CloseCode::Again,
+                description: Some(reason.to_string()),
+            }));
+            ctx.stop();
+        }
+    }
+}
+
+impl Handler<CloseSession> for ChatSession {
+    type Result = ();
+    fn handle(&mut self, msg: CloseSession, ctx: &mut Self::Context) {
+        let reason = if msg.restart {
+            // Frames already queued go out before the close frame
+            self.write_queued(ctx);
+            // Same resume hint as for slow consumers, so no messages are missed in between
+            let reason = json!({"code": "server_restart", "message": msg.reason, "resume_after": self.resume_after});
+            ws::CloseReason {
+                code: ws::CloseCode::Restart,
+                description: Some(reason.to_string()),
+            }
+        } else {
+            ws::CloseReason {
+                code: ws::CloseCode::Policy,
+                description: Some(msg.reason),
+            }
+        };
+        ctx.close(Some(reason));
+        ctx.stop();
     }
 }
 
@@ -169,12 +714,28 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for ChatSession {
                 self.hb = Instant::now();
             }
             Ok(ws::Message::Text(text)) => {
-                self.server.do_send(ClientMessage {
-                    room: self.room.clone(),
-                    message: text.trim().to_string(),
-                });
+                self.frames_received += 1;
+                let span = tracing::info_span!(parent: &self.span, "ws_frame", seq = self.frames_received);
+                if let Err(retry_after) = self.rate_limits.messages.check(&self.rate_key) {
+                    span.in_scope(|| tracing::debug!(?retry_after, "frame rate limited"));
+                    let event = ServerEvent::RateLimited {
+                        retry_after_ms: retry_after.as_millis() as u64,
+                    };
+                    if let Some(frame) = event.frame() {
+                        ctx.text(frame.text);
+                    }
+                    return;
+                }
+                if let Some(room) = &self.room_addr {
+                    room.do_send(ClientMessage {
+                        id: self.id,
+                        user_id: self.user_id,
+                        message: text.trim().to_string(),
+                        span,
+                    });
+                }
             }
-            Ok(ws::Message::Binary(_)) => println!("Unexpected binary"),
+            Ok(ws::Message::Binary(_)) => tracing::debug!("ignoring binary frame"),
             Ok(ws::Message::Close(reason)) => {
                 ctx.close(reason);
                 ctx.stop();
@@ -184,29 +745,296 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for ChatSession {
     }
 }
 
+/// Outbound queue figures for every session on this instance.
+pub async fn outbox_stats() -> HttpResponse {
+    HttpResponse::Ok().json(outbox::stats())
+}
+
+fn query_param(req: &HttpRequest, key: &str) -> Option<String> {
+    req.uri().query().and_then(|q| {
+        q.split('&').find_map(|param| {
+            let mut parts = param.split('=');
+            match (parts.next(), parts.next()) {
+                (Some(k), Some(val)) if k == key => Some(val.to_owned()),
+                _ => None,
+            }
+        })
+    })
+}
+
+// Who a chat session in `room` belongs to, from its session token; None for anonymous sessions.
+// Rooms named after a group are for its members only.
+fn admit_user(
+    token: Option<&str>,
+    room: &str,
+    signer: &SessionSigner,
+    auth: &AuthSettings,
+    conn: &mut PgConnection,
+) -> Result<Option<Uuid>, ApiError> {
+    let group_id = crate::schema::groups::table
+        .filter(crate::schema::groups::name.eq(room))
+        .select(crate::schema::groups::id)
+        .first::<Uuid>(conn)
+        .optional()?;
+    let Some(token) = token else {
+        // Nobody to hold to verification, suspensions, bans or membership
+        if auth.require_email_verification {
+            return Err(ApiError::Unauthorized("Sign in to chat"));
+        }
+        if group_id.is_some() {
+            return Err(ApiError::Unauthorized("Sign in to chat in a group room"));
+        }
+        return Ok(None);
+    };
+    let user = signer.authenticate(token, conn)?;
+    // Bots prove who they are with an API key
+    if user.is_bot {
+        return Err(ApiError::Forbidden("Bots connect with an API key"));
+    }
+    verification::require_verified(user.id, auth, conn)?;
+    if let Some(group_id) = group_id {
+        if commands::is_banned(user.id, group_id, conn)? {
+            return Err(ApiError::Forbidden("You are banned from this group"));
+        }
+        if groups::member_role(user.id, group_id, conn)?.is_none() {
+            return Err(ApiError::Forbidden("Only members can chat in this group"));
+        }
+    }
+    Ok(Some(user.id))
+}
+
+/// Opens a chat session. Bots send `Authorization: Bearer <API key>`; people send
+/// the session token from `/login` as `?token=` or as the bearer token. Without
+/// one the session is anonymous, which `auth.require_email_verification` forbids.
+/// Group rooms admit members of the group only.
 pub async fn ws_index(
     req: HttpRequest,
     stream: web::Payload,
+    pool: web::Data<DbPool>,
     srv: web::Data<Addr<ChatServer>>,
+    settings: web::Data<Settings>,
+    sessions: web::Data<SessionSigner>,
+    rate_limits: web::Data<RateLimits>,
 ) -> Result<HttpResponse, actix_web::Error> {
-    let room = req
-        .uri()
-        .query()
-        .and_then(|q| {
-            q.split('&').find_map(|param| {
-                let mut parts = param.split('=');
-                if let (Some(key), Some(val)) = (parts.next(), parts.next()) {
-                    if key == "room" {
-                        Some(val.to_owned())
-                    } else {
-                        None
-                    }
-                } else {
-                    None
-                }
-            })
+    let client_addr = rate_limits.client_addr(&req);
+    let room = query_param(&req, "room").unwrap_or_else(|| "general".to_owned());
+    let bearer = bots::bearer_token(&req).map(str::to_owned);
+    // Bots may only join rooms of groups they belong to
+    if let Some(key) = bearer.clone().filter(|token| bots::is_api_key(token)) {
+        let bot_room = room.clone();
+        let bot = db::run(&pool, move |conn| {
+            let bot = bots::authenticate_key(&key, conn).ok_or(ApiError::Unauthorized("Invalid API key"))?;
+            let is_member = crate::schema::user_groups::table
+                .inner_join(crate::schema::groups::table)
+                .filter(crate::schema::user_groups::user_id.eq(bot.id))
+                .filter(crate::schema::groups::name.eq(&bot_room))
+                .count()
+                .get_result::<i64>(conn)?
+                > 0;
+            if !is_member {
+                return Err(ApiError::Forbidden("Bot is not a member of this group"));
+            }
+            Ok(bot)
         })
-        .unwrap_or_else(|| "general".to_owned());
-    let session = ChatSession::new(room, srv.get_ref().clone());
+        .await?;
+        let session = ChatSession::new(
+            room,
+            Some(bot.id),
+            srv.get_ref().clone(),
+            &settings.websocket,
+            rate_limits,
+            client_addr,
+        );
+        return ws::start(session, &req, stream);
+    }
+    // Anonymous sessions can chat but are not stored or allowed to vote
+    let token = query_param(&req, "token").or(bearer);
+    let (auth, user_room) = (settings.auth.clone(), room.clone());
+    let user_id = db::run(&pool, move |conn| {
+        admit_user(token.as_deref(), &user_room, &sessions, &auth, conn)
+    })
+    .await?;
+    let session = ChatSession::new(
+        room,
+        user_id,
+        srv.get_ref().clone(),
+        &settings.websocket,
+        rate_limits,
+        client_addr,
+    );
     ws::start(session, &req, stream)
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+
+    fn auth(require_email_verification: bool) -> AuthSettings {
+        AuthSettings {
+            jwt_secret: Some("a-secret-of-at-least-thirty-two-chars".to_owned()),
+            require_email_verification,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn anonymous_sessions_need_verification_turned_off() {
+        let Some(mut conn) = test_support::connection() else { return };
+        let signer = SessionSigner::new(&auth(true));
+        let result = admit_user(None, "lobby", &signer, &auth(true), &mut conn);
+        assert!(matches!(result, Err(ApiError::Unauthorized(_))));
+        assert!(matches!(admit_user(None, "lobby", &signer, &auth(false), &mut conn), Ok(None)));
+        // A bad token is never taken for an anonymous session
+        let result = admit_user(Some("not-a-token"), "lobby", &signer, &auth(false), &mut conn);
+        assert!(matches!(result, Err(ApiError::Unauthorized(_))));
+    }
+
+    #[test]
+    fn sessions_are_checked_for_verification_suspension_and_bots() {
+        let Some(mut conn) = test_support::connection() else { return };
+        let signer = SessionSigner::new(&auth(true));
+        let user = test_support::user(&mut conn);
+        let token = signer.issue(user.id, 0).unwrap();
+        assert_eq!(admit_user(Some(&token), "lobby", &signer, &auth(true), &mut conn).unwrap(), Some(user.id));
+
+        diesel::update(crate::schema::users::table.find(user.id))
+            .set(crate::schema::users::email_verified_at.eq(None::<NaiveDateTime>))
+            .execute(&mut conn)
+            .unwrap();
+        let result = admit_user(Some(&token), "lobby", &signer, &auth(true), &mut conn);
+        assert!(matches!(result, Err(ApiError::Forbidden(_))));
+        assert!(admit_user(Some(&token), "lobby", &signer, &auth(false), &mut conn).is_ok());
+
+        crate::admin::set_disabled(user.id, true, &mut conn).unwrap();
+        let result = admit_user(Some(&token), "lobby", &signer, &auth(false), &mut conn);
+        assert!(matches!(result, Err(ApiError::Forbidden(_))));
+
+        let owner = test_support::user(&mut conn);
+        let bot = test_support::user(&mut conn);
+        diesel::update(crate::schema::users::table.find(bot.id))
+            .set((
+                crate::schema::users::is_bot.eq(true),
+                crate::schema::users::owner_id.eq(owner.id),
+            ))
+            .execute(&mut conn)
+            .unwrap();
+        let token = signer.issue(bot.id, 0).unwrap();
+        let result = admit_user(Some(&token), "lobby", &signer, &auth(false), &mut conn);
+        assert!(matches!(result, Err(ApiError::Forbidden(_))));
+    }
+
+    #[test]
+    fn banned_users_cannot_reopen_a_session_in_the_group() {
+        let Some(mut conn) = test_support::connection() else { return };
+        let signer = SessionSigner::new(&auth(true));
+        let owner = test_support::user(&mut conn);
+        let user = test_support::user(&mut conn);
+        let group = test_support::group(owner.id, &mut conn);
+        test_support::join(user.id, group.id, "member", &mut conn);
+        let token = signer.issue(user.id, 0).unwrap();
+        assert!(admit_user(Some(&token), &group.name, &signer, &auth(true), &mut conn).is_ok());
+
+        test_support::ban(user.id, group.id, &mut conn);
+        let result = admit_user(Some(&token), &group.name, &signer, &auth(true), &mut conn);
+        assert!(matches!(result, Err(ApiError::Forbidden("You are banned from this group"))));
+        // Other rooms are unaffected
+        assert!(admit_user(Some(&token), "lobby", &signer, &auth(true), &mut conn).is_ok());
+    }
+
+    #[test]
+    fn group_rooms_are_for_members() {
+        let Some(mut conn) = test_support::connection() else { return };
+        let signer = SessionSigner::new(&auth(false));
+        let (owner, outsider) = (test_support::user(&mut conn), test_support::user(&mut conn));
+        let group = test_support::group(owner.id, &mut conn);
+        let token = |user: &crate::models::User| signer.issue(user.id, 0).unwrap();
+
+        let result = admit_user(Some(&token(&outsider)), &group.name, &signer, &auth(false), &mut conn);
+        assert!(matches!(result, Err(ApiError::Forbidden("Only members can chat in this group"))));
+        let result = admit_user(None, &group.name, &signer, &auth(false), &mut conn);
+        assert!(matches!(result, Err(ApiError::Unauthorized(_))));
+        let result = admit_user(Some(&token(&owner)), &group.name, &signer, &auth(false), &mut conn);
+        assert_eq!(result.unwrap(), Some(owner.id));
+        // Rooms without a group stay open
+        assert!(admit_user(Some(&token(&outsider)), "lobby", &signer, &auth(false), &mut conn).is_ok());
+    }
+
+    #[test]
+    fn api_keys_are_told_apart_from_session_tokens() {
+        assert!(bots::is_api_key("nck_abc_def"));
+        assert!(!bots::is_api_key("eyJhbGciOiJIUzI1NiJ9.e30.sig"));
+        assert!(!bots::is_api_key("nckabc"));
+    }
+
+    #[actix_web::test]
+    async fn peer_events_reach_local_rooms_only() {
+        let Some(pool) = test_support::pool() else { return };
+        let server = test_support::chat_server(pool, FilterChain::default());
+        let session = test_support::TestSession::connect(&server, "lobby", None).await;
+        session.frames().await;
+
+        let (peer, remote_user) = (Uuid::new_v4(), Uuid::new_v4());
+        let from_peer = |event| Envelope { origin: peer, event };
+        server
+            .send(from_peer(ClusterEvent::Presence {
+                room: "lobby".to_owned(),
+                user_ids: vec![remote_user],
+            }))
+            .await
+            .unwrap();
+        for room in ["lobby", "elsewhere"] {
+            let frame = Arc::new(Frame::text(format!("from afar to {}", room)));
+            let event = ClusterEvent::Broadcast { room: room.to_owned(), frame };
+            server.send(from_peer(event)).await.unwrap();
+        }
+
+        let frames = session.frames().await;
+        assert!(frames.iter().any(|frame| frame == "from afar to lobby"));
+        assert!(!frames.iter().any(|frame| frame == "from afar to elsewhere"));
+        // Rooms are not started for events from elsewhere
+        let rooms = server.send(ListRooms).await.unwrap();
+        assert_eq!(rooms.len(), 1);
+        assert_eq!((rooms[0].name.as_str(), rooms[0].remote_users), ("lobby", 1));
+
+        server
+            .send(from_peer(ClusterEvent::Presence {
+                room: "lobby".to_owned(),
+                user_ids: Vec::new(),
+            }))
+            .await
+            .unwrap();
+        assert_eq!(server.send(ListRooms).await.unwrap()[0].remote_users, 0);
+    }
+
+    #[actix_web::test]
+    async fn rooms_are_separate_and_stop_once_idle() {
+        let Some(pool) = test_support::pool() else { return };
+        let mut settings = Settings::default();
+        settings.websocket.room_idle_secs = 1;
+        settings.websocket.room_threads = 2;
+        let server = ChatServer::new(
+            pool,
+            CommandRegistry::default(),
+            FilterChain::default(),
+            60,
+            Box::new(crate::pubsub::LocalOnly),
+            &settings,
+        )
+        .start();
+        let first = test_support::TestSession::connect(&server, "first", None).await;
+        let second = test_support::TestSession::connect(&server, "second", None).await;
+        first.send("only for the first room");
+        assert!(first.frames().await.iter().any(|frame| frame == "only for the first room"));
+        assert!(second.frames().await.is_empty());
+        let names: Vec<String> = server.send(ListRooms).await.unwrap().into_iter().map(|room| room.name).collect();
+        assert_eq!(names, ["first", "second"]);
+
+        first.joined.room.do_send(Disconnect { id: first.joined.id });
+        // Two idle checks, a second apart, before the room asks to stop
+        actix_web::rt::time::sleep(Duration::from_millis(2500)).await;
+        let rooms = server.send(ListRooms).await.unwrap();
+        assert_eq!(rooms.len(), 1);
+        assert_eq!((rooms[0].name.as_str(), rooms[0].sessions), ("second", 1));
+    }
+}
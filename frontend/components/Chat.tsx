@@ -82,7 +82,8 @@ export default function Chat({ room, currentGroup, onSaveCommunity, onDeleteComm
   }, [messages]);
 
   useEffect(() => {
-    const websocket = new WebSocket(`ws://localhost:8080/ws?room=${room}`);
+    if (!currentUser?.token) return;
+    const websocket = new WebSocket(`ws://localhost:8080/ws?room=${room}&token=${currentUser.token}`);
     websocket.onopen = () => console.log('Connected to WebSocket');
     websocket.onmessage = (event) => {
       const text = event.data;
@@ -90,7 +91,7 @@ export default function Chat({ room, currentGroup, onSaveCommunity, onDeleteComm
     };
     setWs(websocket);
     return () => websocket.close();
-  }, [room]);
+  }, [room, currentUser?.token]);
 
   const { user } = useAuth();
 
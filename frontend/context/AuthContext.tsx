@@ -5,6 +5,7 @@ type User = {
   id: string;
   username: string;
   email: string;
+  token?: string; // Session token from /login; signing up does not hand one out
 };
 
 type AuthContextType = {
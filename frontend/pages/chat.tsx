@@ -44,6 +44,7 @@ export default function ChatPage() {
       method: "PUT",
       headers: {
         "Content-Type": "application/json",
+        Authorization: `Bearer ${user?.token}`,
       },
       body: JSON.stringify(updatedCommunity),
     });
@@ -62,6 +63,9 @@ export default function ChatPage() {
     try {
       const response = await fetch(`http://localhost:8080/groups/${communityId}`, {
         method: 'DELETE',
+        headers: {
+          Authorization: `Bearer ${user?.token}`,
+        },
       });
       console.log("DELETE response status:", response.status);
       if (response.ok) {
@@ -90,11 +94,11 @@ export default function ChatPage() {
       method: 'POST',
       headers: {
         'Content-Type': 'application/json',
+        Authorization: `Bearer ${user.token}`,
       },
       body: JSON.stringify({
         name: form.name,
         description: form.description || "",
-        owner: user.id,
         members: form.members || [],
       }),
     });